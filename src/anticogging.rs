@@ -0,0 +1,55 @@
+use std::io::Error;
+use std::io::ErrorKind;
+use std::path::Path;
+
+use crate::controller_interface::ANTICOGGING_TABLE_LEN;
+
+fn is_binary(path : &Path) -> bool {
+    path.extension().map_or(false, |ext| ext.eq_ignore_ascii_case("bin"))
+}
+
+/// Saves the table as little endian `f32`s if the path ends in `.bin`, otherwise as CSV.
+pub fn save_table(path : &Path, table : &[f32]) -> std::io::Result<()> {
+    if is_binary(path) {
+        let bytes = table.iter()
+            .flat_map(|v| v.to_le_bytes().to_vec())
+            .collect::<Vec<u8>>();
+
+        std::fs::write(path, bytes)
+    } else {
+        let mut text = String::from("index,torque\n");
+
+        for (i, v) in table.iter().enumerate() {
+            text.push_str(&format!("{},{}\n", i, v));
+        }
+
+        std::fs::write(path, text)
+    }
+}
+
+pub fn load_table(path : &Path) -> std::io::Result<Vec<f32>> {
+    let table = if is_binary(path) {
+        std::fs::read(path)?
+            .chunks_exact(4)
+            .map(|b| f32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect::<Vec<_>>()
+    } else {
+        std::fs::read_to_string(path)?
+            .lines()
+            .skip(1)
+            .filter(|line| !line.trim().is_empty())
+            .map(|line| {
+                line.split(',')
+                    .last()
+                    .and_then(|v| v.trim().parse::<f32>().ok())
+                    .ok_or_else(|| Error::new(ErrorKind::InvalidData, format!("Invalid line: {}", line)))
+            })
+            .collect::<Result<Vec<_>, _>>()?
+    };
+
+    if table.len() != ANTICOGGING_TABLE_LEN {
+        return Err(Error::new(ErrorKind::InvalidData, format!("Expected {} entries, found {}", ANTICOGGING_TABLE_LEN, table.len())));
+    }
+
+    Ok(table)
+}
@@ -0,0 +1,39 @@
+use std::path::Path;
+use std::path::PathBuf;
+use std::time::SystemTime;
+
+use crate::controller_interface::ServoConfig;
+
+pub fn save_config(path : &Path, config : &ServoConfig) -> std::io::Result<()> {
+    let text = toml::to_string_pretty(config)
+        .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))?;
+
+    std::fs::write(path, text)
+}
+
+/// Reads a config written by `save_config`. Every field must be present as a float and unknown
+/// fields are rejected, so a malformed file is never written to the controller.
+pub fn load_config(path : &Path) -> Result<ServoConfig, String> {
+    let text = std::fs::read_to_string(path).map_err(|e| e.to_string())?;
+
+    let config : ServoConfig = toml::from_str(&text).map_err(|e| e.to_string())?;
+
+    if let Some((name, _)) = config.fields().iter().find(|(_, value)| !value.is_finite()) {
+        return Err(format!("{} is not a finite number", name));
+    }
+
+    Ok(config)
+}
+
+/// Writes `config` into `dir` under a name with the current unix time, creating the directory if
+/// needed. Returns the path written.
+pub fn save_timestamped_config(dir : &Path, config : &ServoConfig) -> std::io::Result<PathBuf> {
+    std::fs::create_dir_all(dir)?;
+
+    let timestamp = SystemTime::now().duration_since(std::time::UNIX_EPOCH).unwrap_or_default().as_secs();
+    let path = dir.join(format!("servo-config-{}.toml", timestamp));
+
+    save_config(&path, config)?;
+
+    Ok(path)
+}
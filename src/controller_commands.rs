@@ -1,5 +1,5 @@
 use crate::controller_interface::ControllerPointers;
-use crate::stlink::STLink;
+use crate::memory_access::MemoryAccess;
 
 const SWD_COMMAND_BUFFER_LEN: usize = 32;
 
@@ -28,27 +28,214 @@ pub enum Command {
     SaveServoConfig,
     SetMotionProfile {
         profile : u32,
-    }
+    },
+    /// Not in the original firmware, only sent when `PointerExtensions::staged_config` says the
+    /// firmware has a staging region.
+    ApplyStagedConfig,
+    /// Needs a firmware change, no released firmware has an entry point for starting the
+    /// calibration. Only sent to firmware whose `PointerExtensions::command_count` includes it.
+    StartAnticoggingCalibration,
 }
 
+/// Variants the original firmware handles, everything from `ApplyStagedConfig` on is newer.
+pub const BASE_COMMAND_COUNT : usize = 15;
+
+/// Every `Command` variant by name, with the label of its field if it has one. Keep in the same
+/// order as the enum.
+pub const COMMAND_VARIANTS : [(&str, Option<&str>); 17] = [
+    ("MotorStop", None),
+    ("MotorStart", None),
+    ("SetStepDirectionControl", None),
+    ("SetPositionControl", None),
+    ("SetVelocityControl", None),
+    ("SetTorqueControl", None),
+    ("ClearFaultState", None),
+    ("PositionCommand", Some("position")),
+    ("VelocityCommand", Some("velocity")),
+    ("TorqueCommand", Some("torque")),
+    ("FindUpperMotionLimit", None),
+    ("FindLowerMotionLimit", None),
+    ("LoadServoConfig", None),
+    ("SaveServoConfig", None),
+    ("SetMotionProfile", Some("profile")),
+    ("ApplyStagedConfig", None),
+    ("StartAnticoggingCalibration", None),
+];
+
+impl Command {
+    /// Builds the variant at `index` of `COMMAND_VARIANTS`, using `value` for its field.
+    pub fn from_variant_index(index : usize, value : f32) -> Option<Command> {
+        let cmd = match index {
+            0 => Command::MotorStop,
+            1 => Command::MotorStart,
+            2 => Command::SetStepDirectionControl,
+            3 => Command::SetPositionControl,
+            4 => Command::SetVelocityControl,
+            5 => Command::SetTorqueControl,
+            6 => Command::ClearFaultState,
+            7 => Command::PositionCommand { position : value },
+            8 => Command::VelocityCommand { velocity : value },
+            9 => Command::TorqueCommand { torque : value },
+            10 => Command::FindUpperMotionLimit,
+            11 => Command::FindLowerMotionLimit,
+            12 => Command::LoadServoConfig,
+            13 => Command::SaveServoConfig,
+            14 => Command::SetMotionProfile { profile : value.max(0.0) as u32 },
+            15 => Command::ApplyStagedConfig,
+            16 => Command::StartAnticoggingCalibration,
+            _ => return None,
+        };
+
+        Some(cmd)
+    }
+
+    /// Position of the variant in the enum, and so in `COMMAND_VARIANTS`.
+    pub fn variant_index(&self) -> usize {
+        match self {
+            Command::MotorStop => 0,
+            Command::MotorStart => 1,
+            Command::SetStepDirectionControl => 2,
+            Command::SetPositionControl => 3,
+            Command::SetVelocityControl => 4,
+            Command::SetTorqueControl => 5,
+            Command::ClearFaultState => 6,
+            Command::PositionCommand{..} => 7,
+            Command::VelocityCommand{..} => 8,
+            Command::TorqueCommand{..} => 9,
+            Command::FindUpperMotionLimit => 10,
+            Command::FindLowerMotionLimit => 11,
+            Command::LoadServoConfig => 12,
+            Command::SaveServoConfig => 13,
+            Command::SetMotionProfile{..} => 14,
+            Command::ApplyStagedConfig => 15,
+            Command::StartAnticoggingCalibration => 16,
+        }
+    }
+
+    /// Commands that can set the motor moving, which an E-STOP holds back until it's cleared.
+    pub fn moves_motor(&self) -> bool {
+        matches!(self,
+            Command::MotorStart |
+            Command::PositionCommand{..} |
+            Command::VelocityCommand{..} |
+            Command::TorqueCommand{..} |
+            Command::FindUpperMotionLimit |
+            Command::FindLowerMotionLimit |
+            Command::StartAnticoggingCalibration)
+    }
+}
 
 #[derive(Debug, Clone)]
 #[repr(C)]
 pub struct CommandBufferInfo {
-    front : u32,
-    back : u32,
-    capacity : u32,
-    data_addr : u32,
+    pub front : u32,
+    pub back : u32,
+    pub capacity : u32,
+    pub data_addr : u32,
 }
 
-pub fn send_command(link : &mut STLink, ptrs : &ControllerPointers, cmd : Command) -> Result<(), ()> {
-    let command_buffer_info = link.read_struct::<CommandBufferInfo>(ptrs.command_buffer_addr);
+impl CommandBufferInfo {
+    /// Commands queued and not yet taken by the firmware.
+    pub fn queued(&self) -> u32 {
+        if self.capacity == 0 {
+            return 0;
+        }
+
+        // read while the firmware is resetting or before it's set up, the indices can be anything
+        self.back.wrapping_add(self.capacity).wrapping_sub(self.front) % self.capacity
+    }
+
+    fn is_valid(&self) -> bool {
+        self.front < self.capacity && self.back < self.capacity
+    }
+
+    /// One slot is always left empty to tell a full ring from an empty one.
+    pub fn usable_capacity(&self) -> u32 {
+        self.capacity.saturating_sub(1)
+    }
+}
+
+/// Queues `cmd` in the firmware's command buffer. Returns `Ok(false)` if the buffer is full.
+pub fn send_command<M : MemoryAccess>(link : &mut M, ptrs : &ControllerPointers, cmd : Command) -> Result<bool, rusb::Error> {
+    let command_buffer_info = link.read_struct::<CommandBufferInfo>(ptrs.command_buffer_addr)?;
+
+    // a ring that isn't set up can't take anything, and writing at its `back` could land anywhere
+    if !command_buffer_info.is_valid() {
+        return Ok(false);
+    }
 
     if (command_buffer_info.back + 1) % command_buffer_info.capacity != command_buffer_info.front {
-        link.write_struct_array_offset(command_buffer_info.data_addr, command_buffer_info.back, &[cmd]);
-        link.write_struct_array_offset(ptrs.command_buffer_addr, 1, &[(command_buffer_info.back + 1) % command_buffer_info.capacity]);
-        Ok(())
+        link.write_struct_array_offset(command_buffer_info.data_addr, command_buffer_info.back, &[cmd])?;
+        link.write_struct_array_offset(ptrs.command_buffer_addr, 1, &[(command_buffer_info.back + 1) % command_buffer_info.capacity])?;
+        Ok(true)
     } else {
-        Err(())
+        Ok(false)
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::controller_interface::CONFIG_ADDR_ADDR;
+    use crate::mock_probe::MockStlink;
+
+    fn pointers(link : &mut MockStlink) -> ControllerPointers {
+        let addr = link.read_struct::<u32>(CONFIG_ADDR_ADDR).unwrap();
+        link.read_struct::<ControllerPointers>(addr).unwrap()
+    }
+
+    #[test]
+    fn full_command_buffer_refuses_commands() {
+        let mut link = MockStlink::paused();
+        let ptrs = pointers(&mut link);
+        let info = link.read_struct::<CommandBufferInfo>(ptrs.command_buffer_addr).unwrap();
+
+        for _ in 0..info.usable_capacity() {
+            assert!(send_command(&mut link, &ptrs, Command::MotorStop).unwrap());
+        }
+        assert!(!send_command(&mut link, &ptrs, Command::MotorStart).unwrap());
+
+        let info = link.read_struct::<CommandBufferInfo>(ptrs.command_buffer_addr).unwrap();
+        assert_eq!(info.queued(), info.usable_capacity());
+    }
+
+    #[test]
+    fn command_buffer_wraps_around() {
+        let mut link = MockStlink::paused();
+        let ptrs = pointers(&mut link);
+        let mut info = link.read_struct::<CommandBufferInfo>(ptrs.command_buffer_addr).unwrap();
+
+        // as if the firmware had consumed everything up to the last slot
+        info.front = info.capacity - 1;
+        info.back = info.capacity - 1;
+        link.write_struct(ptrs.command_buffer_addr, info.clone()).unwrap();
+
+        assert!(send_command(&mut link, &ptrs, Command::PositionCommand{position : 1.0}).unwrap());
+        assert!(send_command(&mut link, &ptrs, Command::PositionCommand{position : 2.0}).unwrap());
+
+        let after = link.read_struct::<CommandBufferInfo>(ptrs.command_buffer_addr).unwrap();
+        assert_eq!(after.back, 1);
+        assert_eq!(after.queued(), 2);
+
+        let wrapped = link.read_struct::<Command>(info.data_addr).unwrap();
+        assert!(matches!(wrapped, Command::PositionCommand{position} if position == 2.0));
+    }
+
+    #[test]
+    fn uninitialised_command_buffer() {
+        let mut link = MockStlink::paused();
+        let ptrs = pointers(&mut link);
+
+        let garbage = CommandBufferInfo { front : u32::MAX, back : 3, capacity : 0, data_addr : 0 };
+        assert_eq!(garbage.queued(), 0);
+
+        link.write_struct(ptrs.command_buffer_addr, garbage).unwrap();
+        link.writes.clear();
+
+        assert!(!send_command(&mut link, &ptrs, Command::MotorStart).unwrap());
+        assert!(link.writes.is_empty());
+
+        let garbage = CommandBufferInfo { front : 2, back : u32::MAX, capacity : 8, data_addr : 0 };
+        assert!(garbage.queued() < 8);
+    }
+}
@@ -1,14 +1,42 @@
+use std::collections::VecDeque;
+use std::mem::size_of;
 use std::sync::Arc;
 use std::sync::atomic::AtomicBool;
+use std::time::Duration;
+use std::time::Instant;
+use std::time::SystemTime;
 use parking_lot::Mutex;
+use serde::Deserialize;
+use serde::Serialize;
 
 use crate::controller_commands::Command;
+use crate::controller_commands::BASE_COMMAND_COUNT;
+use crate::controller_commands::CommandBufferInfo;
 use crate::controller_commands::send_command;
-use crate::stlink::STLink;
+use crate::debug_probe::DebugProbe;
+use crate::debug_probe::read_flash_struct;
+use crate::firmware_loader::*;
+use crate::memory_access::MemoryAccess;
+use crate::rate_limiter::CommandRateLimiter;
+use crate::rate_limiter::CommandRateLimits;
+use crate::stlink::LinkTestResult;
+use crate::stlink::NrstMode;
+use crate::stlink::SELF_TEST_WORDS;
+use crate::stlink::StlinkMode;
+use crate::stlink::StlinkDebugApiv2SwdFreq;
+use crate::stlink::SWD_FREQUENCIES;
+use crate::swd_adapter::AdaptiveSwdSettings;
+use crate::swd_adapter::SwdAdapter;
+use crate::trigger::Trigger;
 
-const MAGIC : [u8; 7] = [0x54, 0xA4, 0x2F, 0x6F, 0x07, 0x8A, 0x48];
+pub const MAGIC : [u8; 7] = [0x54, 0xA4, 0x2F, 0x6F, 0x07, 0x8A, 0x48];
 
-const CONFIG_ADDR_ADDR : u32 = 0x2000_0000;
+/// Magic of firmware that follows its `ControllerPointers` with `PointerExtensions`. With `MAGIC`
+/// the words after the pointers are unrelated RAM.
+pub const MAGIC_EXTENDED : [u8; 7] = [0x54, 0xA4, 0x2F, 0x6F, 0x07, 0x8A, 0x49];
+
+/// Holds the address of the firmware's `ControllerPointers`.
+pub const CONFIG_ADDR_ADDR : u32 = 0x2000_0000;
 
 #[derive(Debug, Clone)]
 #[repr(C)]
@@ -22,6 +50,82 @@ pub struct ControllerPointers {
     pub command_buffer_addr : u32,
 }
 
+impl ControllerPointers {
+    pub fn magic_valid(&self) -> bool {
+        self.magic == MAGIC || self.has_extensions()
+    }
+
+    pub fn has_extensions(&self) -> bool {
+        self.magic == MAGIC_EXTENDED
+    }
+
+    /// Where the firmware's `PointerExtensions` are, if it has them.
+    pub fn extensions_addr(pointers_addr : u32) -> u32 {
+        pointers_addr + size_of::<ControllerPointers>() as u32
+    }
+}
+
+/// Exports added after `ControllerPointers`, only present in firmware using `MAGIC_EXTENDED`.
+/// Fields are only ever appended, and `version` is the number of fields after it the firmware
+/// fills in, so a newer tool can tell which ones an older firmware doesn't have.
+#[derive(Debug, Clone, Default)]
+#[repr(C)]
+pub struct PointerExtensions {
+    pub version : u32,
+    /// Where a full `ServoConfig` is written before `Command::ApplyStagedConfig` swaps it in, zero
+    /// if the firmware can't stage configs.
+    pub staged_config_addr : u32,
+    /// Incremented by the firmware every control cycle.
+    pub heartbeat : u32,
+    /// Byte sizes of the shared structs as compiled into the firmware.
+    pub servo_config_size : u32,
+    pub servo_state_size : u32,
+    pub sample_point_size : u32,
+    /// Number of `Command` variants the firmware handles.
+    pub command_count : u32,
+}
+
+impl PointerExtensions {
+    // `index` counts from the field after `version`
+    fn has_field(&self, index : u32) -> bool {
+        self.version > index
+    }
+
+    pub fn has_heartbeat(&self) -> bool {
+        self.has_field(1)
+    }
+
+    pub fn command_count(&self) -> usize {
+        if self.has_field(5) { self.command_count as usize } else { BASE_COMMAND_COUNT }
+    }
+
+    /// Describes every shared struct whose size differs between the host and the firmware. Sizes
+    /// the firmware doesn't report aren't checked.
+    pub fn struct_size_mismatches(&self) -> Vec<String> {
+        if !self.has_field(4) {
+            return vec![];
+        }
+
+        [
+            ("ServoConfig", size_of::<ServoConfig>(), self.servo_config_size),
+            ("ServoState", size_of::<ServoState>(), self.servo_state_size),
+            ("OscilloscopeSamplePoint", size_of::<OscilloscopeSamplePoint>(), self.sample_point_size),
+        ].iter()
+            .filter(|(_, host, firmware)| *host as u32 != *firmware)
+            .map(|(name, host, firmware)| format!("{} is {} bytes on the host but {} bytes in the firmware", name, host, firmware))
+            .collect()
+    }
+
+    /// Staging address, only reported by firmware that also handles `Command::ApplyStagedConfig`.
+    pub fn staged_config(&self) -> Option<u32> {
+        if self.has_field(0) && self.staged_config_addr != 0 {
+            Some(self.staged_config_addr)
+        } else {
+            None
+        }
+    }
+}
+
 
 pub const OFFSET_POSITION_GAIN               : u32 = 0;
 pub const OFFSET_VELOCITY_GAIN               : u32 = 1;
@@ -38,7 +142,13 @@ pub const OFFSET_INERTIA                     : u32 = 11;
 pub const OFFSET_TORQUE_BANDWIDTH            : u32 = 12;
 pub const OFFSET_VEL_PLLKI                   : u32 = 13;
 
-#[derive(Debug, Clone)]
+// word offsets into `ServoState`
+pub const STATE_OFFSET_MAX_VEL_ABS_OBS       : u32 = 11;
+pub const STATE_OFFSET_ENCODER_OFFSET        : u32 = 12;
+pub const STATE_OFFSET_STEP_DIR_OFFSET       : u32 = 13;
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 #[repr(C)]
 pub struct ServoConfig {
     pub position_gain : f32,
@@ -55,7 +165,14 @@ pub struct ServoConfig {
     pub inertia : f32,
     pub torque_bandwidth : f32,
     pub vel_pllki : f32,
-    // pub antcogging_torque : [f32; 512],
+    /// Not saved with the rest of the config, the table has its own export and loading a config
+    /// file keeps the controller's calibration.
+    #[serde(skip, default = "empty_anticogging_table")]
+    pub antcogging_torque : [f32; ANTICOGGING_TABLE_LEN],
+}
+
+fn empty_anticogging_table() -> [f32; ANTICOGGING_TABLE_LEN] {
+    [0.0; ANTICOGGING_TABLE_LEN]
 }
 
 impl Default for ServoConfig {
@@ -75,12 +192,33 @@ impl Default for ServoConfig {
             inertia: 0.0,
             torque_bandwidth: 0.0,
             vel_pllki: 0.0,
-            // antcogging_torque: [0.0; 512],
+            antcogging_torque: empty_anticogging_table(),
         }
     }
 }
 
-#[derive(Debug, Clone)]
+impl ServoConfig {
+    pub fn fields(&self) -> [(&'static str, f32); 14] {
+        [
+            ("Position Gain",             self.position_gain),
+            ("Velocity Gain",             self.velocity_gain),
+            ("Velocity Integrator Gain",  self.velocity_integrator_gain),
+            ("Velocity Integrator Limit", self.velocity_integrator_max_abs),
+            ("Index Scan Speed",          self.index_scan_speed),
+            ("Steps Per Turn",            self.steps_per_turn),
+            ("Velocity Limit",            self.vel_max_abs),
+            ("Torque Limit",              self.tor_max_abs),
+            ("Max Position Step",         self.max_pos_step),
+            ("Input Filter Kp",           self.input_filt_kp),
+            ("Input Filter Ki",           self.input_filt_ki),
+            ("Inertia",                   self.inertia),
+            ("Torque Bandwidth",          self.torque_bandwidth),
+            ("Velocity PLL Ki",           self.vel_pllki),
+        ]
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
 #[repr(C)]
 pub enum ServoControlState {
   Uninit,
@@ -95,6 +233,15 @@ pub enum ServoControlState {
   EnabledTorque,
 }
 
+impl ServoControlState {
+    pub fn is_motor_active(&self) -> bool {
+        match self {
+            ServoControlState::Uninit | ServoControlState::Disabled => false,
+            _ => true,
+        }
+    }
+}
+
 impl Default for ServoControlState {
     fn default() -> Self {
         ServoControlState::Uninit
@@ -130,6 +277,14 @@ pub struct ServoState {
     pub anticogging_returning : bool,
 }
 
+impl ServoState {
+    /// A failed read on the target side leaves garbage rather than returning an error, which
+    /// usually shows up as a non-finite measurement.
+    pub fn looks_corrupt(&self) -> bool {
+        ![self.position, self.velocity, self.accel, self.raw_position].iter().all(|v| v.is_finite())
+    }
+}
+
 
 #[derive(Debug, Clone, Default)]
 #[repr(C)]
@@ -165,6 +320,21 @@ pub struct OscilloscopeSamplePoint {
     pub vel_input : f32,
 }
 
+pub const ANTICOGGING_TABLE_LEN : usize = 512;
+
+/// Every plottable field of `OscilloscopeSamplePoint` by name. A field added to the struct only
+/// needs an entry here to show up in the plot, statistics, trigger and capture export.
+pub const SAMPLE_CHANNELS : &[(&str, fn(&OscilloscopeSamplePoint) -> f32)] = &[
+    ("Position",           |p| p.pos),
+    ("Velocity",           |p| p.vel),
+    ("Acceleration",       |p| p.acc),
+    ("Position Setpoint",  |p| p.pos_setpoint),
+    ("Velocity Setpoint",  |p| p.vel_setpoint),
+    ("Torque Setpoint",    |p| p.tor_setpoint),
+    ("Position Input",     |p| p.pos_input),
+    ("Velocity Input",     |p| p.vel_input),
+];
+
 #[derive(Debug, Clone, Default)]
 #[repr(C)]
 pub struct Oscilloscope {
@@ -196,11 +366,248 @@ pub struct PIDController {
     pub previous_error : f32,
 }
 
+/// The velocity loop, at `ServoPointers.vel_piv_addr`.
+#[derive(Debug, Clone, Default)]
+#[repr(C)]
+pub struct PIVController {
+    pub position_gain : f32,
+    pub velocity_gain : f32,
+    pub integrator_gain : f32,
+    pub integrator_max_abs : f32,
+
+    pub integral_term : f32,
+}
+
+impl PIVController {
+    /// Checked against `ServoConfig.velocity_integrator_max_abs`, so a limit that was just
+    /// edited shows up before the firmware copies it into the loop.
+    pub fn integrator_saturated(&self, max_abs : f32, margin : f32) -> bool {
+        near_limit(self.integral_term, -max_abs, max_abs, margin)
+    }
+}
+
+/// True when `value` is within `margin` (a fraction of the span between the limits) of either
+/// limit, i.e. anti-windup is clamping it. Never true for an empty or invalid span.
+fn near_limit(value : f32, lower : f32, upper : f32, margin : f32) -> bool {
+    let span = upper - lower;
+    if !(span > 0.0) || !span.is_finite() {
+        return false;
+    }
+
+    value >= upper - margin * span || value <= lower + margin * span
+}
+
+/// A command as dispatched to the controller by the connection task.
+#[derive(Debug, Clone)]
+pub struct CommandLogEntry {
+    pub timestamp : SystemTime,
+    /// Time since the connection was established.
+    pub elapsed : Duration,
+    pub description : String,
+    pub success : bool,
+}
+
 #[derive(Debug, Default)]
 pub struct ControllerData {
     pub servo_config : ServoConfig,
     pub servo_state : ServoState,
     pub oscilloscope : Oscilloscope,
+    pub link_test : Vec<LinkTestResult>,
+    /// Set when the motor leaves an active state without the host asking it to.
+    pub fault_detected : bool,
+    /// Size of the firmware's sample array, taken from `Oscilloscope.len` at connection.
+    pub oscilloscope_capacity : u32,
+    pub velocity_piv : Option<PIVController>,
+    pub probe_mode : Option<StlinkMode>,
+    pub target_voltage : Option<f32>,
+    /// Config as last read back from flash, i.e. what the firmware loads on boot.
+    pub saved_config : Option<ServoConfig>,
+    pub anticogging_table : Option<Vec<f32>>,
+    pub command_log : VecDeque<CommandLogEntry>,
+    /// Base address and contents of the last memory dump.
+    pub memory_dump : Option<(u32, Vec<u8>)>,
+    /// Step of the connection sequence in progress, `None` once connected.
+    pub connection_stage : Option<&'static str>,
+    /// Why the last connection attempt was abandoned.
+    pub connection_error : Option<String>,
+    /// Firmware position treated as zero. Positions in `servo_state` and the sample buffer have
+    /// this subtracted, and it's added back onto position commands.
+    pub home_offset : f32,
+    /// Duration of the last connection loop iteration, not counting the poll sleep.
+    pub loop_time : Duration,
+    /// The heartbeat stopped advancing while memory reads still succeed.
+    pub firmware_stalled : bool,
+    pub firmware_load : Option<LoadProgress>,
+    pub controller_pointers : Option<ControllerPointers>,
+    pub pointer_extensions : Option<PointerExtensions>,
+    /// Set by the connection task when it stopped so it can be started again, after a hardware
+    /// reset the firmware restarts and its pointers have to be read again.
+    pub restart_requested : bool,
+    /// Read from the target when connecting, to tell boards apart.
+    pub core_id : Option<u32>,
+    pub idcodes : Option<[u32; 2]>,
+    /// Oscilloscope samples received per second, averaged over the last second.
+    pub sample_rate : f32,
+    /// Dropped commands and USB errors not yet picked up by the GUI, oldest first.
+    pub errors : Vec<String>,
+    /// Queued commands and how many fit, as of the last poll.
+    pub command_buffer : Option<(u32, u32)>,
+}
+
+impl ControllerData {
+    pub fn integrator_saturated(&self, margin : f32) -> bool {
+        self.velocity_piv.as_ref()
+            .map_or(false, |piv| piv.integrator_saturated(self.servo_config.velocity_integrator_max_abs, margin))
+    }
+
+    /// Appends to the command log, dropping the oldest entry once it's full.
+    pub fn log_command(&mut self, elapsed : Duration, description : String, success : bool) {
+        if self.command_log.len() >= MAX_COMMAND_LOG_ENTRIES {
+            self.command_log.pop_front();
+        }
+
+        self.command_log.push_back(CommandLogEntry {
+            timestamp : SystemTime::now(),
+            elapsed,
+            description,
+            success,
+        });
+    }
+}
+
+/// Maps between the firmware's position frame and the one shown to the user, which has the host-side
+/// home offset removed and is optionally mirrored when the encoder counts the other way.
+#[derive(Debug, Clone, Copy)]
+struct AxisFrame {
+    home_offset : f32,
+    sign : f32,
+}
+
+impl AxisFrame {
+    fn position_to_firmware(&self, position : f32) -> f32 {
+        self.sign * position + self.home_offset
+    }
+
+    fn position_from_firmware(&self, position : f32) -> f32 {
+        self.sign * (position - self.home_offset)
+    }
+
+    fn sample_from_firmware(&self, pt : &mut OscilloscopeSamplePoint) {
+        pt.pos = self.position_from_firmware(pt.pos);
+        pt.pos_setpoint = self.position_from_firmware(pt.pos_setpoint);
+        pt.pos_input = self.position_from_firmware(pt.pos_input);
+        pt.vel *= self.sign;
+        pt.acc *= self.sign;
+        pt.vel_setpoint *= self.sign;
+        pt.tor_setpoint *= self.sign;
+        pt.vel_input *= self.sign;
+    }
+
+    fn state_from_firmware(&self, state : &mut ServoState) {
+        state.position = self.position_from_firmware(state.position);
+        state.pos_setpoint = self.position_from_firmware(state.pos_setpoint);
+        state.pos_input = self.position_from_firmware(state.pos_input);
+        state.velocity *= self.sign;
+        state.accel *= self.sign;
+        state.vel_setpoint *= self.sign;
+        state.tor_setpoint *= self.sign;
+        state.vel_input *= self.sign;
+        state.tor_input *= self.sign;
+    }
+}
+
+const HEARTBEAT_TIMEOUT : Duration = Duration::from_millis(500);
+
+/// Byte offset of `heartbeat` in `PointerExtensions`, it's read on its own every poll.
+pub const HEARTBEAT_OFFSET : u32 = 8;
+
+/// Tracks the firmware heartbeat counter. Firmware that doesn't increment it is never reported as
+/// stalled, since the counter has to be seen advancing first.
+struct Heartbeat {
+    last_count : u32,
+    last_change : Instant,
+    seen_advancing : bool,
+}
+
+impl Heartbeat {
+    fn new(count : u32) -> Self {
+        Heartbeat {
+            last_count : count,
+            last_change : Instant::now(),
+            seen_advancing : false,
+        }
+    }
+
+    /// Returns true if the counter hasn't changed for longer than `HEARTBEAT_TIMEOUT`.
+    fn update(&mut self, count : u32) -> bool {
+        if count != self.last_count {
+            self.last_count = count;
+            self.last_change = Instant::now();
+            self.seen_advancing = true;
+        }
+
+        self.seen_advancing && self.last_change.elapsed() > HEARTBEAT_TIMEOUT
+    }
+}
+
+/// Host-side travel limits applied to every position command before it is sent.
+#[derive(Debug, Clone)]
+pub struct PositionLimits {
+    pub enabled : bool,
+    pub min : f32,
+    pub max : f32,
+}
+
+impl Default for PositionLimits {
+    fn default() -> Self {
+        PositionLimits {
+            enabled : false,
+            min : -1.0,
+            max : 1.0,
+        }
+    }
+}
+
+impl PositionLimits {
+    pub fn clamp(&self, position : f32) -> f32 {
+        if self.enabled {
+            position.max(self.min.min(self.max)).min(self.max.max(self.min))
+        } else {
+            position
+        }
+    }
+}
+
+/// How often the connection task polls the controller and how much history it keeps.
+#[derive(Debug, Clone)]
+pub struct PollSettings {
+    pub poll_interval : Duration,
+    pub max_sample_storage : usize,
+}
+
+impl Default for PollSettings {
+    fn default() -> Self {
+        PollSettings {
+            poll_interval : Duration::from_millis(5),
+            max_sample_storage : 10_000,
+        }
+    }
+}
+
+/// Whether a connection that drops with an error is retried, and how many times.
+#[derive(Debug, Clone)]
+pub struct ReconnectSettings {
+    pub enabled : bool,
+    pub max_attempts : u32,
+}
+
+impl Default for ReconnectSettings {
+    fn default() -> Self {
+        ReconnectSettings {
+            enabled : false,
+            max_attempts : 3,
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -214,110 +621,861 @@ pub enum InterfaceCommand {
     UpdateConfigParameter(u32, f32),
     SendCommand(Command),
     ResetController,
+    /// Pulses the target's NRST pin, then restarts the connection.
+    HardwareReset,
+    /// Tests each SWD frequency against scratch memory at the address, which is restored afterwards.
+    TestLink(u32),
+    ResetObservedMaxVelocity,
+    ResetEncoderOffset,
+    ResetStepDirOffset,
+    SetOscilloscopeLength(u32),
+    /// Sets how many control loop cycles pass between oscilloscope samples.
+    SetOscilloscopeInterval(u32),
+    /// Re-enters SWD debug mode, optionally resetting the target system afterwards.
+    EnterDebugSwd(bool),
+    ExitDebug,
+    /// Reads the saved `ServoConfig` from the given flash address.
+    ReadSavedConfig(u32),
+    /// Makes the current position the host-side zero.
+    SetHome,
+    ClearHome,
+    /// Dumps `len` bytes at `addr`, repeating periodically if `live` is set.
+    DumpMemory {
+        addr : u32,
+        len : u32,
+        live : bool,
+    },
+    StopMemoryDump,
+    /// Stops the motor, parks the setpoints where they can't cause a jump on the next start, and
+    /// latches the E-STOP.
+    EmergencyStop,
+    ReadAnticoggingTable(u32),
+    WriteAnticoggingTable(u32, Vec<f32>),
+    /// Writes a firmware image at `addr` and verifies it by reading it back.
+    LoadFirmware {
+        addr : u32,
+        data : Vec<u8>,
+        target : LoadTarget,
+    },
+}
+
+impl InterfaceCommand {
+    /// Summary for the command log, large payloads are described rather than printed.
+    pub fn description(&self) -> String {
+        match self {
+            InterfaceCommand::WriteServoConfig(cfg) => {
+                let fields = cfg.fields().iter()
+                    .map(|(name, value)| format!("{}: {}", name, value))
+                    .collect::<Vec<_>>();
+                format!("WriteServoConfig {{ {} }}", fields.join(", "))
+            }
+            InterfaceCommand::WriteAnticoggingTable(addr, table) =>
+                format!("WriteAnticoggingTable {{ addr: 0x{:08X}, len: {} }}", addr, table.len()),
+            InterfaceCommand::LoadFirmware{addr, data, target} =>
+                format!("LoadFirmware {{ addr: 0x{:08X}, len: {}, target: {:?} }}", addr, data.len(), target),
+            cmd => format!("{:?}", cmd),
+        }
+    }
+
+    pub fn moves_motor(&self) -> bool {
+        match self {
+            InterfaceCommand::StartMotor | InterfaceCommand::PositionCommand(_) => true,
+            InterfaceCommand::SendCommand(cmd) => cmd.moves_motor(),
+            _ => false,
+        }
+    }
+}
+
+/// Part of the oscilloscope ring that's new since it was last read up to `last_index`, and where
+/// the next read starts. After a wrap only the end of the ring is read, the start comes with the
+/// next poll.
+fn sample_range(last_index : u32, index : u32, len : u32) -> (u32, u32, u32) {
+    if index < last_index {
+        (last_index, len, 0)
+    } else {
+        (last_index, index, index)
+    }
+}
+
+/// Reads the servo config and state. Config, pointers and state are one `ServoData` in the
+/// firmware, so when it's laid out as expected they're read as one block. With the anticogging
+/// table in the config that's 3 transfers a poll instead of 4, see `servo_read_transfers` in the
+/// tests. The effect on throughput is shown live as samples/s in the poll settings.
+fn read_servo<M : MemoryAccess>(link : &mut M, base : &ControllerPointers, combined : bool) -> Result<(ServoConfig, ServoState), rusb::Error> {
+    if combined {
+        let servo_data = link.read_struct::<ServoData>(base.servo_config_addr)?;
+        Ok((servo_data.config, servo_data.state))
+    } else {
+        Ok((link.read_struct::<ServoConfig>(base.servo_config_addr)?, link.read_struct::<ServoState>(base.servo_state_addr)?))
+    }
 }
 
+const MAX_COMMAND_LOG_ENTRIES : usize = 10_000;
+
+const POINTER_READ_ATTEMPTS : u32 = 5;
+const POINTER_RETRY_DELAY : Duration = Duration::from_millis(100);
+
+const PROBE_MODE_CHECK_INTERVAL : Duration = Duration::from_millis(1000);
+const MEMORY_DUMP_INTERVAL : Duration = Duration::from_millis(200);
+// polls between read status checks, each one is another USB round trip
+const RW_STATUS_INTERVAL : u32 = 20;
+
 pub fn controller_connection_task(
-    link : Arc<Mutex<STLink>>, 
+    link : Arc<Mutex<Box<dyn DebugProbe>>>, 
     running : Arc<AtomicBool>,
     controller_data : Arc<Mutex<ControllerData>>,
     sample_buffer : Arc<Mutex<Vec<OscilloscopeSamplePoint>>>,
-    command_list : Arc<Mutex<Vec<InterfaceCommand>>>) {
+    command_list : Arc<Mutex<Vec<InterfaceCommand>>>,
+    rate_limits : Arc<Mutex<CommandRateLimits>>,
+    trigger : Arc<Mutex<Trigger>>,
+    slow_poll : Arc<AtomicBool>,
+    position_limits : Arc<Mutex<PositionLimits>>,
+    swd_settings : Arc<Mutex<AdaptiveSwdSettings>>,
+    invert_direction : Arc<AtomicBool>,
+    estop : Arc<AtomicBool>,
+    poll_settings : Arc<Mutex<PollSettings>>) {
 
     running.store(true, std::sync::atomic::Ordering::SeqCst);
 
     command_list.lock().clear();
+    controller_data.lock().connection_error = None;
 
-    link.lock().connect();
+    let set_stage = |stage : Option<&'static str>| controller_data.lock().connection_stage = stage;
+    let cancelled = || !running.load(std::sync::atomic::Ordering::Relaxed);
 
-    link.lock().enter_debug_swd();
+    // each step can block on USB for up to the link timeout, so check for a cancel in between
+    let setup = (|| -> Result<_, rusb::Error> {
+        set_stage(Some("Opening probe"));
+        link.lock().connect()?;
+        link.lock().init()?;
 
-    let config_addr = link.lock().read_struct::<u32>(CONFIG_ADDR_ADDR);
+        if cancelled() {
+            return Ok(None);
+        }
 
-    // println!("Base pointers location: {:4X}", config_addr);
-    
-    let base = link.lock().read_struct::<ControllerPointers>(config_addr);
-    
-    let mut osc = link.lock().read_struct::<Oscilloscope>(base.oscilloscope_addr);
+        set_stage(Some("Entering SWD debug"));
+        link.lock().enter_debug_swd()?;
 
-    osc.recording = true;
-    
-    link.lock().write_struct(base.oscilloscope_addr, osc.clone());
+        let initial_freq = swd_settings.lock().initial_freq;
+        link.lock().set_swd_freq(initial_freq)?;
+
+        // only informational, older probes don't support both commands
+        {
+            let mut link = link.lock();
+            let mut controller_data = controller_data.lock();
+
+            controller_data.core_id = link.read_core_id().ok();
+            controller_data.idcodes = link.read_idcodes().ok();
+        }
+
+        if cancelled() {
+            return Ok(None);
+        }
+
+        set_stage(Some("Reading controller pointers"));
+
+        // the firmware may still be booting, so give it a few tries to publish its pointers
+        let mut attempt = 0;
+
+        let (config_addr, base) = loop {
+            let read = (|| -> Result<_, rusb::Error> {
+                let mut link = link.lock();
+
+                let config_addr = link.read_struct::<u32>(CONFIG_ADDR_ADDR)?;
+
+                // println!("Base pointers location: {:4X}", config_addr);
+
+                let base = link.read_struct::<ControllerPointers>(config_addr)?;
 
-    // println!("{:?}", osc);
+                Ok((config_addr, base, link.get_last_rw_status()?))
+            })();
+
+            // long cables garble transfers at high clock speeds, so try slower ones before giving up
+            if !matches!(read, Ok((_, _, true))) {
+                let current = link.lock().swd_freq().unwrap_or(initial_freq);
+
+                if let Some(slower) = SWD_FREQUENCIES.iter().skip_while(|f| **f != current).nth(1).copied() {
+                    eprintln!("Reading the controller pointers failed at {} kHz, retrying at {} kHz", current.hz() / 1000, slower.hz() / 1000);
+                    set_stage(Some("Lowering SWD frequency"));
+                    link.lock().set_swd_freq(slower)?;
+                    continue;
+                }
+            }
+
+            let (config_addr, base, _) = read?;
+
+            if cancelled() {
+                return Ok(None);
+            }
+
+            if base.magic_valid() && base.ready {
+                break (config_addr, base);
+            }
+
+            attempt += 1;
+
+            if attempt >= POINTER_READ_ATTEMPTS {
+                let error = if base.magic_valid() {
+                    "Firmware found but it never reported ready".to_string()
+                } else {
+                    format!("No controller firmware found (pointers at 0x{:08X} have the wrong magic)", config_addr)
+                };
+                eprintln!("{}", error);
+                controller_data.lock().connection_error = Some(error);
+                return Ok(None);
+            }
+
+            set_stage(Some("Waiting for firmware"));
+            std::thread::sleep(POINTER_RETRY_DELAY);
+        };
+
+        let extensions = if base.has_extensions() {
+            Some(link.lock().read_struct::<PointerExtensions>(ControllerPointers::extensions_addr(config_addr))?)
+        } else {
+            None
+        };
+
+        // every struct read after this point would be garbage, so stop here with a clear reason
+        let mismatches = extensions.as_ref().map_or(vec![], |extensions| extensions.struct_size_mismatches());
+        if !mismatches.is_empty() {
+            let error = format!("Firmware struct layout doesn't match this tool:\n{}", mismatches.join("\n"));
+            eprintln!("{}", error);
+            controller_data.lock().connection_error = Some(error);
+            return Ok(None);
+        }
+
+        set_stage(Some("Starting oscilloscope"));
+        let mut osc = link.lock().read_struct::<Oscilloscope>(base.oscilloscope_addr)?;
+
+        osc.recording = true;
+        
+        link.lock().write_struct(base.oscilloscope_addr, osc.clone())?;
+
+        // println!("{:?}", osc);
+
+        if cancelled() {
+            return Ok(None);
+        }
+
+        // the pointers aren't exported directly, but sit between the config and the state in `ServoData`
+        set_stage(Some("Reading servo pointers"));
+        let servo_pointers_addr = base.servo_state_addr.wrapping_sub(size_of::<ServoPointers>() as u32);
+        let servo_pointers = if base.servo_config_addr + size_of::<ServoConfig>() as u32 == servo_pointers_addr {
+            Some(link.lock().read_struct::<ServoPointers>(servo_pointers_addr)?)
+        } else {
+            None
+        };
+
+        Ok(Some((config_addr, base, extensions, osc, servo_pointers)))
+    })();
+
+    set_stage(None);
+
+    let (config_addr, base, extensions, mut osc, servo_pointers) = match setup {
+        Ok(Some(setup)) => setup,
+        result => {
+            if let Err(e) = result {
+                let error = format!("USB error while connecting: {}", e);
+                eprintln!("{}", error);
+                controller_data.lock().errors.push(error.clone());
+                controller_data.lock().connection_error = Some(error);
+            }
+
+            running.store(false, std::sync::atomic::Ordering::SeqCst);
+
+            let mut link = link.lock();
+            if link.is_connected() {
+                link.disconnect();
+            }
+
+            return;
+        }
+    };
 
     let mut last_index = osc.index;
-    
-    let max_sample_storage = 10_000;
+
+    // without the firmware exporting it there's nothing to read, and stalls just go unreported
+    let mut heartbeat = extensions.as_ref()
+        .filter(|extensions| extensions.has_heartbeat())
+        .map(|extensions| (ControllerPointers::extensions_addr(config_addr) + HEARTBEAT_OFFSET, Heartbeat::new(extensions.heartbeat)));
+    controller_data.lock().firmware_stalled = false;
+
+    controller_data.lock().controller_pointers = Some(base.clone());
+    controller_data.lock().pointer_extensions = extensions.clone();
+
+    let command_count = extensions.as_ref().map_or(BASE_COMMAND_COUNT, |extensions| extensions.command_count());
+
+    // the sample array is statically allocated, so the ring can be shortened but never grown past this
+    let oscilloscope_capacity = osc.len;
+    controller_data.lock().oscilloscope_capacity = oscilloscope_capacity;
 
     let mut record_samples = true;
 
-    while running.load(std::sync::atomic::Ordering::Relaxed) {
+    // a failure here shows up again on the first poll, which disconnects cleanly
+    let mut probe_mode = link.lock().get_current_mode().unwrap_or(None);
+    let mut last_mode_check = Instant::now();
+    controller_data.lock().probe_mode = probe_mode;
 
-        let cmds = command_list.lock().drain(0..).collect::<Vec<_>>();
+    let mut rate_limiter = CommandRateLimiter::default();
 
-        for cmd in cmds {
-            match cmd {
-                InterfaceCommand::WriteServoConfig(cfg) => {
-                    link.lock().write_struct(base.servo_config_addr, cfg);
-                },
-                InterfaceCommand::StartRecording => {
-                    record_samples = true;
-                },
-                InterfaceCommand::StopRecording => {
-                    record_samples = false;
-                },
-                InterfaceCommand::StopMotor => {
-                    send_command(&mut link.lock(), &base, Command::MotorStop).ok();
-                },
-                InterfaceCommand::StartMotor => {
-                    send_command(&mut link.lock(), &base, Command::MotorStart).ok();
-                },
-                InterfaceCommand::PositionCommand(position) => {
-                    send_command(&mut link.lock(), &base, Command::PositionCommand{position}).ok();
-                },
-                InterfaceCommand::UpdateConfigParameter(offset, value) => {
-                    link.lock().write_struct_array_offset(base.servo_config_addr, offset, &[value])
-                },
-                InterfaceCommand::SendCommand(cmd) => {
-                    send_command(&mut link.lock(), &base, cmd).ok();
-                },
-                InterfaceCommand::ResetController => {
-                    link.lock().debug_resetsys()
-                },
+    let connected_at = Instant::now();
+
+    let mut swd_adapter = SwdAdapter::new(&**link.lock());
+
+    // a motor dropping out of an active state is only treated as a fault if we didn't stop it
+    let mut motor_was_active = false;
+    let mut motor_stop_requested = false;
+
+    // the firmware has no notion of a home position, so zeroing is done on the host
+    let mut home_offset = 0.0f32;
+    let mut raw_position = 0.0f32;
+    controller_data.lock().home_offset = home_offset;
+
+    // region being dumped and when it was last read, `None` after a one-shot dump completes
+    let mut memory_dump : Option<(u32, u32, bool)> = None;
+    let mut last_memory_dump = Instant::now();
+
+    let mut catching_up = false;
+    let mut polls_since_rw_status = 0;
+    let mut received_samples = 0;
+    let mut sample_rate_since = Instant::now();
+
+    // any USB error ends the loop, leaving a chance to stop the motor before giving up on the connection
+    let poll_result = (|| -> Result<(), rusb::Error> {
+        while running.load(std::sync::atomic::Ordering::Relaxed) {
+
+            let loop_start = Instant::now();
+
+            let mut frame = AxisFrame {
+                home_offset,
+                sign : if invert_direction.load(std::sync::atomic::Ordering::Relaxed) { -1.0 } else { 1.0 },
+            };
+
+            let cmds = command_list.lock().drain(0..).collect::<Vec<_>>();
+            let cmds = rate_limiter.filter(&rate_limits.lock(), cmds);
+
+            for cmd in cmds {
+                let description = cmd.description();
+                let firmware_load = matches!(cmd, InterfaceCommand::LoadFirmware{..});
+
+                // the firmware would skip a command it doesn't know, so don't pretend it was sent
+                if let InterfaceCommand::SendCommand(firmware_cmd) = &cmd {
+                    if firmware_cmd.variant_index() >= command_count {
+                        let error = format!("The firmware doesn't handle this command, it was dropped: {}", description);
+                        eprintln!("{}", error);
+                        controller_data.lock().errors.push(error);
+                        continue;
+                    }
+                }
+
+                // generator threads keep pushing commands too, so the E-STOP is enforced here
+                if estop.load(std::sync::atomic::Ordering::SeqCst) && cmd.moves_motor() {
+                    let error = format!("E-STOP latched, command blocked: {}", description);
+                    eprintln!("{}", error);
+                    controller_data.lock().errors.push(error);
+                    continue;
+                }
+
+                let mut success = true;
+
+                match cmd {
+                    InterfaceCommand::WriteServoConfig(cfg) => {
+                        // staged so the firmware never runs with a half written config
+                        match extensions.as_ref().and_then(|extensions| extensions.staged_config()) {
+                            Some(staged_addr) => {
+                                let mut link = link.lock();
+                                link.write_struct(staged_addr, cfg)?;
+                                success = send_command(&mut *link, &base, Command::ApplyStagedConfig)?;
+                            }
+                            None => link.lock().write_struct(base.servo_config_addr, cfg)?,
+                        }
+                    },
+                    InterfaceCommand::StartRecording => {
+                        record_samples = true;
+                    },
+                    InterfaceCommand::StopRecording => {
+                        record_samples = false;
+                    },
+                    InterfaceCommand::StopMotor => {
+                        motor_stop_requested = true;
+                        success = send_command(&mut *link.lock(), &base, Command::MotorStop)?;
+                    },
+                    InterfaceCommand::StartMotor => {
+                        success = send_command(&mut *link.lock(), &base, Command::MotorStart)?;
+                    },
+                    InterfaceCommand::PositionCommand(position) => {
+                        let position = frame.position_to_firmware(position_limits.lock().clamp(position));
+                        success = send_command(&mut *link.lock(), &base, Command::PositionCommand{position})?;
+                    },
+                    InterfaceCommand::UpdateConfigParameter(offset, value) => {
+                        link.lock().write_struct_array_offset(base.servo_config_addr, offset, &[value])?
+                    },
+                    InterfaceCommand::SendCommand(cmd) => {
+                        let cmd = match cmd {
+                            Command::MotorStop => {
+                                motor_stop_requested = true;
+                                cmd
+                            },
+                            Command::ClearFaultState => {
+                                controller_data.lock().fault_detected = false;
+                                cmd
+                            },
+                            Command::PositionCommand{position} => {
+                                Command::PositionCommand{position : frame.position_to_firmware(position_limits.lock().clamp(position))}
+                            },
+                            Command::VelocityCommand{velocity} => {
+                                Command::VelocityCommand{velocity : frame.sign * velocity}
+                            },
+                            Command::TorqueCommand{torque} => {
+                                Command::TorqueCommand{torque : frame.sign * torque}
+                            },
+                            cmd => cmd,
+                        };
+                        success = send_command(&mut *link.lock(), &base, cmd)?;
+                    },
+                    InterfaceCommand::ResetController => {
+                        link.lock().debug_resetsys()?
+                    },
+                    InterfaceCommand::HardwareReset => {
+                        link.lock().drive_nrst(NrstMode::Pulse)?;
+
+                        controller_data.lock().restart_requested = true;
+                        running.store(false, std::sync::atomic::Ordering::SeqCst);
+                    },
+                    InterfaceCommand::ResetObservedMaxVelocity => {
+                        link.lock().write_struct_array_offset(base.servo_state_addr, STATE_OFFSET_MAX_VEL_ABS_OBS, &[0.0f32])?
+                    },
+                    InterfaceCommand::ResetEncoderOffset => {
+                        link.lock().write_struct_array_offset(base.servo_state_addr, STATE_OFFSET_ENCODER_OFFSET, &[0i32])?
+                    },
+                    InterfaceCommand::ResetStepDirOffset => {
+                        link.lock().write_struct_array_offset(base.servo_state_addr, STATE_OFFSET_STEP_DIR_OFFSET, &[0i32])?
+                    },
+                    InterfaceCommand::SetOscilloscopeLength(len) => {
+                        osc.len = len.max(1).min(oscilloscope_capacity);
+                        osc.index = 0;
+                        osc.recording = record_samples;
+                        link.lock().write_struct(base.oscilloscope_addr, osc.clone())?;
+                        last_index = 0;
+                    },
+                    InterfaceCommand::SetOscilloscopeInterval(interval) => {
+                        osc.interval = interval.max(1);
+                        link.lock().write_struct(base.oscilloscope_addr, osc.clone())?;
+                    },
+                    InterfaceCommand::EnterDebugSwd(reset) => {
+                        let mut link = link.lock();
+
+                        link.enter_debug_swd()?;
+
+                        if reset {
+                            link.debug_resetsys()?;
+                        }
+
+                        probe_mode = link.get_current_mode()?;
+                        controller_data.lock().probe_mode = probe_mode;
+                    },
+                    InterfaceCommand::ExitDebug => {
+                        let mut link = link.lock();
+
+                        link.exit_debug()?;
+
+                        probe_mode = link.get_current_mode()?;
+                        controller_data.lock().probe_mode = probe_mode;
+                    },
+                    InterfaceCommand::SetHome => {
+                        home_offset = raw_position;
+                        controller_data.lock().home_offset = home_offset;
+                        frame.home_offset = home_offset;
+                    },
+                    InterfaceCommand::ClearHome => {
+                        home_offset = 0.0;
+                        controller_data.lock().home_offset = home_offset;
+                        frame.home_offset = home_offset;
+                    },
+                    InterfaceCommand::DumpMemory{addr, len, live} => {
+                        memory_dump = Some((addr, len, live));
+                        last_memory_dump = Instant::now() - MEMORY_DUMP_INTERVAL;
+                    },
+                    InterfaceCommand::StopMemoryDump => {
+                        memory_dump = None;
+                    },
+                    InterfaceCommand::EmergencyStop => {
+                        estop.store(true, std::sync::atomic::Ordering::SeqCst);
+                        motor_stop_requested = true;
+
+                        let mut link = link.lock();
+                        success = send_command(&mut *link, &base, Command::MotorStop)?;
+
+                        // the position setpoint goes to where the motor is now, zero would be a jump of its own
+                        let setpoints = [
+                            Command::VelocityCommand{velocity : 0.0},
+                            Command::TorqueCommand{torque : 0.0},
+                            Command::PositionCommand{position : raw_position},
+                        ];
+
+                        for setpoint in setpoints.iter() {
+                            success &= send_command(&mut *link, &base, *setpoint)?;
+                        }
+                    },
+                    InterfaceCommand::ReadAnticoggingTable(addr) => {
+                        let table = link.lock().read_struct_array::<f32>(addr, ANTICOGGING_TABLE_LEN as u32)?;
+                        controller_data.lock().anticogging_table = Some(table);
+                    },
+                    InterfaceCommand::WriteAnticoggingTable(addr, table) => {
+                        link.lock().write_struct_array(addr, &table)?;
+
+                        // read back so the displayed table is what actually landed on the controller
+                        let table = link.lock().read_struct_array::<f32>(addr, ANTICOGGING_TABLE_LEN as u32)?;
+                        controller_data.lock().anticogging_table = Some(table);
+                    },
+                    InterfaceCommand::LoadFirmware{addr, data, target} => {
+                        let mut link = link.lock();
+                        let mut report = |progress : LoadProgress| controller_data.lock().firmware_load = Some(progress);
+
+                        let result = halt_core(&mut **link)
+                            .and_then(|_| match target {
+                                LoadTarget::Ram => write_ram(&mut *link, addr, &data, &mut report),
+                                LoadTarget::Flash => write_flash(&mut **link, addr, &data, &mut report),
+                            })
+                            .and_then(|_| verify(&mut *link, addr, &data, &mut report));
+
+                        success = result.is_ok();
+
+                        report(LoadProgress {
+                            stage : match result {
+                                Ok(()) => LoadStage::Done,
+                                Err(e) => LoadStage::Failed(e),
+                            },
+                            done : data.len(),
+                            total : data.len(),
+                        });
+                    },
+                    InterfaceCommand::ReadSavedConfig(addr) => {
+                        let saved = read_flash_struct::<ServoConfig>(&mut **link.lock(), addr)?;
+                        controller_data.lock().saved_config = Some(saved);
+                    },
+                    InterfaceCommand::TestLink(addr) => {
+                        let mut link = link.lock();
+
+                        // the probe starts out at 1.8MHz until told otherwise
+                        let previous_freq = link.swd_freq().unwrap_or(StlinkDebugApiv2SwdFreq::Freq1800000);
+
+                        // saved at the frequency that's been working, a corrupted copy would be worse than none
+                        let original = link.get_mem32(addr, SELF_TEST_WORDS * 4)?;
+
+                        // a frequency too fast to talk at is a result, not a reason to drop the connection
+                        let results = SWD_FREQUENCIES.iter()
+                            .map(|freq| link.memory_self_test(addr, *freq).unwrap_or_else(|_| LinkTestResult::failed(*freq)))
+                            .collect::<Vec<_>>();
+
+                        link.set_swd_freq(previous_freq)?;
+                        link.set_mem32(addr, &original)?;
+
+                        controller_data.lock().link_test = results;
+                    },
+                }
+
+                let mut data_lock = controller_data.lock();
+
+                // everything else only fails when the firmware's command ring has no room
+                if !success {
+                    let error = if firmware_load {
+                        "Firmware load failed".to_string()
+                    } else {
+                        format!("Command buffer full, command dropped: {}", description)
+                    };
+                    eprintln!("{}", error);
+                    data_lock.errors.push(error);
+                }
+
+                data_lock.log_command(connected_at.elapsed(), description, success);
             }
-        }
 
-        if record_samples {
-            osc = link.lock().read_struct::<Oscilloscope>(base.oscilloscope_addr);
-            let index = osc.index;
+            // the target is restarting after a hardware reset, leave the rest to the reconnect
+            if controller_data.lock().restart_requested {
+                return Ok(());
+            }
+
+            let command_buffer = link.lock().read_struct::<CommandBufferInfo>(base.command_buffer_addr)?;
+            controller_data.lock().command_buffer = Some((command_buffer.queued(), command_buffer.usable_capacity()));
+
+            let swd_change = swd_adapter.update(&swd_settings.lock(), &mut **link.lock())?;
 
-            let start_off = last_index;
-            let mut end_off = index;
+            if let Some((from, to)) = swd_change {
+                let description = format!("Adaptive SWD: {} kHz -> {} kHz", from.hz() / 1000, to.hz() / 1000);
+                eprintln!("{}", description);
 
-            if index < last_index {
-                end_off = osc.len;
-                last_index = 0;
+                controller_data.lock().log_command(connected_at.elapsed(), description, true);
+            }
+
+            if last_mode_check.elapsed() >= PROBE_MODE_CHECK_INTERVAL {
+                last_mode_check = Instant::now();
+                probe_mode = link.lock().get_current_mode()?;
+                controller_data.lock().probe_mode = probe_mode;
+                // some probes can't measure it, which isn't worth dropping the connection over
+                let target_voltage = link.lock().get_target_voltage().ok();
+                controller_data.lock().target_voltage = target_voltage;
+            }
+
+            // target memory can't be accessed until the probe is back in debug mode
+            if probe_mode.map_or(false, |mode| mode != StlinkMode::Debug) {
+                std::thread::sleep(std::time::Duration::from_millis(50));
+                continue;
+            }
+
+            if let Some((addr, len, live)) = memory_dump {
+                if last_memory_dump.elapsed() >= MEMORY_DUMP_INTERVAL {
+                    last_memory_dump = Instant::now();
+
+                    // a bad range is the user's mistake, not a reason to drop the connection
+                    match link.lock().read_bytes(addr, len) {
+                        Ok(bytes) => controller_data.lock().memory_dump = Some((addr, bytes)),
+                        Err(rusb::Error::InvalidParam) => {
+                            controller_data.lock().errors.push(format!("Memory dump range 0x{:08X} + {} runs past the end of the address space", addr, len));
+                            memory_dump = None;
+                        }
+                        Err(e) => return Err(e),
+                    }
+
+                    if !live {
+                        memory_dump = None;
+                    }
+                }
+            }
+
+            if record_samples {
+                osc = link.lock().read_struct::<Oscilloscope>(base.oscilloscope_addr)?;
+                controller_data.lock().oscilloscope = osc.clone();
+                let (start_off, end_off, next_index) = sample_range(last_index, osc.index, osc.len);
+                last_index = next_index;
+
+                let mut data = link.lock().read_struct_array_with_offset::<OscilloscopeSamplePoint>(base.oscilloscope_data_addr, end_off - start_off, start_off)?;
+
+                // more than half the ring arrived since the last poll, go again straight away before it
+                // wraps. Skipping the poll interval is what lifts the sample rate once the ring fills
+                // faster than the interval, the reads themselves don't get any cheaper
+                catching_up = 2 * (end_off - start_off) > osc.len;
+
+                received_samples += data.len();
+                if sample_rate_since.elapsed() >= Duration::from_secs(1) {
+                    controller_data.lock().sample_rate = received_samples as f32 / sample_rate_since.elapsed().as_secs_f32();
+                    received_samples = 0;
+                    sample_rate_since = Instant::now();
+                }
+
+                for pt in data.iter_mut() {
+                    frame.sample_from_firmware(pt);
+                }
+
+                if trigger.lock().process_samples(&data) {
+                    record_samples = false;
+                }
+
+                let mut lock = sample_buffer.lock();
+                lock.append(&mut data);
+
+                let max_sample_storage = poll_settings.lock().max_sample_storage;
+
+                if lock.len() > max_sample_storage {
+                    let to_remove = lock.len() - max_sample_storage;
+                    lock.drain(0..to_remove);
+                }
+            }
+
+            let (servo_config, mut servo_state) = read_servo(&mut *link.lock(), &base, servo_pointers.is_some())?;
+            polls_since_rw_status += 1;
+
+            let read_ok = if servo_state.looks_corrupt() || polls_since_rw_status >= RW_STATUS_INTERVAL {
+                polls_since_rw_status = 0;
+                link.lock().get_last_rw_status()?
             } else {
-                last_index = index;
+                true
+            };
+
+            // whatever a failed read left behind isn't shown, and the next poll is checked too
+            if !read_ok {
+                polls_since_rw_status = RW_STATUS_INTERVAL;
+                std::thread::sleep(poll_settings.lock().poll_interval);
+                continue;
+            }
+
+            if let Some((heartbeat_addr, heartbeat)) = heartbeat.as_mut() {
+                let count = link.lock().read_struct::<u32>(*heartbeat_addr)?;
+                let stalled = heartbeat.update(count);
+
+                let mut data_lock = controller_data.lock();
+                if stalled != data_lock.firmware_stalled {
+                    data_lock.firmware_stalled = stalled;
+
+                    let description = if stalled { "Firmware control loop stalled" } else { "Firmware control loop resumed" };
+                    eprintln!("{}", description);
+
+                    data_lock.log_command(connected_at.elapsed(), description.to_string(), !stalled);
+                }
             }
 
-            let mut data = link.lock().read_struct_array_with_offset::<OscilloscopeSamplePoint>(base.oscilloscope_data_addr, end_off - start_off, start_off);
+            raw_position = servo_state.position;
+            frame.state_from_firmware(&mut servo_state);
 
-            let mut lock = sample_buffer.lock();
-            lock.append(&mut data);
+            let motor_active = servo_state.state.is_motor_active();
+            let fault = motor_was_active && !motor_active && !motor_stop_requested;
 
-            if lock.len() > max_sample_storage {
-                let to_remove = lock.len() - max_sample_storage;
-                lock.drain(0..to_remove);
+            if motor_active {
+                motor_stop_requested = false;
+            }
+            motor_was_active = motor_active;
+
+            if trigger.lock().process_fault(fault) {
+                record_samples = false;
+            }
+
+            let mut data_lock = controller_data.lock();
+            if fault {
+                data_lock.fault_detected = true;
+            }
+            data_lock.servo_state = servo_state;
+            data_lock.servo_config = servo_config;
+            drop(data_lock);
+
+            if let Some(ptrs) = &servo_pointers {
+                controller_data.lock().velocity_piv = Some(link.lock().read_struct::<PIVController>(ptrs.vel_piv_addr)?);
+            }
+
+            controller_data.lock().loop_time = loop_start.elapsed();
+
+            // poll less often while the window is in the background
+            if catching_up {
+                catching_up = false;
+            } else if slow_poll.load(std::sync::atomic::Ordering::Relaxed) {
+                std::thread::sleep(std::time::Duration::from_millis(50));
+            } else {
+                std::thread::sleep(poll_settings.lock().poll_interval);
             }
         }
 
-        controller_data.lock().servo_state = link.lock().read_struct::<ServoState>(base.servo_state_addr);
-        controller_data.lock().servo_config = link.lock().read_struct::<ServoConfig>(base.servo_config_addr);
+        Ok(())
+    })();
 
+    if let Err(e) = poll_result {
+        eprintln!("Lost connection to controller: {}", e);
 
-        std::thread::sleep(std::time::Duration::from_millis(5));
+        let motor_active = controller_data.lock().servo_state.state.is_motor_active();
+
+        if motor_active {
+            // best effort, the probe may already be gone
+            match send_command(&mut *link.lock(), &base, Command::MotorStop) {
+                Ok(true) => eprintln!("Motor stop command sent"),
+                _ => eprintln!("Failed to send motor stop command"),
+            }
+        }
+
+        controller_data.lock().errors.push(format!("USB error, lost connection: {}", e));
+        controller_data.lock().connection_error = Some(format!("Lost connection: {}", e));
+        running.store(false, std::sync::atomic::Ordering::SeqCst);
     }
 
     link.lock().disconnect();
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_probe::MockStlink;
+
+    #[test]
+    fn sample_range_follows_the_ring() {
+        assert_eq!(sample_range(10, 20, 100), (10, 20, 20));
+        assert_eq!(sample_range(20, 20, 100), (20, 20, 20));
+    }
+
+    #[test]
+    fn sample_range_reads_a_wrapped_ring_in_two_parts() {
+        let (start, end, next) = sample_range(90, 5, 100);
+        assert_eq!((start, end, next), (90, 100, 0));
+        assert_eq!(sample_range(next, 5, 100), (0, 5, 5));
+    }
+
+    #[test]
+    fn integrator_saturation() {
+        let piv = |integral_term| PIVController { integral_term, ..Default::default() };
+
+        assert!(piv(0.95).integrator_saturated(1.0, 0.05));
+        assert!(piv(-1.0).integrator_saturated(1.0, 0.05));
+        assert!(!piv(0.5).integrator_saturated(1.0, 0.05));
+
+        // no limit configured, nothing to clamp against
+        assert!(!piv(0.0).integrator_saturated(0.0, 0.05));
+        assert!(!piv(0.0).integrator_saturated(f32::NAN, 0.05));
+    }
+
+    #[test]
+    fn corrupt_servo_state() {
+        let mut state = ServoState::default();
+        assert!(!state.looks_corrupt());
+
+        state.velocity = f32::NAN;
+        assert!(state.looks_corrupt());
+
+        state.velocity = 0.0;
+        state.position = f32::INFINITY;
+        assert!(state.looks_corrupt());
+    }
+
+    #[test]
+    fn servo_config_with_anticogging_table_round_trips() {
+        // the table alone makes it more than two full transfers
+        assert!(size_of::<ServoConfig>() > 2048);
+
+        let mut config = ServoConfig::default();
+        config.position_gain = 12.5;
+        config.vel_pllki = 3.0;
+        for (i, torque) in config.antcogging_torque.iter_mut().enumerate() {
+            *torque = i as f32 * 0.25;
+        }
+
+        let mut mem = MockStlink::paused();
+        mem.write_struct(0x2000_A000, config.clone()).unwrap();
+        let read = mem.read_struct::<ServoConfig>(0x2000_A000).unwrap();
+
+        assert_eq!(read.fields(), config.fields());
+        assert_eq!(read.antcogging_torque.to_vec(), config.antcogging_torque.to_vec());
+    }
+
+    #[test]
+    fn large_commands_are_summarised_in_the_log() {
+        let description = InterfaceCommand::WriteAnticoggingTable(0x2000_4000, vec![0.0; ANTICOGGING_TABLE_LEN]).description();
+        assert_eq!(description, format!("WriteAnticoggingTable {{ addr: 0x20004000, len: {} }}", ANTICOGGING_TABLE_LEN));
+
+        let description = InterfaceCommand::WriteServoConfig(ServoConfig::default()).description();
+        assert!(description.starts_with("WriteServoConfig { "));
+        assert!(description.len() < 1000);
+    }
+
+    #[test]
+    fn command_log_drops_the_oldest_entries() {
+        let mut data = ControllerData::default();
+
+        for i in 0..MAX_COMMAND_LOG_ENTRIES + 5 {
+            data.log_command(Duration::from_secs(0), i.to_string(), true);
+        }
+
+        assert_eq!(data.command_log.len(), MAX_COMMAND_LOG_ENTRIES);
+        assert_eq!(data.command_log.front().unwrap().description, "5");
+    }
+
+    #[test]
+    fn servo_read_transfers() {
+        let mut mock = MockStlink::paused();
+        let pointers_addr = mock.read_struct::<u32>(CONFIG_ADDR_ADDR).unwrap();
+        let base = mock.read_struct::<ControllerPointers>(pointers_addr).unwrap();
+
+        // 3 for the config with its anticogging table, 1 for the state
+        mock.reads.clear();
+        read_servo(&mut mock, &base, false).unwrap();
+        assert_eq!(mock.reads.len(), 4);
+
+        mock.reads.clear();
+        read_servo(&mut mock, &base, true).unwrap();
+        assert_eq!(mock.reads.len(), 3);
+    }
+}
@@ -0,0 +1,203 @@
+use std::mem::size_of;
+use std::time::Duration;
+
+use crate::memory_access::items_from_bytes;
+use crate::memory_access::MemoryAccess;
+use crate::stlink::LinkTestResult;
+use crate::stlink::NrstMode;
+use crate::stlink::STLink;
+use crate::stlink::STLinkFirmwareVersion;
+use crate::stlink::StlinkDebugApiv2SwdFreq;
+use crate::stlink::StlinkMode;
+use crate::stlink::UsbErrorCounters;
+
+/// Everything the connection task and the GUI need from a probe, so a simulated one can stand in
+/// for an ST-Link. Memory access comes from `MemoryAccess`, whose struct helpers are available on
+/// `Box<dyn DebugProbe>`.
+pub trait DebugProbe : MemoryAccess + Send {
+    /// Short name of the probe model, shown in the device list.
+    fn name(&self) -> String;
+
+    /// Bus and address the probe is plugged into, `None` for probes that aren't on USB.
+    fn usb_location(&self) -> Option<(u8, u8)>;
+
+    /// Stays the same across replugging, unlike the USB location.
+    fn serial_number(&self) -> Option<String>;
+
+    fn firmware_version(&self) -> Option<STLinkFirmwareVersion>;
+
+    fn connect(&mut self) -> Result<(), rusb::Error>;
+
+    fn init(&mut self) -> Result<(), rusb::Error>;
+
+    fn disconnect(&mut self);
+
+    fn is_connected(&self) -> bool;
+
+    fn usb_timeout(&self) -> Duration;
+
+    fn set_usb_timeout(&mut self, timeout : Duration);
+
+    fn usb_errors(&self) -> &UsbErrorCounters;
+
+    fn usb_errors_mut(&mut self) -> &mut UsbErrorCounters;
+
+    fn enter_debug_swd(&mut self) -> Result<(), rusb::Error>;
+
+    fn exit_debug(&mut self) -> Result<(), rusb::Error>;
+
+    fn debug_resetsys(&mut self) -> Result<(), rusb::Error>;
+
+    fn drive_nrst(&mut self, mode : NrstMode) -> Result<(), rusb::Error>;
+
+    fn get_current_mode(&mut self) -> Result<Option<StlinkMode>, rusb::Error>;
+
+    fn get_target_voltage(&mut self) -> Result<f32, rusb::Error>;
+
+    fn get_last_rw_status(&mut self) -> Result<bool, rusb::Error>;
+
+    fn read_core_id(&mut self) -> Result<u32, rusb::Error>;
+
+    fn read_idcodes(&mut self) -> Result<[u32; 2], rusb::Error>;
+
+    /// Reads `len` bytes from any address, alignment and transfer size are handled by the probe.
+    fn read_bytes(&mut self, addr : u32, len : u32) -> Result<Vec<u8>, rusb::Error>;
+
+    fn set_mem16(&mut self, addr : u32, size : u32, data : &[u8]) -> Result<(), rusb::Error>;
+
+    fn swd_freq(&self) -> Option<StlinkDebugApiv2SwdFreq>;
+
+    /// Frequency the probe actually picked, if it reports one.
+    fn com_freq_khz(&self) -> Option<u32>;
+
+    fn set_swd_freq(&mut self, freq : StlinkDebugApiv2SwdFreq) -> Result<(), rusb::Error>;
+
+    fn memory_self_test(&mut self, addr : u32, freq : StlinkDebugApiv2SwdFreq) -> Result<LinkTestResult, rusb::Error>;
+}
+
+impl MemoryAccess for Box<dyn DebugProbe> {
+    fn get_mem32(&mut self, addr : u32, size : u32) -> Result<Vec<u8>, rusb::Error> {
+        (**self).get_mem32(addr, size)
+    }
+
+    fn set_mem32(&mut self, addr : u32, data : &[u8]) -> Result<(), rusb::Error> {
+        (**self).set_mem32(addr, data)
+    }
+}
+
+/// Reads a struct from flash rather than RAM. The struct doesn't need to be word aligned.
+pub fn read_flash_struct<T : Clone>(probe : &mut dyn DebugProbe, addr : u32) -> Result<T, rusb::Error> {
+    let buffer = probe.read_bytes(addr, size_of::<T>() as u32)?;
+
+    Ok(items_from_bytes::<T>(buffer, 1).remove(0))
+}
+
+impl DebugProbe for STLink {
+    fn name(&self) -> String {
+        format!("{:?}", self.dev_type.version)
+    }
+
+    fn usb_location(&self) -> Option<(u8, u8)> {
+        Some((self.device.bus_number(), self.device.address()))
+    }
+
+    fn serial_number(&self) -> Option<String> {
+        self.serial.clone()
+    }
+
+    fn firmware_version(&self) -> Option<STLinkFirmwareVersion> {
+        self.firmware_version
+    }
+
+    fn connect(&mut self) -> Result<(), rusb::Error> {
+        STLink::connect(self)
+    }
+
+    fn init(&mut self) -> Result<(), rusb::Error> {
+        STLink::init(self)
+    }
+
+    fn disconnect(&mut self) {
+        STLink::disconnect(self)
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn usb_timeout(&self) -> Duration {
+        self.usb_timeout
+    }
+
+    fn set_usb_timeout(&mut self, timeout : Duration) {
+        self.usb_timeout = timeout;
+    }
+
+    fn usb_errors(&self) -> &UsbErrorCounters {
+        &self.usb_errors
+    }
+
+    fn usb_errors_mut(&mut self) -> &mut UsbErrorCounters {
+        &mut self.usb_errors
+    }
+
+    fn enter_debug_swd(&mut self) -> Result<(), rusb::Error> {
+        STLink::enter_debug_swd(self)
+    }
+
+    fn exit_debug(&mut self) -> Result<(), rusb::Error> {
+        STLink::exit_debug(self)
+    }
+
+    fn debug_resetsys(&mut self) -> Result<(), rusb::Error> {
+        STLink::debug_resetsys(self)
+    }
+
+    fn drive_nrst(&mut self, mode : NrstMode) -> Result<(), rusb::Error> {
+        STLink::drive_nrst(self, mode)
+    }
+
+    fn get_current_mode(&mut self) -> Result<Option<StlinkMode>, rusb::Error> {
+        STLink::get_current_mode(self)
+    }
+
+    fn get_target_voltage(&mut self) -> Result<f32, rusb::Error> {
+        STLink::get_target_voltage(self)
+    }
+
+    fn get_last_rw_status(&mut self) -> Result<bool, rusb::Error> {
+        STLink::get_last_rw_status(self)
+    }
+
+    fn read_core_id(&mut self) -> Result<u32, rusb::Error> {
+        STLink::read_core_id(self)
+    }
+
+    fn read_idcodes(&mut self) -> Result<[u32; 2], rusb::Error> {
+        STLink::read_idcodes(self)
+    }
+
+    fn read_bytes(&mut self, addr : u32, len : u32) -> Result<Vec<u8>, rusb::Error> {
+        STLink::read_bytes(self, addr, len)
+    }
+
+    fn set_mem16(&mut self, addr : u32, size : u32, data : &[u8]) -> Result<(), rusb::Error> {
+        STLink::set_mem16(self, addr, size, data)
+    }
+
+    fn swd_freq(&self) -> Option<StlinkDebugApiv2SwdFreq> {
+        self.swd_freq
+    }
+
+    fn com_freq_khz(&self) -> Option<u32> {
+        self.com_freq_khz
+    }
+
+    fn set_swd_freq(&mut self, freq : StlinkDebugApiv2SwdFreq) -> Result<(), rusb::Error> {
+        STLink::set_swd_freq(self, freq)
+    }
+
+    fn memory_self_test(&mut self, addr : u32, freq : StlinkDebugApiv2SwdFreq) -> Result<LinkTestResult, rusb::Error> {
+        STLink::memory_self_test(self, addr, freq)
+    }
+}
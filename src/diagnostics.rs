@@ -0,0 +1,55 @@
+use crate::stlink::StlinkMode;
+
+/// Everything the checklist looks at, gathered by the GUI at the time of the check.
+#[derive(Debug, Clone)]
+pub struct DiagnosticInputs {
+    pub probes_found : usize,
+    pub connected : bool,
+    pub probe_mode : Option<StlinkMode>,
+    pub usb_errors_per_minute : f32,
+    /// `None` until the controller pointers have been read.
+    pub magic_valid : Option<bool>,
+    pub ready : Option<bool>,
+    pub aligned : bool,
+    pub fault : bool,
+    pub firmware_stalled : bool,
+    pub samples_flowing : bool,
+}
+
+#[derive(Debug, Clone)]
+pub struct DiagnosticCheck {
+    pub name : &'static str,
+    pub passed : bool,
+    /// What to try if this check fails.
+    pub hint : &'static str,
+}
+
+// more than this many USB errors a minute means the link is unreliable
+const USB_ERROR_RATE_LIMIT : f32 = 10.0;
+
+/// Runs the checks in the order a connection comes up, so the first failure is usually the cause
+/// of the ones after it.
+pub fn run_checks(inputs : &DiagnosticInputs) -> Vec<DiagnosticCheck> {
+    let check = |name, passed, hint| DiagnosticCheck { name, passed, hint };
+
+    vec![
+        check("Probe present", inputs.probes_found > 0,
+            "No ST-Link found. Check the USB cable and drivers, then press Refresh Devices."),
+        check("Connected", inputs.connected,
+            "Not connected. Press Connect next to the probe in the Devices panel."),
+        check("SWD link OK", inputs.probe_mode == Some(StlinkMode::Debug) && inputs.usb_errors_per_minute < USB_ERROR_RATE_LIMIT,
+            "The probe isn't in SWD debug mode or the link is dropping transfers. Try Re-enter SWD Debug or a lower SWD frequency."),
+        check("Firmware magic valid", inputs.magic_valid == Some(true),
+            "Firmware magic mismatch. The target isn't running servo firmware, reflash it."),
+        check("Firmware ready", inputs.ready == Some(true),
+            "The firmware hasn't finished initializing. Reset the target and reconnect."),
+        check("Control loop running", !inputs.firmware_stalled,
+            "The firmware heartbeat stopped. The control loop has hung, reset the target."),
+        check("Motor aligned", inputs.aligned,
+            "Motor not aligned. Start the motor to run the index search."),
+        check("No faults", !inputs.fault,
+            "A fault was detected. Check the fault log, then press Clear Faults."),
+        check("Samples flowing", inputs.samples_flowing,
+            "No new samples. Press Start Recording and check the oscilloscope interval."),
+    ]
+}
@@ -0,0 +1,57 @@
+use std::collections::VecDeque;
+
+const EDIT_HISTORY_LEN : usize = 100;
+
+#[derive(Debug, Clone, Copy)]
+pub struct ParameterEdit {
+    pub name : &'static str,
+    /// Offset of the parameter in `ServoConfig`, as used by `UpdateConfigParameter`.
+    pub offset : u32,
+    pub old_value : f32,
+    pub new_value : f32,
+}
+
+/// Config parameter edits that can be undone and redone, oldest first.
+#[derive(Debug, Clone, Default)]
+pub struct EditHistory {
+    undo : VecDeque<ParameterEdit>,
+    redo : Vec<ParameterEdit>,
+}
+
+impl EditHistory {
+    /// Records a new edit, which drops anything that could have been redone.
+    pub fn push(&mut self, edit : ParameterEdit) {
+        if self.undo.len() >= EDIT_HISTORY_LEN {
+            self.undo.pop_front();
+        }
+        self.undo.push_back(edit);
+        self.redo.clear();
+    }
+
+    /// Returns the edit to revert, the caller writes its `old_value` back.
+    pub fn undo(&mut self) -> Option<ParameterEdit> {
+        let edit = self.undo.pop_back()?;
+        self.redo.push(edit);
+        Some(edit)
+    }
+
+    /// Returns the edit to reapply, the caller writes its `new_value` back.
+    pub fn redo(&mut self) -> Option<ParameterEdit> {
+        let edit = self.redo.pop()?;
+        self.undo.push_back(edit);
+        Some(edit)
+    }
+
+    pub fn last_undo(&self) -> Option<&ParameterEdit> {
+        self.undo.back()
+    }
+
+    pub fn last_redo(&self) -> Option<&ParameterEdit> {
+        self.redo.last()
+    }
+
+    pub fn clear(&mut self) {
+        self.undo.clear();
+        self.redo.clear();
+    }
+}
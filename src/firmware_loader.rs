@@ -0,0 +1,299 @@
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::debug_probe::DebugProbe;
+use crate::memory_access::MAXIMUM_TRANSFER_SIZE;
+use crate::memory_access::MemoryAccess;
+
+// cortex-m debug halting control and status register
+pub const DHCSR           : u32 = 0xE000_EDF0;
+pub const DHCSR_KEY       : u32 = 0xA05F_0000;
+pub const DHCSR_C_DEBUGEN : u32 = 1 << 0;
+pub const DHCSR_C_HALT    : u32 = 1 << 1;
+pub const DHCSR_S_HALT    : u32 = 1 << 17;
+
+const HALT_TIMEOUT : Duration = Duration::from_millis(100);
+
+// the flash controller below is only right for the parts with these device ids
+const DBGMCU_IDCODE : u32 = 0xE004_2000;
+
+// STM32F1/F3 style flash controller
+const FLASH_KEYR : u32 = 0x4002_2004;
+const FLASH_SR   : u32 = 0x4002_200C;
+const FLASH_CR   : u32 = 0x4002_2010;
+const FLASH_AR   : u32 = 0x4002_2014;
+
+const FLASH_KEY1 : u32 = 0x4567_0123;
+const FLASH_KEY2 : u32 = 0xCDEF_89AB;
+
+const FLASH_SR_BSY      : u32 = 1 << 0;
+const FLASH_SR_PGERR    : u32 = 1 << 2;
+const FLASH_SR_WRPRTERR : u32 = 1 << 4;
+const FLASH_SR_EOP      : u32 = 1 << 5;
+
+const FLASH_CR_PG   : u32 = 1 << 0;
+const FLASH_CR_PER  : u32 = 1 << 1;
+const FLASH_CR_STRT : u32 = 1 << 6;
+const FLASH_CR_LOCK : u32 = 1 << 7;
+
+
+// half-words are programmed one at a time by the controller, so keep each transfer small enough
+// that it finishes well inside the busy timeout
+const FLASH_PROGRAM_CHUNK : usize = 64;
+const FLASH_BUSY_TIMEOUT : Duration = Duration::from_millis(500);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum LoadTarget {
+    Ram,
+    Flash,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum LoadStage {
+    Erasing,
+    Writing,
+    Verifying,
+    Done,
+    Failed(String),
+}
+
+#[derive(Debug, Clone)]
+pub struct LoadProgress {
+    pub stage : LoadStage,
+    pub done : usize,
+    pub total : usize,
+}
+
+impl LoadProgress {
+    pub fn fraction(&self) -> f32 {
+        if self.total == 0 {
+            1.0
+        } else {
+            self.done as f32 / self.total as f32
+        }
+    }
+}
+
+fn usb_error(e : rusb::Error) -> String {
+    format!("USB error: {}", e)
+}
+
+fn progress(report : &mut dyn FnMut(LoadProgress), stage : LoadStage, done : usize, total : usize) {
+    report(LoadProgress { stage, done, total });
+}
+
+/// Writes `data` to RAM at `addr`, which must be word aligned. The data is zero padded to a whole
+/// number of words.
+pub fn write_ram<M : MemoryAccess>(link : &mut M, addr : u32, data : &[u8], report : &mut dyn FnMut(LoadProgress)) -> Result<(), String> {
+    if addr % 4 != 0 {
+        return Err("RAM address must be word aligned".to_string());
+    }
+
+    let mut padded = data.to_vec();
+    padded.resize((data.len() + 3) & !3, 0);
+
+    if addr.checked_add(padded.len() as u32).is_none() {
+        return Err("Image runs past the end of the address space".to_string());
+    }
+
+    for (i, chunk) in padded.chunks(MAXIMUM_TRANSFER_SIZE).enumerate() {
+        let offset = i * MAXIMUM_TRANSFER_SIZE;
+        progress(report, LoadStage::Writing, offset, padded.len());
+
+        link.set_mem32(addr + offset as u32, chunk).map_err(usb_error)?;
+    }
+
+    Ok(())
+}
+
+/// Reads back `data.len()` bytes at `addr` and reports the address of the first mismatch.
+pub fn verify<M : MemoryAccess>(link : &mut M, addr : u32, data : &[u8], report : &mut dyn FnMut(LoadProgress)) -> Result<(), String> {
+    let len = (data.len() + 3) & !3;
+    let mut offset = 0;
+
+    while offset < len {
+        progress(report, LoadStage::Verifying, offset, len);
+
+        let n = (len - offset).min(MAXIMUM_TRANSFER_SIZE);
+        let read = link.get_mem32(addr + offset as u32, n as u32).map_err(usb_error)?;
+
+        let expected = &data[offset.min(data.len())..(offset + n).min(data.len())];
+
+        if let Some(i) = (0..expected.len()).find(|&i| read.get(i) != Some(&expected[i])) {
+            return Err(format!("Verify failed at 0x{:08X}: expected 0x{:02X}, read {}",
+                addr + (offset + i) as u32,
+                expected[i],
+                read.get(i).map_or("nothing".to_string(), |b| format!("0x{:02X}", b))));
+        }
+
+        offset += n;
+    }
+
+    Ok(())
+}
+
+fn read_reg(link : &mut dyn DebugProbe, reg : u32) -> Result<u32, String> {
+    let bytes = link.get_mem32(reg, 4).map_err(usb_error)?;
+
+    if bytes.len() == 4 {
+        Ok(u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    } else {
+        Ok(0)
+    }
+}
+
+fn write_reg(link : &mut dyn DebugProbe, reg : u32, value : u32) -> Result<(), String> {
+    link.set_mem32(reg, &value.to_le_bytes()).map_err(usb_error)
+}
+
+/// Halts the core so the running firmware can't touch memory while it's being replaced.
+pub fn halt_core(link : &mut dyn DebugProbe) -> Result<(), String> {
+    write_reg(link, DHCSR, DHCSR_KEY | DHCSR_C_HALT | DHCSR_C_DEBUGEN)?;
+
+    let start = Instant::now();
+
+    while read_reg(link, DHCSR)? & DHCSR_S_HALT == 0 {
+        if start.elapsed() > HALT_TIMEOUT {
+            return Err("Timed out waiting for the core to halt".to_string());
+        }
+    }
+
+    Ok(())
+}
+
+/// Flash page size of the STM32F1/F3 parts, `None` for anything else.
+fn flash_page_size(dev_id : u32) -> Option<u32> {
+    match dev_id {
+        // f1 low and medium density, f1 value line low and medium density
+        0x412 | 0x410 | 0x420 => Some(1024),
+        // f1 high and xl density, connectivity line, value line high density
+        0x414 | 0x430 | 0x418 | 0x428 => Some(2048),
+        // f3
+        0x422 | 0x432 | 0x438 | 0x439 | 0x446 => Some(2048),
+        _ => None,
+    }
+}
+
+fn wait_flash_ready(link : &mut dyn DebugProbe) -> Result<(), String> {
+    let start = Instant::now();
+
+    loop {
+        let sr = read_reg(link, FLASH_SR)?;
+
+        if sr & FLASH_SR_BSY == 0 {
+            // the error and end of operation flags are cleared by writing 1
+            write_reg(link, FLASH_SR, sr & (FLASH_SR_PGERR | FLASH_SR_WRPRTERR | FLASH_SR_EOP))?;
+
+            if sr & FLASH_SR_WRPRTERR != 0 {
+                return Err("Flash is write protected".to_string());
+            }
+            if sr & FLASH_SR_PGERR != 0 {
+                return Err("Flash programming error (was the page erased?)".to_string());
+            }
+
+            return Ok(());
+        }
+
+        if start.elapsed() > FLASH_BUSY_TIMEOUT {
+            return Err("Timed out waiting for the flash controller".to_string());
+        }
+    }
+}
+
+/// Erases the pages covering `data` and programs it at `addr`, which must be page aligned. The
+/// flash is locked again afterwards even if programming fails.
+pub fn write_flash(link : &mut dyn DebugProbe, addr : u32, data : &[u8], report : &mut dyn FnMut(LoadProgress)) -> Result<(), String> {
+    let dev_id = read_reg(link, DBGMCU_IDCODE)? & 0xFFF;
+    let page_size = flash_page_size(dev_id)
+        .ok_or_else(|| format!("Device ID 0x{:03X} isn't an STM32F1/F3, its flash controller isn't supported", dev_id))?;
+
+    if addr % page_size != 0 {
+        return Err(format!("Flash address must be aligned to the {} byte page size", page_size));
+    }
+
+    let mut padded = data.to_vec();
+    padded.resize((data.len() + 1) & !1, 0xFF);
+
+    if read_reg(link, FLASH_CR)? & FLASH_CR_LOCK != 0 {
+        write_reg(link, FLASH_KEYR, FLASH_KEY1)?;
+        write_reg(link, FLASH_KEYR, FLASH_KEY2)?;
+
+        if read_reg(link, FLASH_CR)? & FLASH_CR_LOCK != 0 {
+            return Err("Failed to unlock flash".to_string());
+        }
+    }
+
+    let result : Result<(), String> = (|| {
+        let pages = (padded.len() as u32 + page_size - 1) / page_size;
+
+        for page in 0..pages {
+            progress(report, LoadStage::Erasing, page as usize, pages as usize);
+
+            write_reg(link, FLASH_CR, FLASH_CR_PER)?;
+            write_reg(link, FLASH_AR, addr + page * page_size)?;
+            write_reg(link, FLASH_CR, FLASH_CR_PER | FLASH_CR_STRT)?;
+            wait_flash_ready(link)?;
+        }
+
+        write_reg(link, FLASH_CR, FLASH_CR_PG)?;
+
+        for (i, chunk) in padded.chunks(FLASH_PROGRAM_CHUNK).enumerate() {
+            let offset = i * FLASH_PROGRAM_CHUNK;
+            progress(report, LoadStage::Writing, offset, padded.len());
+
+            link.set_mem16(addr + offset as u32, chunk.len() as u32, chunk).map_err(usb_error)?;
+            wait_flash_ready(link)?;
+        }
+
+        Ok(())
+    })();
+
+    // keep the programming error if there was one, it's the more useful of the two
+    let locked = write_reg(link, FLASH_CR, FLASH_CR_LOCK);
+
+    result.and(locked)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_probe::MockStlink;
+
+    #[test]
+    fn halt_core_waits_for_the_halt() {
+        let mut mock = MockStlink::paused();
+
+        halt_core(&mut mock).unwrap();
+        assert!(read_reg(&mut mock, DHCSR).unwrap() & DHCSR_S_HALT != 0);
+    }
+
+    #[test]
+    fn flash_refuses_unknown_devices() {
+        // the simulated target reads back a zero device id
+        let mut mock = MockStlink::paused();
+
+        let result = write_flash(&mut mock, 0x0800_0000, &[0; 16], &mut |_| {});
+        assert!(result.unwrap_err().contains("Device ID 0x000"));
+        assert!(mock.writes.is_empty());
+    }
+
+    #[test]
+    fn ram_address_must_be_aligned() {
+        let mut mock = MockStlink::paused();
+
+        assert!(write_ram(&mut mock, 0x2000_1002, &[0; 16], &mut |_| {}).is_err());
+        assert!(write_ram(&mut mock, 0xFFFF_FFF8, &[0; 16], &mut |_| {}).is_err());
+        assert!(mock.writes.is_empty());
+
+        write_ram(&mut mock, 0x2000_1000, &[0; 16], &mut |_| {}).unwrap();
+        assert_eq!(mock.writes, vec![(0x2000_1000, 16)]);
+    }
+
+    #[test]
+    fn flash_page_sizes() {
+        assert_eq!(flash_page_size(0x410), Some(1024));
+        assert_eq!(flash_page_size(0x414), Some(2048));
+        assert_eq!(flash_page_size(0x422), Some(2048));
+        assert_eq!(flash_page_size(0x413), None);
+    }
+}
@@ -0,0 +1,43 @@
+use std::collections::VecDeque;
+use std::time::Duration;
+
+const TIMING_HISTORY_LEN : usize = 120;
+
+/// Recent durations of one stage, for showing a smoothed average next to the worst case.
+#[derive(Debug, Clone, Default)]
+pub struct TimingHistory {
+    samples : VecDeque<Duration>,
+}
+
+impl TimingHistory {
+    pub fn push(&mut self, time : Duration) {
+        if self.samples.len() >= TIMING_HISTORY_LEN {
+            self.samples.pop_front();
+        }
+        self.samples.push_back(time);
+    }
+
+    pub fn average_ms(&self) -> f32 {
+        if self.samples.is_empty() {
+            return 0.0;
+        }
+
+        1000.0 * self.samples.iter().map(|t| t.as_secs_f32()).sum::<f32>() / self.samples.len() as f32
+    }
+
+    pub fn max_ms(&self) -> f32 {
+        1000.0 * self.samples.iter().max().map_or(0.0, |t| t.as_secs_f32())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct FrameProfiler {
+    /// Whole frame, from starting the frame to presenting it.
+    pub frame : TimingHistory,
+    /// Time spent building the UI in `GuiState::frame`.
+    pub gui : TimingHistory,
+    /// Rebuilding the plot line buffers, only on frames where they were refreshed.
+    pub line_build : TimingHistory,
+    /// One iteration of the connection loop, excluding its sleep.
+    pub connection_loop : TimingHistory,
+}
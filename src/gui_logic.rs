@@ -1,307 +1,3262 @@
+use std::path::Path;
 use std::sync::Arc;
 use std::sync::atomic::AtomicBool;
 use std::sync::atomic::Ordering;
+use std::time::Duration;
+use std::time::Instant;
 
 use cgmath::Vector3;
 use parking_lot::Mutex;
 use winit::dpi::PhysicalSize;
+use winit::event::VirtualKeyCode;
 
+use crate::anticogging::*;
 use crate::controller_commands::Command;
+use crate::controller_commands::COMMAND_VARIANTS;
+use crate::config_file::*;
 use crate::controller_interface::*;
+use crate::debug_probe::DebugProbe;
+use crate::diagnostics::*;
+use crate::edit_history::*;
+use crate::firmware_loader::*;
+use crate::frame_profiler::FrameProfiler;
 use crate::gui_renderer::System;
+use crate::input_generators::*;
 use crate::layout::LayoutRect;
 use crate::line_renderer::LineRenderer;
+use crate::mock_probe::MockStlink;
+use crate::rate_limiter::CommandRateLimits;
+use crate::sample_analysis::*;
+use crate::servo_model::*;
 use crate::stlink::STLink;
+use crate::stlink::StlinkMode;
+use crate::stlink::SELF_TEST_WORDS;
+use crate::stlink::SWD_FREQUENCIES;
+use crate::swd_adapter::AdaptiveSwdSettings;
+use crate::move_sequence::*;
+use crate::plot_settings::*;
+use crate::settings::Settings;
+use crate::status_indicators::*;
+use crate::theme::*;
+use crate::trigger::*;
+use crate::tuning_wizard::*;
 
 pub struct GuiTask {
     name : String,
     running : Arc<AtomicBool>,
 }
 
+/// A probe and the state its connection task shares with the GUI.
+#[derive(Clone)]
+pub struct DeviceContext {
+    link : Arc<Mutex<Box<dyn DebugProbe>>>,
+    connected : Arc<AtomicBool>,
+    sample_buffer : Arc<Mutex<Vec<OscilloscopeSamplePoint>>>,
+    controller_data : Arc<Mutex<ControllerData>>,
+    controller_commands : Arc<Mutex<Vec<InterfaceCommand>>>,
+    trigger : Arc<Mutex<Trigger>>,
+    // each axis has its own mechanics, so these aren't shared between devices
+    position_limits : Arc<Mutex<PositionLimits>>,
+    invert_direction : Arc<AtomicBool>,
+}
+
+impl DeviceContext {
+    fn new(link : Box<dyn DebugProbe>, settings : &Settings) -> DeviceContext {
+        let invert_direction = settings.inverted_probes.contains(&DeviceContext::settings_key(&*link));
+
+        DeviceContext {
+            link : Arc::new(Mutex::new(link)),
+            connected : Arc::new(AtomicBool::new(false)),
+            sample_buffer : Arc::new(Mutex::new(vec![])),
+            controller_data : Arc::new(Mutex::new(ControllerData::default())),
+            controller_commands : Arc::new(Mutex::new(vec![])),
+            trigger : Arc::new(Mutex::new(Trigger::default())),
+            position_limits : Arc::new(Mutex::new(PositionLimits::default())),
+            invert_direction : Arc::new(AtomicBool::new(invert_direction)),
+        }
+    }
+
+    // opens the probe just long enough to measure, so the target can be checked before connecting
+    fn read_target_voltage(&self) -> Result<f32, rusb::Error> {
+        let mut link = self.link.lock();
+
+        link.connect()?;
+        let voltage = link.init().and_then(|_| link.get_target_voltage());
+        link.disconnect();
+
+        voltage
+    }
+
+    fn usb_location(&self) -> Option<(u8, u8)> {
+        self.link.lock().usb_location()
+    }
+
+    // what per-probe settings are saved under
+    fn settings_key(link : &dyn DebugProbe) -> String {
+        link.serial_number().unwrap_or_else(|| link.name())
+    }
+
+    fn label(&self) -> String {
+        let name = self.link.lock().name();
+
+        match self.usb_location() {
+            Some((bus, addr)) => format!("{} ({}:{})", name, bus, addr),
+            None => name,
+        }
+    }
+}
+
+pub struct Panels {
+    devices : bool,
+    servo_status : bool,
+    configuration : bool,
+    tuning_controls : bool,
+    analysis : bool,
+    raw_command : bool,
+    usb_diagnostics : bool,
+    hexdump : bool,
+    command_log : bool,
+    error_log : bool,
+    tuning_wizard : bool,
+    profiler : bool,
+    firmware_loader : bool,
+    move_sequence : bool,
+    locked : bool,
+}
+
 pub struct GuiState {
-    stlinks : Vec<Arc<Mutex<STLink>>>,
+    devices : Vec<DeviceContext>,
+    active_device : usize,
+    // handles of the selected device, the panels and plot all work on these
     connected : Arc<AtomicBool>,
     sample_buffer : Arc<Mutex<Vec<OscilloscopeSamplePoint>>>,
     controller_data : Arc<Mutex<ControllerData>>,
     controller_commands : Arc<Mutex<Vec<InterfaceCommand>>>,
+    rate_limits : Arc<Mutex<CommandRateLimits>>,
+    swd_settings : Arc<Mutex<AdaptiveSwdSettings>>,
+    poll_settings : Arc<Mutex<PollSettings>>,
+    reconnect_settings : Arc<Mutex<ReconnectSettings>>,
+    trigger : Arc<Mutex<Trigger>>,
+    position_limits : Arc<Mutex<PositionLimits>>,
+    invert_direction : Arc<AtomicBool>,
+    slow_poll : Arc<AtomicBool>,
+    /// Latched by the E-STOP button, the connection tasks drop anything that would move the motor
+    /// until it's cleared.
+    estop : Arc<AtomicBool>,
+    backgrounded : bool,
+    slow_poll_in_background : bool,
+    frozen_samples : Option<Vec<OscilloscopeSamplePoint>>,
+    frozen_at : Instant,
+    capture_name : imgui::ImString,
+    sample_export_status : Option<String>,
+    config_file_status : Option<String>,
+    link_test_addr : imgui::ImString,
+    swd_reset_on_enter : bool,
+    usb_timeout : Duration,
+    flash_config_addr : imgui::ImString,
     tasks : Vec<GuiTask>,
+    panels : Panels,
+    smooth_move_target : f32,
+    smooth_move_duration : f32,
+    step_measure_size : f32,
+    step_measure_duration : f32,
+    /// When the measured step was sent, cleared once the response has been measured.
+    step_measure_started : Option<Instant>,
+    step_measure_result : Option<Option<StepResponse>>,
+    sine_frequency : f32,
+    sine_amplitude : f32,
+    chirp_start_hz : f32,
+    chirp_end_hz : f32,
+    chirp_duration : f32,
+    chirp_amplitude : f32,
+    smooth_move_profile : usize,
+    trapezoid_target : f32,
+    trapezoid_max_vel : f32,
+    trapezoid_accel : f32,
+    motion_profile : usize,
+    /// The servo state doesn't report the profile, so this is the last one sent.
+    sent_motion_profile : Option<u32>,
+    /// Homing command waiting for the user to confirm it.
+    pending_homing : Option<Command>,
+    stats_channel : usize,
+    control_loop_hz : f32,
+    input_filter_bandwidth : f32,
+    input_filter_preview : bool,
+    model_prediction_preview : bool,
+    plot_follow_live : bool,
+    /// Zoomed `(start, end)` sample range, `None` for the full buffer or live window.
+    plot_view : Option<(f32, f32)>,
+    plot_window_seconds : f32,
+    show_peak_velocity : bool,
+    show_tuning_hud : bool,
+    plot_refresh_hz : f32,
+    plot_max_points : i32,
+    plot_profile_name : imgui::ImString,
+    /// `(slot, min, max)` each band was scaled with on the last plot refresh.
+    plot_band_ranges : Vec<(BandSlot, f32, f32)>,
+    last_plot_refresh : Instant,
+    windup_margin : f32,
+    show_windup : bool,
+    oscilloscope_len : i32,
+    oscilloscope_interval : i32,
+    raw_command_index : usize,
+    raw_command_value : f32,
+    parameter_focus : ParameterFocus,
+    hexdump_addr : imgui::ImString,
+    hexdump_len : i32,
+    hexdump_live : bool,
+    anticogging_addr : imgui::ImString,
+    anticogging_status : Option<String>,
+    /// Table loaded from a file, waiting for the user to confirm writing it to the address.
+    pending_anticogging_import : Option<(u32, Vec<f32>, String)>,
+    command_log_autoscroll : bool,
+    command_log_status : Option<String>,
+    /// Dropped commands and USB errors from every device, oldest first.
+    error_log : Vec<String>,
+    error_log_autoscroll : bool,
+    started_at : Instant,
+    /// A divider was dragged or the plot settings changed and they haven't been saved since.
+    settings_changed : bool,
+    tuning_wizard : TuningWizard,
+    settings : Settings,
+    profiler : FrameProfiler,
+    last_connection_loop_time : Duration,
+    // target of the last jog, so clicks faster than the state is polled still add up
+    jog_target : Option<(f32, Instant)>,
+    firmware_path : Option<String>,
+    firmware_addr : imgui::ImString,
+    firmware_allow_flash : bool,
+    firmware_to_flash : bool,
+    auto_save_dir : imgui::ImString,
+    diagnostics : Option<Vec<DiagnosticCheck>>,
+    // sample buffer length last frame and when it last grew
+    last_sample_count : usize,
+    last_sample_growth : Instant,
+    sequence_steps : Vec<SequenceStep>,
+    sequence_repeats : i32,
+    sequence_loop : bool,
+    /// When the last point was recorded, so the next one can be preceded by the time in between.
+    sequence_recorded_at : Option<Instant>,
+    sequence_running : Option<Arc<AtomicBool>>,
+    quit_requested : bool,
+    /// Why the config couldn't be exported on exit, shown before closing.
+    auto_save_error : Option<String>,
+    /// Set by "Quit Without Exporting" after a failed export.
+    skip_auto_save : bool,
+}
+
+#[derive(Default)]
+struct ParameterFocus {
+    // most recently focused or edited parameter, shown in the HUD
+    edited : Option<(&'static str, f32)>,
+    // set when a value is committed with Enter so the next parameter field takes keyboard focus
+    focus_next : bool,
+    history : EditHistory,
 }
 
+// edited as text so both `.` and `,` are accepted as the decimal separator
 macro_rules! cfg_parameter_widget {
-    ($ui:expr, $cmdbuf:expr, $text:expr, $label:expr, $value:expr, $offset:expr) => {
+    ($ui:expr, $cmdbuf:expr, $focus:expr, $text:expr, $label:expr, $help:expr, $value:expr, $offset:expr) => {
         $ui.text($text);
-        let changed = $ui.input_float(im_str!($label), &mut $value)
+        if $ui.is_item_hovered() {
+            $ui.tooltip_text($help);
+        }
+        let mut buffer = imstring_buffer(&format!("{}", $value), 32);
+
+        if $focus.focus_next {
+            $focus.focus_next = false;
+            $ui.set_keyboard_focus_here(imgui::FocusedWidget::Next);
+        }
+
+        let changed = $ui.input_text(im_str!($label), &mut buffer)
             .enter_returns_true(true)
             .build();
 
-        if changed {
-            $cmdbuf.lock().push(
-                InterfaceCommand::UpdateConfigParameter($offset, $value)
-            );
-        }
-    };
-}
+        if $ui.is_item_hovered() {
+            $ui.tooltip_text($help);
+        }
+
+        if $ui.is_item_active() {
+            $focus.edited = Some(($text, $value));
+        }
+
+        if changed {
+            $focus.focus_next = true;
+
+            if let Some(value) = parse_locale_float(buffer.to_str()) {
+                if value != $value {
+                    $focus.history.push(ParameterEdit { name : $text, offset : $offset, old_value : $value, new_value : value });
+                }
+                $value = value;
+                $focus.edited = Some(($text, $value));
+                $cmdbuf.lock().push(
+                    InterfaceCommand::UpdateConfigParameter($offset, $value)
+                );
+            }
+        }
+    };
+}
+
+fn imstring_buffer(text : &str, capacity : usize) -> imgui::ImString {
+    let mut buffer = imgui::ImString::with_capacity(capacity);
+    buffer.push_str(text);
+    buffer
+}
+
+// accepts `0.5`, `0,5`, `1,234.5` and `1.234,5`, treating the last separator as the decimal point
+fn parse_locale_float(text : &str) -> Option<f32> {
+    let text = text.trim().replace(' ', "");
+
+    let normalized = match (text.rfind('.'), text.rfind(',')) {
+        (Some(dot), Some(comma)) if comma > dot => text.replace('.', "").replace(',', "."),
+        (Some(_), Some(_)) => text.replace(',', ""),
+        _ => text.replace(',', "."),
+    };
+
+    normalized.parse::<f32>().ok()
+}
+
+fn parse_hex_u32(text : &str) -> Option<u32> {
+    let text = text.trim();
+    let digits = text.strip_prefix("0x").or_else(|| text.strip_prefix("0X")).unwrap_or(text);
+
+    u32::from_str_radix(&digits.replace('_', ""), 16).ok()
+}
+
+/// End of `len` words starting at `addr`, or `None` if `addr` isn't word aligned or the range
+/// runs past the end of the address space.
+fn word_range_end(addr : u32, len : usize) -> Option<u32> {
+    if addr % 4 != 0 {
+        return None;
+    }
+
+    (len as u32).checked_mul(4).and_then(|size| addr.checked_add(size))
+}
+
+// panels are pinned to their layout rect while the layout is locked
+fn panel_window<'a>(name : &'a imgui::ImStr, rect : LayoutRect, locked : bool) -> imgui::Window<'a> {
+    let condition = if locked { imgui::Condition::Always } else { imgui::Condition::FirstUseEver };
+
+    imgui::Window::new(name)
+        .position(rect.position(), condition)
+        .size(rect.dimensions(), condition)
+        .resizable(!locked)
+        .movable(!locked)
+        .collapsible(true)
+        .scrollable(true)
+}
+
+// the strip between two panels, which resizes them when dragged. Returns how far it was dragged
+// this frame.
+fn split_divider(ui : &imgui::Ui, name : &imgui::ImStr, rect : LayoutRect, cursor : imgui::MouseCursor) -> [f32; 2] {
+    let mut drag = [0.0; 2];
+
+    if rect.w == 0 || rect.h == 0 {
+        return drag;
+    }
+
+    let tok = ui.push_style_vars(&[
+        imgui::StyleVar::WindowPadding([0.0; 2]),
+        imgui::StyleVar::WindowMinSize([1.0; 2]),
+        imgui::StyleVar::WindowBorderSize(0.0),
+    ]);
+
+    imgui::Window::new(name)
+        .position(rect.position(), imgui::Condition::Always)
+        .size(rect.dimensions(), imgui::Condition::Always)
+        .no_decoration()
+        .draw_background(false)
+        .build(ui, || {
+            ui.invisible_button(name, rect.dimensions());
+
+            if ui.is_item_hovered() || ui.is_item_active() {
+                ui.set_mouse_cursor(Some(cursor));
+            }
+
+            if ui.is_item_active() {
+                drag = ui.io().mouse_delta;
+            }
+        });
+
+    tok.pop(ui);
+
+    drag
+}
+
+// builds a line list for the renderer, scaling the values into the band at `slot`
+fn push_trace_points(points : &mut Vec<Vector3<f32>>, values : impl Iterator<Item = f32>, n : usize, min : f32, max : f32, slot : BandSlot) {
+
+    let mut first = true;
+    for (i, val) in values.enumerate() {
+        let t = Vector3::new(
+            i as f32 / n as f32 * 2.0 - 1.0,
+            slot.y(val, min, max),
+            0.5
+        );
+
+        points.push(t);
+        if first {
+            first = false;
+        } else {
+            points.push(t);
+        }
+    }
+    points.pop();
+}
+
+// largest 1/2/5 x 10^k step that gives at least `max_ticks` ticks over `span`
+fn tick_step(span : f32, max_ticks : f32) -> f32 {
+    let raw = span / max_ticks.max(1.0);
+    let magnitude = 10f32.powf(raw.log10().floor());
+
+    [1.0, 2.0, 5.0, 10.0].iter()
+        .map(|m| m * magnitude)
+        .find(|step| *step >= raw)
+        .unwrap_or(10.0 * magnitude)
+}
+
+// formats an axis value with enough decimals to tell gridlines `step` apart
+fn format_value(val : f32, step : f32) -> String {
+    let decimals = (-step.log10().floor()).max(0.0) as usize;
+    format!("{:.*}", decimals, val)
+}
+
+fn format_time(seconds : f32, step : f32) -> String {
+    if step < 1e-3 {
+        format!("{:.0} us", seconds * 1e6)
+    } else if step < 1.0 {
+        let decimals = (-(step * 1e3).log10().floor()).max(0.0) as usize;
+        format!("{:.*} ms", decimals, seconds * 1e3)
+    } else {
+        format!("{:.0} s", seconds)
+    }
+}
+
+fn save_command_log(path : &Path, log : &[CommandLogEntry]) -> std::io::Result<()> {
+    let mut text = String::from("unix_time,elapsed_s,success,command\n");
+
+    for entry in log {
+        let unix_time = entry.timestamp.duration_since(std::time::UNIX_EPOCH).unwrap_or_default();
+
+        text.push_str(&format!(
+            "{:.3},{:.3},{},\"{}\"\n",
+            unix_time.as_secs_f64(),
+            entry.elapsed.as_secs_f64(),
+            entry.success,
+            entry.description.replace('"', "\"\"")
+        ));
+    }
+
+    std::fs::write(path, text)
+}
+
+// file name safe version of a capture name, e.g. "kp=5, ki=0.1" -> "kp_5_ki_0.1"
+fn capture_file_name(name : &str) -> String {
+    let mut file_name = String::new();
+
+    for c in name.trim().chars() {
+        if c.is_ascii_alphanumeric() || c == '-' || c == '.' {
+            file_name.push(c);
+        } else if !file_name.ends_with('_') {
+            file_name.push('_');
+        }
+    }
+
+    let file_name = file_name.trim_matches('_');
+
+    if file_name.is_empty() {
+        "capture.csv".to_string()
+    } else {
+        format!("{}.csv", file_name)
+    }
+}
+
+fn save_capture(path : &Path, name : &str, samples : &[OscilloscopeSamplePoint], sample_period : f32) -> std::io::Result<()> {
+    let mut text = String::new();
+
+    if !name.is_empty() {
+        text.push_str(&format!("# capture: {}\n", name.replace('\n', " ")));
+    }
+
+    text.push_str("index,time_s");
+    for (channel, _) in SAMPLE_CHANNELS {
+        text.push_str(&format!(",{}", channel));
+    }
+    text.push('\n');
+
+    for (i, pt) in samples.iter().enumerate() {
+        text.push_str(&format!("{},{:.6}", i, i as f32 * sample_period));
+        for (_, func) in SAMPLE_CHANNELS {
+            text.push_str(&format!(",{}", func(pt)));
+        }
+        text.push('\n');
+    }
+
+    std::fs::write(path, text)
+}
+
+const MAX_HEXDUMP_LEN : usize = 16 * 1024;
+// gives a reset board time to come back on the bus before the probe is looked up again
+const RECONNECT_DELAY : Duration = Duration::from_millis(1000);
+
+const MAX_ERROR_LOG_ENTRIES : usize = 1000;
+
+// width of the draggable strip between panels
+const DIVIDER_SIZE : u32 = 4;
+
+// `addr: 16 hex bytes |ascii|`, short rows are padded so the ascii column lines up
+fn hexdump_line(addr : u32, bytes : &[u8]) -> String {
+    let hex = (0..16)
+        .map(|i| bytes.get(i).map_or("  ".to_string(), |b| format!("{:02X}", b)))
+        .collect::<Vec<_>>()
+        .join(" ");
+
+    let ascii = bytes.iter()
+        .map(|&b| if b.is_ascii_graphic() || b == b' ' { b as char } else { '.' })
+        .collect::<String>();
+
+    format!("{:08X}: {} |{}|", addr, hex, ascii)
+}
+
+fn duration_ms_input(ui : &imgui::Ui, label : &imgui::ImStr, duration : &mut Duration) {
+    let mut ms = duration.as_millis() as i32;
+
+    if ui.input_int(label, &mut ms).build() {
+        *duration = Duration::from_millis(ms.max(0) as u64);
+    }
+}
+
+impl GuiState {
+    pub fn init() -> Self {
+        let settings = Settings::load();
+        let auto_save_dir = imstring_buffer(&settings.auto_save_dir, 256);
+
+        GuiState {
+            devices : vec![],
+            active_device : 0,
+            connected : Arc::new(AtomicBool::new(false)),
+            sample_buffer: Arc::new(Mutex::new(vec![])),
+            controller_data: Arc::new(Mutex::new(ControllerData::default())),
+            controller_commands: Arc::new(Mutex::new(vec![])),
+            rate_limits: Arc::new(Mutex::new(CommandRateLimits::default())),
+            position_limits: Arc::new(Mutex::new(PositionLimits::default())),
+            swd_settings: Arc::new(Mutex::new(AdaptiveSwdSettings::default())),
+            poll_settings: Arc::new(Mutex::new(PollSettings::default())),
+            reconnect_settings: Arc::new(Mutex::new(ReconnectSettings::default())),
+            trigger: Arc::new(Mutex::new(Trigger::default())),
+            slow_poll: Arc::new(AtomicBool::new(false)),
+            invert_direction : Arc::new(AtomicBool::new(false)),
+            estop : Arc::new(AtomicBool::new(false)),
+            backgrounded : false,
+            slow_poll_in_background : true,
+            frozen_samples : None,
+            frozen_at : Instant::now(),
+            capture_name : imgui::ImString::with_capacity(64),
+            sample_export_status : None,
+            config_file_status : None,
+            link_test_addr : imstring_buffer("", 32),
+            swd_reset_on_enter : false,
+            usb_timeout : Duration::from_millis(200),
+            flash_config_addr : imstring_buffer("0x0801F800", 32),
+            tasks : vec![],
+            panels : Panels {
+                devices : true,
+                servo_status : true,
+                configuration : true,
+                tuning_controls : true,
+                analysis : true,
+                raw_command : false,
+                usb_diagnostics : false,
+                hexdump : false,
+                command_log : false,
+                error_log : false,
+                tuning_wizard : false,
+                profiler : false,
+                firmware_loader : false,
+                move_sequence : false,
+                locked : true,
+            },
+            smooth_move_target : 1.0,
+            smooth_move_duration : 0.5,
+            step_measure_size : 1.0,
+            step_measure_duration : 1.0,
+            step_measure_started : None,
+            step_measure_result : None,
+            sine_frequency : 1.0,
+            sine_amplitude : 1.0,
+            chirp_start_hz : 1.0,
+            chirp_end_hz : 50.0,
+            chirp_duration : 10.0,
+            chirp_amplitude : 0.1,
+            smooth_move_profile : 0,
+            trapezoid_target : 1.0,
+            trapezoid_max_vel : 5.0,
+            trapezoid_accel : 50.0,
+            motion_profile : 0,
+            sent_motion_profile : None,
+            pending_homing : None,
+            stats_channel : 0,
+            control_loop_hz : 10_000.0,
+            input_filter_bandwidth : 20.0,
+            input_filter_preview : false,
+            model_prediction_preview : false,
+            plot_follow_live : true,
+            plot_view : None,
+            plot_window_seconds : settings.plot.window_seconds,
+            show_peak_velocity : false,
+            show_tuning_hud : true,
+            plot_refresh_hz : settings.plot.refresh_hz,
+            plot_max_points : settings.plot.max_points,
+            plot_profile_name : imstring_buffer("", 64),
+            plot_band_ranges : vec![],
+            last_plot_refresh : Instant::now(),
+            windup_margin : 0.01,
+            show_windup : true,
+            oscilloscope_len : 0,
+            oscilloscope_interval : 0,
+            raw_command_index : 0,
+            raw_command_value : 0.0,
+            parameter_focus : ParameterFocus::default(),
+            hexdump_addr : imstring_buffer("0x20000000", 32),
+            hexdump_len : 256,
+            hexdump_live : false,
+            anticogging_addr : imstring_buffer("", 32),
+            anticogging_status : None,
+            pending_anticogging_import : None,
+            command_log_autoscroll : true,
+            command_log_status : None,
+            error_log : vec![],
+            error_log_autoscroll : true,
+            started_at : Instant::now(),
+            settings_changed : false,
+            tuning_wizard : TuningWizard::default(),
+            settings,
+            profiler : FrameProfiler::default(),
+            last_connection_loop_time : Duration::from_secs(0),
+            jog_target : None,
+            firmware_path : None,
+            // clear of the pointer slot at CONFIG_ADDR_ADDR
+            firmware_addr : imstring_buffer("0x20001000", 32),
+            firmware_allow_flash : false,
+            firmware_to_flash : false,
+            auto_save_dir,
+            diagnostics : None,
+            last_sample_count : 0,
+            last_sample_growth : Instant::now(),
+            sequence_steps : vec![
+                SequenceStep { kind : StepKind::Position, value : 0.0 },
+                SequenceStep { kind : StepKind::Wait, value : 1.0 },
+                SequenceStep { kind : StepKind::Position, value : 1.0 },
+                SequenceStep { kind : StepKind::Wait, value : 1.0 },
+            ],
+            sequence_repeats : 1,
+            sequence_loop : false,
+            sequence_recorded_at : None,
+            sequence_running : None,
+            quit_requested : false,
+            auto_save_error : None,
+            skip_auto_save : false,
+        }
+    }
+
+    /// Replaces the whole controller config in a single write rather than one parameter at a time,
+    /// so the firmware never runs on a mix of old and new gains. Used for anything that loads a
+    /// complete config.
+    fn import_config(&self, config : ServoConfig) {
+        self.controller_commands.lock().push(InterfaceCommand::WriteServoConfig(config));
+    }
+
+    /// Called by the event loop when the window is closed.
+    /// Exports the config if auto-save is on. Returns `false` if the export failed, the window then
+    /// stays open to show why.
+    pub fn on_exit(&mut self) -> bool {
+        // the last change may still be waiting for the mouse to be released
+        if self.settings_changed {
+            self.settings_changed = false;
+            self.settings.save();
+        }
+
+        if !self.settings.auto_save_config || self.skip_auto_save {
+            return true;
+        }
+
+        let config = {
+            let controller_data = self.controller_data.lock();
+
+            // nothing was ever read from a controller this session
+            if controller_data.controller_pointers.is_none() {
+                return true;
+            }
+
+            controller_data.servo_config.clone()
+        };
+
+        let dir = self.settings.auto_save_path();
+
+        match save_timestamped_config(&dir, &config) {
+            Ok(_) => true,
+            Err(e) => {
+                self.auto_save_error = Some(format!("Failed to export the config to {}: {}", dir.display(), e));
+                self.quit_requested = false;
+                false
+            }
+        }
+    }
+
+    /// Set when Quit is picked from the File menu, the event loop exits after the frame.
+    pub fn quit_requested(&self) -> bool {
+        self.quit_requested
+    }
+
+    fn save_config_to_file(&mut self, config : &ServoConfig) {
+        if let Ok(nfd::Response::Okay(path)) = nfd::open_save_dialog(Some("toml"), None) {
+            self.config_file_status = Some(match save_config(Path::new(&path), config) {
+                Ok(()) => format!("Saved to {}", path),
+                Err(e) => format!("Failed to save: {}", e),
+            });
+        }
+    }
+
+    fn load_config_from_file(&mut self) {
+        if let Ok(nfd::Response::Okay(path)) = nfd::open_file_dialog(Some("toml"), None) {
+            self.config_file_status = Some(match load_config(Path::new(&path)) {
+                Ok(mut config) => {
+                    config.antcogging_torque = self.controller_data.lock().servo_config.antcogging_torque;
+                    self.import_config(config);
+                    format!("Wrote {} to the controller", path)
+                }
+                Err(e) => format!("Refused to load {}: {}", path, e),
+            });
+        }
+    }
+
+    fn export_samples(&mut self) {
+        let samples = self.sample_buffer.lock().clone();
+
+        if samples.is_empty() {
+            self.sample_export_status = Some("No samples recorded".to_string());
+        } else if let Ok(nfd::Response::Okay(path)) = nfd::open_save_dialog(Some("csv"), None) {
+            self.sample_export_status = Some(match save_capture(Path::new(&path), "", &samples, self.sample_period()) {
+                Ok(()) => format!("Exported {} samples", samples.len()),
+                Err(e) => format!("Export failed: {}", e),
+            });
+        }
+    }
+
+    /// Called by the event loop after each rendered frame.
+    pub fn record_frame_timing(&mut self, frame : Duration, gui : Duration) {
+        self.profiler.frame.push(frame);
+        self.profiler.gui.push(gui);
+
+        // the connection loop runs on its own schedule, so only count each iteration once
+        let loop_time = self.controller_data.lock().loop_time;
+        if self.connected.load(Ordering::Relaxed) && loop_time != self.last_connection_loop_time {
+            self.profiler.connection_loop.push(loop_time);
+            self.last_connection_loop_time = loop_time;
+        }
+    }
+
+    /// Called by the event loop when the window loses focus or is minimized, and when it comes back.
+    pub fn set_backgrounded(&mut self, backgrounded : bool) {
+        self.backgrounded = backgrounded;
+        self.slow_poll.store(backgrounded && self.slow_poll_in_background, Ordering::Relaxed);
+    }
+
+    /// Points the panels and plot at another device.
+    fn select_device(&mut self, index : usize) {
+        if let Some(dev) = self.devices.get(index) {
+            self.active_device = index;
+            self.connected = dev.connected.clone();
+            self.sample_buffer = dev.sample_buffer.clone();
+            self.controller_data = dev.controller_data.clone();
+            self.controller_commands = dev.controller_commands.clone();
+            self.trigger = dev.trigger.clone();
+            self.position_limits = dev.position_limits.clone();
+            self.invert_direction = dev.invert_direction.clone();
+
+            self.frozen_samples = None;
+            self.last_sample_count = 0;
+
+            // undoing would write another controller's old values to this one
+            self.parameter_focus.history.clear();
+            self.parameter_focus.edited = None;
+
+            // picked up again from the newly selected device
+            self.oscilloscope_len = 0;
+            self.oscilloscope_interval = 0;
+        }
+    }
+
+    // only shown with more than one device connected
+    fn device_selector(&mut self, ui : &imgui::Ui, id : &str) {
+        let connected = self.devices.iter()
+            .enumerate()
+            .filter(|(_, dev)| dev.connected.load(Ordering::Relaxed))
+            .map(|(i, _)| i)
+            .collect::<Vec<_>>();
+
+        if connected.len() < 2 {
+            return;
+        }
+
+        let mut selected = self.active_device;
+
+        ui.text("Device:");
+
+        for i in connected {
+            ui.same_line(0.0);
+            ui.radio_button(im_strf!("Device {}##{}", i, id), &mut selected, i);
+        }
+
+        if selected != self.active_device {
+            self.select_device(selected);
+        }
+    }
+
+    fn refresh_devices(&mut self) {
+        // connected devices are kept, their tasks still hold them, and simulated ones can't be found again
+        self.devices.retain(|dev| dev.connected.load(Ordering::Relaxed) || dev.link.lock().is_connected() || dev.usb_location().is_none());
+
+        for link in STLink::enumerate() {
+            let location = Some((link.device.bus_number(), link.device.address()));
+
+            if !self.devices.iter().any(|dev| dev.usb_location() == location) {
+                self.devices.push(DeviceContext::new(Box::new(link), &self.settings));
+            }
+        }
+
+        // removed devices shift the rest down, so the selection is found again by identity
+        let active = self.devices.iter().position(|dev| Arc::ptr_eq(&dev.connected, &self.connected));
+
+        match active {
+            Some(index) if self.connected.load(Ordering::Relaxed) => self.active_device = index,
+            _ => self.select_device(0),
+        }
+    }
+
+    // runs against a simulated controller, for working without a probe and motor
+    fn add_simulated_device(&mut self) {
+        self.devices.push(DeviceContext::new(Box::new(MockStlink::new()), &self.settings));
+    }
+
+    fn connect_device(&mut self, index : usize) {
+        let dev = self.devices[index].clone();
+
+        dev.link.lock().set_usb_timeout(self.usb_timeout.max(Duration::from_millis(1)));
+
+        let rate_limits = self.rate_limits.clone();
+        let slow_poll = self.slow_poll.clone();
+        let swd_settings = self.swd_settings.clone();
+        let estop = self.estop.clone();
+        let poll_settings = self.poll_settings.clone();
+        let reconnect_settings = self.reconnect_settings.clone();
+
+        std::thread::spawn(move || {
+            let location = dev.usb_location();
+            let mut attempts = 0;
+
+            loop {
+                // the task reads the controller pointers again on every connect, they can move after a reset
+                controller_connection_task(
+                    dev.link.clone(), 
+                    dev.connected.clone(), 
+                    dev.controller_data.clone(), 
+                    dev.sample_buffer.clone(),
+                    dev.controller_commands.clone(),
+                    rate_limits.clone(),
+                    dev.trigger.clone(),
+                    slow_poll.clone(),
+                    dev.position_limits.clone(),
+                    swd_settings.clone(),
+                    dev.invert_direction.clone(),
+                    estop.clone(),
+                    poll_settings.clone(),
+                );
+
+                let settings = reconnect_settings.lock().clone();
+
+                // the task asked to be started again, e.g. after a hardware reset
+                if std::mem::replace(&mut dev.controller_data.lock().restart_requested, false) {
+                    continue;
+                }
+
+                // disconnecting or cancelling leaves no error behind
+                if dev.controller_data.lock().connection_error.is_none() || !settings.enabled || attempts >= settings.max_attempts {
+                    break;
+                }
+
+                attempts += 1;
+                eprintln!("Reconnecting to {} (attempt {}/{})", dev.label(), attempts, settings.max_attempts);
+
+                // marked as connecting so the device can't be connected twice, Cancel stops the retries
+                dev.connected.store(true, Ordering::SeqCst);
+                dev.controller_data.lock().connection_stage = Some("Waiting to reconnect");
+                std::thread::sleep(RECONNECT_DELAY);
+                dev.controller_data.lock().connection_stage = None;
+
+                if !dev.connected.load(Ordering::Relaxed) {
+                    break;
+                }
+
+                // the old handle is stale once the probe has been re-enumerated
+                let found = STLink::enumerate().into_iter()
+                    .find(|link| Some((link.device.bus_number(), link.device.address())) == location);
+
+                if let Some(mut link) = found {
+                    link.usb_timeout = dev.link.lock().usb_timeout();
+                    *dev.link.lock() = Box::new(link);
+                }
+            }
+        });
+
+        self.select_device(index);
+    }
+
+    pub fn theme(&self) -> Theme {
+        self.settings.theme
+    }
+
+    /// Motor shortcuts, handled as the key events arrive so a stop isn't held up by a slow frame.
+    /// Space and Escape stop the motor even while typing, the others are ignored then.
+    pub fn handle_key(&mut self, key : VirtualKeyCode, typing : bool) {
+        let cmd = match key {
+            VirtualKeyCode::Space | VirtualKeyCode::Escape => InterfaceCommand::StopMotor,
+            _ if typing || self.estop.load(Ordering::SeqCst) => return,
+            VirtualKeyCode::S => InterfaceCommand::StartMotor,
+            VirtualKeyCode::Key0 | VirtualKeyCode::Numpad0 => InterfaceCommand::PositionCommand(0.0),
+            VirtualKeyCode::Key1 | VirtualKeyCode::Numpad1 => InterfaceCommand::PositionCommand(1.0),
+            _ => return,
+        };
+
+        if self.connected.load(Ordering::Relaxed) {
+            self.controller_commands.lock().push(cmd);
+        }
+    }
+
+    fn emergency_stop(&mut self) {
+        self.estop.store(true, Ordering::SeqCst);
+
+        // their commands would be blocked anyway, but they shouldn't pick up again after a clear
+        for task in self.tasks.drain(..) {
+            task.running.store(false, Ordering::Relaxed);
+        }
+
+        for dev in self.devices.iter().filter(|dev| dev.connected.load(Ordering::Relaxed)) {
+            let mut commands = dev.controller_commands.lock();
+
+            // whatever is still queued was meant to run before the stop
+            commands.clear();
+            commands.push(InterfaceCommand::EmergencyStop);
+        }
+    }
+
+    fn estop_button(&mut self, ui : &imgui::Ui, size : [f32; 2]) {
+        use imgui::im_str;
+        use imgui::StyleColor;
+
+        if self.estop.load(Ordering::SeqCst) {
+            let colors = ui.push_style_colors(&[
+                (StyleColor::Button, [0.85, 0.55, 0.0, 1.0]),
+                (StyleColor::ButtonHovered, [0.95, 0.65, 0.1, 1.0]),
+                (StyleColor::ButtonActive, [0.75, 0.45, 0.0, 1.0]),
+            ]);
+            if ui.button(im_str!("Clear E-STOP"), size) {
+                self.estop.store(false, Ordering::SeqCst);
+            }
+            colors.pop(ui);
+            if ui.is_item_hovered() {
+                ui.tooltip_text("E-STOP is latched, motion commands are blocked until this is cleared");
+            }
+        } else {
+            let colors = ui.push_style_colors(&[
+                (StyleColor::Button, [0.8, 0.05, 0.05, 1.0]),
+                (StyleColor::ButtonHovered, [0.95, 0.1, 0.1, 1.0]),
+                (StyleColor::ButtonActive, [0.6, 0.0, 0.0, 1.0]),
+                (StyleColor::Text, [1.0, 1.0, 1.0, 1.0]),
+            ]);
+            if ui.button(im_str!("E-STOP"), size) {
+                self.emergency_stop();
+            }
+            colors.pop(ui);
+            if ui.is_item_hovered() {
+                ui.tooltip_text("Stops the motor on every connected device, zeros the setpoints and blocks motion until cleared");
+            }
+        }
+    }
+
+    fn undo_parameter_edit(&mut self) {
+        if let Some(edit) = self.parameter_focus.history.undo() {
+            self.controller_commands.lock().push(InterfaceCommand::UpdateConfigParameter(edit.offset, edit.old_value));
+        }
+    }
+
+    fn redo_parameter_edit(&mut self) {
+        if let Some(edit) = self.parameter_focus.history.redo() {
+            self.controller_commands.lock().push(InterfaceCommand::UpdateConfigParameter(edit.offset, edit.new_value));
+        }
+    }
+
+    fn diagnostic_inputs(&self) -> DiagnosticInputs {
+        let connected_link = self.devices.get(self.active_device).filter(|dev| dev.link.lock().is_connected());
+        let controller_data = self.controller_data.lock();
+        let pointers = controller_data.controller_pointers.as_ref();
+
+        DiagnosticInputs {
+            probes_found : self.devices.len(),
+            connected : self.connected.load(Ordering::Relaxed),
+            probe_mode : controller_data.probe_mode,
+            usb_errors_per_minute : connected_link.map_or(0.0, |dev| dev.link.lock().usb_errors().per_minute()),
+            magic_valid : pointers.map(|p| p.magic_valid()),
+            ready : pointers.map(|p| p.ready),
+            aligned : controller_data.servo_state.aligned,
+            fault : controller_data.fault_detected,
+            firmware_stalled : controller_data.firmware_stalled,
+            samples_flowing : self.last_sample_growth.elapsed() < Duration::from_secs(1),
+        }
+    }
+
+    fn sample_period(&self) -> f32 {
+        self.controller_data.lock().oscilloscope.interval.max(1) as f32 / self.control_loop_hz
+    }
+
+    // range of the sample buffer shown in the plot
+    fn visible_range(&self, len : usize) -> (usize, usize) {
+        if let Some((start, end)) = self.plot_view {
+            let end = (end.max(0.0) as usize).min(len);
+
+            ((start.max(0.0) as usize).min(end.saturating_sub(2)), end)
+        } else if self.plot_follow_live {
+            let window = (self.plot_window_seconds / self.sample_period()).ceil() as usize;
+
+            (len.saturating_sub(window.max(2)), len)
+        } else {
+            (0, len)
+        }
+    }
+
+    pub fn frame(&mut self, system : &mut System, ui : &mut imgui::Ui, _async_runtime : &mut tokio::runtime::Runtime, viewport : &mut crate::viewport::Viewport, line_renderer : &mut LineRenderer) {
+
+        use imgui::im_str;
+
+        self.tasks.retain(|task| task.running.load(Ordering::Relaxed));
+
+        let sample_count = self.sample_buffer.lock().len();
+        if sample_count != self.last_sample_count {
+            self.last_sample_count = sample_count;
+            self.last_sample_growth = Instant::now();
+        }
+
+        for (i, dev) in self.devices.iter().enumerate() {
+            let errors = std::mem::take(&mut dev.controller_data.lock().errors);
+
+            for error in errors {
+                self.error_log.push(format!("[{:>10.3} s] Device {}: {}", self.started_at.elapsed().as_secs_f32(), i, error));
+            }
+        }
+
+        if self.error_log.len() > MAX_ERROR_LOG_ENTRIES {
+            let excess = self.error_log.len() - MAX_ERROR_LOG_ENTRIES;
+            self.error_log.drain(0..excess);
+        }
+
+        // the old values belong to the controller that was connected
+        if !self.connected.load(Ordering::Relaxed) {
+            self.parameter_focus.history.clear();
+        }
+
+        // text fields handle their own Ctrl+Z
+        if ui.io().key_ctrl && !ui.io().want_text_input {
+            if ui.is_key_pressed(imgui::Key::Z) {
+                self.undo_parameter_edit();
+            } else if ui.is_key_pressed(imgui::Key::Y) {
+                self.redo_parameter_edit();
+            }
+        }
+
+        if self.trigger.lock().state == TriggerState::Captured && self.frozen_samples.is_none() {
+            self.frozen_samples = Some(self.sample_buffer.lock().clone());
+            self.frozen_at = Instant::now();
+        }
+
+        let PhysicalSize { width, height } = system.surface.window().inner_size();
+
+        let mut menu_bar_height = 0.0;
+
+        if self.auto_save_error.is_some() {
+            ui.open_popup(im_str!("Config Export Failed"));
+        }
+
+        imgui::PopupModal::new(im_str!("Config Export Failed"))
+            .always_auto_resize(true)
+            .build(ui, || {
+                if let Some(error) = &self.auto_save_error {
+                    ui.text(error);
+                }
+
+                if ui.button(im_str!("Quit Without Exporting"), [0.0, 0.0]) {
+                    self.auto_save_error = None;
+                    self.skip_auto_save = true;
+                    self.quit_requested = true;
+                    ui.close_current_popup();
+                }
+                ui.same_line(0.0);
+                if ui.button(im_str!("Cancel##Config Export Failed"), [0.0, 0.0]) {
+                    self.auto_save_error = None;
+                    ui.close_current_popup();
+                }
+            });
+
+        ui.main_menu_bar(|| {
+            let connected = self.connected.load(Ordering::Relaxed);
+
+            ui.menu(im_str!("File"), true, || {
+                if imgui::MenuItem::new(im_str!("Save Config...")).enabled(connected).build(ui) {
+                    let config = self.controller_data.lock().servo_config.clone();
+                    self.save_config_to_file(&config);
+                }
+                if imgui::MenuItem::new(im_str!("Load Config...")).enabled(connected).build(ui) {
+                    self.load_config_from_file();
+                }
+                if imgui::MenuItem::new(im_str!("Export Samples CSV...")).build(ui) {
+                    self.export_samples();
+                }
+                ui.separator();
+                if imgui::MenuItem::new(im_str!("Quit")).build(ui) {
+                    self.quit_requested = true;
+                }
+            });
+
+            ui.menu(im_str!("Device"), true, || {
+                if imgui::MenuItem::new(im_str!("Refresh Devices")).build(ui) {
+                    self.refresh_devices();
+                }
+
+                if imgui::MenuItem::new(im_str!("Add Simulated Device")).build(ui) {
+                    self.add_simulated_device();
+                }
+
+                let mut connect_to = None;
+
+                ui.menu(im_str!("Connect"), self.devices.iter().any(|dev| !dev.connected.load(Ordering::Relaxed)), || {
+                    for (i, dev) in self.devices.iter().enumerate() {
+                        if dev.connected.load(Ordering::Relaxed) {
+                            continue;
+                        }
+
+                        let label = format!("[{}] {}", i, dev.label());
+
+                        if imgui::MenuItem::new(&imgui::ImString::new(label)).build(ui) {
+                            connect_to = Some(i);
+                        }
+                    }
+                });
+
+                if let Some(i) = connect_to {
+                    self.connect_device(i);
+                }
+
+                if imgui::MenuItem::new(im_str!("Disconnect")).enabled(connected).build(ui) {
+                    self.connected.store(false, Ordering::Relaxed);
+                }
+                ui.separator();
+                if imgui::MenuItem::new(im_str!("Reset Microcontroller")).enabled(connected).build(ui) {
+                    self.controller_commands.lock().push(InterfaceCommand::ResetController);
+                }
+                if imgui::MenuItem::new(im_str!("Hardware Reset (NRST)")).enabled(connected).build(ui) {
+                    self.controller_commands.lock().push(InterfaceCommand::HardwareReset);
+                }
+            });
+
+            ui.menu(im_str!("View"), true, || {
+                imgui::MenuItem::new(im_str!("Devices")).build_with_ref(ui, &mut self.panels.devices);
+                imgui::MenuItem::new(im_str!("Servo Status")).build_with_ref(ui, &mut self.panels.servo_status);
+                imgui::MenuItem::new(im_str!("Configuration")).build_with_ref(ui, &mut self.panels.configuration);
+                imgui::MenuItem::new(im_str!("Tuning Controls")).build_with_ref(ui, &mut self.panels.tuning_controls);
+                imgui::MenuItem::new(im_str!("Analysis")).build_with_ref(ui, &mut self.panels.analysis);
+                imgui::MenuItem::new(im_str!("Raw Command")).build_with_ref(ui, &mut self.panels.raw_command);
+                imgui::MenuItem::new(im_str!("USB Diagnostics")).build_with_ref(ui, &mut self.panels.usb_diagnostics);
+                imgui::MenuItem::new(im_str!("Hexdump")).build_with_ref(ui, &mut self.panels.hexdump);
+                imgui::MenuItem::new(im_str!("Command Log")).build_with_ref(ui, &mut self.panels.command_log);
+                imgui::MenuItem::new(im_str!("Error Log")).build_with_ref(ui, &mut self.panels.error_log);
+                imgui::MenuItem::new(im_str!("Tuning Wizard")).build_with_ref(ui, &mut self.panels.tuning_wizard);
+                imgui::MenuItem::new(im_str!("Profiler Overlay")).build_with_ref(ui, &mut self.panels.profiler);
+                imgui::MenuItem::new(im_str!("Firmware Loader")).build_with_ref(ui, &mut self.panels.firmware_loader);
+                imgui::MenuItem::new(im_str!("Move Sequence")).build_with_ref(ui, &mut self.panels.move_sequence);
+                ui.separator();
+                ui.menu(im_str!("Traces"), true, || {
+                    let mut changed = false;
+
+                    for channel in self.settings.plot.channels.iter_mut() {
+                        changed |= imgui::MenuItem::new(&imgui::ImString::new(channel.name.as_str())).build_with_ref(ui, &mut channel.visible);
+                    }
+
+                    if changed {
+                        self.settings_changed = true;
+                        self.last_plot_refresh = Instant::now() - Duration::from_secs(1);
+                    }
+                });
+                ui.menu(im_str!("Theme"), true, || {
+                    for theme in THEMES.iter() {
+                        if imgui::MenuItem::new(&imgui::ImString::new(theme.name())).selected(self.settings.theme == *theme).build(ui) {
+                            self.settings.theme = *theme;
+                            self.settings.save();
+                        }
+                    }
+                });
+                imgui::MenuItem::new(im_str!("Lock Layout")).build_with_ref(ui, &mut self.panels.locked);
+                imgui::MenuItem::new(im_str!("Slow USB Polling in Background")).build_with_ref(ui, &mut self.slow_poll_in_background);
+            });
+
+            self.estop_button(ui, [0.0, 0.0]);
+
+            menu_bar_height = ui.window_size()[1];
+        });
+
+        let (_, window_rect) = LayoutRect::new(width, height).horizontal_split_top_abs(menu_bar_height as u32);
+
+        // hidden panels give their space to the plot
+        let show_sidepanel = self.panels.devices || self.panels.servo_status || self.panels.configuration;
+        let show_tool_menu = self.panels.tuning_controls || self.panels.analysis;
+        let layout = self.settings.layout.clone();
+
+        let (sidepanel_rect, viewport_rect) = window_rect.vertical_split_left_frac(if show_sidepanel { layout.sidepanel } else { 0.0 });
+        let (sidepanel_divider, viewport_rect) = viewport_rect.vertical_split_left_abs(if show_sidepanel { DIVIDER_SIZE } else { 0 });
+
+        let (viewport_rect, tool_menu_rect) = viewport_rect.horizontal_split_bottom_frac(if show_tool_menu { layout.tool_menu } else { 0.0 });
+        let (viewport_rect, tool_menu_divider) = viewport_rect.horizontal_split_bottom_abs(if show_tool_menu { DIVIDER_SIZE } else { 0 });
+
+        let analysis_w = match (self.panels.tuning_controls, self.panels.analysis) {
+            (_, false) => 0,
+            (false, true) => tool_menu_rect.w,
+            (true, true) => 300,
+        };
+
+        let (tool_menu_rect, analysis_rect) = tool_menu_rect.vertical_split_right_abs(analysis_w);
+
+        let devices_split = self.panels.devices && (self.panels.servo_status || self.panels.configuration);
+
+        let devices_frac = match (self.panels.devices, devices_split) {
+            (false, _) => 0.0,
+            (true, false) => 1.0,
+            (true, true) => layout.devices,
+        };
+
+        let (devices_rect, config_menu_rect) = sidepanel_rect.horizontal_split_top_frac(devices_frac);
+        let (devices_divider, config_menu_rect) = config_menu_rect.horizontal_split_top_abs(if devices_split { DIVIDER_SIZE } else { 0 });
+
+        let status_h = match (self.panels.servo_status, self.panels.configuration) {
+            (false, _) => 0,
+            (true, false) => config_menu_rect.h,
+            (true, true) => 150,
+        };
+
+        let (status_rect, config_menu_rect) = config_menu_rect.horizontal_split_top_abs(status_h);
+
+        {
+            let sidepanel_drag = split_divider(ui, im_str!("##Side Panel Divider"), sidepanel_divider, imgui::MouseCursor::ResizeEW)[0];
+            let tool_menu_drag = split_divider(ui, im_str!("##Tool Menu Divider"), tool_menu_divider, imgui::MouseCursor::ResizeNS)[1];
+            let devices_drag = split_divider(ui, im_str!("##Devices Divider"), devices_divider, imgui::MouseCursor::ResizeNS)[1];
+
+            let resize = |frac : f32, drag : f32, len : u32| (frac + drag / len.max(1) as f32).max(0.05).min(0.95);
+
+            let layout = &mut self.settings.layout;
+            layout.sidepanel = resize(layout.sidepanel, sidepanel_drag, window_rect.w);
+            // the tool menu is split off the bottom, so dragging down shrinks it
+            layout.tool_menu = resize(layout.tool_menu, -tool_menu_drag, window_rect.h);
+            layout.devices = resize(layout.devices, devices_drag, sidepanel_rect.h);
+
+            if sidepanel_drag != 0.0 || tool_menu_drag != 0.0 || devices_drag != 0.0 {
+                self.settings_changed = true;
+            }
+        }
+
+
+        if self.panels.devices {
+            let mut open = true;
+
+            panel_window(im_str!("Devices"), devices_rect, self.panels.locked)
+                .opened(&mut open)
+                .build(ui, || {
+                    if ui.small_button(im_str!("Refresh Devices")) {
+                        self.refresh_devices();
+                    }
+                    ui.same_line(0.0);
+                    if ui.small_button(im_str!("Add Simulated")) {
+                        self.add_simulated_device();
+                    }
+                    ui.same_line(0.0);
+                    if ui.small_button(im_str!("Diagnose")) {
+                        self.diagnostics = Some(run_checks(&self.diagnostic_inputs()));
+                    }
+
+                    if let Some(checks) = &self.diagnostics {
+                        for check in checks {
+                            if check.passed {
+                                ui.text_colored([0.3, 0.9, 0.3, 1.0], format!("[PASS] {}", check.name));
+                            } else {
+                                ui.text_colored([1.0, 0.3, 0.3, 1.0], format!("[FAIL] {}", check.name));
+                            }
+                        }
+
+                        if let Some(failed) = checks.iter().find(|check| !check.passed) {
+                            ui.text_wrapped(&imgui::ImString::new(failed.hint));
+                        } else {
+                            ui.text("Everything looks good.");
+                        }
+
+                        if ui.small_button(im_str!("Dismiss##Diagnose")) {
+                            self.diagnostics = None;
+                        }
+
+                        ui.separator();
+                    }
+
+                    duration_ms_input(ui, im_str!("USB Timeout (ms)"), &mut self.usb_timeout);
+
+                    {
+                        let mut settings = self.swd_settings.lock();
+
+                        let freq_names = SWD_FREQUENCIES.iter()
+                            .map(|f| imgui::ImString::new(format!("{} kHz", f.hz() / 1000)))
+                            .collect::<Vec<_>>();
+                        let freq_refs = freq_names.iter().collect::<Vec<_>>();
+
+                        // only applied when connecting, the adaptive setting takes over from there
+                        let mut freq_index = SWD_FREQUENCIES.iter().position(|f| *f == settings.initial_freq).unwrap_or(0);
+                        if imgui::ComboBox::new(im_str!("SWD Frequency")).build_simple_string(ui, &mut freq_index, &freq_refs) {
+                            settings.initial_freq = SWD_FREQUENCIES[freq_index];
+                        }
+                    }
+
+                    {
+                        let mut reconnect = self.reconnect_settings.lock();
+
+                        ui.checkbox(im_str!("Auto-reconnect"), &mut reconnect.enabled);
+
+                        if reconnect.enabled {
+                            ui.same_line(0.0);
+                            ui.set_next_item_width(80.0);
+                            let mut max_attempts = reconnect.max_attempts as i32;
+                            if ui.input_int(im_str!("Attempts##Reconnect"), &mut max_attempts).build() {
+                                reconnect.max_attempts = max_attempts.max(1) as u32;
+                            }
+                        }
+                    }
+
+                    let mut connect_to = None;
+
+                    for (i, dev) in self.devices.iter().enumerate() {
+
+                        let name = dev.link.lock().name();
+
+                        match dev.link.lock().firmware_version() {
+                            Some(version) => ui.text(format!("[{}] {} ({})", i, name, version)),
+                            None => ui.text(format!("[{}] {}", i, name)),
+                        }
+
+                        let connection_stage = dev.controller_data.lock().connection_stage;
+
+                        if dev.link.lock().is_connected() {
+                            ui.same_line(400.0 - 80.0);
+                            if ui.small_button(im_strf!("Disconnect##Disconnect Device {:03}", i)) {
+                                dev.connected.store(false, std::sync::atomic::Ordering::Relaxed);
+                            }
+                        } else {
+                            ui.same_line(400.0 - 80.0);
+                            if !dev.connected.load(Ordering::Relaxed) && connection_stage.is_none() && ui.small_button(im_strf!("Connect##Connect Device {:03}", i)) {
+                                connect_to = Some(i);
+                            }
+                        }
+                        match dev.usb_location() {
+                            Some((bus, addr)) => ui.text(format!("  USB Bus: {}:{}", bus, addr)),
+                            None => ui.text("  Simulated controller"),
+                        }
+
+                        match dev.controller_data.lock().target_voltage {
+                            // anything this low means the target isn't powered or VCC isn't wired to the probe
+                            Some(voltage) if voltage < 1.5 => ui.text_colored([1.0, 0.3, 0.3, 1.0], format!("  Target Voltage: {:.2} V", voltage)),
+                            Some(voltage) => ui.text(format!("  Target Voltage: {:.2} V", voltage)),
+                            None => ui.text("  Target Voltage: unknown"),
+                        }
+
+                        if !dev.connected.load(Ordering::Relaxed) {
+                            ui.same_line(0.0);
+                            if ui.small_button(im_strf!("Check##Target Voltage {:03}", i)) {
+                                let voltage = dev.read_target_voltage();
+
+                                if let Err(e) = &voltage {
+                                    eprintln!("Failed to read target voltage: {}", e);
+                                }
+
+                                dev.controller_data.lock().target_voltage = voltage.ok();
+                            }
+                        }
+
+                        if let Some(stage) = connection_stage {
+                            ui.text_colored([0.9, 0.9, 0.2, 1.0], format!("  Connecting: {}...", stage));
+                            ui.same_line(0.0);
+                            if ui.small_button(im_strf!("Cancel##Connecting {:03}", i)) {
+                                dev.connected.store(false, Ordering::Relaxed);
+                            }
+                        }
+
+                        if let Some(error) = dev.controller_data.lock().connection_error.clone() {
+                            ui.text_colored([1.0, 0.3, 0.3, 1.0], "  Connection failed");
+                            ui.text_wrapped(&imgui::ImString::new(error));
+                        }
+
+                        if dev.link.lock().is_connected() {
+                            let (core_id, idcodes) = {
+                                let controller_data = dev.controller_data.lock();
+                                (controller_data.core_id, controller_data.idcodes)
+                            };
+
+                            if let Some(core_id) = core_id {
+                                ui.text(format!("  Core ID: 0x{:08X}", core_id));
+                            }
+                            if let Some([first, second]) = idcodes {
+                                ui.text(format!("  IDCODEs: 0x{:08X} 0x{:08X}", first, second));
+                            }
+
+                            match dev.controller_data.lock().probe_mode {
+                                Some(StlinkMode::Debug) => ui.text("  Probe Mode: Debug (SWD)"),
+                                Some(mode) => ui.text_colored([1.0, 0.6, 0.2, 1.0], format!("  Probe Mode: {:?}", mode)),
+                                None => ui.text_colored([1.0, 0.3, 0.3, 1.0], "  Probe Mode: unknown"),
+                            }
+
+                            if ui.small_button(im_strf!("Re-enter SWD Debug##SWD {:03}", i)) {
+                                dev.controller_commands.lock().push(InterfaceCommand::EnterDebugSwd(self.swd_reset_on_enter));
+                            }
+                            ui.same_line(0.0);
+                            if ui.small_button(im_strf!("Exit Debug##SWD {:03}", i)) {
+                                dev.controller_commands.lock().push(InterfaceCommand::ExitDebug);
+                            }
+                            ui.same_line(0.0);
+                            ui.checkbox(im_strf!("Reset Target##SWD {:03}", i), &mut self.swd_reset_on_enter);
+
+                            {
+                                let mut settings = self.swd_settings.lock();
+
+                                ui.checkbox(im_strf!("Adaptive SWD Frequency##SWD {:03}", i), &mut settings.enabled);
+
+                                if settings.enabled {
+                                    let mut threshold = settings.error_threshold as i32;
+                                    if ui.input_int(im_strf!("Errors/s Threshold##SWD {:03}", i), &mut threshold).build() {
+                                        settings.error_threshold = threshold.max(0) as u32;
+                                    }
+
+                                    let freq_names = SWD_FREQUENCIES.iter()
+                                        .map(|f| imgui::ImString::new(format!("{} kHz", f.hz() / 1000)))
+                                        .collect::<Vec<_>>();
+                                    let freq_refs = freq_names.iter().collect::<Vec<_>>();
+
+                                    let mut floor_index = SWD_FREQUENCIES.iter().position(|f| *f == settings.floor).unwrap_or(0);
+                                    if imgui::ComboBox::new(im_strf!("Floor##SWD {:03}", i)).build_simple_string(ui, &mut floor_index, &freq_refs) {
+                                        settings.floor = SWD_FREQUENCIES[floor_index];
+                                    }
+
+                                    let mut step_up = settings.step_up_after.is_some();
+                                    if ui.checkbox(im_strf!("Step Up When Stable##SWD {:03}", i), &mut step_up) {
+                                        settings.step_up_after = if step_up { Some(Duration::from_secs(60)) } else { None };
+                                    }
+                                }
+
+                                let link = dev.link.lock();
+
+                                if let Some(khz) = link.com_freq_khz() {
+                                    ui.text(format!("  SWD Frequency: {} kHz", khz));
+                                } else if let Some(freq) = link.swd_freq() {
+                                    ui.text(format!("  SWD Frequency: {} kHz", freq.hz() / 1000));
+                                }
+                            }
+
+                            ui.input_text(im_strf!("Scratch Address##Link Test {:03}", i), &mut self.link_test_addr).build();
+                            if ui.is_item_hovered() {
+                                ui.tooltip_text(format!(
+                                    "{} bytes of target RAM the firmware doesn't touch, they're overwritten during the test and restored afterwards",
+                                    SELF_TEST_WORDS * 4
+                                ));
+                            }
+
+                            // no default, any RAM address could be in use by the running firmware
+                            if let Some(addr) = parse_hex_u32(self.link_test_addr.to_str()).filter(|addr| addr % 4 == 0) {
+                                ui.same_line(0.0);
+                                if ui.small_button(im_strf!("Test Link##Test Link {:03}", i)) {
+                                    dev.controller_commands.lock().push(InterfaceCommand::TestLink(addr));
+                                }
+                            } else {
+                                ui.text_disabled("  Enter an unused, word aligned RAM address to test the link");
+                            }
+
+                            let controller_data = dev.controller_data.lock();
+
+                            for result in &controller_data.link_test {
+                                if result.corrupted_words == 0 {
+                                    ui.text(format!("  {:>5} kHz: OK", result.freq.hz() / 1000));
+                                } else {
+                                    ui.text_colored([1.0, 0.3, 0.3, 1.0], format!("  {:>5} kHz: {}/{} words corrupted", result.freq.hz() / 1000, result.corrupted_words, result.words_tested));
+                                }
+                            }
+
+                            if !controller_data.link_test.is_empty() {
+                                match controller_data.link_test.iter().find(|r| r.corrupted_words == 0) {
+                                    Some(r) => ui.text(format!("  Max reliable frequency: {} kHz", r.freq.hz() / 1000)),
+                                    None => ui.text_colored([1.0, 0.3, 0.3, 1.0], "  No reliable frequency found"),
+                                }
+                            }
+                        }
+                    }
+
+                    if let Some(i) = connect_to {
+                        self.connect_device(i);
+                    }
+                });
+
+            self.panels.devices = open;
+        }
+
+        if self.panels.servo_status {
+            let mut open = true;
+
+            panel_window(im_str!("Servo Status"), status_rect, self.panels.locked)
+                .opened(&mut open)
+                .build(ui, || {
+                    if self.connected.load(Ordering::Relaxed) {
+                        let (state, fault) = {
+                            let controller_data = self.controller_data.lock();
+                            (controller_data.servo_state.clone(), controller_data.fault_detected)
+                        };
+
+                        let state_text = format!("State: {:?}{}", state.state, if fault { " (fault)" } else { "" });
+
+                        if state.state == ServoControlState::Uninit || fault {
+                            ui.text_colored([1.0, 0.3, 0.3, 1.0], state_text);
+                        } else {
+                            ui.text(state_text);
+                        }
+
+                        ui.text(format!("Aligned: {}", state.aligned));
+                        ui.same_line(150.0);
+                        ui.text(format!("Anticogging Calibrated: {}", state.anticogging_calibrated));
+
+                        ui.separator();
+
+                        ui.text(format!("Position: {:10.4}", state.position));
+                        ui.text(format!("Velocity: {:10.4}", state.velocity));
+                        ui.text(format!("Accel:    {:10.4}", state.accel));
+
+                        if let Some((queued, capacity)) = self.controller_data.lock().command_buffer {
+                            ui.separator();
+
+                            // a full ring drops every command sent until the firmware catches up
+                            if queued >= capacity {
+                                ui.text_colored([1.0, 0.3, 0.3, 1.0], "Command Buffer: full");
+                            } else {
+                                ui.text("Command Buffer:");
+                            }
+
+                            imgui::ProgressBar::new(queued as f32 / capacity.max(1) as f32)
+                                .overlay_text(im_strf!("{} / {}", queued, capacity))
+                                .build(ui);
+                        }
+                    } else {
+                        ui.text("Not connected");
+                    }
+                });
+
+            self.panels.servo_status = open;
+        }
+        
+        if self.panels.configuration {
+            let mut open = true;
+
+            panel_window(im_str!("Configuration"), config_menu_rect, self.panels.locked)
+                .opened(&mut open)
+                .build(ui, || {
+
+                    self.device_selector(ui, "Configuration");
+
+                    if self.connected.load(Ordering::Relaxed) {
+
+                        let undo_label = self.parameter_focus.history.last_undo().map(|edit| edit.name);
+                        let redo_label = self.parameter_focus.history.last_redo().map(|edit| edit.name);
+
+                        if ui.small_button(im_str!("Undo")) {
+                            self.undo_parameter_edit();
+                        }
+                        if let Some(name) = undo_label {
+                            if ui.is_item_hovered() {
+                                ui.tooltip_text(format!("Undo {} (Ctrl+Z)", name));
+                            }
+                        }
+                        ui.same_line(0.0);
+                        if ui.small_button(im_str!("Redo")) {
+                            self.redo_parameter_edit();
+                        }
+                        if let Some(name) = redo_label {
+                            if ui.is_item_hovered() {
+                                ui.tooltip_text(format!("Redo {} (Ctrl+Y)", name));
+                            }
+                        }
+
+                        // a copy, the sections below lock the controller data themselves
+                        let mut servo_config = self.controller_data.lock().servo_config.clone();
+                        let servo_config = &mut servo_config;
+
+                        if imgui::CollapsingHeader::new(im_str!("Position Controller")).build(ui) {
+
+                            // let servo_cfg = self.controller_data.lock().servo_config.clone();
+
+                            cfg_parameter_widget!(
+                                ui, self.controller_commands, self.parameter_focus,
+                                "Position Gain", "Value##Position Gain",
+                                "Velocity commanded per unit of position error, (turns/s)/turn. Typical 10 - 100.",
+                                servo_config.position_gain, OFFSET_POSITION_GAIN
+                            );
+
+                            cfg_parameter_widget!(
+                                ui, self.controller_commands, self.parameter_focus,
+                                "Velocity Limit", "Value##Velocity Limit",
+                                "Largest velocity the controller will command, turns/s. Typical 5 - 50.",
+                                servo_config.vel_max_abs, OFFSET_VEL_MAX_ABS
+                            );
+
+                            cfg_parameter_widget!(
+                                ui, self.controller_commands, self.parameter_focus,
+                                "Max Position Step", "Value##Max Position Step",
+                                "Largest change in position input accepted in one control cycle, turns. Larger jumps are limited to this.",
+                                servo_config.max_pos_step, OFFSET_MAX_POS_STEP
+                            );
+
+                            cfg_parameter_widget!(
+                                ui, self.controller_commands, self.parameter_focus,
+                                "Input Filter Kp", "Value##Input Filter Kp",
+                                "Stiffness of the position input filter, 1/s^2. Equal to (2 pi bandwidth)^2, typically 1e3 - 1e5.",
+                                servo_config.input_filt_kp, OFFSET_INPUT_FILT_KP
+                            );
+
+                            cfg_parameter_widget!(
+                                ui, self.controller_commands, self.parameter_focus,
+                                "Input Filter Ki", "Value##Input Filter Ki",
+                                "Damping of the position input filter, 1/s. Equal to 2 (2 pi bandwidth) for critical damping, typically 50 - 500.",
+                                servo_config.input_filt_ki, OFFSET_INPUT_FILT_KI
+                            );
+
+                            ui.text("Input Filter Bandwidth (Hz)");
+                            ui.input_float(im_str!("##Input Filter Bandwidth"), &mut self.input_filter_bandwidth).build();
+                            ui.same_line(0.0);
+                            if ui.small_button(im_str!("Set Gains")) {
+                                let (kp, ki) = input_filter_gains(self.input_filter_bandwidth);
+
+                                servo_config.input_filt_kp = kp;
+                                servo_config.input_filt_ki = ki;
+
+                                let mut cmds = self.controller_commands.lock();
+                                cmds.push(InterfaceCommand::UpdateConfigParameter(OFFSET_INPUT_FILT_KP, kp));
+                                cmds.push(InterfaceCommand::UpdateConfigParameter(OFFSET_INPUT_FILT_KI, ki));
+                            }
+
+                            ui.checkbox(im_str!("Preview Filtered Step"), &mut self.input_filter_preview);
+                        }
+                    
+                        if imgui::CollapsingHeader::new(im_str!("Velocity Controller")).build(ui) {
+
+                            cfg_parameter_widget!(
+                                ui, self.controller_commands, self.parameter_focus,
+                                "Velocity Gain", "Value##Velocity Gain",
+                                "Torque commanded per unit of velocity error, Nm/(turn/s). Typical 0.01 - 0.5.",
+                                servo_config.velocity_gain, OFFSET_VELOCITY_GAIN
+                            );
+                        
+                            cfg_parameter_widget!(
+                                ui, self.controller_commands, self.parameter_focus,
+                                "Velocity Integrator Gain", "Value##Velocity Integrator Gain",
+                                "Torque added per unit of accumulated velocity error, Nm/turn. Typical 0.1 - 5. Too high causes overshoot.",
+                                servo_config.velocity_integrator_gain, OFFSET_VELOCITY_INTEGRATOR_GAIN
+                            );
+                        
+                            cfg_parameter_widget!(
+                                ui, self.controller_commands, self.parameter_focus,
+                                "Velocity Integrator Limit", "Value##Velocity Integrator Limit",
+                                "Clamp on the velocity integrator contribution, Nm. Typical 10 - 50% of the torque limit.",
+                                servo_config.velocity_integrator_max_abs, OFFSET_VELOCITY_INTEGRATOR_MAX_ABS
+                            );
+                        
+                            cfg_parameter_widget!(
+                                ui, self.controller_commands, self.parameter_focus,
+                                "Torque Limit", "Value##Torque Limit",
+                                "Largest torque the controller will command, Nm. Keep below the motor and driver rating.",
+                                servo_config.tor_max_abs, OFFSET_TOR_MAX_ABS
+                            );
+                        }
+                    
+                        if imgui::CollapsingHeader::new(im_str!("Servo Configuration")).build(ui) {
+
+                            cfg_parameter_widget!(
+                                ui, self.controller_commands, self.parameter_focus,
+                                "Index Scan Speed", "Value##Index Scan Speed",
+                                "Speed used while searching for the encoder index during alignment, turns/s. Typical 0.5 - 5.",
+                                servo_config.index_scan_speed, OFFSET_INDEX_SCAN_SPEED
+                            );
+                        
+                            cfg_parameter_widget!(
+                                ui, self.controller_commands, self.parameter_focus,
+                                "Steps Per Turn", "Value##Steps Per Turn",
+                                "Step/direction input steps per motor turn. Match the controller sending the steps, e.g. 200 - 51200.",
+                                servo_config.steps_per_turn, OFFSET_TURNS_PER_STEP
+                            );
+                        
+                            cfg_parameter_widget!(
+                                ui, self.controller_commands, self.parameter_focus,
+                                "Inertia", "Value##Inertia",
+                                "Rotor plus load inertia used for acceleration feedforward, kg m^2. Typical 1e-6 - 1e-3.",
+                                servo_config.inertia, OFFSET_INERTIA
+                            );
+                        
+                            cfg_parameter_widget!(
+                                ui, self.controller_commands, self.parameter_focus,
+                                "Torque Bandwidth", "Value##Torque Bandwidth",
+                                "Bandwidth of the current/torque loop, Hz. Typical 500 - 3000. Too high amplifies current noise.",
+                                servo_config.torque_bandwidth, OFFSET_TORQUE_BANDWIDTH
+                            );
+
+                            cfg_parameter_widget!(
+                                ui, self.controller_commands, self.parameter_focus,
+                                "Velocity PLL Ki", "Value##Velocity PLL Ki",
+                                "Integral gain of the encoder velocity estimator PLL. Higher tracks faster but passes more encoder noise.",
+                                servo_config.vel_pllki, OFFSET_VEL_PLLKI
+                            );
+
+                        }
+
+                        ui.separator();
+
+                        if ui.small_button(im_str!("Save Config to File...")) {
+                            self.save_config_to_file(servo_config);
+                        }
+                        ui.same_line(0.0);
+                        if ui.small_button(im_str!("Load Config from File...")) {
+                            self.load_config_from_file();
+                        }
+                        if let Some(status) = &self.config_file_status {
+                            ui.text_wrapped(&imgui::ImString::new(status.as_str()));
+                        }
+
+                        if imgui::CollapsingHeader::new(im_str!("Anticogging Table")).build(ui) {
+                            let table = self.controller_data.lock().anticogging_table.clone();
+
+                            let state = self.controller_data.lock().servo_state.clone();
+                            let calibrating = state.state == ServoControlState::AnticoggingCalibration;
+
+                            let calibration_supported = self.controller_data.lock().pointer_extensions.as_ref()
+                                .map_or(false, |extensions| extensions.command_count() > Command::StartAnticoggingCalibration.variant_index());
+
+                            if !calibration_supported {
+                                ui.text_colored([1.0, 0.6, 0.2, 1.0], "The firmware has no command for starting a calibration");
+                            } else if ui.small_button(im_str!("Start Calibration##Anticogging")) && !calibrating {
+                                self.controller_commands.lock().push(InterfaceCommand::SendCommand(Command::StartAnticoggingCalibration));
+                            }
+                            ui.same_line(0.0);
+                            if state.anticogging_calibrated {
+                                ui.text_colored([0.3, 1.0, 0.3, 1.0], "Calibrated - safe to save");
+                            } else {
+                                ui.text("Not calibrated");
+                            }
+
+                            if calibrating {
+                                let progress = state.anticogging_index as f32 / ANTICOGGING_TABLE_LEN as f32;
+                                imgui::ProgressBar::new(progress.min(1.0))
+                                    .overlay_text(im_strf!("{} / {}{}", state.anticogging_index, ANTICOGGING_TABLE_LEN, if state.anticogging_returning { " (returning)" } else { "" }))
+                                    .build(ui);
+                            }
+
+                            // the firmware doesn't export where the table lives, it has to come from its map file
+                            ui.input_text(im_str!("Table Address"), &mut self.anticogging_addr).build();
+                            let addr = parse_hex_u32(self.anticogging_addr.to_str());
+                            let table_addr = addr.filter(|addr| word_range_end(*addr, ANTICOGGING_TABLE_LEN).is_some());
+                            if addr.is_none() {
+                                ui.text_colored([1.0, 0.6, 0.2, 1.0], "Enter the table's address from the firmware's map file");
+                            } else if table_addr.is_none() {
+                                ui.text_colored([1.0, 0.6, 0.2, 1.0], "The table address has to be word aligned, with the whole table below 0xFFFFFFFF");
+                            }
+                            let addr = table_addr;
+
+                            if let Some(addr) = addr {
+                                if ui.small_button(im_str!("Read Table")) {
+                                    self.controller_commands.lock().push(InterfaceCommand::ReadAnticoggingTable(addr));
+                                }
+                            }
+
+                            if let Some(table) = &table {
+                                ui.same_line(0.0);
+                                if ui.small_button(im_str!("Export...##Anticogging")) {
+                                    if let Ok(nfd::Response::Okay(path)) = nfd::open_save_dialog(Some("csv,bin"), None) {
+                                        self.anticogging_status = Some(match save_table(Path::new(&path), table) {
+                                            Ok(()) => format!("Saved to {}", path),
+                                            Err(e) => format!("Failed to save: {}", e),
+                                        });
+                                    }
+                                }
+                            }
+
+                            ui.same_line(0.0);
+                            if addr.is_some() && ui.small_button(im_str!("Import...##Anticogging")) {
+                                if let (Ok(nfd::Response::Okay(path)), Some(addr)) = (nfd::open_file_dialog(Some("csv,bin"), None), addr) {
+                                    match load_table(Path::new(&path)) {
+                                        Ok(table) => {
+                                            self.pending_anticogging_import = Some((addr, table, path));
+                                            ui.open_popup(im_str!("Confirm Anticogging Import"));
+                                        }
+                                        Err(e) => self.anticogging_status = Some(format!("Failed to load: {}", e)),
+                                    }
+                                }
+                            }
+
+                            imgui::PopupModal::new(im_str!("Confirm Anticogging Import"))
+                                .always_auto_resize(true)
+                                .build(ui, || {
+                                    if let Some((addr, table, path)) = &self.pending_anticogging_import {
+                                        let end = word_range_end(*addr, table.len()).unwrap_or(u32::MAX);
+
+                                        ui.text(format!("Write {} entries from {}", table.len(), path));
+                                        ui.text(format!("to 0x{:08X} - 0x{:08X}?", addr, end));
+                                        ui.text_colored([1.0, 0.6, 0.2, 1.0], "Anything else in that range is overwritten.");
+                                    }
+
+                                    if ui.button(im_str!("Write Table"), [0.0, 0.0]) {
+                                        if let Some((addr, table, path)) = self.pending_anticogging_import.take() {
+                                            self.controller_commands.lock().push(InterfaceCommand::WriteAnticoggingTable(addr, table));
+                                            self.anticogging_status = Some(format!("Wrote {} to the controller", path));
+                                        }
+                                        ui.close_current_popup();
+                                    }
+                                    ui.same_line(0.0);
+                                    if ui.button(im_str!("Cancel##Anticogging Import"), [0.0, 0.0]) {
+                                        self.pending_anticogging_import = None;
+                                        ui.close_current_popup();
+                                    }
+                                });
+
+                            if let Some(table) = &table {
+                                let min = table.iter().cloned().fold(f32::INFINITY, f32::min);
+                                let max = table.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+                                ui.text(format!("{} entries, range [{:.4}, {:.4}]", table.len(), min, max));
+                            }
+
+                            if let Some(status) = &self.anticogging_status {
+                                ui.text_wrapped(&imgui::ImString::new(status.as_str()));
+                            }
+                        }
+
+                        if imgui::CollapsingHeader::new(im_str!("Saved Config (Flash)")).build(ui) {
+                            ui.input_text(im_str!("Flash Address"), &mut self.flash_config_addr).build();
+
+                            if ui.small_button(im_str!("Read Saved Config (Flash)")) {
+                                if let Some(addr) = parse_hex_u32(self.flash_config_addr.to_str()) {
+                                    self.controller_commands.lock().push(InterfaceCommand::ReadSavedConfig(addr));
+                                }
+                            }
+
+                            let saved_config = self.controller_data.lock().saved_config.clone();
+
+                            if let Some(saved_config) = saved_config {
+                                ui.columns(3, im_str!("Saved Config Columns"), false);
+                                ui.text("Parameter");
+                                ui.next_column();
+                                ui.text("RAM");
+                                ui.next_column();
+                                ui.text("Flash");
+                                ui.next_column();
+
+                                for ((name, live), (_, saved)) in servo_config.fields().iter().zip(saved_config.fields().iter()) {
+                                    ui.text(name);
+                                    ui.next_column();
+                                    ui.text(format!("{}", live));
+                                    ui.next_column();
+                                    if live == saved {
+                                        ui.text(format!("{}", saved));
+                                    } else {
+                                        ui.text_colored([1.0, 0.6, 0.2, 1.0], format!("{}", saved));
+                                    }
+                                    ui.next_column();
+                                }
+
+                                ui.columns(1, im_str!("Saved Config Columns"), false);
+
+                                if ui.small_button(im_str!("Load Saved Config into RAM")) {
+                                    self.import_config(saved_config);
+                                }
+                            }
+                        }
+                    } else {
+                        ui.text("Connect to a device to see configuration.");
+                    }
+
+                    if imgui::CollapsingHeader::new(im_str!("Auto-Save on Exit")).build(ui) {
+                        if ui.checkbox(im_str!("Export Config When Closing"), &mut self.settings.auto_save_config) {
+                            self.settings.save();
+                        }
+                        if ui.input_text(im_str!("Directory##Auto-Save"), &mut self.auto_save_dir).enter_returns_true(true).build() {
+                            self.settings.auto_save_dir = self.auto_save_dir.to_str().to_string();
+                            self.settings.save();
+                        }
+                        ui.same_line(0.0);
+                        if ui.small_button(im_str!("Browse...##Auto-Save")) {
+                            if let Ok(nfd::Response::Okay(dir)) = nfd::open_pick_folder(None) {
+                                self.auto_save_dir = imstring_buffer(&dir, 256);
+                                self.settings.auto_save_dir = dir;
+                                self.settings.save();
+                            }
+                        }
+                        ui.text(format!("Exports to {}", self.settings.auto_save_path().display()));
+                    }
+
+                    if imgui::CollapsingHeader::new(im_str!("Direction")).build(ui) {
+                        let mut inverted = self.invert_direction.load(Ordering::Relaxed);
+
+                        if ui.checkbox(im_str!("Invert Direction"), &mut inverted) {
+                            self.invert_direction.store(inverted, Ordering::Relaxed);
+
+                            if let Some(dev) = self.devices.get(self.active_device) {
+                                let key = DeviceContext::settings_key(&**dev.link.lock());
+
+                                if inverted {
+                                    self.settings.inverted_probes.insert(key);
+                                } else {
+                                    self.settings.inverted_probes.remove(&key);
+                                }
+                                self.settings.save();
+                            }
+                        }
+                        if ui.is_item_hovered() {
+                            ui.tooltip_text("Mirrors positions, velocities and commands on the host for this probe's axis. The firmware is unchanged.");
+                        }
+                    }
+
+                    if imgui::CollapsingHeader::new(im_str!("Position Limits")).build(ui) {
+                        let position = self.controller_data.lock().servo_state.position;
+                        let mut limits = self.position_limits.lock();
+
+                        ui.checkbox(im_str!("Clamp Position Commands"), &mut limits.enabled);
+
+                        ui.input_float(im_str!("Min##Position Limits"), &mut limits.min).build();
+                        ui.same_line(0.0);
+                        if ui.small_button(im_str!("From Current##Position Limits Min")) {
+                            limits.min = position;
+                        }
+
+                        ui.input_float(im_str!("Max##Position Limits"), &mut limits.max).build();
+                        ui.same_line(0.0);
+                        if ui.small_button(im_str!("From Current##Position Limits Max")) {
+                            limits.max = position;
+                        }
+
+                        if limits.min > limits.max {
+                            ui.text_colored([1.0, 0.6, 0.2, 1.0], "Min is above max, the limits will be swapped.");
+                        }
+                    }
+
+                    if imgui::CollapsingHeader::new(im_str!("Command Rate Limits")).build(ui) {
+                        let mut limits = self.rate_limits.lock();
+
+                        duration_ms_input(ui, im_str!("Config Writes (ms)"), &mut limits.config_interval);
+                        duration_ms_input(ui, im_str!("Setpoints (ms)"), &mut limits.position_interval);
+                        duration_ms_input(ui, im_str!("Other Commands (ms)"), &mut limits.command_interval);
+                    }
+                });
+
+            self.panels.configuration = open;
+        }
+
+
+        
+        if self.panels.tuning_controls {
+            let mut open = true;
+
+            panel_window(im_str!("Tuning Controls"), tool_menu_rect, self.panels.locked)
+                .opened(&mut open)
+                .build(ui, || {
+                    if self.connected.load(Ordering::Relaxed) {
+                        ui.columns(4, im_str!("tool columns"), true);
+
+                        if ui.small_button(im_str!("Start Recording")) {
+                            self.controller_commands.lock().push(InterfaceCommand::StartRecording);
+                        }
+                        if ui.small_button(im_str!("Stop Recording")) {
+                            self.controller_commands.lock().push(InterfaceCommand::StopRecording);
+                        }
+                        if ui.small_button(im_str!("Clear Faults")) {
+                            self.controller_commands.lock().push(InterfaceCommand::SendCommand(Command::ClearFaultState));
+                        }
+                        if ui.small_button(im_str!("Save Configuration")) {
+                            self.controller_commands.lock().push(InterfaceCommand::SendCommand(Command::SaveServoConfig));
+                        }
+                        if ui.small_button(im_str!("Reset Microcontroller")) {
+                            self.controller_commands.lock().push(InterfaceCommand::ResetController);
+                        }
+                        if ui.small_button(im_str!("Hardware Reset (NRST)")) {
+                            self.controller_commands.lock().push(InterfaceCommand::HardwareReset);
+                        }
+                        if ui.small_button(im_str!("Export CSV##Samples")) {
+                            self.export_samples();
+                        }
+                        if let Some(status) = &self.sample_export_status {
+                            ui.text_wrapped(&imgui::ImString::new(status.as_str()));
+                        }
+
+                        ui.next_column();
+
+                        self.estop_button(ui, [ui.column_width(-1) - 16.0, 40.0]);
+
+                        let motion_allowed = !self.estop.load(Ordering::SeqCst);
+                        if !motion_allowed {
+                            ui.text_colored([1.0, 0.3, 0.3, 1.0], "E-STOP latched, motion is blocked");
+                        }
+
+                        if ui.small_button(im_str!("Stop Motor")) {
+                            self.controller_commands.lock().push(InterfaceCommand::StopMotor);
+                        }
+                        if ui.is_item_hovered() {
+                            ui.tooltip_text("Space or Escape, works even while typing");
+                        }
+                        if ui.small_button(im_str!("Start Motor")) && motion_allowed {
+                            self.controller_commands.lock().push(InterfaceCommand::StartMotor);
+                        }
+                        if ui.is_item_hovered() {
+                            ui.tooltip_text("S");
+                        }
+                        if ui.small_button(im_str!("Position Step 0.0")) && motion_allowed {
+                            self.controller_commands.lock().push(InterfaceCommand::PositionCommand(0.0));
+                        }
+                        if ui.is_item_hovered() {
+                            ui.tooltip_text("0");
+                        }
+                        if ui.small_button(im_str!("Position Step 1.0")) && motion_allowed {
+                            self.controller_commands.lock().push(InterfaceCommand::PositionCommand(1.0));
+                        }
+                        if ui.is_item_hovered() {
+                            ui.tooltip_text("1");
+                        }
+
+                        ui.set_next_item_width(80.0);
+                        ui.input_float(im_str!("Step##Measure Step"), &mut self.step_measure_size).build();
+                        ui.same_line(0.0);
+                        ui.set_next_item_width(80.0);
+                        ui.input_float(im_str!("Capture (s)##Measure Step"), &mut self.step_measure_duration).build();
+                        self.step_measure_duration = self.step_measure_duration.max(0.05);
+
+                        if let Some(started) = self.step_measure_started {
+                            let elapsed = started.elapsed().as_secs_f32();
+
+                            if elapsed >= self.step_measure_duration {
+                                let sample_period = self.sample_period();
+                                let samples = self.sample_buffer.lock();
+
+                                self.step_measure_result = Some(step_response(&samples, |p| p.pos_setpoint, |p| p.pos, sample_period));
+                                self.step_measure_started = None;
+                            } else {
+                                imgui::ProgressBar::new(elapsed / self.step_measure_duration)
+                                    .overlay_text(im_str!("Waiting for the response to settle"))
+                                    .build(ui);
+                            }
+                        } else if ui.small_button(im_str!("Measure Step Response")) && motion_allowed {
+                            let start = self.controller_data.lock().servo_state.pos_input;
+
+                            // only the step and what follows it should be in the buffer
+                            self.sample_buffer.lock().clear();
+
+                            let mut commands = self.controller_commands.lock();
+                            commands.push(InterfaceCommand::StartRecording);
+                            commands.push(InterfaceCommand::PositionCommand(start + self.step_measure_size));
+
+                            self.step_measure_started = Some(Instant::now());
+                            self.step_measure_result = None;
+                        }
+
+                        match &self.step_measure_result {
+                            Some(Some(response)) => {
+                                let fmt_time = |t : Option<f32>| t.map_or("--".to_string(), |t| format!("{:.1} ms", 1000.0 * t));
+
+                                ui.text(format!("Rise Time:     {}", fmt_time(response.rise_time)));
+                                ui.text(format!("Overshoot:     {:.1} %", response.overshoot));
+                                ui.text(format!("Settling Time: {}", fmt_time(response.settling_time)));
+                                ui.text(format!("SS Error:      {:.4}", response.steady_state_error));
+                            }
+                            Some(None) => ui.text_colored([1.0, 0.3, 0.3, 1.0], "No step found in the recorded samples"),
+                            None => {}
+                        }
+                        ui.set_next_item_width(80.0);
+                        ui.input_float(im_str!("Hz##Sine Input"), &mut self.sine_frequency).build();
+                        ui.same_line(0.0);
+                        ui.set_next_item_width(80.0);
+                        ui.input_float(im_str!("Amplitude##Sine Input"), &mut self.sine_amplitude).build();
+
+                        if ui.small_button(im_str!("Sine Input")) && motion_allowed {
+                            let running = Arc::new(AtomicBool::new(true));
+                            let running_thread = running.clone();
+                            let commands = self.controller_commands.clone();
+
+                            let center = self.controller_data.lock().servo_state.pos_input;
+                            let frequency = self.sine_frequency.max(0.01);
+                            let amplitude = self.sine_amplitude;
+
+                            std::thread::spawn(move || {
+                                sine_wave(commands, running_thread, center, amplitude, frequency);
+                            });
+
+                            self.tasks.push(GuiTask{name : format!("{:.2} Hz Sine Input ({:.3})", frequency, amplitude), running});
+                        }
+                        if ui.small_button(im_str!("Clear Motion Profile")) {
+                            self.controller_commands.lock().push(InterfaceCommand::SendCommand(Command::SetMotionProfile{profile: 0}));
+                            self.sent_motion_profile = Some(0);
+                        }
+
+                        let mut profile_count = self.settings.motion_profile_count as i32;
+                        ui.set_next_item_width(100.0);
+                        if ui.input_int(im_str!("Profile Count"), &mut profile_count).build() {
+                            self.settings.motion_profile_count = profile_count.max(1) as u32;
+                            self.settings.save();
+                        }
+
+                        let motion_profile_names = (0..self.settings.motion_profile_count)
+                            .map(|i| if i == 0 { imgui::ImString::new("0 (none)") } else { imgui::ImString::new(i.to_string()) })
+                            .collect::<Vec<_>>();
+                        let motion_profile_refs = motion_profile_names.iter().collect::<Vec<_>>();
+
+                        self.motion_profile = self.motion_profile.min(motion_profile_refs.len() - 1);
+                        ui.set_next_item_width(100.0);
+                        imgui::ComboBox::new(im_str!("##Motion Profile"))
+                            .build_simple_string(ui, &mut self.motion_profile, &motion_profile_refs);
+                        ui.same_line(0.0);
+                        if ui.small_button(im_str!("Set Motion Profile")) {
+                            let profile = self.motion_profile as u32;
+                            self.controller_commands.lock().push(InterfaceCommand::SendCommand(Command::SetMotionProfile{profile}));
+                            self.sent_motion_profile = Some(profile);
+                        }
+
+                        match self.sent_motion_profile {
+                            Some(profile) => ui.text(format!("Active Profile: {} (last sent)", profile)),
+                            None => ui.text("Active Profile: unknown"),
+                        }
+
+                        ui.separator();
+
+                        ui.input_float(im_str!("Target##Smoothed Move"), &mut self.smooth_move_target).build();
+                        ui.input_float(im_str!("Time (s)##Smoothed Move"), &mut self.smooth_move_duration).build();
+
+                        let profile_names = MOVE_PROFILES.iter()
+                            .map(|profile| imgui::ImString::new(profile.name()))
+                            .collect::<Vec<_>>();
+                        let profile_refs = profile_names.iter().collect::<Vec<_>>();
+
+                        imgui::ComboBox::new(im_str!("Profile##Smoothed Move"))
+                            .build_simple_string(ui, &mut self.smooth_move_profile, &profile_refs);
+
+                        if ui.small_button(im_str!("Smoothed Move")) && motion_allowed {
+                            let running = Arc::new(AtomicBool::new(true));
+                            let running_thread = running.clone();
+                            let commands = self.controller_commands.clone();
+
+                            let start = self.controller_data.lock().servo_state.pos_input;
+                            let target = self.smooth_move_target;
+                            let duration = self.smooth_move_duration.max(GENERATOR_TICK.as_secs_f32());
+                            let profile = MOVE_PROFILES[self.smooth_move_profile];
+
+                            std::thread::spawn(move || {
+                                smoothed_move(commands, running_thread, start, target, duration, profile);
+                            });
+
+                            self.tasks.push(GuiTask{name : format!("{} Move to {:.3} ({:.2}s)", profile.name(), target, duration), running});
+                        }
+
+                        ui.separator();
+
+                        ui.input_float(im_str!("Target##Trapezoidal Move"), &mut self.trapezoid_target).build();
+                        ui.input_float(im_str!("Max Vel (turns/s)##Trapezoidal Move"), &mut self.trapezoid_max_vel).build();
+                        ui.input_float(im_str!("Accel (turns/s^2)##Trapezoidal Move"), &mut self.trapezoid_accel).build();
+                        self.trapezoid_max_vel = self.trapezoid_max_vel.max(0.0);
+                        self.trapezoid_accel = self.trapezoid_accel.max(0.0);
+
+                        let (start, vel_limit) = {
+                            let controller_data = self.controller_data.lock();
+                            let config = &controller_data.servo_config;
+
+                            (controller_data.servo_state.pos_input, firmware_velocity_limit(config.vel_max_abs, config.max_pos_step))
+                        };
+
+                        let max_vel = vel_limit.map_or(self.trapezoid_max_vel, |limit| self.trapezoid_max_vel.min(limit));
+                        if let Some(limit) = vel_limit.filter(|limit| *limit < self.trapezoid_max_vel) {
+                            ui.text_colored([1.0, 0.6, 0.2, 1.0], format!("Limited to {:.3} turns/s by the servo config", limit));
+                        }
+
+                        if ui.small_button(im_str!("Trapezoidal Move")) && motion_allowed {
+                            let running = Arc::new(AtomicBool::new(true));
+                            let running_thread = running.clone();
+                            let commands = self.controller_commands.clone();
+
+                            let target = self.trapezoid_target;
+                            let profile = TrapezoidalMove::new(start, target, max_vel, self.trapezoid_accel);
+
+                            std::thread::spawn(move || {
+                                trapezoidal_move(commands, running_thread, profile, target);
+                            });
+
+                            self.tasks.push(GuiTask{
+                                name : format!("Trapezoidal Move to {:.3} ({:.2} turns/s, {:.2}s)", target, profile.peak_velocity(), profile.duration()),
+                                running,
+                            });
+                        }
+
+                        ui.separator();
+
+                        ui.input_float(im_str!("Start (Hz)##Chirp"), &mut self.chirp_start_hz).build();
+                        ui.input_float(im_str!("End (Hz)##Chirp"), &mut self.chirp_end_hz).build();
+                        ui.input_float(im_str!("Time (s)##Chirp"), &mut self.chirp_duration).build();
+                        ui.input_float(im_str!("Amplitude##Chirp"), &mut self.chirp_amplitude).build();
+
+                        if ui.small_button(im_str!("Chirp Input")) && motion_allowed {
+                            let running = Arc::new(AtomicBool::new(true));
+                            let running_thread = running.clone();
+                            let commands = self.controller_commands.clone();
+
+                            let center = self.controller_data.lock().servo_state.pos_input;
+                            let f_start = self.chirp_start_hz.max(0.01);
+                            let f_end = self.chirp_end_hz.max(0.01);
+                            let duration = self.chirp_duration.max(GENERATOR_TICK.as_secs_f32());
+                            let amplitude = self.chirp_amplitude;
+
+                            std::thread::spawn(move || {
+                                chirp(commands, running_thread, center, amplitude, f_start, f_end, duration);
+                            });
+
+                            self.tasks.push(GuiTask{name : format!("Chirp {:.1}-{:.1} Hz ({:.1}s)", f_start, f_end, duration), running});
+                        }
+
+                        ui.next_column();
+
+                        for i in (0..(self.tasks.len())).rev() {
+                            ui.text(format!("Task {:2}: {}", i, self.tasks[i].name));
+                            if ui.small_button(im_strf!("Cancel##Cancel Task {}", i)) {
+                                self.tasks[i].running.store(false, Ordering::Relaxed);
+                                self.tasks.remove(i);
+                            }
+                        }
+
+                        ui.next_column();
+
+                        if ui.small_button(im_str!("Enable Position Control")) {
+                            self.controller_commands.lock().push(InterfaceCommand::SendCommand(Command::SetPositionControl));
+                        }
+                        if ui.small_button(im_str!("Enable Step/Direction Control")) {
+                            self.controller_commands.lock().push(InterfaceCommand::SendCommand(Command::SetStepDirectionControl));
+                        }
+
+                        let control_state = self.controller_data.lock().servo_state.state.clone();
+                        if control_state == ServoControlState::EnabledStepDirection {
+                            ui.text_colored([0.3, 1.0, 0.3, 1.0], format!("Mode: {:?}", control_state));
+                        } else {
+                            ui.text(format!("Mode: {:?}", control_state));
+                        }
+
+                        ui.separator();
+
+                        ui.text("Homing");
+
+                        if ui.small_button(im_str!("Find Lower Limit...")) {
+                            self.pending_homing = Some(Command::FindLowerMotionLimit);
+                            ui.open_popup(im_str!("Confirm Homing"));
+                        }
+                        ui.same_line(0.0);
+                        if ui.small_button(im_str!("Find Upper Limit...")) {
+                            self.pending_homing = Some(Command::FindUpperMotionLimit);
+                            ui.open_popup(im_str!("Confirm Homing"));
+                        }
+
+                        imgui::PopupModal::new(im_str!("Confirm Homing"))
+                            .always_auto_resize(true)
+                            .build(ui, || {
+                                let direction = match self.pending_homing {
+                                    Some(Command::FindUpperMotionLimit) => "upper",
+                                    _ => "lower",
+                                };
+
+                                ui.text(format!("The motor will drive until it hits the {} hard stop.", direction));
+                                ui.text("Make sure the axis is clear and the torque limit is safe.");
+
+                                if ui.button(im_str!("Start Homing"), [0.0, 0.0]) {
+                                    if let Some(cmd) = self.pending_homing.take() {
+                                        self.controller_commands.lock().push(InterfaceCommand::SendCommand(cmd));
+                                    }
+                                    ui.close_current_popup();
+                                }
+                                ui.same_line(0.0);
+                                if ui.button(im_str!("Cancel##Homing"), [0.0, 0.0]) {
+                                    self.pending_homing = None;
+                                    ui.close_current_popup();
+                                }
+                            });
+
+                        // the firmware doesn't report the limits it finds, the position once it stops is the best there is
+                        ui.text(format!("Position: {:.4}", self.controller_data.lock().servo_state.position));
+
+                        ui.separator();
+
+                        ui.text("Jog (counts)");
+
+                        let mut counts_per_turn = self.settings.counts_per_turn as i32;
+                        ui.set_next_item_width(100.0);
+                        if ui.input_int(im_str!("Counts/Turn"), &mut counts_per_turn).build() {
+                            self.settings.counts_per_turn = counts_per_turn.max(1) as u32;
+                            self.settings.save();
+                        }
+
+                        let mut jog = None;
+
+                        for (i, counts) in [-100, -10, -1, 1, 10, 100].iter().enumerate() {
+                            if i % 3 != 0 {
+                                ui.same_line(0.0);
+                            }
+                            if ui.small_button(im_strf!("{:+}##Jog", counts)) {
+                                jog = Some(*counts);
+                            }
+                        }
+
+                        if let Some(counts) = jog {
+                            let start = match self.jog_target {
+                                Some((target, t)) if t.elapsed() < Duration::from_millis(500) => target,
+                                _ => self.controller_data.lock().servo_state.pos_input,
+                            };
+
+                            let target = start + counts as f32 / self.settings.counts_per_turn.max(1) as f32;
+
+                            self.controller_commands.lock().push(InterfaceCommand::PositionCommand(target));
+                            self.jog_target = Some((target, Instant::now()));
+                        }
+                
+                    } else {
+                        ui.text("Connect to a device to see tuning menu.");
+                    }
+                });
+
+            self.panels.tuning_controls = open;
+        }
+            
+        if self.panels.analysis {
+            let mut open = true;
+
+            panel_window(im_str!("Analysis"), analysis_rect, self.panels.locked)
+                .opened(&mut open)
+                .build(ui, || {
+                    if imgui::CollapsingHeader::new(im_str!("Telemetry")).default_open(true).build(ui) {
+                        let servo_state = self.controller_data.lock().servo_state.clone();
+
+                        ui.text(format!("Position:      {:.4}", servo_state.position));
+                        ui.same_line(0.0);
+                        if ui.small_button(im_str!("Set as Zero")) {
+                            self.controller_commands.lock().push(InterfaceCommand::SetHome);
+                        }
+
+                        let home_offset = self.controller_data.lock().home_offset;
+                        if home_offset != 0.0 {
+                            ui.text(format!("Home Offset:   {:.4}", home_offset));
+                            ui.same_line(0.0);
+                            if ui.small_button(im_str!("Clear##Home Offset")) {
+                                self.controller_commands.lock().push(InterfaceCommand::ClearHome);
+                            }
+                        }
+                        ui.text(format!("Velocity:      {:.4}", servo_state.velocity));
+                        ui.text(format!("Peak Velocity: {:.4}", servo_state.max_vel_abs_obs));
+                        ui.same_line(0.0);
+                        if ui.small_button(im_str!("Reset##Peak Velocity")) {
+                            self.controller_commands.lock().push(InterfaceCommand::ResetObservedMaxVelocity);
+                        }
+
+                        ui.checkbox(im_str!("Show Peak Velocity on Plot"), &mut self.show_peak_velocity);
+
+                        ui.text(format!("Encoder Offset:  {}", servo_state.encoder_offset));
+                        ui.same_line(0.0);
+                        if ui.small_button(im_str!("Reset##Encoder Offset")) {
+                            self.controller_commands.lock().push(InterfaceCommand::ResetEncoderOffset);
+                        }
+                        ui.text(format!("Step/Dir Offset: {}", servo_state.step_dir_offset));
+                        ui.same_line(0.0);
+                        if ui.small_button(im_str!("Reset##Step Dir Offset")) {
+                            self.controller_commands.lock().push(InterfaceCommand::ResetStepDirOffset);
+                        }
+                    }
+
+                    if imgui::CollapsingHeader::new(im_str!("Status")).default_open(true).build(ui) {
+                        let indicators = &mut self.settings.status_indicators;
+
+                        let active = {
+                            let controller_data = self.controller_data.lock();
+                            let saturated = controller_data.integrator_saturated(self.windup_margin);
+
+                            indicators.active(&controller_data, saturated)
+                        };
+
+                        if active.is_empty() {
+                            ui.text("No active flags");
+                        }
+
+                        for (flag, severity) in &active {
+                            ui.text_colored(indicators.color(*severity), format!("{:<8} {}", severity.name(), flag.name()));
+                        }
+
+                        let mut changed = false;
+
+                        imgui::TreeNode::new(im_str!("Indicator Settings")).build(ui, || {
+                            let severity_names = SEVERITIES.iter()
+                                .map(|severity| imgui::ImString::new(severity.name()))
+                                .collect::<Vec<_>>();
+                            let severity_refs = severity_names.iter().collect::<Vec<_>>();
+
+                            for flag in STATUS_FLAGS.iter() {
+                                let mut index = SEVERITIES.iter().position(|s| *s == indicators.severity(*flag)).unwrap_or(0);
+
+                                ui.set_next_item_width(100.0);
+                                if imgui::ComboBox::new(im_strf!("{}##Severity", flag.name())).build_simple_string(ui, &mut index, &severity_refs) {
+                                    indicators.set_severity(*flag, SEVERITIES[index]);
+                                    changed = true;
+                                }
+                            }
+
+                            ui.separator();
+
+                            changed |= imgui::ColorEdit::new(im_str!("Error Color"), &mut indicators.error_color).build(ui);
+                            changed |= imgui::ColorEdit::new(im_str!("Warning Color"), &mut indicators.warning_color).build(ui);
+                            changed |= imgui::ColorEdit::new(im_str!("Info Color"), &mut indicators.info_color).build(ui);
+
+                            if ui.small_button(im_str!("Restore Defaults##Status Indicators")) {
+                                *indicators = StatusIndicatorSettings::default();
+                                changed = true;
+                            }
+                        });
+
+                        if changed {
+                            self.settings.save();
+                        }
+                    }
+
+                    if imgui::CollapsingHeader::new(im_str!("Plot Channels")).build(ui) {
+                        let band_names = PLOT_BANDS.iter()
+                            .map(|name| imgui::ImString::new(*name))
+                            .collect::<Vec<_>>();
+                        let band_refs = band_names.iter().collect::<Vec<_>>();
+
+                        let mut changed = false;
+
+                        for channel in self.settings.plot.channels.iter_mut() {
+                            changed |= ui.checkbox(im_strf!("##Visible {}", channel.name), &mut channel.visible);
+                            ui.same_line(0.0);
+                            changed |= imgui::ColorEdit::new(im_strf!("##Color {}", channel.name), &mut channel.color)
+                                .inputs(false)
+                                .build(ui);
+                            ui.same_line(0.0);
+                            ui.set_next_item_width(80.0);
+                            changed |= imgui::ComboBox::new(im_strf!("{}##Band", channel.name))
+                                .build_simple_string(ui, &mut channel.band, &band_refs);
+                        }
+
+                        if ui.small_button(im_str!("Restore Defaults##Plot Channels")) {
+                            self.settings.plot.channels = PlotSettings::default().channels;
+                            self.settings.plot.add_missing_channels();
+                            changed = true;
+                        }
+
+                        ui.separator();
+                        ui.text("Profiles");
+
+                        ui.set_next_item_width(150.0);
+                        ui.input_text(im_str!("##Plot Profile Name"), &mut self.plot_profile_name).build();
+                        ui.same_line(0.0);
+                        let name = self.plot_profile_name.to_str().trim().to_string();
+                        if ui.small_button(im_str!("Save Current##Plot Profile")) && !name.is_empty() {
+                            self.settings.plot_profiles.insert(name, self.settings.plot.clone());
+                            changed = true;
+                        }
+
+                        let mut load = None;
+                        let mut delete = None;
+
+                        for name in self.settings.plot_profiles.keys() {
+                            ui.text(name);
+                            ui.same_line(0.0);
+                            if ui.small_button(im_strf!("Load##Plot Profile {}", name)) {
+                                load = Some(name.clone());
+                            }
+                            ui.same_line(0.0);
+                            if ui.small_button(im_strf!("Delete##Plot Profile {}", name)) {
+                                delete = Some(name.clone());
+                            }
+                        }
+
+                        if let Some(profile) = load.and_then(|name| self.settings.plot_profiles.get(&name).cloned()) {
+                            self.plot_window_seconds = profile.window_seconds;
+                            self.plot_refresh_hz = profile.refresh_hz;
+                            self.plot_max_points = profile.max_points;
+                            self.settings.plot = profile;
+                            changed = true;
+                        }
+                        if let Some(name) = delete {
+                            self.settings.plot_profiles.remove(&name);
+                            changed = true;
+                        }
+
+                        if changed {
+                            self.settings_changed = true;
+                            // rebuild the lines now rather than at the next refresh
+                            self.last_plot_refresh = Instant::now() - Duration::from_secs(1);
+                        }
+                    }
+
+                    if imgui::CollapsingHeader::new(im_str!("Integrator")).build(ui) {
+                        let (piv, max_abs) = {
+                            let controller_data = self.controller_data.lock();
+                            (controller_data.velocity_piv.clone(), controller_data.servo_config.velocity_integrator_max_abs)
+                        };
+
+                        if let Some(piv) = piv {
+                            ui.text(format!("Integral Term: {:.4}", piv.integral_term));
+                            ui.text(format!("Limits:        [{:.4}, {:.4}]", -max_abs, max_abs));
+
+                            if piv.integrator_saturated(max_abs, self.windup_margin) {
+                                ui.text_colored([0.9, 0.2, 0.2, 1.0], "Anti-Windup Clamping");
+                            } else {
+                                ui.text_colored([0.3, 0.9, 0.3, 1.0], "Within Limits");
+                            }
+                        } else {
+                            ui.text("Controller internals not available.");
+                        }
+
+                        ui.input_float(im_str!("Margin##Integrator"), &mut self.windup_margin).build();
+                        self.windup_margin = self.windup_margin.max(0.0).min(0.5);
+                        ui.checkbox(im_str!("Shade Plot When Clamping"), &mut self.show_windup);
+                    }
+
+                    if imgui::CollapsingHeader::new(im_str!("Trigger")).build(ui) {
+                        let mut trigger = self.trigger.lock();
+
+                        let mut source_index = TRIGGER_SOURCES.iter().position(|s| *s == trigger.source).unwrap_or(0);
+
+                        let source_names = TRIGGER_SOURCES.iter()
+                            .map(|source| imgui::ImString::new(source.name()))
+                            .collect::<Vec<_>>();
+                        let source_refs = source_names.iter().collect::<Vec<_>>();
+
+                        if imgui::ComboBox::new(im_str!("Source##Trigger"))
+                            .build_simple_string(ui, &mut source_index, &source_refs) {
+                            trigger.source = TRIGGER_SOURCES[source_index];
+                        }
+
+                        if trigger.source != TriggerSource::Fault {
+                            let channel_names = SAMPLE_CHANNELS.iter()
+                                .map(|(name, _)| imgui::ImString::new(*name))
+                                .collect::<Vec<_>>();
+                            let channel_refs = channel_names.iter().collect::<Vec<_>>();
+
+                            imgui::ComboBox::new(im_str!("Channel##Trigger"))
+                                .build_simple_string(ui, &mut trigger.channel, &channel_refs);
+
+                            ui.input_float(im_str!("Level##Trigger"), &mut trigger.level).build();
+                        }
+
+                        let mut post_trigger_samples = trigger.post_trigger_samples as i32;
+                        if ui.input_int(im_str!("Post-Trigger Samples"), &mut post_trigger_samples).build() {
+                            trigger.post_trigger_samples = post_trigger_samples.max(0) as usize;
+                        }
+
+                        match trigger.state {
+                            TriggerState::Idle => ui.text("Idle"),
+                            TriggerState::Armed => ui.text_colored([0.9, 0.9, 0.2, 1.0], "Armed"),
+                            TriggerState::Triggered{remaining} => ui.text_colored([0.9, 0.5, 0.2, 1.0], format!("Triggered, {} samples remaining", remaining)),
+                            TriggerState::Captured => ui.text_colored([0.3, 0.9, 0.3, 1.0], "Captured"),
+                        }
+
+                        if ui.small_button(im_str!("Arm")) {
+                            trigger.arm();
+                            self.frozen_samples = None;
+                            self.controller_commands.lock().push(InterfaceCommand::StartRecording);
+                        }
+                        ui.same_line(0.0);
+                        if ui.small_button(im_str!("Disarm")) {
+                            trigger.disarm();
+                        }
+                    }
+
+                    if imgui::CollapsingHeader::new(im_str!("Time Base")).build(ui) {
+                        ui.input_float(im_str!("Control Loop (Hz)"), &mut self.control_loop_hz).build();
+                        self.control_loop_hz = self.control_loop_hz.max(1.0);
+
+                        let interval = self.controller_data.lock().oscilloscope.interval.max(1);
+                        ui.text(format!("Sample Interval: {} cycles ({:.3} ms)", interval, 1000.0 * interval as f32 / self.control_loop_hz));
+
+                        if self.oscilloscope_interval == 0 {
+                            self.oscilloscope_interval = interval as i32;
+                        }
+
+                        if ui.input_int(im_str!("##Sample Interval"), &mut self.oscilloscope_interval).build() {
+                            self.oscilloscope_interval = self.oscilloscope_interval.max(1);
+                        }
+                        ui.same_line(0.0);
+                        if ui.small_button(im_str!("Set Sample Interval")) {
+                            self.controller_commands.lock().push(InterfaceCommand::SetOscilloscopeInterval(self.oscilloscope_interval as u32));
+                        }
+
+                        let (len, capacity) = {
+                            let controller_data = self.controller_data.lock();
+                            (controller_data.oscilloscope.len, controller_data.oscilloscope_capacity)
+                        };
+
+                        // the firmware ring wraps after `len` samples, so the host has to poll at least this often
+                        ui.text(format!("Ring Length: {} / {} samples ({:.3} s)", len, capacity, len as f32 * self.sample_period()));
+
+                        if self.oscilloscope_len == 0 {
+                            self.oscilloscope_len = len as i32;
+                        }
+
+                        if ui.input_int(im_str!("##Ring Length"), &mut self.oscilloscope_len).build() {
+                            self.oscilloscope_len = self.oscilloscope_len.max(1).min(capacity.max(1) as i32);
+                        }
+                        ui.same_line(0.0);
+                        if ui.small_button(im_str!("Set Ring Length")) {
+                            self.controller_commands.lock().push(InterfaceCommand::SetOscilloscopeLength(self.oscilloscope_len as u32));
+                        }
+
+                        ui.separator();
+
+                        let mut poll_settings = self.poll_settings.lock();
+
+                        ui.text(format!("Receiving {:.0} samples/s", self.controller_data.lock().sample_rate));
+
+                        duration_ms_input(ui, im_str!("Poll Interval (ms)"), &mut poll_settings.poll_interval);
+                        poll_settings.poll_interval = poll_settings.poll_interval.max(Duration::from_millis(1));
+
+                        let mut max_samples = poll_settings.max_sample_storage as i32;
+                        if ui.input_int(im_str!("Max Stored Samples"), &mut max_samples).build() {
+                            poll_settings.max_sample_storage = max_samples.max(100) as usize;
+
+                            // drop only the oldest samples past the new limit
+                            let mut samples = self.sample_buffer.lock();
+                            if samples.len() > poll_settings.max_sample_storage {
+                                let excess = samples.len() - poll_settings.max_sample_storage;
+                                samples.drain(0..excess);
+                            }
+                        }
+                    }
+
+                    if imgui::CollapsingHeader::new(im_str!("Statistics")).default_open(true).build(ui) {
+
+                        let channel_names = SAMPLE_CHANNELS.iter()
+                            .map(|(name, _)| imgui::ImString::new(*name))
+                            .collect::<Vec<_>>();
+                        let channel_refs = channel_names.iter().collect::<Vec<_>>();
+
+                        imgui::ComboBox::new(im_str!("Channel##Statistics"))
+                            .build_simple_string(ui, &mut self.stats_channel, &channel_refs);
+
+                        // the same samples the plot is showing, so zooming or panning picks the stretch to measure
+                        let sample_buffer = self.sample_buffer.lock();
+
+                        let samples : &[OscilloscopeSamplePoint] = match &self.frozen_samples {
+                            Some(frozen) => frozen,
+                            None => &sample_buffer,
+                        };
+
+                        let (start, end) = self.visible_range(samples.len());
+                        let window = &samples[start..end];
+
+                        ui.text_disabled("Over the visible part of the plot");
+
+                        if let Some(stats) = channel_statistics(window, SAMPLE_CHANNELS[self.stats_channel].1) {
+                            ui.text(format!("Samples:      {}", stats.count));
+                            ui.text(format!("Min:          {:.5}", stats.min));
+                            ui.text(format!("Max:          {:.5}", stats.max));
+                            ui.text(format!("Mean:         {:.5}", stats.mean));
+                            ui.text(format!("Peak-to-Peak: {:.5}", stats.peak_to_peak));
+                            ui.text(format!("RMS:          {:.5}", stats.rms));
+                            ui.text(format!("Std. Dev.:    {:.5}", stats.std_dev));
+                        } else {
+                            ui.text("No samples recorded.");
+                        }
+
+                        let sample_rate = 1.0 / self.sample_period();
+                        ui.text(format!("Sample Rate:  {:.1} Hz (Nyquist {:.1} Hz)", sample_rate, 0.5 * sample_rate));
+
+                        if let Some(freq) = dominant_frequency(window, SAMPLE_CHANNELS[self.stats_channel].1, sample_rate) {
+                            ui.text(format!("Dominant:     {:.2} Hz", freq));
+                        }
+
+                        for (name, func) in SAMPLE_CHANNELS {
+                            match dominant_frequency(window, *func, sample_rate) {
+                                Some(freq) if aliasing_likely(freq, sample_rate) => {
+                                    ui.text_colored([1.0, 0.6, 0.2, 1.0], format!("{}: aliasing likely, decrease interval", name));
+                                }
+                                _ => {}
+                            }
+                        }
+                    }
+                });
+
+            self.panels.analysis = open;
+        }
+
+        // developer tool, floats over the fixed layout
+        if self.panels.raw_command {
+            let mut open = true;
+
+            imgui::Window::new(im_str!("Raw Command"))
+                .position([width as f32 * 0.5 - 150.0, height as f32 * 0.5 - 75.0], imgui::Condition::FirstUseEver)
+                .size([300.0, 150.0], imgui::Condition::FirstUseEver)
+                .opened(&mut open)
+                .build(ui, || {
+                    let variant_names = COMMAND_VARIANTS.iter()
+                        .map(|(name, _)| imgui::ImString::new(*name))
+                        .collect::<Vec<_>>();
+                    let variant_refs = variant_names.iter().collect::<Vec<_>>();
+
+                    imgui::ComboBox::new(im_str!("Command##Raw Command"))
+                        .build_simple_string(ui, &mut self.raw_command_index, &variant_refs);
+
+                    if let Some(field) = COMMAND_VARIANTS[self.raw_command_index].1 {
+                        ui.input_float(im_strf!("{}##Raw Command", field), &mut self.raw_command_value).build();
+                    }
+
+                    let cmd = Command::from_variant_index(self.raw_command_index, self.raw_command_value);
+
+                    if let Some(cmd) = cmd {
+                        ui.text(format!("{:?}", cmd));
+
+                        if ui.small_button(im_str!("Send##Raw Command")) {
+                            self.controller_commands.lock().push(InterfaceCommand::SendCommand(cmd));
+                        }
+                    }
+                });
+
+            self.panels.raw_command = open;
+        }
+
+        if self.panels.usb_diagnostics {
+            let mut open = true;
+
+            imgui::Window::new(im_str!("USB Diagnostics"))
+                .position([width as f32 * 0.5 - 150.0, height as f32 * 0.5 - 100.0], imgui::Condition::FirstUseEver)
+                .size([300.0, 200.0], imgui::Condition::FirstUseEver)
+                .opened(&mut open)
+                .build(ui, || {
+                    for (i, dev) in self.devices.iter().enumerate() {
+                        let mut dev = dev.link.lock();
+
+                        if !dev.is_connected() {
+                            continue;
+                        }
+
+                        let errors = dev.usb_errors();
+
+                        ui.text(format!("Device {}", i));
+                        ui.text(format!("  Timeouts:           {}", errors.timeouts));
+                        ui.text(format!("  Short Reads:        {}", errors.short_reads));
+                        ui.text(format!("  Short Writes:       {}", errors.short_writes));
+                        ui.text(format!("  Read Retries:       {}", errors.retries));
+                        ui.text(format!("  RW Status Failures: {}", errors.rw_status_failures));
+                        ui.text(format!("  Errors/min:         {:.2} over {:.0} s", errors.per_minute(), errors.since.elapsed().as_secs_f32()));
+
+                        if ui.small_button(im_strf!("Reset Counters##USB Diagnostics {:03}", i)) {
+                            dev.usb_errors_mut().reset();
+                        }
+                    }
+                });
+
+            self.panels.usb_diagnostics = open;
+        }
+
+        if self.panels.hexdump {
+            let mut open = true;
+
+            imgui::Window::new(im_str!("Hexdump"))
+                .position([width as f32 * 0.5 - 250.0, height as f32 * 0.5 - 200.0], imgui::Condition::FirstUseEver)
+                .size([500.0, 400.0], imgui::Condition::FirstUseEver)
+                .opened(&mut open)
+                .build(ui, || {
+                    ui.input_text(im_str!("Address##Hexdump"), &mut self.hexdump_addr).build();
+                    ui.input_int(im_str!("Length##Hexdump"), &mut self.hexdump_len).build();
+                    self.hexdump_len = self.hexdump_len.max(1).min(MAX_HEXDUMP_LEN as i32);
+
+                    // a range past the top of the address space would wrap around to address 0
+                    let addr = parse_hex_u32(self.hexdump_addr.to_str())
+                        .filter(|addr| addr.checked_add(self.hexdump_len as u32 + 3).is_some());
+
+                    if ui.small_button(im_str!("Read##Hexdump")) {
+                        if let Some(addr) = addr {
+                            self.controller_commands.lock().push(InterfaceCommand::DumpMemory{addr, len : self.hexdump_len as u32, live : false});
+                        }
+                    }
+                    ui.same_line(0.0);
+                    if ui.checkbox(im_str!("Live##Hexdump"), &mut self.hexdump_live) {
+                        let cmd = match (self.hexdump_live, addr) {
+                            (true, Some(addr)) => InterfaceCommand::DumpMemory{addr, len : self.hexdump_len as u32, live : true},
+                            _ => InterfaceCommand::StopMemoryDump,
+                        };
+
+                        self.controller_commands.lock().push(cmd);
+                    }
+
+                    if addr.is_none() {
+                        ui.text_colored([1.0, 0.3, 0.3, 1.0], "Invalid address, or the range runs past 0xFFFFFFFF");
+                    }
+
+                    ui.separator();
 
-impl GuiState {
-    pub fn init() -> Self {
-        GuiState {
-            stlinks : vec![],
-            connected : Arc::new(AtomicBool::new(false)),
-            sample_buffer: Arc::new(Mutex::new(vec![])),
-            controller_data: Arc::new(Mutex::new(ControllerData::default())),
-            controller_commands: Arc::new(Mutex::new(vec![])),
-            tasks : vec![]
+                    let dump = self.controller_data.lock().memory_dump.clone();
+
+                    if let Some((base, bytes)) = dump {
+                        for (row, chunk) in bytes.chunks(16).enumerate() {
+                            match base.checked_add(16 * row as u32) {
+                                Some(row_addr) => ui.text(hexdump_line(row_addr, chunk)),
+                                None => break,
+                            }
+                        }
+                    }
+                });
+
+            self.panels.hexdump = open;
         }
-    }
 
-    pub fn frame(&mut self, system : &mut System, ui : &mut imgui::Ui, _async_runtime : &mut tokio::runtime::Runtime, viewport : &mut crate::viewport::Viewport, line_renderer : &mut LineRenderer) {
+        if self.panels.command_log {
+            let mut open = true;
 
-        use imgui::im_str;
+            imgui::Window::new(im_str!("Command Log"))
+                .position([width as f32 * 0.5 - 250.0, height as f32 * 0.5 - 200.0], imgui::Condition::FirstUseEver)
+                .size([500.0, 400.0], imgui::Condition::FirstUseEver)
+                .opened(&mut open)
+                .build(ui, || {
+                    if ui.small_button(im_str!("Clear##Command Log")) {
+                        self.controller_data.lock().command_log.clear();
+                    }
+                    ui.same_line(0.0);
+                    if ui.small_button(im_str!("Export...##Command Log")) {
+                        if let Ok(nfd::Response::Okay(path)) = nfd::open_save_dialog(Some("csv"), None) {
+                            let log = self.controller_data.lock().command_log.iter().cloned().collect::<Vec<_>>();
 
-        let PhysicalSize { width, height } = system.surface.window().inner_size();
+                            self.command_log_status = Some(match save_command_log(Path::new(&path), &log) {
+                                Ok(()) => format!("Saved to {}", path),
+                                Err(e) => format!("Failed to save: {}", e),
+                            });
+                        }
+                    }
+                    ui.same_line(0.0);
+                    ui.checkbox(im_str!("Auto-Scroll##Command Log"), &mut self.command_log_autoscroll);
+
+                    if let Some(status) = &self.command_log_status {
+                        ui.text_wrapped(&imgui::ImString::new(status.as_str()));
+                    }
 
-        let window_rect = LayoutRect::new(width, height);
+                    ui.separator();
 
-        let (sidepanel_rect, viewport_rect) = window_rect.vertical_split_left_abs(400);
+                    imgui::ChildWindow::new(im_str!("Command Log Entries")).build(ui, || {
+                        let len = self.controller_data.lock().command_log.len();
 
-        let (viewport_rect, tool_menu_rect) = viewport_rect.horizontal_split_bottom_abs(400);
+                        // only the visible rows are copied out, so the connection task isn't held
+                        // up while they're drawn
+                        let mut clipper = imgui::ListClipper::new(len as i32).begin(ui);
 
-        let (devices_rect, config_menu_rect) = sidepanel_rect.horizontal_split_top_abs(100);
+                        while clipper.step() {
+                            let start = clipper.display_start() as usize;
+                            let end = clipper.display_end() as usize;
 
+                            let entries = self.controller_data.lock().command_log.iter()
+                                .skip(start)
+                                .take(end.saturating_sub(start))
+                                .cloned()
+                                .collect::<Vec<_>>();
 
-        imgui::Window::new(im_str!("Devices"))
-            .position(devices_rect.position(), imgui::Condition::Always)
-            .size(devices_rect.dimensions(), imgui::Condition::Always)
-            .resizable(false)
-            .movable(false)
-            .collapsible(false)
-            .scrollable(true)
-            .build(ui, || {
-                if ui.small_button(im_str!("Refresh Devices")) {
-                    self.stlinks.clear();
+                            for entry in entries {
+                                let text = format!("[{:>10.3} s] {}", entry.elapsed.as_secs_f32(), entry.description);
 
-                    self.stlinks.extend(STLink::enumerate().into_iter().map(|link| Arc::new(Mutex::new(link))));
-                }
+                                if entry.success {
+                                    ui.text(text);
+                                } else {
+                                    ui.text_colored([1.0, 0.3, 0.3, 1.0], format!("{}  FAILED", text));
+                                }
+                            }
+                        }
+
+                        clipper.end();
 
-                let is_device_connected = self.stlinks.iter().any(|dev|dev.lock().connected);
+                        if self.command_log_autoscroll && ui.scroll_y() >= ui.scroll_max_y() {
+                            ui.set_scroll_here_y_with_ratio(1.0);
+                        }
+                    });
+                });
+
+            self.panels.command_log = open;
+        }
 
-                for (i, dev) in self.stlinks.iter_mut().enumerate() {
+        if self.panels.error_log {
+            let mut open = true;
 
-                    let dev_addr = dev.lock().device.address();
-                    let dev_bus = dev.lock().device.bus_number();
-                    let dev_type = dev.lock().dev_type;
+            imgui::Window::new(im_str!("Error Log"))
+                .position([width as f32 * 0.5 - 250.0, height as f32 * 0.5 - 150.0], imgui::Condition::FirstUseEver)
+                .size([500.0, 300.0], imgui::Condition::FirstUseEver)
+                .opened(&mut open)
+                .build(ui, || {
+                    if ui.small_button(im_str!("Clear##Error Log")) {
+                        self.error_log.clear();
+                    }
+                    ui.same_line(0.0);
+                    ui.checkbox(im_str!("Auto-Scroll##Error Log"), &mut self.error_log_autoscroll);
 
-                    ui.text(format!("[{}] {:?}", i, dev_type.version));
+                    ui.separator();
 
-                    if dev.lock().connected {
-                        ui.same_line(400.0 - 80.0);
-                        if ui.small_button(im_strf!("Disconnect##Disconnect Device {:03}", i)) {
-                            self.connected.store(false, std::sync::atomic::Ordering::Relaxed);
+                    imgui::ChildWindow::new(im_str!("Error Log Entries")).build(ui, || {
+                        for entry in &self.error_log {
+                            ui.text_colored([1.0, 0.3, 0.3, 1.0], entry);
                         }
-                    } else {
-                        ui.same_line(400.0 - 80.0);
-                        if !is_device_connected && ui.small_button(im_strf!("Connect##Connect Device {:03}", i)) {
-
-                            let dev = dev.clone();
-                            let connected = self.connected.clone();
-                            let sample_buffer = self.sample_buffer.clone();
-                            let controller_data = self.controller_data.clone();
-                            let controller_commands = self.controller_commands.clone();
-
-                            std::thread::spawn(|| {
-                                controller_connection_task(
-                                    dev, 
-                                    connected, 
-                                    controller_data, 
-                                    sample_buffer,
-                                    controller_commands,
-                                );
-                            });
+
+                        if self.error_log_autoscroll && ui.scroll_y() >= ui.scroll_max_y() {
+                            ui.set_scroll_here_y_with_ratio(1.0);
+                        }
+                    });
+                });
+
+            self.panels.error_log = open;
+        }
+
+        if self.panels.tuning_wizard {
+            let mut open = true;
+
+            imgui::Window::new(im_str!("Tuning Wizard"))
+                .position([width as f32 * 0.5 - 200.0, height as f32 * 0.5 - 175.0], imgui::Condition::FirstUseEver)
+                .size([400.0, 350.0], imgui::Condition::FirstUseEver)
+                .opened(&mut open)
+                .build(ui, || {
+                    if !self.connected.load(Ordering::Relaxed) {
+                        // nothing to restore on a controller that's gone
+                        self.tuning_wizard.close();
+                        ui.text("Connect to a device to run the tuning wizard.");
+                        return;
+                    }
+
+                    if !self.tuning_wizard.active {
+                        let config = self.controller_data.lock().servo_config.clone();
+                        if let Some(cmd) = self.tuning_wizard.open(&config) {
+                            self.controller_commands.lock().push(cmd);
                         }
                     }
-                    ui.text(format!("  USB Bus: {}:{}", dev_bus, dev_addr));
-                }
-            });
-        
-        imgui::Window::new(im_str!("Configuration"))
-            .position(config_menu_rect.position(), imgui::Condition::Always)
-            .size(config_menu_rect.dimensions(), imgui::Condition::Always)
-            .resizable(false)
-            .movable(false)
-            .collapsible(false)
-            .scrollable(true)
-            .build(ui, || {
 
-                if self.connected.load(Ordering::Relaxed) {
+                    let step = self.tuning_wizard.step;
 
-                    let servo_config = &mut self.controller_data.lock().servo_config;
+                    ui.text(step.title());
+                    ui.separator();
+                    ui.text_wrapped(&imgui::ImString::new(step.instructions()));
+                    ui.separator();
 
-                    if imgui::CollapsingHeader::new(im_str!("Position Controller")).build(ui) {
+                    if let Some((name, offset)) = step.parameter() {
+                        let current = step.parameter_value(&self.controller_data.lock().servo_config);
 
-                        // let servo_cfg = self.controller_data.lock().servo_config.clone();
+                        ui.text(format!("Current {}: {:.4}", name, current));
 
-                        cfg_parameter_widget!(
-                            ui, self.controller_commands, 
-                            "Position Gain", "Value##Position Gain", 
-                            servo_config.position_gain, OFFSET_POSITION_GAIN
-                        );
+                        if ui.input_float(im_strf!("{}##Tuning Wizard", name), &mut self.tuning_wizard.candidate).enter_returns_true(true).build() {
+                            self.controller_commands.lock().push(InterfaceCommand::UpdateConfigParameter(offset, self.tuning_wizard.candidate));
+                        }
+                        ui.same_line(0.0);
+                        if ui.small_button(im_str!("Try##Tuning Wizard")) {
+                            self.controller_commands.lock().push(InterfaceCommand::UpdateConfigParameter(offset, self.tuning_wizard.candidate));
+                        }
 
-                        cfg_parameter_widget!(
-                            ui, self.controller_commands, 
-                            "Velocity Limit", "Value##Velocity Limit", 
-                            servo_config.vel_max_abs, OFFSET_VEL_MAX_ABS
-                        );
-                        
+                        ui.input_float(im_str!("Amplitude##Tuning Wizard"), &mut self.tuning_wizard.amplitude).build();
+                        ui.input_float(im_str!("Period (s)##Tuning Wizard"), &mut self.tuning_wizard.period).build();
+
+                        if self.tuning_wizard.excitation_running() {
+                            if ui.small_button(im_str!("Stop Test Signal")) {
+                                self.tuning_wizard.stop_excitation();
+                            }
+                        } else if ui.small_button(im_str!("Start Test Signal")) && !self.estop.load(Ordering::SeqCst) {
+                            let running = Arc::new(AtomicBool::new(true));
+                            let running_thread = running.clone();
+                            let commands = self.controller_commands.clone();
+
+                            let center = match step {
+                                WizardStep::PositionGain => self.controller_data.lock().servo_state.pos_input,
+                                _ => 0.0,
+                            };
+                            let amplitude = self.tuning_wizard.amplitude;
+                            let period = self.tuning_wizard.period;
+                            let command = step.excitation();
+
+                            if let Some(mode) = step.control_mode() {
+                                commands.lock().push(InterfaceCommand::SendCommand(mode));
+                            }
+
+                            std::thread::spawn(move || {
+                                square_wave(commands, running_thread, center, amplitude, period, command);
+                            });
+
+                            self.tasks.push(GuiTask{name : format!("Tuning Wizard: {}", step.title()), running : running.clone()});
+                            self.tuning_wizard.excitation = Some(running);
+                        }
+
+                        ui.separator();
+
+                        let (setpoint, measured) = step.response_channels();
+                        let sample_period = self.sample_period();
+                        let sample_buffer = self.sample_buffer.lock();
+                        let window_len = ((self.tuning_wizard.period / sample_period) as usize).min(sample_buffer.len());
+
+                        match step_response(&sample_buffer[(sample_buffer.len() - window_len)..], setpoint, measured, sample_period) {
+                            Some(response) => {
+                                let fmt_time = |t : Option<f32>| t.map_or("--".to_string(), |t| format!("{:.1} ms", 1000.0 * t));
+
+                                ui.text(format!("Rise Time:     {}", fmt_time(response.rise_time)));
+                                ui.text(format!("Overshoot:     {:.1} %", response.overshoot));
+                                ui.text(format!("Settling Time: {}", fmt_time(response.settling_time)));
+                                ui.text(format!("SS Error:      {:.4}", response.steady_state_error));
+                            }
+                            None => ui.text("Record while the test signal runs to see the step response."),
+                        }
                     }
-                    
-                    if imgui::CollapsingHeader::new(im_str!("Velocity Controller")).build(ui) {
 
-                        cfg_parameter_widget!(
-                            ui, self.controller_commands, 
-                            "Velocity Gain", "Value##Velocity Gain", 
-                            servo_config.velocity_gain, OFFSET_VELOCITY_GAIN
-                        );
-                        
-                        cfg_parameter_widget!(
-                            ui, self.controller_commands, 
-                            "Velocity Integrator Gain", "Value##Velocity Integrator Gain", 
-                            servo_config.velocity_integrator_gain, OFFSET_VELOCITY_INTEGRATOR_GAIN
-                        );
-                        
-                        cfg_parameter_widget!(
-                            ui, self.controller_commands, 
-                            "Velocity Integrator Limit", "Value##Velocity Integrator Limit", 
-                            servo_config.velocity_integrator_max_abs, OFFSET_VELOCITY_INTEGRATOR_MAX_ABS
-                        );
-                        
-                        cfg_parameter_widget!(
-                            ui, self.controller_commands, 
-                            "Torque Limit", "Value##Torque Limit", 
-                            servo_config.tor_max_abs, OFFSET_TOR_MAX_ABS
-                        );
+                    ui.separator();
+
+                    let config = self.controller_data.lock().servo_config.clone();
+
+                    if step != WizardStep::VelocityGain {
+                        if ui.small_button(im_str!("Back##Tuning Wizard")) {
+                            let index = WIZARD_STEPS.iter().position(|s| *s == step).unwrap_or(1);
+                            if let Some(cmd) = self.tuning_wizard.go_to(WIZARD_STEPS[index - 1], &config) {
+                                self.controller_commands.lock().push(cmd);
+                            }
+                        }
+                        ui.same_line(0.0);
                     }
-                    
-                    if imgui::CollapsingHeader::new(im_str!("Servo Configuration")).build(ui) {
 
-                        cfg_parameter_widget!(
-                            ui, self.controller_commands, 
-                            "Index Scan Speed", "Value##Index Scan Speed", 
-                            servo_config.index_scan_speed, OFFSET_INDEX_SCAN_SPEED
-                        );
-                        
-                        cfg_parameter_widget!(
-                            ui, self.controller_commands, 
-                            "Steps Per Turn", "Value##Steps Per Turn", 
-                            servo_config.steps_per_turn, OFFSET_TURNS_PER_STEP
-                        );
-                        
-                        cfg_parameter_widget!(
-                            ui, self.controller_commands, 
-                            "Inertia", "Value##Inertia", 
-                            servo_config.inertia, OFFSET_INERTIA
-                        );
-                        
-                        cfg_parameter_widget!(
-                            ui, self.controller_commands, 
-                            "Torque Bandwidth", "Value##Torque Bandwidth", 
-                            servo_config.torque_bandwidth, OFFSET_TORQUE_BANDWIDTH
-                        );
-                        
+                    if let Some((_, offset)) = step.parameter() {
+                        if ui.small_button(im_str!("Accept##Tuning Wizard")) {
+                            self.controller_commands.lock().push(InterfaceCommand::UpdateConfigParameter(offset, self.tuning_wizard.candidate));
+                            if let Some(cmd) = self.tuning_wizard.go_to(step.next(), &config) {
+                                self.controller_commands.lock().push(cmd);
+                            }
+                        }
+                    } else if ui.small_button(im_str!("Restart##Tuning Wizard")) {
+                        if let Some(cmd) = self.tuning_wizard.go_to(WizardStep::VelocityGain, &config) {
+                            self.controller_commands.lock().push(cmd);
+                        }
                     }
-                } else {
-                    ui.text("Connect to a device to see configuration.");
+                });
+
+            if !open {
+                if let Some(cmd) = self.tuning_wizard.close() {
+                    self.controller_commands.lock().push(cmd);
                 }
-            });
+            }
 
+            self.panels.tuning_wizard = open;
+        } else if self.tuning_wizard.active {
+            // closed from the menu
+            if let Some(cmd) = self.tuning_wizard.close() {
+                self.controller_commands.lock().push(cmd);
+            }
+        }
 
-        
-        imgui::Window::new(im_str!("Tuning Controls"))
-            .position(tool_menu_rect.position(), imgui::Condition::Always)
-            .size(tool_menu_rect.dimensions(), imgui::Condition::Always)
-            .resizable(false)
-            .movable(false)
-            .collapsible(false)
-            .scrollable(true)
-            .build(ui, || {
-                if self.connected.load(Ordering::Relaxed) {
-                    ui.columns(4, im_str!("tool columns"), true);
+        if self.panels.move_sequence {
+            let mut open = true;
 
-                    if ui.small_button(im_str!("Start Recording")) {
-                        self.controller_commands.lock().push(InterfaceCommand::StartRecording);
-                    }
-                    if ui.small_button(im_str!("Stop Recording")) {
-                        self.controller_commands.lock().push(InterfaceCommand::StopRecording);
-                    }
-                    if ui.small_button(im_str!("Clear Faults")) {
-                        self.controller_commands.lock().push(InterfaceCommand::SendCommand(Command::ClearFaultState));
+            imgui::Window::new(im_str!("Move Sequence"))
+                .position([width as f32 * 0.5 - 200.0, height as f32 * 0.5 - 175.0], imgui::Condition::FirstUseEver)
+                .size([400.0, 350.0], imgui::Condition::FirstUseEver)
+                .opened(&mut open)
+                .build(ui, || {
+                    let running = self.sequence_running.as_ref().map_or(false, |r| r.load(Ordering::Relaxed));
+
+                    let kind_names = STEP_KINDS.iter()
+                        .map(|kind| imgui::ImString::new(kind.name()))
+                        .collect::<Vec<_>>();
+                    let kind_refs = kind_names.iter().collect::<Vec<_>>();
+
+                    let mut remove = None;
+                    let mut move_up = None;
+
+                    for (i, step) in self.sequence_steps.iter_mut().enumerate() {
+                        ui.text(format!("{:2}.", i + 1));
+                        ui.same_line(0.0);
+
+                        let mut kind_index = STEP_KINDS.iter().position(|k| *k == step.kind).unwrap_or(0);
+                        ui.set_next_item_width(130.0);
+                        if imgui::ComboBox::new(im_strf!("##Sequence Kind {}", i)).build_simple_string(ui, &mut kind_index, &kind_refs) {
+                            step.kind = STEP_KINDS[kind_index];
+                        }
+
+                        if step.kind.has_value() {
+                            ui.same_line(0.0);
+                            ui.set_next_item_width(80.0);
+                            if ui.input_float(im_strf!("##Sequence Value {}", i), &mut step.value).build() && step.kind == StepKind::Wait {
+                                step.value = wait_duration(step.value).as_secs_f32();
+                            }
+                        }
+
+                        ui.same_line(0.0);
+                        if i > 0 && ui.small_button(im_strf!("Up##Sequence {}", i)) {
+                            move_up = Some(i);
+                        }
+                        ui.same_line(0.0);
+                        if ui.small_button(im_strf!("Remove##Sequence {}", i)) {
+                            remove = Some(i);
+                        }
                     }
-                    if ui.small_button(im_str!("Save Configuration")) {
-                        self.controller_commands.lock().push(InterfaceCommand::SendCommand(Command::SaveServoConfig));
+
+                    if let Some(i) = move_up {
+                        self.sequence_steps.swap(i - 1, i);
                     }
-                    if ui.small_button(im_str!("Reset Microcontroller")) {
-                        self.controller_commands.lock().push(InterfaceCommand::ResetController);
+                    if let Some(i) = remove {
+                        self.sequence_steps.remove(i);
                     }
 
-                    ui.next_column();
+                    if ui.small_button(im_str!("Add Step")) {
+                        self.sequence_steps.push(SequenceStep { kind : StepKind::Wait, value : 1.0 });
+                    }
+                    ui.same_line(0.0);
+                    if ui.small_button(im_str!("Record Position")) {
+                        // the time since the last recorded point is kept so the playback has the same pacing
+                        if let Some(recorded_at) = self.sequence_recorded_at {
+                            self.sequence_steps.push(SequenceStep { kind : StepKind::Wait, value : recorded_at.elapsed().as_secs_f32() });
+                        }
 
-                    if ui.small_button(im_str!("Stop Motor")) {
-                        self.controller_commands.lock().push(InterfaceCommand::StopMotor);
+                        let position = self.controller_data.lock().servo_state.position;
+                        self.sequence_steps.push(SequenceStep { kind : StepKind::Position, value : position });
+                        self.sequence_recorded_at = Some(Instant::now());
                     }
-                    if ui.small_button(im_str!("Start Motor")) {
-                        self.controller_commands.lock().push(InterfaceCommand::StartMotor);
+                    if ui.is_item_hovered() {
+                        ui.tooltip_text("Adds the current position, after a wait as long as the time since the last recorded point");
                     }
-                    if ui.small_button(im_str!("Position Step 0.0")) {
-                        self.controller_commands.lock().push(InterfaceCommand::PositionCommand(0.0));
+                    ui.same_line(0.0);
+                    if ui.small_button(im_str!("Clear##Move Sequence")) {
+                        self.sequence_steps.clear();
+                        self.sequence_recorded_at = None;
                     }
-                    if ui.small_button(im_str!("Position Step 1.0")) {
-                        self.controller_commands.lock().push(InterfaceCommand::PositionCommand(1.0));
+
+                    ui.separator();
+
+                    ui.checkbox(im_str!("Loop##Move Sequence"), &mut self.sequence_loop);
+                    if !self.sequence_loop {
+                        ui.same_line(0.0);
+                        ui.set_next_item_width(80.0);
+                        ui.input_int(im_str!("Repeat"), &mut self.sequence_repeats).build();
+                        self.sequence_repeats = self.sequence_repeats.max(1);
                     }
-                    if ui.small_button(im_str!("Sine Input")) {
-                        // let running = Arc::new(AtomicBool::new(true));
-                        // let running_thread = running.clone();
-                        // let commands = self.controller_commands.clone();
-                        // std::thread::spawn(move || {
-                        //     let mut t = 0.0;
-                        //     while running_thread.load(Ordering::Relaxed) {
-                        //         let x = 0.25 * (4.0*t*std::f32::consts::TAU).sin();
-                        //         commands.lock().push(InterfaceCommand::PositionCommand(x));
-                        //         std::thread::sleep(Duration::from_millis(5));
-                        //         t += 0.005;
-                        //     }
-                        // });
-                        // self.tasks.push(GuiTask{name : "Sine Input".to_string(), running});
 
-                        self.controller_commands.lock().push(InterfaceCommand::SendCommand(Command::SetMotionProfile{profile: 1}));
+                    if running {
+                        if ui.small_button(im_str!("Stop##Move Sequence")) {
+                            if let Some(running) = self.sequence_running.take() {
+                                running.store(false, Ordering::Relaxed);
+                            }
+                        }
+                    } else if !self.connected.load(Ordering::Relaxed) {
+                        ui.text("Connect to a device to run the sequence.");
+                    } else if ui.small_button(im_str!("Run##Move Sequence")) && !self.estop.load(Ordering::SeqCst) {
+                        let running = Arc::new(AtomicBool::new(true));
+                        let running_thread = running.clone();
+                        let commands = self.controller_commands.clone();
+                        let steps = self.sequence_steps.clone();
+                        let repeats = if self.sequence_loop { None } else { Some(self.sequence_repeats as u32) };
+
+                        std::thread::spawn(move || {
+                            run_sequence(commands, running_thread, steps, repeats);
+                        });
 
+                        let name = match repeats {
+                            Some(repeats) => format!("Move Sequence ({} steps x{})", self.sequence_steps.len(), repeats),
+                            None => format!("Move Sequence ({} steps, looping)", self.sequence_steps.len()),
+                        };
+                        self.tasks.push(GuiTask{name, running : running.clone()});
+                        self.sequence_running = Some(running);
                     }
-                    if ui.small_button(im_str!("Clear Motion Profile")) {
-                        self.controller_commands.lock().push(InterfaceCommand::SendCommand(Command::SetMotionProfile{profile: 0}));
+                });
+
+            self.panels.move_sequence = open;
+        }
+
+        if self.panels.firmware_loader {
+            let mut open = true;
+
+            imgui::Window::new(im_str!("Firmware Loader"))
+                .position([width as f32 * 0.5 - 200.0, height as f32 * 0.5 - 120.0], imgui::Condition::FirstUseEver)
+                .size([400.0, 240.0], imgui::Condition::FirstUseEver)
+                .opened(&mut open)
+                .build(ui, || {
+                    if ui.small_button(im_str!("Choose Image...")) {
+                        if let Ok(nfd::Response::Okay(path)) = nfd::open_file_dialog(Some("bin"), None) {
+                            self.firmware_path = Some(path);
+                        }
                     }
+                    ui.same_line(0.0);
+                    ui.text(self.firmware_path.as_deref().unwrap_or("No image selected"));
 
-                    ui.next_column();
+                    ui.input_text(im_str!("Address##Firmware"), &mut self.firmware_addr).build();
 
-                    for i in (0..(self.tasks.len())).rev() {
-                        ui.text(format!("Task {:2}: {}", i, self.tasks[i].name));
-                        if ui.small_button(im_strf!("Cancel##Cancel Task {}", i)) {
-                            self.tasks[i].running.store(false, Ordering::Relaxed);
-                            self.tasks.remove(i);
+                    ui.checkbox(im_str!("Advanced: Allow Flash Programming"), &mut self.firmware_allow_flash);
+                    if self.firmware_allow_flash {
+                        ui.checkbox(im_str!("Write to Flash"), &mut self.firmware_to_flash);
+                        if self.firmware_to_flash {
+                            ui.text_colored([1.0, 0.6, 0.2, 1.0], "Erases whole pages. STM32F1/F3 only, checked against the device ID.");
                         }
+                    } else {
+                        self.firmware_to_flash = false;
                     }
 
-                    ui.next_column();
+                    let busy = self.controller_data.lock().firmware_load.as_ref()
+                        .map_or(false, |p| !matches!(p.stage, LoadStage::Done | LoadStage::Failed(_)));
 
-                    if ui.small_button(im_str!("Enable Position Control")) {
-                        self.controller_commands.lock().push(InterfaceCommand::SendCommand(Command::SetPositionControl));
+                    let addr = parse_hex_u32(self.firmware_addr.to_str());
+
+                    ui.text("The core is halted before loading and stays halted until it's reset.");
+                    if let (Some(addr), false) = (addr, self.firmware_to_flash) {
+                        if addr == CONFIG_ADDR_ADDR {
+                            ui.text_colored([1.0, 0.6, 0.2, 1.0], "Overwrites the controller pointer slot, the tool can't reconnect until the firmware sets it again.");
+                        }
                     }
-                    if ui.small_button(im_str!("Enable Step/Direction Control")) {
-                        self.controller_commands.lock().push(InterfaceCommand::SendCommand(Command::SetStepDirectionControl));
+
+                    if !self.connected.load(Ordering::Relaxed) {
+                        ui.text("Connect to a device to load firmware.");
+                    } else if let (Some(path), Some(addr), false) = (&self.firmware_path, addr, busy) {
+                        if ui.small_button(im_str!("Load and Verify")) {
+                            match std::fs::read(path) {
+                                Ok(data) => {
+                                    let target = if self.firmware_to_flash { LoadTarget::Flash } else { LoadTarget::Ram };
+                                    self.controller_commands.lock().push(InterfaceCommand::LoadFirmware{addr, data, target});
+                                }
+                                Err(e) => {
+                                    self.controller_data.lock().firmware_load = Some(LoadProgress {
+                                        stage : LoadStage::Failed(format!("Failed to read {}: {}", path, e)),
+                                        done : 0,
+                                        total : 0,
+                                    });
+                                }
+                            }
+                        }
                     }
-                
-                } else {
-                    ui.text("Connect to a device to see tuning menu.");
-                }
-            });
-            
+
+                    if let Some(progress) = self.controller_data.lock().firmware_load.clone() {
+                        let label = match &progress.stage {
+                            LoadStage::Erasing => format!("Erasing page {}/{}", progress.done, progress.total),
+                            LoadStage::Writing => format!("Writing {}/{} bytes", progress.done, progress.total),
+                            LoadStage::Verifying => format!("Verifying {}/{} bytes", progress.done, progress.total),
+                            LoadStage::Done => "Loaded and verified".to_string(),
+                            LoadStage::Failed(_) => "Failed".to_string(),
+                        };
+
+                        imgui::ProgressBar::new(progress.fraction())
+                            .overlay_text(&imgui::ImString::new(label))
+                            .build(ui);
+
+                        if let LoadStage::Failed(e) = &progress.stage {
+                            ui.text_colored([1.0, 0.3, 0.3, 1.0], e);
+                        }
+                    }
+                });
+
+            self.panels.firmware_loader = open;
+        }
+
         let tok = ui.push_style_var(imgui::StyleVar::WindowPadding([0.0; 2]));
 
         imgui::Window::new(im_str!("Position/Velocity/Acceleration Plot"))
@@ -313,80 +3268,285 @@ impl GuiState {
             .scrollable(false)
             .collapsible(false)
             .build(ui, || {
-                
-                let dim = ui.window_content_region_max();
+
+                self.device_selector(ui, "Plot");
+
+                if ui.small_button(im_str!("Reset View")) {
+                    self.plot_follow_live = false;
+                    self.plot_view = None;
+                }
+                if ui.is_item_hovered() {
+                    ui.tooltip_text("Show the whole buffer. Scroll over the plot to zoom, drag to pan.");
+                }
+                ui.same_line(0.0);
+                if ui.small_button(im_str!("Reset Time Window")) {
+                    self.plot_follow_live = true;
+                    self.plot_view = None;
+                }
+                ui.same_line(0.0);
+                if ui.checkbox(im_str!("Follow Live"), &mut self.plot_follow_live) && self.plot_follow_live {
+                    self.plot_view = None;
+                }
+                ui.same_line(0.0);
+                ui.checkbox(im_str!("HUD"), &mut self.show_tuning_hud);
+                ui.same_line(0.0);
+                ui.checkbox(im_str!("Model Prediction"), &mut self.model_prediction_preview);
+                if ui.is_item_hovered() {
+                    ui.tooltip_text("Dimmed position predicted from the current gains, inertia and torque bandwidth.");
+                }
+                ui.same_line(0.0);
+                if ui.small_button(im_str!("Y Scale...")) {
+                    ui.open_popup(im_str!("Y Scale##Popup"));
+                }
+                ui.popup(im_str!("Y Scale##Popup"), || {
+                    let scale_names = TRACE_SCALES.iter()
+                        .map(|scale| imgui::ImString::new(scale.name()))
+                        .collect::<Vec<_>>();
+                    let scale_refs = scale_names.iter().collect::<Vec<_>>();
+
+                    let mut changed = false;
+
+                    for channel in self.settings.plot.channels.iter_mut().filter(|c| c.visible) {
+                        let mut index = TRACE_SCALES.iter().position(|s| *s == channel.scale).unwrap_or(0);
+
+                        ui.set_next_item_width(100.0);
+                        if imgui::ComboBox::new(im_strf!("{}##Y Scale", channel.name)).build_simple_string(ui, &mut index, &scale_refs) {
+                            channel.scale = TRACE_SCALES[index];
+                            changed = true;
+                        }
+
+                        if channel.scale == TraceScale::Fixed {
+                            ui.same_line(0.0);
+                            ui.set_next_item_width(150.0);
+                            changed |= ui.input_float2(im_strf!("Min/Max##{}", channel.name), &mut channel.fixed_range).build();
+                        }
+                    }
+
+                    if changed {
+                        self.settings_changed = true;
+                        self.last_plot_refresh = Instant::now() - Duration::from_secs(1);
+                    }
+                });
+                if self.frozen_samples.is_some() {
+                    ui.same_line(0.0);
+                    if ui.small_button(im_str!("Resume Live Plot")) {
+                        self.frozen_samples = None;
+                        self.trigger.lock().disarm();
+                    }
+                    ui.same_line(0.0);
+                    ui.text_colored([0.9, 0.9, 0.2, 1.0], format!("Frozen {:.0} s ago, still recording", self.frozen_at.elapsed().as_secs_f32()));
+                    ui.same_line(0.0);
+                    ui.set_next_item_width(150.0);
+                    ui.input_text(im_str!("Name##Capture"), &mut self.capture_name).build();
+                    ui.same_line(0.0);
+                    if ui.small_button(im_str!("Export CSV...##Capture")) {
+                        let file_name = capture_file_name(self.capture_name.to_str());
+
+                        if let Ok(nfd::Response::Okay(path)) = nfd::open_save_dialog(Some("csv"), Some(&file_name)) {
+                            let sample_period = self.sample_period();
+
+                            if let Some(samples) = &self.frozen_samples {
+                                if let Err(e) = save_capture(Path::new(&path), self.capture_name.to_str(), samples, sample_period) {
+                                    eprintln!("Failed to export capture: {}", e);
+                                }
+                            }
+                        }
+                    }
+                } else {
+                    ui.same_line(0.0);
+                    // only the plot stops, the connection task keeps appending to the sample buffer
+                    if ui.small_button(im_str!("Freeze Plot")) {
+                        self.frozen_samples = Some(self.sample_buffer.lock().clone());
+                        self.frozen_at = Instant::now();
+                    }
+                }
+                ui.same_line(0.0);
+                ui.set_next_item_width(80.0);
+                ui.input_float(im_str!("Window (s)"), &mut self.plot_window_seconds).build();
+                self.plot_window_seconds = self.plot_window_seconds.max(0.01);
+                ui.same_line(0.0);
+                ui.set_next_item_width(60.0);
+                ui.input_float(im_str!("Refresh (Hz)"), &mut self.plot_refresh_hz).build();
+                self.plot_refresh_hz = self.plot_refresh_hz.max(1.0);
+                ui.same_line(0.0);
+                ui.set_next_item_width(80.0);
+                ui.input_int(im_str!("Max Points"), &mut self.plot_max_points).build();
+                self.plot_max_points = self.plot_max_points.max(2);
+                ui.same_line(0.0);
+                if ui.checkbox(im_str!("Grid"), &mut self.settings.plot.show_grid) {
+                    self.settings_changed = true;
+                    self.last_plot_refresh = Instant::now() - Duration::from_secs(1);
+                }
+
+                let plot_settings = &mut self.settings.plot;
+                if plot_settings.window_seconds != self.plot_window_seconds
+                    || plot_settings.refresh_hz != self.plot_refresh_hz
+                    || plot_settings.max_points != self.plot_max_points {
+
+                    plot_settings.window_seconds = self.plot_window_seconds;
+                    plot_settings.refresh_hz = self.plot_refresh_hz;
+                    plot_settings.max_points = self.plot_max_points;
+                    self.settings_changed = true;
+                }
+
+                // leave a strip under the plot for the time axis labels
+                let axis_h = ui.text_line_height_with_spacing() + 4.0;
+                let avail = ui.content_region_avail();
+                let dim = [avail[0], (avail[1] - axis_h).max(1.0)];
+                let image_pos = ui.cursor_screen_pos();
 
                 let sample_buffer = self.sample_buffer.lock();
 
-                let n = sample_buffer.len();
+                let samples : &[OscilloscopeSamplePoint] = match &self.frozen_samples {
+                    Some(frozen) => frozen,
+                    None => &sample_buffer,
+                };
 
-                let funcs = [
-                    |p : &OscilloscopeSamplePoint| p.pos_input,
+                let (start, end) = self.visible_range(samples.len());
+                let visible = &samples[start..end];
 
-                    |p : &OscilloscopeSamplePoint| p.pos_setpoint,
-                    |p : &OscilloscopeSamplePoint| p.vel_setpoint,
-                    |p : &OscilloscopeSamplePoint| p.tor_setpoint,
+                let n = visible.len();
 
-                    |p : &OscilloscopeSamplePoint| p.pos,
-                    |p : &OscilloscopeSamplePoint| p.vel,
-                    |p : &OscilloscopeSamplePoint| p.acc,
-                ];
+                // line buffers are kept between frames and only rebuilt at the plot refresh rate
+                let refresh_lines = self.last_plot_refresh.elapsed().as_secs_f32() >= 1.0 / self.plot_refresh_hz
+                    || (dim[0] as u32).max(1) != viewport.width || (dim[1] as u32).max(1) != viewport.height;
 
-                let cols = [
-                    [0.0, 0.6, 0.0, 1.0],
+                if refresh_lines {
+                    let line_build_start = Instant::now();
+                    self.last_plot_refresh = Instant::now();
+                    line_renderer.clear_line_buffer();
 
-                    [0.2, 0.2, 0.8, 1.0],
-                    [0.2, 0.2, 0.8, 1.0],
-                    [0.2, 0.2, 0.8, 1.0],
-                    
-                    [0.8, 0.4, 0.4, 1.0],
-                    [0.8, 0.4, 0.4, 1.0],
-                    [0.8, 0.4, 0.4, 1.0],
-                ];
+                    // decimate long windows, there's no point drawing more points than the plot can show
+                    let step = (n / self.plot_max_points.max(1) as usize).max(1);
+                    let n_drawn = (n + step - 1) / step;
 
-                let offsets = [
-                    -0.666,
+                    let mut points = Vec::with_capacity(2 * n_drawn + 1);
 
-                    -0.666,
-                    0.0,
-                    0.666,
-                
-                    -0.666,
-                    0.0,
-                    0.666,
-                ];
-                let mut points = Vec::with_capacity(2 * n + 1);
-
-                for (func, (color, offset)) in funcs.iter().zip(cols.iter().zip(offsets.iter())) {
-
-                    points.clear();
-
-                    let min = sample_buffer.iter().map(func).min_by(|a,b| a.partial_cmp(b).unwrap()).unwrap_or(-1.0)-0.01;
-                    let max = sample_buffer.iter().map(func).max_by(|a,b| a.partial_cmp(b).unwrap()).unwrap_or( 1.0)+0.01;
-    
-                    let diff = max - min;
-    
-    
-                    let mut first = true;
-                    for (i, pt) in sample_buffer.iter().enumerate() {
-                        // let i = i * 8;
-                        let val = func(pt);
-                        let t = Vector3::new(
-                            i as f32 / n as f32 * 2.0 - 1.0,
-                            0.333 * (2.0 * (val - min) / diff - 1.0) + offset,
-                            0.5
-                        );
-                        
-                        points.push(t);
-                        if first {
-                            first = false;
-                        } else {
-                            points.push(t);
+                    // gridlines and axis labels follow the range of the first visible trace in each band, so they line up with its values
+                    self.plot_band_ranges.clear();
+                    if n > 0 {
+                        for band in 0..PLOT_BANDS.len() {
+                            let slot = match self.settings.plot.band_slot(band) {
+                                Some(slot) => slot,
+                                None => continue,
+                            };
+
+                            let (channel, func) = match self.settings.plot.channels.iter()
+                                .filter(|c| c.visible && c.band_index() == band)
+                                .find_map(|c| c.func().map(|func| (c, func))) {
+                                Some(found) => found,
+                                None => continue,
+                            };
+
+                            let (min, max) = channel.y_range(visible.iter().map(func));
+                            self.plot_band_ranges.push((slot, min, max));
                         }
                     }
-                    points.pop();
-                    
-                    line_renderer.draw_line(&points, *color);
+
+                    if self.settings.plot.show_grid {
+                        for &(slot, min, max) in self.plot_band_ranges.iter() {
+                            points.clear();
+                            for level in grid_levels(min, max, 4) {
+                                let y = slot.y(level, min, max);
+                                points.push(Vector3::new(-1.0, y, 0.5));
+                                points.push(Vector3::new(1.0, y, 0.5));
+                            }
+                            if !points.is_empty() {
+                                line_renderer.draw_line(&points, self.settings.theme.plot_grid());
+                            }
+
+                            if min <= 0.0 && 0.0 <= max {
+                                let y = slot.y(0.0, min, max);
+                                line_renderer.draw_line(&[Vector3::new(-1.0, y, 0.5), Vector3::new(1.0, y, 0.5)], self.settings.theme.plot_zero_line());
+                            }
+                        }
+                    }
+
+                    for channel in self.settings.plot.channels.iter().filter(|c| c.visible) {
+
+                        let (func, slot) = match (channel.func(), self.settings.plot.band_slot(channel.band_index())) {
+                            (Some(func), Some(slot)) => (func, slot),
+                            _ => continue,
+                        };
+
+                        points.clear();
+
+                        let (min, max) = channel.y_range(visible.iter().map(func));
+                        let values = visible.iter().step_by(step).map(|pt| channel.clip(func(pt), min, max));
+
+                        push_trace_points(&mut points, values, n_drawn, min, max, slot);
+
+                        line_renderer.draw_line(&points, channel.color);
+                    }
+
+                    let setpoint_slot = self.settings.plot.channel_slot("Position Setpoint");
+
+                    if let Some(slot) = setpoint_slot.filter(|_| self.input_filter_preview && n > 0) {
+                        let (kp, ki, interval) = {
+                            let controller_data = self.controller_data.lock();
+                            (controller_data.servo_config.input_filt_kp, controller_data.servo_config.input_filt_ki, controller_data.oscilloscope.interval)
+                        };
+
+                        let inputs = visible.iter().map(|p| p.pos_input).collect::<Vec<_>>();
+                        let preview = simulate_input_filter(&inputs, kp, ki, 1.0 / self.control_loop_hz, interval.max(1));
+
+                        // drawn on the same scale as the measured setpoint so the two can be compared directly
+                        let min = visible.iter().map(|p| p.pos_setpoint).fold(f32::INFINITY, f32::min)-0.01;
+                        let max = visible.iter().map(|p| p.pos_setpoint).fold(f32::NEG_INFINITY, f32::max)+0.01;
+
+                        points.clear();
+                        push_trace_points(&mut points, preview.into_iter(), n, min, max, slot);
+                        line_renderer.draw_line(&points, [0.6, 0.6, 0.9, 0.5]);
+                    }
+
+                    let position_slot = self.settings.plot.channel_slot("Position");
+
+                    if let Some(slot) = position_slot.filter(|_| self.model_prediction_preview && n > 0) {
+                        let (model, interval) = {
+                            let controller_data = self.controller_data.lock();
+                            let config = &controller_data.servo_config;
+
+                            (LoopModel {
+                                position_gain : config.position_gain,
+                                velocity_gain : config.velocity_gain,
+                                velocity_integrator_gain : config.velocity_integrator_gain,
+                                velocity_integrator_max_abs : config.velocity_integrator_max_abs,
+                                vel_max_abs : config.vel_max_abs,
+                                tor_max_abs : config.tor_max_abs,
+                                inertia : config.inertia,
+                                torque_bandwidth : config.torque_bandwidth,
+                            }, controller_data.oscilloscope.interval)
+                        };
+
+                        let setpoints = visible.iter().map(|p| p.pos_setpoint).collect::<Vec<_>>();
+                        let prediction = simulate_position_loop(&setpoints, &model, 1.0 / self.control_loop_hz, interval.max(1));
+
+                        // same scale as the measured position trace
+                        let min = visible.iter().map(|p| p.pos).fold(f32::INFINITY, f32::min)-0.01;
+                        let max = visible.iter().map(|p| p.pos).fold(f32::NEG_INFINITY, f32::max)+0.01;
+
+                        points.clear();
+                        push_trace_points(&mut points, prediction.into_iter(), n, min, max, slot);
+                        line_renderer.draw_line(&points, [0.9, 0.7, 0.7, 0.4]);
+                    }
+
+                    let velocity_slot = self.settings.plot.channel_slot("Velocity");
+
+                    if let Some(slot) = velocity_slot.filter(|_| self.show_peak_velocity && n > 0) {
+                        let peak = self.controller_data.lock().servo_state.max_vel_abs_obs;
+
+                        let min = visible.iter().map(|p| p.vel).fold(f32::INFINITY, f32::min)-0.01;
+                        let max = visible.iter().map(|p| p.vel).fold(f32::NEG_INFINITY, f32::max)+0.01;
+
+                        for level in [peak, -peak].iter() {
+                            if min <= *level && *level <= max {
+                                let y = slot.y(*level, min, max);
+                                line_renderer.draw_line(&[Vector3::new(-1.0, y, 0.5), Vector3::new(1.0, y, 0.5)], [0.9, 0.6, 0.2, 0.8]);
+                            }
+                        }
+                    }
+
+                    self.profiler.line_build.push(line_build_start.elapsed());
                 }
 
                 viewport.update(system, dim[0] as u32, dim[1] as u32);
@@ -396,28 +3556,199 @@ impl GuiState {
                         .build(ui);
                 }
 
+                if ui.is_item_hovered() && n > 1 {
+                    // the stored view keeps fractional samples so slow drags still move it
+                    let (s0, e0) = self.plot_view.unwrap_or((start as f32, end as f32));
+                    let width = e0 - s0;
+                    let wheel = ui.io().mouse_wheel;
+                    let drag = ui.io().mouse_delta[0];
+
+                    let view = if wheel != 0.0 {
+                        // zoom about the sample under the cursor
+                        let f = ((ui.io().mouse_pos[0] - image_pos[0]) / dim[0]).max(0.0).min(1.0);
+                        let new_width = (width * if wheel > 0.0 { 0.8 } else { 1.25 }).max(10.0).min(samples.len() as f32);
+                        let new_start = s0 + f * (width - new_width);
+
+                        Some((new_start, new_start + new_width))
+                    } else if ui.is_mouse_dragging(imgui::MouseButton::Left) && drag != 0.0 {
+                        let shift = -drag / dim[0] * width;
+
+                        Some((s0 + shift, e0 + shift))
+                    } else {
+                        None
+                    };
+
+                    if let Some((view_start, view_end)) = view {
+                        // keep the view inside the buffer without changing its width
+                        let len = samples.len() as f32;
+                        let width = view_end - view_start;
+                        let view_start = view_start.max(0.0).min(len - width);
+
+                        self.plot_view = Some((view_start, view_start + width));
+                        self.plot_follow_live = false;
+                        self.last_plot_refresh = Instant::now() - Duration::from_secs(1);
+                    }
+                }
+
                 
                 let draw_list = ui.get_window_draw_list();
 
+                if self.trigger.lock().state == TriggerState::Captured && self.frozen_samples.is_some() {
+                    // flash at 2Hz
+                    let color = if (ui.time() * 2.0).fract() < 0.5 { 0xFF3030FF } else { 0xFFFFFFFF };
+                    draw_list.add_text([image_pos[0] + 8.0, image_pos[1] + 8.0], color, "TRIGGERED - capture frozen");
+                }
+
+                if self.frozen_samples.is_some() && !self.capture_name.to_str().trim().is_empty() {
+                    let y = image_pos[1] + 8.0 + ui.text_line_height_with_spacing();
+                    draw_list.add_text([image_pos[0] + 8.0, y], 0xFFFFFFFF, self.capture_name.to_str());
+                }
+
+                let windup = self.controller_data.lock().integrator_saturated(self.windup_margin);
+
+                if self.show_windup && windup && self.connected.load(Ordering::Relaxed) {
+                    draw_list.add_rect(image_pos, [image_pos[0] + dim[0], image_pos[1] + dim[1]], 0x300000FF)
+                        .filled(true)
+                        .build();
+                }
+
+                if self.show_tuning_hud && self.connected.load(Ordering::Relaxed) {
+                    let (state, fault, stalled) = {
+                        let controller_data = self.controller_data.lock();
+                        (controller_data.servo_state.clone(), controller_data.fault_detected, controller_data.firmware_stalled)
+                    };
+
+                    let mut lines = vec![
+                        (0xFFFFFFFF, format!("State:     {:?}", state.state)),
+                        (0xFFFFFFFF, format!("Pos Error: {:.4}", state.pos_setpoint - state.position)),
+                        (0xFFFFFFFF, format!("Aligned:   {}", if state.aligned { "yes" } else { "no" })),
+                        if fault {
+                            (0xFF3030FF, "Fault:     FAULT".to_string())
+                        } else {
+                            (0xFF30FF30, "Fault:     none".to_string())
+                        },
+                    ];
+
+                    if windup {
+                        lines.push((0xFF3030FF, "Integrator clamped".to_string()));
+                    }
+
+                    if stalled {
+                        lines.push((0xFF3030FF, "Firmware control loop stalled".to_string()));
+                    }
+
+                    if let Some((name, value)) = self.parameter_focus.edited {
+                        lines.push((0xFF30FFFF, format!("{}: {}", name, value)));
+                    }
+
+                    let line_h = ui.text_line_height_with_spacing();
+                    let hud_w = 240.0;
+                    let hud_h = line_h * lines.len() as f32 + 8.0;
+                    let hud_x = image_pos[0] + dim[0] - hud_w - 8.0;
+                    let hud_y = image_pos[1] + 8.0;
+
+                    draw_list.add_rect([hud_x, hud_y], [hud_x + hud_w, hud_y + hud_h], 0xA0000000)
+                        .filled(true)
+                        .build();
+
+                    for (i, (color, line)) in lines.iter().enumerate() {
+                        draw_list.add_text([hud_x + 6.0, hud_y + 4.0 + line_h * i as f32], *color, line);
+                    }
+                }
+
+                if self.panels.profiler {
+                    let stages = [
+                        ("Frame", &self.profiler.frame),
+                        ("GUI", &self.profiler.gui),
+                        ("Line Build", &self.profiler.line_build),
+                        ("USB Loop", &self.profiler.connection_loop),
+                    ];
+
+                    let line_h = ui.text_line_height_with_spacing();
+                    let box_w = 260.0;
+                    let box_h = line_h * (stages.len() + 1) as f32 + 8.0;
+                    let box_x = image_pos[0] + 8.0;
+                    let box_y = image_pos[1] + dim[1] - box_h - 8.0;
+
+                    draw_list.add_rect([box_x, box_y], [box_x + box_w, box_y + box_h], 0xA0000000)
+                        .filled(true)
+                        .build();
+
+                    draw_list.add_text([box_x + 6.0, box_y + 4.0], 0xFFFFFFFF, format!("{:<11} {:>8} {:>8}", "", "avg ms", "max ms"));
+
+                    for (i, (name, history)) in stages.iter().enumerate() {
+                        let text = format!("{:<11} {:>8.2} {:>8.2}", name, history.average_ms(), history.max_ms());
+                        draw_list.add_text([box_x + 6.0, box_y + 4.0 + line_h * (i + 1) as f32], 0xFFFFFFFF, text);
+                    }
+                }
+
                 let [mx, my] = ui.io().mouse_pos;
 
-                let [wx0, wy0] = ui.window_pos();
-                let [ww, wh] = ui.window_size();
-                let [wx1, wy1] = [wx0 + ww, wy0 + wh];
+                let [ix0, iy0] = image_pos;
+                let [iw, ih] = dim;
+                let [ix1, iy1] = [ix0 + iw, iy0 + ih];
+
+                for &(slot, min, max) in self.plot_band_ranges.iter() {
+                    let to_screen = |val : f32| iy0 + 0.5 * (slot.y(val, min, max) + 1.0) * ih;
+                    let step = grid_step(min, max, 4);
+                    let line_h = ui.text_line_height();
 
-                if sample_buffer.len() > 0 {
-                    if wx0 < mx && mx < wx1 {
-                        if wy0 < my && my < wy1 {
-                            let ix = (((mx - wx0) / ww) * sample_buffer.len() as f32) as usize;
-                            let y_pos = sample_buffer[ix].pos;
-                            let y_vel = sample_buffer[ix].vel;
-                            let y_acc = sample_buffer[ix].acc;
-                            draw_list.add_text([mx, my], 0xFFFFFFFF, format!("  [{:.3}, {:.3}, {:.3}]", y_pos, y_vel, y_acc));
+                    let mut labels = vec![(min, to_screen(min)), (max, to_screen(max))];
+                    if min < 0.0 && 0.0 < max {
+                        labels.push((0.0, to_screen(0.0)));
+                    }
+
+                    for (val, y) in labels {
+                        let y = (y - 0.5 * line_h).max(iy0).min(iy1 - line_h);
+                        draw_list.add_text([ix0 + 4.0, y], self.settings.theme.plot_text(), format_value(val, step));
+                    }
+                }
+
+                let sample_period = self.sample_period();
+
+                if n > 1 {
+                    let t0 = start as f32 * sample_period;
+                    let span = n as f32 * sample_period;
+                    let step = tick_step(span, iw / 100.0);
+
+                    let mut t = (t0 / step).ceil() * step;
+                    while t <= t0 + span {
+                        let x = ix0 + (t - t0) / span * iw;
+
+                        draw_list.add_line([x, iy1], [x, iy1 + 4.0], self.settings.theme.plot_text()).build();
+                        draw_list.add_text([x + 2.0, iy1 + 2.0], self.settings.theme.plot_text(), format_time(t, step));
+
+                        t += step;
+                    }
+                }
+
+                if n > 0 {
+                    if ix0 < mx && mx < ix1 {
+                        if iy0 < my && my < iy1 {
+                            // sample i is drawn at i / n of the image width, so snap to the nearest one
+                            let ix = ((((mx - ix0) / iw) * n as f32).round() as usize).min(n - 1);
+                            let t = (start + ix) as f32 * sample_period;
+                            let x = ix0 + ix as f32 / n as f32 * iw;
+
+                            let values = self.settings.plot.channels.iter()
+                                .filter(|c| c.visible)
+                                .filter_map(|c| c.func().map(|func| format!("{:.3}", func(&visible[ix]))))
+                                .collect::<Vec<_>>();
+
+                            draw_list.add_line([x, iy0], [x, iy1], self.settings.theme.plot_text() & 0x80FFFFFF).build();
+                            draw_list.add_text([mx, my], self.settings.theme.plot_text(), format!("  #{} t={:.2} ms [{}]", start + ix, 1e3 * t, values.join(", ")));
                         }
                     }
                 }
             });
 
         tok.pop(ui);
+
+        // saved once a drag ends rather than every frame, dividers and color pickers change
+        // continuously while held
+        if self.settings_changed && !ui.is_mouse_down(imgui::MouseButton::Left) {
+            self.settings_changed = false;
+            self.settings.save();
+        }
     }
 }
\ No newline at end of file
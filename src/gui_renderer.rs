@@ -126,6 +126,9 @@ pub fn init(title: &str, event_loop : &EventLoop<()>) -> (System, Context) {
     let mut imgui = Context::create();
     imgui.set_ini_filename(None);
 
+    // Tab/Shift-Tab and arrow keys move between widgets, so config values can be entered without the mouse
+    imgui.io_mut().config_flags |= imgui::ConfigFlags::NAV_ENABLE_KEYBOARD;
+
     if let Some(backend) = clipboard::init() {
         imgui.set_clipboard_backend(Box::new(backend));
     } else {
@@ -191,7 +194,10 @@ impl System {
                 match self.swapchain.recreate().dimensions(dimensions).build() {
                     Ok(r) => r,
                     Err(SwapchainCreationError::UnsupportedDimensions) => return Err(()),
-                    Err(e) => panic!("Failed to recreate swapchain: {:?}", e),
+                    Err(e) => {
+                        eprintln!("Failed to recreate swapchain: {:?}", e);
+                        return Err(());
+                    }
                 };
 
             self.images = new_images;
@@ -207,7 +213,11 @@ impl System {
                     self.recreate_swapchain = true;
                     return Err(());
                 }
-                Err(e) => panic!("Failed to acquire next image: {:?}", e),
+                Err(e) => {
+                    eprintln!("Failed to acquire next image: {:?}", e);
+                    self.recreate_swapchain = true;
+                    return Err(());
+                }
             };
 
         if suboptimal {
@@ -224,17 +234,39 @@ impl System {
         Ok((cmd_buf_builder, self.images[image_num].clone(), image_num))
     }
 
+    /// For a frame given up part way through. Its image is never presented, so the swapchain is
+    /// recreated to get it back.
+    pub fn abandon_frame(&mut self) {
+        self.acquire_future = None;
+        self.recreate_swapchain = true;
+
+        if self.previous_frame_end.is_none() {
+            self.previous_frame_end = Some(sync::now(self.device.clone()).boxed());
+        }
+    }
+
     pub fn end_frame(&mut self, cmd_buf_builder : AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>, image_num : usize) {
 
-        let cmd_buf = cmd_buf_builder.build()
-            .expect("Failed to build command buffer");
+        let acquire_future = core::mem::replace(&mut self.acquire_future, None).expect("No acquire future, was `start_frame` called?");
+
+        let cmd_buf = match cmd_buf_builder.build() {
+            Ok(cmd_buf) => cmd_buf,
+            Err(e) => {
+                println!("Failed to build command buffer: {:?}", e);
+                return;
+            }
+        };
+
+        let future = match self.previous_frame_end.take().unwrap().join(acquire_future).then_execute(self.queue.clone(), cmd_buf) {
+            Ok(future) => future,
+            Err(e) => {
+                println!("Failed to execute command buffer: {:?}", e);
+                self.previous_frame_end = Some(sync::now(self.device.clone()).boxed());
+                return;
+            }
+        };
 
-        let future = self.previous_frame_end
-            .take()
-            .unwrap()
-            .join(core::mem::replace(&mut self.acquire_future, None).expect("No acquire future, was `start_frame` called?"))
-            .then_execute(self.queue.clone(), cmd_buf)
-            .unwrap()
+        let future = future
             .then_signal_fence()
             .then_swapchain_present(self.queue.clone(), self.swapchain.clone(), image_num);
 
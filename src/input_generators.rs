@@ -0,0 +1,263 @@
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+
+use crate::controller_interface::InterfaceCommand;
+
+pub const GENERATOR_TICK : Duration = Duration::from_millis(5);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MoveProfile {
+    Trapezoidal,
+    SCurve,
+}
+
+pub const MOVE_PROFILES : [MoveProfile; 2] = [MoveProfile::Trapezoidal, MoveProfile::SCurve];
+
+// fraction of the move spent accelerating (and decelerating) for the trapezoidal profile
+const TRAPEZOID_ACCEL_FRACTION : f32 = 0.25;
+
+impl MoveProfile {
+    pub fn name(&self) -> &'static str {
+        match self {
+            MoveProfile::Trapezoidal => "Trapezoidal",
+            MoveProfile::SCurve => "S-Curve",
+        }
+    }
+
+    /// Fraction of the distance covered at normalized time `t` in `[0, 1]`.
+    pub fn fraction(&self, t : f32) -> f32 {
+        let t = t.max(0.0).min(1.0);
+
+        match self {
+            MoveProfile::Trapezoidal => {
+                let ta = TRAPEZOID_ACCEL_FRACTION;
+                let v_peak = 1.0 / (1.0 - ta);
+
+                if t < ta {
+                    0.5 * v_peak / ta * t * t
+                } else if t <= 1.0 - ta {
+                    0.5 * v_peak * ta + v_peak * (t - ta)
+                } else {
+                    1.0 - 0.5 * v_peak / ta * (1.0 - t) * (1.0 - t)
+                }
+            }
+            // quintic smoothstep, continuous acceleration so the jerk stays bounded
+            MoveProfile::SCurve => t * t * t * (t * (6.0 * t - 15.0) + 10.0),
+        }
+    }
+}
+
+pub fn smoothed_move(
+    commands : Arc<Mutex<Vec<InterfaceCommand>>>,
+    running : Arc<AtomicBool>,
+    start : f32,
+    target : f32,
+    duration : f32,
+    profile : MoveProfile) {
+
+    let tick = GENERATOR_TICK.as_secs_f32();
+    let mut t = 0.0;
+
+    while running.load(Ordering::Relaxed) && t < duration {
+        let x = start + (target - start) * profile.fraction(t / duration);
+        commands.lock().push(InterfaceCommand::PositionCommand(x));
+
+        std::thread::sleep(GENERATOR_TICK);
+        t += tick;
+    }
+
+    if running.load(Ordering::Relaxed) {
+        commands.lock().push(InterfaceCommand::PositionCommand(target));
+    }
+
+    running.store(false, Ordering::Relaxed);
+}
+
+/// Fastest the setpoints can move without the firmware limiting them: its velocity limit, and its
+/// largest accepted position step, which each tick's change lands in as one jump. Limits that are
+/// zero are unset.
+pub fn firmware_velocity_limit(vel_max_abs : f32, max_pos_step : f32) -> Option<f32> {
+    let step_limit = if max_pos_step > 0.0 { Some(max_pos_step / GENERATOR_TICK.as_secs_f32()) } else { None };
+    let vel_limit = if vel_max_abs > 0.0 { Some(vel_max_abs) } else { None };
+
+    match (vel_limit, step_limit) {
+        (Some(a), Some(b)) => Some(a.min(b)),
+        (a, b) => a.or(b),
+    }
+}
+
+/// Trapezoidal velocity profile from `start` to `target`, accelerating at `accel` up to `max_vel`.
+/// Moves too short to reach `max_vel` become triangular.
+#[derive(Debug, Clone, Copy)]
+pub struct TrapezoidalMove {
+    start : f32,
+    distance : f32,
+    direction : f32,
+    vel : f32,
+    accel : f32,
+    t_accel : f32,
+    t_cruise : f32,
+}
+
+impl TrapezoidalMove {
+    pub fn new(start : f32, target : f32, max_vel : f32, accel : f32) -> TrapezoidalMove {
+        let distance = (target - start).abs();
+        let accel = accel.max(1e-6);
+        let mut vel = max_vel.max(1e-6);
+
+        // not enough room to reach full speed, peak wherever accelerating and decelerating meet
+        if vel * vel / accel > distance {
+            vel = (distance * accel).sqrt();
+        }
+
+        let t_accel = vel / accel;
+        let t_cruise = if vel > 0.0 { (distance - vel * t_accel) / vel } else { 0.0 };
+
+        TrapezoidalMove {
+            start,
+            distance,
+            direction : if target < start { -1.0 } else { 1.0 },
+            vel,
+            accel,
+            t_accel,
+            t_cruise : t_cruise.max(0.0),
+        }
+    }
+
+    pub fn duration(&self) -> f32 {
+        2.0 * self.t_accel + self.t_cruise
+    }
+
+    /// Peak velocity actually reached, lower than asked for on short moves.
+    pub fn peak_velocity(&self) -> f32 {
+        self.vel
+    }
+
+    pub fn position(&self, t : f32) -> f32 {
+        let t = t.max(0.0).min(self.duration());
+
+        let covered = if t < self.t_accel {
+            0.5 * self.accel * t * t
+        } else if t < self.t_accel + self.t_cruise {
+            0.5 * self.vel * self.t_accel + self.vel * (t - self.t_accel)
+        } else {
+            let remaining = self.duration() - t;
+            self.distance - 0.5 * self.accel * remaining * remaining
+        };
+
+        self.start + self.direction * covered
+    }
+}
+
+pub fn trapezoidal_move(
+    commands : Arc<Mutex<Vec<InterfaceCommand>>>,
+    running : Arc<AtomicBool>,
+    profile : TrapezoidalMove,
+    target : f32) {
+
+    let tick = GENERATOR_TICK.as_secs_f32();
+    let duration = profile.duration();
+    let mut t = 0.0;
+
+    while running.load(Ordering::Relaxed) && t < duration {
+        commands.lock().push(InterfaceCommand::PositionCommand(profile.position(t)));
+
+        std::thread::sleep(GENERATOR_TICK);
+        t += tick;
+    }
+
+    if running.load(Ordering::Relaxed) {
+        commands.lock().push(InterfaceCommand::PositionCommand(target));
+    }
+
+    running.store(false, Ordering::Relaxed);
+}
+
+/// Alternates between `+amplitude` and `-amplitude` around `center` every half `period`.
+pub fn square_wave(
+    commands : Arc<Mutex<Vec<InterfaceCommand>>>,
+    running : Arc<AtomicBool>,
+    center : f32,
+    amplitude : f32,
+    period : f32,
+    command : fn(f32) -> InterfaceCommand) {
+
+    let half_period = Duration::from_secs_f32((0.5 * period).max(GENERATOR_TICK.as_secs_f32()));
+    let mut high = true;
+
+    while running.load(Ordering::Relaxed) {
+        let value = if high { center + amplitude } else { center - amplitude };
+        commands.lock().push(command(value));
+
+        high = !high;
+
+        // sleep in ticks so cancelling doesn't wait out a long half period
+        let mut slept = Duration::from_secs(0);
+        while slept < half_period && running.load(Ordering::Relaxed) {
+            std::thread::sleep(GENERATOR_TICK);
+            slept += GENERATOR_TICK;
+        }
+    }
+
+    commands.lock().push(command(center));
+}
+
+/// `center + amplitude * sin(2 pi frequency t)` until cancelled.
+pub fn sine_wave(
+    commands : Arc<Mutex<Vec<InterfaceCommand>>>,
+    running : Arc<AtomicBool>,
+    center : f32,
+    amplitude : f32,
+    frequency : f32) {
+
+    let tick = GENERATOR_TICK.as_secs_f32();
+    let mut t = 0.0;
+
+    while running.load(Ordering::Relaxed) {
+        let x = center + amplitude * (std::f32::consts::TAU * frequency * t).sin();
+        commands.lock().push(InterfaceCommand::PositionCommand(x));
+
+        std::thread::sleep(GENERATOR_TICK);
+        // wrapped to one period so the phase doesn't lose precision on long runs
+        t = (t + tick) % (1.0 / frequency);
+    }
+
+    commands.lock().push(InterfaceCommand::PositionCommand(center));
+}
+
+/// Logarithmic sine sweep from `f_start` to `f_end` Hz over `duration` seconds around `center`.
+pub fn chirp(
+    commands : Arc<Mutex<Vec<InterfaceCommand>>>,
+    running : Arc<AtomicBool>,
+    center : f32,
+    amplitude : f32,
+    f_start : f32,
+    f_end : f32,
+    duration : f32) {
+
+    let tick = GENERATOR_TICK.as_secs_f32();
+    let ratio = f_end / f_start;
+    let mut t = 0.0;
+
+    while running.load(Ordering::Relaxed) && t < duration {
+        // integral of the instantaneous frequency f_start * ratio^(t / duration)
+        let phase = if (ratio - 1.0).abs() < 1e-6 {
+            std::f32::consts::TAU * f_start * t
+        } else {
+            std::f32::consts::TAU * f_start * duration / ratio.ln() * (ratio.powf(t / duration) - 1.0)
+        };
+
+        commands.lock().push(InterfaceCommand::PositionCommand(center + amplitude * phase.sin()));
+
+        std::thread::sleep(GENERATOR_TICK);
+        t += tick;
+    }
+
+    commands.lock().push(InterfaceCommand::PositionCommand(center));
+
+    running.store(false, Ordering::Relaxed);
+}
@@ -1,4 +1,28 @@
+use serde::Deserialize;
+use serde::Serialize;
 
+/// Panel sizes as fractions of the space they're split from, changed by dragging the dividers
+/// between the panels.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct LayoutSettings {
+    /// Width of the side panel, of the window.
+    pub sidepanel : f32,
+    /// Height of the tuning controls and analysis panels, of the window.
+    pub tool_menu : f32,
+    /// Height of the devices panel, of the side panel.
+    pub devices : f32,
+}
+
+impl Default for LayoutSettings {
+    fn default() -> Self {
+        LayoutSettings {
+            sidepanel : 0.3,
+            tool_menu : 0.4,
+            devices : 0.15,
+        }
+    }
+}
 
 #[derive(Clone, Copy, Debug)]
 pub struct LayoutRect {
@@ -21,7 +45,10 @@ impl LayoutRect {
         [self.w as f32, self.h as f32]
     }
 
+    // a split larger than the rect takes all of it, so tiny windows squash panels instead of panicking
     pub fn vertical_split_left_abs(&self, w : u32) -> (LayoutRect, LayoutRect) {
+        let w = w.min(self.w);
+
         (
             LayoutRect {
                 w,
@@ -36,6 +63,8 @@ impl LayoutRect {
     }
     
     pub fn vertical_split_right_abs(&self, w : u32) -> (LayoutRect, LayoutRect) {
+        let w = w.min(self.w);
+
         (
             LayoutRect {
                 w : self.w - w,
@@ -51,6 +80,8 @@ impl LayoutRect {
     
 
     pub fn horizontal_split_top_abs(&self, h : u32) -> (LayoutRect, LayoutRect) {
+        let h = h.min(self.h);
+
         (
             LayoutRect {
                 h,
@@ -65,6 +96,8 @@ impl LayoutRect {
     }
     
     pub fn horizontal_split_bottom_abs(&self, h : u32) -> (LayoutRect, LayoutRect) {
+        let h = h.min(self.h);
+
         (
             LayoutRect {
                 h : self.h - h,
@@ -77,4 +110,145 @@ impl LayoutRect {
             }
         )
     }
-}
\ No newline at end of file
+
+    // fractions outside [0, 1] are clamped too
+    fn frac_of(len : u32, frac : f32) -> u32 {
+        (len as f32 * frac.max(0.0).min(1.0)).round() as u32
+    }
+
+    pub fn vertical_split_left_frac(&self, frac : f32) -> (LayoutRect, LayoutRect) {
+        self.vertical_split_left_abs(Self::frac_of(self.w, frac))
+    }
+
+    // no panel uses it yet, only the tests
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn vertical_split_right_frac(&self, frac : f32) -> (LayoutRect, LayoutRect) {
+        self.vertical_split_right_abs(Self::frac_of(self.w, frac))
+    }
+
+    pub fn horizontal_split_top_frac(&self, frac : f32) -> (LayoutRect, LayoutRect) {
+        self.horizontal_split_top_abs(Self::frac_of(self.h, frac))
+    }
+
+    pub fn horizontal_split_bottom_frac(&self, frac : f32) -> (LayoutRect, LayoutRect) {
+        self.horizontal_split_bottom_abs(Self::frac_of(self.h, frac))
+    }
+
+    /// Tiles the rect into `rows` by `cols` cells, listed row by row. Cell edges are rounded down,
+    /// so the cells always cover the rect exactly.
+    #[cfg_attr(not(test), allow(dead_code))]
+    pub fn grid(&self, rows : u32, cols : u32) -> Vec<LayoutRect> {
+        if rows == 0 || cols == 0 {
+            return vec![];
+        }
+
+        let edge = |len : u32, n : u32, i : u32| (len as u64 * i as u64 / n as u64) as u32;
+
+        (0..rows)
+            .flat_map(|row| (0..cols).map(move |col| {
+                let (x0, x1) = (edge(self.w, cols, col), edge(self.w, cols, col + 1));
+                let (y0, y1) = (edge(self.h, rows, row), edge(self.h, rows, row + 1));
+
+                LayoutRect {
+                    x : self.x + x0,
+                    y : self.y + y0,
+                    w : x1 - x0,
+                    h : y1 - y0,
+                }
+            }))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect() -> LayoutRect {
+        LayoutRect { x : 10, y : 20, w : 300, h : 200 }
+    }
+
+    fn dims(r : LayoutRect) -> (u32, u32, u32, u32) {
+        (r.x, r.y, r.w, r.h)
+    }
+
+    #[test]
+    fn abs_splits_larger_than_the_rect_take_all_of_it() {
+        let (left, right) = rect().vertical_split_left_abs(400);
+        assert_eq!(dims(left), (10, 20, 300, 200));
+        assert_eq!(dims(right), (310, 20, 0, 200));
+
+        let (left, right) = rect().vertical_split_right_abs(400);
+        assert_eq!(dims(left), (10, 20, 0, 200));
+        assert_eq!(dims(right), (10, 20, 300, 200));
+
+        let (top, bottom) = rect().horizontal_split_top_abs(250);
+        assert_eq!(dims(top), (10, 20, 300, 200));
+        assert_eq!(dims(bottom), (10, 220, 300, 0));
+
+        let (top, bottom) = rect().horizontal_split_bottom_abs(250);
+        assert_eq!(dims(top), (10, 20, 300, 0));
+        assert_eq!(dims(bottom), (10, 20, 300, 200));
+    }
+
+    #[test]
+    fn abs_splits_within_the_rect() {
+        let (left, right) = rect().vertical_split_left_abs(100);
+        assert_eq!(dims(left), (10, 20, 100, 200));
+        assert_eq!(dims(right), (110, 20, 200, 200));
+
+        let (top, bottom) = rect().horizontal_split_bottom_abs(50);
+        assert_eq!(dims(top), (10, 20, 300, 150));
+        assert_eq!(dims(bottom), (10, 170, 300, 50));
+    }
+
+    #[test]
+    fn frac_splits_outside_zero_to_one_are_clamped() {
+        let (left, right) = rect().vertical_split_left_frac(1.5);
+        assert_eq!(dims(left), (10, 20, 300, 200));
+        assert_eq!(dims(right), (310, 20, 0, 200));
+
+        let (top, bottom) = rect().horizontal_split_top_frac(-0.5);
+        assert_eq!(dims(top), (10, 20, 300, 0));
+        assert_eq!(dims(bottom), (10, 20, 300, 200));
+
+        let (top, bottom) = rect().horizontal_split_bottom_frac(2.0);
+        assert_eq!(dims(top), (10, 20, 300, 0));
+        assert_eq!(dims(bottom), (10, 20, 300, 200));
+    }
+
+    #[test]
+    fn right_frac_split() {
+        let (left, right) = rect().vertical_split_right_frac(0.25);
+        assert_eq!(dims(left), (10, 20, 225, 200));
+        assert_eq!(dims(right), (235, 20, 75, 200));
+
+        let (left, right) = rect().vertical_split_right_frac(3.0);
+        assert_eq!(dims(left), (10, 20, 0, 200));
+        assert_eq!(dims(right), (10, 20, 300, 200));
+    }
+
+    #[test]
+    fn grid_tiles_the_rect_exactly() {
+        let r = LayoutRect { x : 5, y : 7, w : 100, h : 50 };
+        let cells = r.grid(3, 3);
+
+        assert_eq!(cells.len(), 9);
+        assert_eq!(dims(cells[0]), (5, 7, 33, 16));
+        assert_eq!(dims(cells[4]), (38, 23, 33, 17));
+        assert_eq!(dims(cells[8]), (71, 40, 34, 17));
+
+        // row by row, each row spanning the full width and the rows the full height
+        for row in cells.chunks(3) {
+            assert_eq!(row.iter().map(|c| c.w).sum::<u32>(), r.w);
+            assert_eq!(row[0].x, r.x);
+        }
+        assert_eq!(cells.iter().step_by(3).map(|c| c.h).sum::<u32>(), r.h);
+    }
+
+    #[test]
+    fn empty_grid() {
+        assert!(rect().grid(0, 4).is_empty());
+        assert!(rect().grid(2, 0).is_empty());
+    }
+}
@@ -43,6 +43,7 @@ pub struct LineRenderer {
     pub uniform_pool : CpuBufferPool<line_vs::ty::UniformBlock0>,
     pub vertex_buffers : Vec<Arc<CpuBufferPoolChunk<Vertex, Arc<StdMemoryPool>>>>,
     pub texture_id : Option<TextureId>,
+    pub background : [f32; 4],
 }
 
 impl LineRenderer {
@@ -115,10 +116,11 @@ impl LineRenderer {
             uniform_pool,
             vertex_buffers : vec![],
             texture_id : None,
+            background : [0.05, 0.05, 0.05, 1.0],
         }
     }
 
-    pub fn render(&mut self, _system : &mut System, viewport : &Viewport, cmd_buf_builder : &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>, tmatrix : Matrix4<f32>, width : u32, height : u32) {
+    pub fn render(&mut self, _system : &mut System, viewport : &Viewport, cmd_buf_builder : &mut AutoCommandBufferBuilder<PrimaryAutoCommandBuffer>, tmatrix : Matrix4<f32>, width : u32, height : u32) -> Result<(), String> {
 
         let framebuffer = viewport.create_framebuffer(self.render_pass.clone());
 
@@ -133,10 +135,12 @@ impl LineRenderer {
                 framebuffer, 
                 SubpassContents::Inline, 
                 // vec![1.0.into(), [0.0, 0.0, 0.0, 1.0].into()]
-                vec![1.0.into(), [0.05, 0.05, 0.05, 1.0].into(), ClearValue::None]
-            ).expect("failed to start render pass");
+                vec![1.0.into(), self.background.into(), ClearValue::None]
+            ).map_err(|e| format!("failed to start render pass: {:?}", e))?;
 
-            for vb in self.vertex_buffers.drain(0..) {
+            // the buffers are kept until the next `clear_line_buffer`, so the plot can be redrawn
+            // without regenerating them every frame
+            for vb in self.vertex_buffers.iter() {
 
                 let ds = DynamicState {
                     viewports : Some(vec![vulkano::pipeline::viewport::Viewport {
@@ -153,12 +157,15 @@ impl LineRenderer {
                         matrix : (v_matrix * tmatrix).into(),
                         viewport : [width as f32, height as f32],
                     }
-                ).unwrap();
+                ).map_err(|e| format!("failed to allocate uniforms: {:?}", e))?;
 
+                // the pipeline is built from the line shaders, which always have this set
                 let layout = self.pipeline.layout().descriptor_set_layout(0).unwrap();
                 let desc_set = Arc::new(PersistentDescriptorSet::start(layout.clone())
-                    .add_buffer(uniforms).unwrap()
-                    .build().unwrap()
+                    .add_buffer(uniforms)
+                    .map_err(|e| format!("failed to add uniforms to descriptor set: {:?}", e))?
+                    .build()
+                    .map_err(|e| format!("failed to build descriptor set: {:?}", e))?
                 );
 
                 cmd_buf_builder
@@ -168,13 +175,15 @@ impl LineRenderer {
                         (),
                         vec![]
                     )
-                    .expect("failed to draw line");
+                    .map_err(|e| format!("failed to draw line: {:?}", e))?;
             }
 
             cmd_buf_builder.end_render_pass()
-                .expect("Failed to finish render pass");
+                .map_err(|e| format!("failed to finish render pass: {:?}", e))?;
 
         }
+
+        Ok(())
     }
 
     pub fn draw_line(&mut self, path : &[Vector3<f32>], col : [f32; 4]) {
@@ -186,11 +195,11 @@ impl LineRenderer {
             })
             .collect::<Vec<_>>();
 
-        let new_vb = Arc::new(
-            self.vertex_pool.chunk(path).expect("failed to allocated vertex buffer")
-        );
-
-        self.vertex_buffers.push(new_vb);
+        // the line is left out of the plot, the next redraw tries again
+        match self.vertex_pool.chunk(path) {
+            Ok(new_vb) => self.vertex_buffers.push(Arc::new(new_vb)),
+            Err(e) => eprintln!("failed to allocate vertex buffer: {:?}", e),
+        }
 
     }
 
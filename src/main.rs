@@ -2,11 +2,19 @@
 
 use cgmath::Matrix4;
 use vulkano::image::view::ImageView;
-use winit::event::{Event, WindowEvent};
+use winit::event::{ElementState, Event, KeyboardInput, WindowEvent};
 use winit::event_loop::ControlFlow;
 
 use winit::event_loop::EventLoop;
 
+use std::panic::AssertUnwindSafe;
+use std::time::Duration;
+use std::time::Instant;
+
+// redraw cadence while the window is unfocused or minimized
+const BACKGROUND_FRAME_INTERVAL : Duration = Duration::from_millis(100);
+const MINIMIZED_FRAME_INTERVAL : Duration = Duration::from_millis(500);
+
 macro_rules! im_strf {
     ($($args:tt)*) => {
         &imgui::ImString::from(format!($($args)*))
@@ -21,12 +29,33 @@ struct WindowRect {
 mod clipboard;
 mod gui_renderer;
 mod gui_logic;
+mod frame_profiler;
+mod firmware_loader;
 mod viewport;
 mod line_renderer;
 mod stlink;
 mod controller_commands;
 mod controller_interface;
+mod debug_probe;
+mod config_file;
+mod diagnostics;
+mod edit_history;
 mod layout;
+mod memory_access;
+mod mock_probe;
+mod input_generators;
+mod move_sequence;
+mod rate_limiter;
+mod sample_analysis;
+mod servo_model;
+mod plot_settings;
+mod settings;
+mod status_indicators;
+mod trigger;
+mod anticogging;
+mod swd_adapter;
+mod theme;
+mod tuning_wizard;
 
 fn main() {
 
@@ -46,6 +75,11 @@ fn main() {
 
     let mut viewport = viewport::Viewport::new();
 
+    let mut focused = true;
+    let mut minimized = false;
+    let mut last_redraw = Instant::now();
+    let mut applied_theme = None;
+
     event_loop.run(move |event, _, control_flow| {
 
         match event {
@@ -53,53 +87,136 @@ fn main() {
                 // gui_ctx.io_mut().update_delta_time(Instant::now());
             }
             Event::MainEventsCleared => {
-                system.platform
-                    .prepare_frame(gui_ctx.io_mut(), &system.surface.window())
-                    .expect("Failed to prepare frame");
-                system.surface.window().request_redraw();
+                let frame_interval = if minimized {
+                    Some(MINIMIZED_FRAME_INTERVAL)
+                } else if !focused {
+                    Some(BACKGROUND_FRAME_INTERVAL)
+                } else {
+                    None
+                };
+
+                match frame_interval {
+                    Some(interval) if last_redraw.elapsed() < interval => {
+                        *control_flow = ControlFlow::WaitUntil(last_redraw + interval);
+                    }
+                    _ => {
+                        *control_flow = ControlFlow::Poll;
+                        last_redraw = Instant::now();
+
+                        // nothing to see while minimized, so only keep the event loop ticking
+                        if !minimized {
+                            if let Err(e) = system.platform.prepare_frame(gui_ctx.io_mut(), &system.surface.window()) {
+                                eprintln!("Failed to prepare frame: {:?}", e);
+                            }
+                            system.surface.window().request_redraw();
+                        }
+                    }
+                }
             }
             Event::RedrawRequested(_) => {
+                let frame_start = Instant::now();
 
                 if let Ok((mut cmd_buf_builder, swapchain_image, image_num)) = system.start_frame() {
 
+                    // the style can only be changed between frames
+                    let theme = gui_state.theme();
+                    if applied_theme != Some(theme) {
+                        theme.apply(gui_ctx.style_mut());
+                        line_renderer.background = theme.plot_background();
+                        applied_theme = Some(theme);
+                    }
 
                     let mut ui = gui_ctx.frame();
 
-                    let run = true;
-
-                    gui_state.frame(&mut system, &mut ui, &mut async_runtime, &mut viewport, &mut line_renderer);
+                    let gui_start = Instant::now();
+                    // a panic while building the GUI loses what was left of this frame, not the session
+                    let built = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                        gui_state.frame(&mut system, &mut ui, &mut async_runtime, &mut viewport, &mut line_renderer);
+                    }));
+                    let gui_time = gui_start.elapsed();
+
+                    if built.is_err() {
+                        eprintln!("Building the frame panicked, the rest of it was skipped");
+
+                        // closes the windows and pops the style changes the panic unwound past, so the
+                        // frame can still end normally
+                        unsafe {
+                            imgui::sys::igErrorCheckEndFrameRecover(None, std::ptr::null_mut());
+                        }
+                    }
 
+                    let run = !gui_state.quit_requested();
 
-                    if !run {
+                    // a failed config export keeps the window open to say so
+                    if !run && gui_state.on_exit() {
                         *control_flow = ControlFlow::Exit;
                     }
                     
                     system.platform.prepare_render(&ui, system.surface.window());
                     let draw_data = ui.render();
 
-                    if let Some(viewport_image) = viewport.image.clone() {
-                        cmd_buf_builder.clear_color_image(viewport_image, [0.1; 4].into()).unwrap();
-
-                        line_renderer.render(&mut system, &viewport, &mut cmd_buf_builder, Matrix4::from_nonuniform_scale(1.0, viewport.height as f32 / viewport.width as f32, 1.0), viewport.width, viewport.height)
+                    let rendered = std::panic::catch_unwind(AssertUnwindSafe(|| {
+                        // a failed draw only loses this frame, the command buffer is still submitted
+                        // so the swapchain image is given back
+                        if let Some(viewport_image) = viewport.image.clone() {
+                            match cmd_buf_builder.clear_color_image(viewport_image, [0.1; 4].into()) {
+                                Ok(_) => {
+                                    if let Err(e) = line_renderer.render(&mut system, &viewport, &mut cmd_buf_builder, Matrix4::from_nonuniform_scale(1.0, viewport.height as f32 / viewport.width as f32, 1.0), viewport.width, viewport.height) {
+                                        eprintln!("Failed to draw the plot: {}", e);
+                                    }
+                                }
+                                Err(e) => eprintln!("Failed to clear the plot: {:?}", e),
+                            }
+                        }
+
+                        if let Err(e) = cmd_buf_builder.clear_color_image(swapchain_image.clone(), [0.0; 4].into()) {
+                            eprintln!("Failed to create image clear command: {:?}", e);
+                        }
+
+                        match ImageView::new(swapchain_image.clone()) {
+                            Ok(view) => {
+                                if let Err(e) = system.renderer.draw_commands(&mut cmd_buf_builder, system.queue.clone(), view, draw_data) {
+                                    eprintln!("Rendering failed: {:?}", e);
+                                }
+                            }
+                            Err(e) => eprintln!("Failed to create swapchain image view: {:?}", e),
+                        }
+
+                        // viewport.update(&mut system, ui_state.viewport_dims[0] as u32, ui_state.viewport_dims[1] as u32);
+
+                        system.end_frame(cmd_buf_builder, image_num);
+                    }));
+
+                    if rendered.is_err() {
+                        eprintln!("Rendering panicked, the frame was skipped");
+                        system.abandon_frame();
                     }
 
-                    cmd_buf_builder.clear_color_image(swapchain_image.clone(), [0.0; 4].into())
-                        .expect("Failed to create image clear command");
-
-                    system.renderer
-                        .draw_commands(&mut cmd_buf_builder, system.queue.clone(), ImageView::new(swapchain_image.clone()).unwrap(), draw_data)
-                        .expect("Rendering failed");
-
-                    // viewport.update(&mut system, ui_state.viewport_dims[0] as u32, ui_state.viewport_dims[1] as u32);
-
-                    system.end_frame(cmd_buf_builder, image_num);
+                    gui_state.record_frame_timing(frame_start.elapsed(), gui_time);
                 }
             }
             Event::WindowEvent {
                 event: WindowEvent::CloseRequested,
                 ..
-            } => *control_flow = ControlFlow::Exit,
+            } => {
+                if gui_state.on_exit() {
+                    *control_flow = ControlFlow::Exit
+                }
+            },
             event => {
+                if let Event::WindowEvent { event : window_event, .. } = &event {
+                    match window_event {
+                        WindowEvent::Focused(f) => focused = *f,
+                        WindowEvent::Resized(size) => minimized = size.width == 0 || size.height == 0,
+                        WindowEvent::KeyboardInput { input : KeyboardInput { state : ElementState::Pressed, virtual_keycode : Some(key), .. }, .. } => {
+                            gui_state.handle_key(*key, gui_ctx.io().want_text_input);
+                        },
+                        _ => {}
+                    }
+
+                    gui_state.set_backgrounded(minimized || !focused);
+                }
+
                 system.platform.handle_event(gui_ctx.io_mut(), system.surface.window(), &event);
             }
         }
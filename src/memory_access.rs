@@ -0,0 +1,184 @@
+use core::mem::size_of;
+
+/// Largest single word-aligned transfer, matching what the ST-Link accepts.
+pub const MAXIMUM_TRANSFER_SIZE : usize = 1024;
+
+// Interprets `buffer` as `count` packed items. A short read is zero padded and any excess is
+// ignored, so a partial transfer never leaves stale bytes in the result.
+pub fn items_from_bytes<T>(mut buffer : Vec<u8>, count : usize) -> Vec<T> {
+
+    buffer.resize(size_of::<T>() * count, 0);
+
+    buffer.chunks_exact(size_of::<T>().max(1))
+        .take(count)
+        .map(|chunk| unsafe { core::ptr::read_unaligned(chunk.as_ptr() as *const T) })
+        .collect()
+}
+
+fn word_align(n : usize) -> usize {
+    (n + 3) & !3
+}
+
+/// Word-aligned access to target memory. Implementors only provide single transfers of at most
+/// `MAXIMUM_TRANSFER_SIZE` bytes; the struct helpers split larger accesses into chunks. Any USB
+/// error aborts the whole access.
+pub trait MemoryAccess {
+    /// Reads `size` bytes at `addr`. May return fewer bytes if the transfer came back short.
+    fn get_mem32(&mut self, addr : u32, size : u32) -> Result<Vec<u8>, rusb::Error>;
+
+    fn set_mem32(&mut self, addr : u32, data : &[u8]) -> Result<(), rusb::Error>;
+
+    fn read_struct<T : Clone>(&mut self, addr : u32) -> Result<T, rusb::Error> where Self : Sized {
+        Ok(self.read_struct_array_with_offset::<T>(addr, 1, 0)?.remove(0))
+    }
+
+    fn read_struct_array<T : Clone>(&mut self, addr : u32, len : u32) -> Result<Vec<T>, rusb::Error> where Self : Sized {
+        self.read_struct_array_with_offset::<T>(addr, len, 0)
+    }
+
+    fn read_struct_array_with_offset<T : Clone>(&mut self, addr : u32, len : u32, offset : u32) -> Result<Vec<T>, rusb::Error> where Self : Sized {
+
+        let count = len as usize;
+        let size = size_of::<T>() * count;
+        let addr = addr + size_of::<T>() as u32 * offset;
+        let mut buffer = Vec::with_capacity(size);
+
+        let chunks = (size + MAXIMUM_TRANSFER_SIZE - 1) / MAXIMUM_TRANSFER_SIZE;
+
+        for chunk in 0..chunks {
+            let start = chunk * MAXIMUM_TRANSFER_SIZE;
+            let n = (size - start).min(MAXIMUM_TRANSFER_SIZE);
+
+            // transfers are whole words, a struct that doesn't end on one reads a little past it
+            let mut data = self.get_mem32(addr + start as u32, word_align(n) as u32)?;
+
+            // keep later chunks at their proper offset if this one came back short, and drop the padding
+            data.resize(n, 0);
+
+            buffer.extend(data.into_iter());
+        }
+
+        Ok(items_from_bytes::<T>(buffer, count))
+    }
+
+    fn write_struct<T>(&mut self, addr : u32, item : T) -> Result<(), rusb::Error> where Self : Sized {
+        self.write_struct_array_offset(addr, 0, &[item])
+    }
+
+    fn write_struct_array<T>(&mut self, addr : u32, items : &[T]) -> Result<(), rusb::Error> where Self : Sized {
+        self.write_struct_array_offset(addr, 0, items)
+    }
+
+    fn write_struct_array_offset<T>(&mut self, addr : u32, offset : u32, items : &[T]) -> Result<(), rusb::Error> where Self : Sized {
+
+        let (_, buffer, _) = unsafe{items.align_to::<u8>()};
+        let addr = addr + offset * size_of::<T>() as u32;
+
+        // an exact multiple of the transfer size ends on a full chunk, with no empty write after it
+        let chunks = (buffer.len() + MAXIMUM_TRANSFER_SIZE - 1) / MAXIMUM_TRANSFER_SIZE;
+
+        for chunk in 0..chunks {
+            let start = chunk * MAXIMUM_TRANSFER_SIZE;
+            let end = (start + MAXIMUM_TRANSFER_SIZE).min(buffer.len());
+            let data = &buffer[start..end];
+            let whole_words = data.len() & !3;
+
+            if whole_words > 0 {
+                self.set_mem32(addr + start as u32, &data[..whole_words])?;
+            }
+
+            // transfers are whole words, so the bytes past the end of the struct are written back unchanged
+            if whole_words < data.len() {
+                let tail_addr = addr + (start + whole_words) as u32;
+                let mut word = self.get_mem32(tail_addr, 4)?;
+                word.resize(4, 0);
+                word[..(data.len() - whole_words)].copy_from_slice(&data[whole_words..]);
+
+                self.set_mem32(tail_addr, &word)?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mock_probe::MockStlink;
+
+    const ADDR : u32 = 0x2000_A000;
+    const GUARD : [u8; 4] = [0xAA; 4];
+
+    fn pattern<const N : usize>() -> [u8; N] {
+        let mut data = [0u8; N];
+        for (i, b) in data.iter_mut().enumerate() {
+            *b = (i * 7 + 3) as u8;
+        }
+        data
+    }
+
+    // writes and reads back `N` bytes, with a guard word after them that has to survive
+    fn round_trip<const N : usize>() -> MockStlink {
+        let mut mem = MockStlink::paused();
+        mem.poke(ADDR + N as u32, &GUARD);
+
+        let data = pattern::<N>();
+        mem.write_struct(ADDR, data).unwrap();
+
+        assert_eq!(mem.peek(ADDR, N as u32), data.to_vec());
+        assert_eq!(mem.peek(ADDR + N as u32, 4), GUARD.to_vec());
+
+        mem.reads.clear();
+        let read = mem.read_struct::<[u8; N]>(ADDR).unwrap();
+        assert_eq!(read.to_vec(), data.to_vec());
+
+        mem
+    }
+
+    #[test]
+    fn read_write_1023_bytes() {
+        let mem = round_trip::<1023>();
+        assert_eq!(mem.reads, vec![(ADDR, 1024)]);
+    }
+
+    #[test]
+    fn read_write_1024_bytes() {
+        let mem = round_trip::<1024>();
+        assert_eq!(mem.reads, vec![(ADDR, 1024)]);
+        assert_eq!(mem.writes, vec![(ADDR, 1024)]);
+    }
+
+    #[test]
+    fn read_write_1025_bytes() {
+        let mem = round_trip::<1025>();
+        assert_eq!(mem.reads, vec![(ADDR, 1024), (ADDR + 1024, 4)]);
+        assert_eq!(mem.writes, vec![(ADDR, 1024), (ADDR + 1024, 4)]);
+    }
+
+    #[test]
+    fn read_write_2048_bytes() {
+        let mem = round_trip::<2048>();
+        assert_eq!(mem.reads, vec![(ADDR, 1024), (ADDR + 1024, 1024)]);
+        assert_eq!(mem.writes, vec![(ADDR, 1024), (ADDR + 1024, 1024)]);
+    }
+
+    #[test]
+    fn unaligned_access_is_an_error() {
+        let mut mem = MockStlink::paused();
+
+        assert_eq!(mem.read_struct::<u32>(ADDR + 2).err(), Some(rusb::Error::InvalidParam));
+        assert_eq!(mem.write_struct(ADDR + 2, 0u32).err(), Some(rusb::Error::InvalidParam));
+        assert!(mem.writes.is_empty());
+    }
+
+    #[test]
+    fn read_array_with_offset() {
+        let mut mem = MockStlink::paused();
+        let values = (0..600).map(|i| i as f32).collect::<Vec<_>>();
+        mem.write_struct_array(ADDR, &values).unwrap();
+
+        let read = mem.read_struct_array_with_offset::<f32>(ADDR, 300, 250).unwrap();
+        assert_eq!(read, values[250..550].to_vec());
+    }
+}
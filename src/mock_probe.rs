@@ -0,0 +1,622 @@
+use std::mem::size_of;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::controller_commands::Command;
+use crate::controller_commands::CommandBufferInfo;
+use crate::controller_commands::COMMAND_VARIANTS;
+use crate::controller_interface::*;
+use crate::debug_probe::DebugProbe;
+use crate::firmware_loader::DHCSR;
+use crate::firmware_loader::DHCSR_C_DEBUGEN;
+use crate::firmware_loader::DHCSR_C_HALT;
+use crate::firmware_loader::DHCSR_KEY;
+use crate::firmware_loader::DHCSR_S_HALT;
+use crate::memory_access::items_from_bytes;
+use crate::memory_access::MemoryAccess;
+use crate::memory_access::MAXIMUM_TRANSFER_SIZE;
+use crate::stlink::LinkTestResult;
+use crate::stlink::NrstMode;
+use crate::stlink::STLinkFirmwareVersion;
+use crate::stlink::StlinkDebugApiv2SwdFreq;
+use crate::stlink::StlinkMode;
+use crate::stlink::UsbErrorCounters;
+
+const RAM_BASE : u32 = 0x2000_0000;
+const RAM_SIZE : usize = 0x1_0000;
+
+// the firmware's layout is up to the linker, this one just keeps everything apart
+const POINTERS_ADDR             : u32 = 0x2000_0010;
+const COMMAND_BUFFER_ADDR       : u32 = 0x2000_0100;
+const COMMAND_DATA_ADDR         : u32 = 0x2000_0110;
+const OSCILLOSCOPE_ADDR         : u32 = 0x2000_0300;
+const SERVO_CONFIG_ADDR         : u32 = 0x2000_0500;
+const SERVO_STATE_ADDR          : u32 = 0x2000_0E00;
+const OSCILLOSCOPE_DATA_ADDR    : u32 = 0x2000_1000;
+const STAGED_CONFIG_ADDR        : u32 = 0x2000_9000;
+
+const COMMAND_CAPACITY : u32 = 32;
+const OSCILLOSCOPE_LEN : u32 = 1000;
+
+const CONTROL_LOOP_HZ : f32 = 10_000.0;
+// a poll that stalls for longer than this doesn't get simulated all at once
+const MAX_CATCH_UP : Duration = Duration::from_millis(100);
+
+// coasts a disabled motor to a stop in about a second
+const VISCOUS_FRICTION : f32 = 1e-4;
+// distance to the simulated hard stops found by the homing commands
+const TRAVEL : f32 = 1.0;
+
+fn default_config() -> ServoConfig {
+    ServoConfig {
+        position_gain : 50.0,
+        velocity_gain : 0.02,
+        velocity_integrator_gain : 1.0,
+        velocity_integrator_max_abs : 0.5,
+        index_scan_speed : 1.0,
+        steps_per_turn : 3200.0,
+        vel_max_abs : 20.0,
+        tor_max_abs : 1.0,
+        max_pos_step : 0.0,
+        input_filt_kp : 0.0,
+        input_filt_ki : 0.0,
+        inertia : 1e-4,
+        torque_bandwidth : 1000.0,
+        vel_pllki : 0.0,
+        antcogging_torque : [0.0; ANTICOGGING_TABLE_LEN],
+    }
+}
+
+fn is_position_mode(state : &ServoControlState) -> bool {
+    match state {
+        ServoControlState::EnabledStepDirection |
+        ServoControlState::EnabledPositionFilter |
+        ServoControlState::EnabledPid |
+        ServoControlState::EnabledPiv => true,
+        _ => false,
+    }
+}
+
+/// A probe attached to a simulated controller, for working on the tool without hardware. Target
+/// RAM holds the same structs the firmware exports, and a rigid inertia under the cascaded
+/// position/velocity loop is stepped in real time whenever memory is accessed, so commands give
+/// plausible step and sine responses in the oscilloscope. Reads outside RAM return zeros and
+/// writes there are ignored.
+pub struct MockStlink {
+    memory : Vec<u8>,
+    connected : bool,
+    mode : StlinkMode,
+    swd_freq : Option<StlinkDebugApiv2SwdFreq>,
+    usb_errors : UsbErrorCounters,
+    usb_timeout : Duration,
+    last_step : Instant,
+    /// Mode entered by the next `MotorStart`.
+    control_mode : ServoControlState,
+    integrator : f32,
+    torque : f32,
+    cycle : u32,
+    heartbeat : u32,
+    /// Stands in for the config in flash.
+    saved_config : ServoConfig,
+    /// Cleared by tests so nothing but the host changes memory.
+    simulating : bool,
+    /// Set through DHCSR, the firmware doesn't run until the next reset.
+    halted : bool,
+    /// `(addr, size)` of every read and write, so tests can check how accesses are chunked.
+    #[cfg(test)]
+    pub reads : Vec<(u32, u32)>,
+    #[cfg(test)]
+    pub writes : Vec<(u32, u32)>,
+}
+
+impl Default for MockStlink {
+    fn default() -> Self {
+        MockStlink::new()
+    }
+}
+
+impl MockStlink {
+    pub fn new() -> MockStlink {
+        let mut mock = MockStlink {
+            memory : vec![0; RAM_SIZE],
+            connected : false,
+            mode : StlinkMode::Mass,
+            swd_freq : None,
+            usb_errors : UsbErrorCounters::default(),
+            usb_timeout : Duration::from_millis(200),
+            last_step : Instant::now(),
+            control_mode : ServoControlState::EnabledPiv,
+            integrator : 0.0,
+            torque : 0.0,
+            cycle : 0,
+            heartbeat : 0,
+            saved_config : default_config(),
+            simulating : true,
+            halted : false,
+            #[cfg(test)]
+            reads : vec![],
+            #[cfg(test)]
+            writes : vec![],
+        };
+
+        mock.boot();
+        mock
+    }
+
+    // what the firmware sets up on reset, the motor disabled and the saved config loaded
+    fn boot(&mut self) {
+        for b in self.memory.iter_mut() {
+            *b = 0;
+        }
+
+        let pointers = ControllerPointers {
+            magic : MAGIC_EXTENDED,
+            ready : true,
+            servo_config_addr : SERVO_CONFIG_ADDR,
+            servo_state_addr : SERVO_STATE_ADDR,
+            oscilloscope_addr : OSCILLOSCOPE_ADDR,
+            oscilloscope_data_addr : OSCILLOSCOPE_DATA_ADDR,
+            command_buffer_addr : COMMAND_BUFFER_ADDR,
+        };
+
+        let extensions = PointerExtensions {
+            version : 6,
+            staged_config_addr : STAGED_CONFIG_ADDR,
+            heartbeat : self.heartbeat,
+            servo_config_size : size_of::<ServoConfig>() as u32,
+            servo_state_size : size_of::<ServoState>() as u32,
+            sample_point_size : size_of::<OscilloscopeSamplePoint>() as u32,
+            command_count : COMMAND_VARIANTS.len() as u32,
+        };
+
+        self.store(CONFIG_ADDR_ADDR, &POINTERS_ADDR);
+        self.store(POINTERS_ADDR, &pointers);
+        self.store(ControllerPointers::extensions_addr(POINTERS_ADDR), &extensions);
+
+        self.store(COMMAND_BUFFER_ADDR, &CommandBufferInfo {
+            front : 0,
+            back : 0,
+            capacity : COMMAND_CAPACITY,
+            data_addr : COMMAND_DATA_ADDR,
+        });
+
+        self.store(OSCILLOSCOPE_ADDR, &Oscilloscope {
+            recording : false,
+            index : 0,
+            interval : 10,
+            len : OSCILLOSCOPE_LEN,
+        });
+
+        let config = self.saved_config.clone();
+        self.store(SERVO_CONFIG_ADDR, &config);
+
+        self.store(SERVO_STATE_ADDR, &ServoState {
+            state : ServoControlState::Disabled,
+            ..ServoState::default()
+        });
+
+        self.control_mode = ServoControlState::EnabledPiv;
+        self.integrator = 0.0;
+        self.torque = 0.0;
+        self.cycle = 0;
+        self.halted = false;
+        self.last_step = Instant::now();
+    }
+
+    /// Connected, with the simulated firmware frozen so memory only changes when the host writes it.
+    #[cfg(test)]
+    pub fn paused() -> MockStlink {
+        let mut mock = MockStlink::new();
+        mock.connected = true;
+        mock.simulating = false;
+        mock
+    }
+
+    /// Puts bytes straight into target memory, at any alignment.
+    #[cfg(test)]
+    pub fn poke(&mut self, addr : u32, data : &[u8]) {
+        self.write_bytes(addr, data);
+    }
+
+    #[cfg(test)]
+    pub fn peek(&self, addr : u32, len : u32) -> Vec<u8> {
+        self.bytes(addr, len)
+    }
+
+    fn byte(&self, addr : u32) -> u8 {
+        addr.checked_sub(RAM_BASE)
+            .and_then(|offset| self.memory.get(offset as usize))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    fn bytes(&self, addr : u32, len : u32) -> Vec<u8> {
+        (0..len).map(|i| self.byte(addr.wrapping_add(i))).collect()
+    }
+
+    fn write_bytes(&mut self, addr : u32, data : &[u8]) {
+        for (i, b) in data.iter().enumerate() {
+            let offset = addr.wrapping_add(i as u32).wrapping_sub(RAM_BASE) as usize;
+
+            if let Some(slot) = self.memory.get_mut(offset) {
+                *slot = *b;
+            }
+        }
+    }
+
+    fn load<T : Clone>(&self, addr : u32) -> T {
+        items_from_bytes::<T>(self.bytes(addr, size_of::<T>() as u32), 1).remove(0)
+    }
+
+    fn store<T>(&mut self, addr : u32, item : &T) {
+        let bytes = unsafe { std::slice::from_raw_parts(item as *const T as *const u8, size_of::<T>()) };
+        self.write_bytes(addr, bytes);
+    }
+
+    // runs the control cycles that would have happened since the last access
+    fn advance(&mut self) {
+        if !self.simulating || self.halted {
+            return;
+        }
+
+        let elapsed = self.last_step.elapsed();
+        let cycles = (elapsed.min(MAX_CATCH_UP).as_secs_f32() * CONTROL_LOOP_HZ) as u32;
+
+        if cycles == 0 {
+            return;
+        }
+
+        // keep the leftover fraction of a cycle unless time was dropped
+        self.last_step = if elapsed > MAX_CATCH_UP {
+            Instant::now()
+        } else {
+            self.last_step + Duration::from_secs_f32(cycles as f32 / CONTROL_LOOP_HZ)
+        };
+
+        let mut state = self.load::<ServoState>(SERVO_STATE_ADDR);
+        self.process_commands(&mut state);
+
+        let config = self.load::<ServoConfig>(SERVO_CONFIG_ADDR);
+        let mut osc = self.load::<Oscilloscope>(OSCILLOSCOPE_ADDR);
+
+        for _ in 0..cycles {
+            self.control_cycle(&config, &mut state, 1.0 / CONTROL_LOOP_HZ);
+
+            self.cycle = self.cycle.wrapping_add(1);
+
+            if osc.recording && osc.len > 0 && self.cycle % osc.interval.max(1) == 0 {
+                let sample = OscilloscopeSamplePoint {
+                    pos : state.position,
+                    vel : state.velocity,
+                    acc : state.accel,
+                    pos_setpoint : state.pos_setpoint,
+                    vel_setpoint : state.vel_setpoint,
+                    tor_setpoint : state.tor_setpoint,
+                    pos_input : state.pos_input,
+                    vel_input : state.vel_input,
+                };
+
+                let index = osc.index.min(osc.len - 1);
+                self.store(OSCILLOSCOPE_DATA_ADDR + index * size_of::<OscilloscopeSamplePoint>() as u32, &sample);
+                osc.index = (index + 1) % osc.len;
+            }
+        }
+
+        self.heartbeat = self.heartbeat.wrapping_add(cycles);
+
+        self.store(SERVO_STATE_ADDR, &state);
+        self.store(OSCILLOSCOPE_ADDR, &osc);
+        let heartbeat = self.heartbeat;
+        self.store(ControllerPointers::extensions_addr(POINTERS_ADDR) + HEARTBEAT_OFFSET, &heartbeat);
+    }
+
+    fn process_commands(&mut self, state : &mut ServoState) {
+        let mut info = self.load::<CommandBufferInfo>(COMMAND_BUFFER_ADDR);
+
+        if info.capacity == 0 {
+            return;
+        }
+
+        while info.front != info.back {
+            let addr = info.data_addr + info.front * size_of::<Command>() as u32;
+
+            // anything the host wrote that isn't a valid variant is skipped, as the firmware would
+            if (self.load::<u32>(addr) as usize) < COMMAND_VARIANTS.len() {
+                let cmd = self.load::<Command>(addr);
+                self.apply_command(cmd, state);
+            }
+
+            info.front = (info.front + 1) % info.capacity;
+        }
+
+        self.store(COMMAND_BUFFER_ADDR, &info.front);
+    }
+
+    // motor starts and mode changes pick up from where the motor is, so nothing jumps
+    fn enter_mode(&mut self, mode : ServoControlState, state : &mut ServoState) {
+        state.state = mode;
+        state.aligned = true;
+        state.pos_input = state.position;
+        state.pos_setpoint = state.position;
+        state.vel_input = 0.0;
+        state.tor_input = 0.0;
+        self.integrator = 0.0;
+    }
+
+    fn apply_command(&mut self, cmd : Command, state : &mut ServoState) {
+        let mode = match cmd {
+            Command::SetStepDirectionControl => Some(ServoControlState::EnabledStepDirection),
+            Command::SetPositionControl => Some(ServoControlState::EnabledPiv),
+            Command::SetVelocityControl => Some(ServoControlState::EnabledVelocity),
+            Command::SetTorqueControl => Some(ServoControlState::EnabledTorque),
+            _ => None,
+        };
+
+        if let Some(mode) = mode {
+            self.control_mode = mode.clone();
+
+            if state.state.is_motor_active() {
+                self.enter_mode(mode, state);
+            }
+            return;
+        }
+
+        match cmd {
+            Command::MotorStop => state.state = ServoControlState::Disabled,
+            Command::MotorStart => self.enter_mode(self.control_mode.clone(), state),
+            Command::PositionCommand{position} => state.pos_input = position,
+            Command::VelocityCommand{velocity} => state.vel_input = velocity,
+            Command::TorqueCommand{torque} => state.tor_input = torque,
+            Command::FindUpperMotionLimit => state.pos_input = TRAVEL,
+            Command::FindLowerMotionLimit => state.pos_input = -TRAVEL,
+            Command::LoadServoConfig => {
+                let config = self.saved_config.clone();
+                self.store(SERVO_CONFIG_ADDR, &config);
+            },
+            Command::SaveServoConfig => self.saved_config = self.load::<ServoConfig>(SERVO_CONFIG_ADDR),
+            Command::ApplyStagedConfig => {
+                let config = self.load::<ServoConfig>(STAGED_CONFIG_ADDR);
+                self.store(SERVO_CONFIG_ADDR, &config);
+            },
+            Command::StartAnticoggingCalibration => state.anticogging_calibrated = true,
+            _ => {},
+        }
+    }
+
+    fn control_cycle(&mut self, config : &ServoConfig, state : &mut ServoState, dt : f32) {
+        // unset limits in the config mean unlimited
+        let limit = |value : f32, max : f32| if max > 0.0 { value.max(-max).min(max) } else { value };
+
+        let torque_cmd = if state.state == ServoControlState::EnabledTorque {
+            state.tor_input
+        } else if is_position_mode(&state.state) || state.state == ServoControlState::EnabledVelocity {
+            if is_position_mode(&state.state) {
+                state.pos_setpoint += limit(state.pos_input - state.pos_setpoint, config.max_pos_step);
+                state.vel_setpoint = limit(config.position_gain * (state.pos_setpoint - state.position), config.vel_max_abs);
+            } else {
+                state.pos_setpoint = state.position;
+                state.vel_setpoint = limit(state.vel_input, config.vel_max_abs);
+            }
+
+            let vel_err = state.vel_setpoint - state.velocity;
+            self.integrator = limit(self.integrator + config.velocity_integrator_gain * vel_err * dt, config.velocity_integrator_max_abs);
+
+            config.velocity_gain * vel_err + self.integrator
+        } else {
+            state.pos_setpoint = state.position;
+            state.vel_setpoint = 0.0;
+            0.0
+        };
+
+        state.tor_setpoint = limit(torque_cmd, config.tor_max_abs);
+
+        let torque_alpha = if config.torque_bandwidth > 0.0 { (std::f32::consts::TAU * config.torque_bandwidth * dt).min(1.0) } else { 1.0 };
+        self.torque += (state.tor_setpoint - self.torque) * torque_alpha;
+
+        state.accel = (self.torque - VISCOUS_FRICTION * state.velocity) / config.inertia.max(1e-9);
+        state.velocity += state.accel * dt;
+        state.position += state.velocity * dt;
+        state.raw_position = state.position;
+        state.max_vel_abs_obs = state.max_vel_abs_obs.max(state.velocity.abs());
+    }
+
+    fn check_connected(&self) -> Result<(), rusb::Error> {
+        if self.connected {
+            Ok(())
+        } else {
+            Err(rusb::Error::NoDevice)
+        }
+    }
+}
+
+impl MemoryAccess for MockStlink {
+    fn get_mem32(&mut self, addr : u32, size : u32) -> Result<Vec<u8>, rusb::Error> {
+        if addr % 4 != 0 || size % 4 != 0 || size > MAXIMUM_TRANSFER_SIZE as u32 {
+            return Err(rusb::Error::InvalidParam);
+        }
+
+        self.check_connected()?;
+        self.advance();
+
+        #[cfg(test)]
+        self.reads.push((addr, size));
+
+        if addr == DHCSR && size == 4 {
+            let status = if self.halted { DHCSR_S_HALT | DHCSR_C_HALT | DHCSR_C_DEBUGEN } else { 0 };
+            return Ok(status.to_le_bytes().to_vec());
+        }
+
+        Ok(self.bytes(addr, size))
+    }
+
+    fn set_mem32(&mut self, addr : u32, data : &[u8]) -> Result<(), rusb::Error> {
+        if addr % 4 != 0 || data.len() % 4 != 0 || data.len() > MAXIMUM_TRANSFER_SIZE {
+            return Err(rusb::Error::InvalidParam);
+        }
+
+        self.check_connected()?;
+        self.advance();
+
+        #[cfg(test)]
+        self.writes.push((addr, data.len() as u32));
+
+        if addr == DHCSR && data.len() == 4 {
+            let value = u32::from_le_bytes([data[0], data[1], data[2], data[3]]);
+            if value & 0xFFFF_0000 == DHCSR_KEY {
+                self.halted = value & DHCSR_C_HALT != 0;
+            }
+            return Ok(());
+        }
+
+        self.write_bytes(addr, data);
+        Ok(())
+    }
+}
+
+impl DebugProbe for MockStlink {
+    fn name(&self) -> String {
+        "Simulated".to_string()
+    }
+
+    fn usb_location(&self) -> Option<(u8, u8)> {
+        None
+    }
+
+    fn serial_number(&self) -> Option<String> {
+        None
+    }
+
+    fn firmware_version(&self) -> Option<STLinkFirmwareVersion> {
+        None
+    }
+
+    fn connect(&mut self) -> Result<(), rusb::Error> {
+        self.connected = true;
+        Ok(())
+    }
+
+    fn init(&mut self) -> Result<(), rusb::Error> {
+        Ok(())
+    }
+
+    fn disconnect(&mut self) {
+        self.connected = false;
+    }
+
+    fn is_connected(&self) -> bool {
+        self.connected
+    }
+
+    fn usb_timeout(&self) -> Duration {
+        self.usb_timeout
+    }
+
+    fn set_usb_timeout(&mut self, timeout : Duration) {
+        self.usb_timeout = timeout;
+    }
+
+    fn usb_errors(&self) -> &UsbErrorCounters {
+        &self.usb_errors
+    }
+
+    fn usb_errors_mut(&mut self) -> &mut UsbErrorCounters {
+        &mut self.usb_errors
+    }
+
+    fn enter_debug_swd(&mut self) -> Result<(), rusb::Error> {
+        self.check_connected()?;
+        self.mode = StlinkMode::Debug;
+        Ok(())
+    }
+
+    fn exit_debug(&mut self) -> Result<(), rusb::Error> {
+        self.check_connected()?;
+        self.mode = StlinkMode::Mass;
+        Ok(())
+    }
+
+    fn debug_resetsys(&mut self) -> Result<(), rusb::Error> {
+        self.check_connected()?;
+        self.boot();
+        Ok(())
+    }
+
+    fn drive_nrst(&mut self, mode : NrstMode) -> Result<(), rusb::Error> {
+        self.check_connected()?;
+
+        // held low the target just stays in reset, which isn't simulated
+        if mode != NrstMode::Low {
+            self.boot();
+        }
+        Ok(())
+    }
+
+    fn get_current_mode(&mut self) -> Result<Option<StlinkMode>, rusb::Error> {
+        self.check_connected()?;
+        Ok(Some(self.mode))
+    }
+
+    fn get_target_voltage(&mut self) -> Result<f32, rusb::Error> {
+        self.check_connected()?;
+        Ok(3.3)
+    }
+
+    fn get_last_rw_status(&mut self) -> Result<bool, rusb::Error> {
+        self.check_connected()?;
+        Ok(true)
+    }
+
+    fn read_core_id(&mut self) -> Result<u32, rusb::Error> {
+        self.check_connected()?;
+        Ok(0x1BA0_1477)
+    }
+
+    fn read_idcodes(&mut self) -> Result<[u32; 2], rusb::Error> {
+        self.check_connected()?;
+        Ok([0x1BA0_1477, 0])
+    }
+
+    fn read_bytes(&mut self, addr : u32, len : u32) -> Result<Vec<u8>, rusb::Error> {
+        self.check_connected()?;
+        self.advance();
+
+        if addr.checked_add(len).and_then(|end| end.checked_add(3)).is_none() {
+            return Err(rusb::Error::InvalidParam);
+        }
+
+        Ok(self.bytes(addr, len))
+    }
+
+    fn set_mem16(&mut self, addr : u32, size : u32, data : &[u8]) -> Result<(), rusb::Error> {
+        self.check_connected()?;
+        self.advance();
+
+        self.write_bytes(addr, &data[..(size as usize).min(data.len())]);
+        Ok(())
+    }
+
+    fn swd_freq(&self) -> Option<StlinkDebugApiv2SwdFreq> {
+        self.swd_freq
+    }
+
+    fn com_freq_khz(&self) -> Option<u32> {
+        None
+    }
+
+    fn set_swd_freq(&mut self, freq : StlinkDebugApiv2SwdFreq) -> Result<(), rusb::Error> {
+        self.check_connected()?;
+        self.swd_freq = Some(freq);
+        Ok(())
+    }
+
+    // there's no wire to corrupt anything, so nothing is actually tested
+    fn memory_self_test(&mut self, _addr : u32, freq : StlinkDebugApiv2SwdFreq) -> Result<LinkTestResult, rusb::Error> {
+        self.set_swd_freq(freq)?;
+
+        Ok(LinkTestResult {
+            freq,
+            words_tested : 0,
+            corrupted_words : 0,
+        })
+    }
+}
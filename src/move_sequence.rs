@@ -0,0 +1,184 @@
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+use std::time::Duration;
+
+use parking_lot::Mutex;
+
+use crate::controller_commands::Command;
+use crate::controller_interface::InterfaceCommand;
+use crate::input_generators::GENERATOR_TICK;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StepKind {
+    Position,
+    Velocity,
+    Torque,
+    Wait,
+    StartRecording,
+    StopRecording,
+}
+
+pub const STEP_KINDS : [StepKind; 6] = [
+    StepKind::Position,
+    StepKind::Velocity,
+    StepKind::Torque,
+    StepKind::Wait,
+    StepKind::StartRecording,
+    StepKind::StopRecording,
+];
+
+impl StepKind {
+    pub fn name(&self) -> &'static str {
+        match self {
+            StepKind::Position => "Position",
+            StepKind::Velocity => "Velocity",
+            StepKind::Torque => "Torque",
+            StepKind::Wait => "Wait (s)",
+            StepKind::StartRecording => "Start Recording",
+            StepKind::StopRecording => "Stop Recording",
+        }
+    }
+
+    pub fn has_value(&self) -> bool {
+        !matches!(self, StepKind::StartRecording | StepKind::StopRecording)
+    }
+
+    /// Control mode the firmware has to be in for the step's setpoint to take effect.
+    pub fn control_mode(&self) -> Option<Command> {
+        match self {
+            StepKind::Position => Some(Command::SetPositionControl),
+            StepKind::Velocity => Some(Command::SetVelocityControl),
+            StepKind::Torque => Some(Command::SetTorqueControl),
+            _ => None,
+        }
+    }
+}
+
+/// Longest a single wait step can be.
+pub const MAX_WAIT_SECONDS : f32 = 3600.0;
+
+/// How long a wait step actually waits, anything that isn't a usable number of seconds is clamped.
+pub fn wait_duration(seconds : f32) -> Duration {
+    if seconds.is_finite() {
+        Duration::from_secs_f32(seconds.max(0.0).min(MAX_WAIT_SECONDS))
+    } else {
+        Duration::from_secs(0)
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct SequenceStep {
+    pub kind : StepKind,
+    pub value : f32,
+}
+
+// sleeps in ticks so a cancelled sequence stops promptly
+fn wait(running : &AtomicBool, duration : Duration) {
+    let mut slept = Duration::from_secs(0);
+
+    while slept < duration && running.load(Ordering::Relaxed) {
+        std::thread::sleep(GENERATOR_TICK);
+        slept += GENERATOR_TICK;
+    }
+}
+
+/// Runs `steps` in order `repeats` times, or until cancelled if `repeats` is `None`. Command steps
+/// are sent immediately, so put waits between moves to give them time to finish. The control mode
+/// is switched before a setpoint whenever it differs from the previous one.
+pub fn run_sequence(
+    commands : Arc<Mutex<Vec<InterfaceCommand>>>,
+    running : Arc<AtomicBool>,
+    steps : Vec<SequenceStep>,
+    repeats : Option<u32>) {
+
+    let mut pass = 0;
+    let mut mode = None;
+
+    let pass_wait = steps.iter()
+        .filter(|step| step.kind == StepKind::Wait)
+        .map(|step| wait_duration(step.value))
+        .sum::<Duration>();
+
+    'sequence: while repeats.map_or(true, |repeats| pass < repeats) {
+        pass += 1;
+
+        // without any waits a loop would flood the command queue
+        if repeats.is_none() && pass_wait < GENERATOR_TICK {
+            wait(&running, GENERATOR_TICK);
+        }
+
+        for step in &steps {
+            if !running.load(Ordering::Relaxed) {
+                break 'sequence;
+            }
+
+            if let Some(step_mode) = step.kind.control_mode() {
+                if mode != Some(step.kind) {
+                    commands.lock().push(InterfaceCommand::SendCommand(step_mode));
+                    mode = Some(step.kind);
+                }
+            }
+
+            let cmd = match step.kind {
+                StepKind::Position => InterfaceCommand::PositionCommand(step.value),
+                StepKind::Velocity => InterfaceCommand::SendCommand(Command::VelocityCommand{velocity : step.value}),
+                StepKind::Torque => InterfaceCommand::SendCommand(Command::TorqueCommand{torque : step.value}),
+                StepKind::StartRecording => InterfaceCommand::StartRecording,
+                StepKind::StopRecording => InterfaceCommand::StopRecording,
+                StepKind::Wait => {
+                    wait(&running, wait_duration(step.value));
+                    continue;
+                }
+            };
+
+            commands.lock().push(cmd);
+        }
+    }
+
+    running.store(false, Ordering::Relaxed);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn wait_duration_is_clamped() {
+        assert_eq!(wait_duration(1.5), Duration::from_millis(1500));
+        assert_eq!(wait_duration(-1.0), Duration::from_secs(0));
+        assert_eq!(wait_duration(f32::NAN), Duration::from_secs(0));
+        assert_eq!(wait_duration(f32::INFINITY), Duration::from_secs(0));
+        assert_eq!(wait_duration(1e9), Duration::from_secs_f32(MAX_WAIT_SECONDS));
+    }
+
+    #[test]
+    fn control_mode_is_set_before_each_change_of_setpoint_kind() {
+        let commands = Arc::new(Mutex::new(vec![]));
+        let running = Arc::new(AtomicBool::new(true));
+
+        let steps = vec![
+            SequenceStep { kind : StepKind::Position, value : 1.0 },
+            SequenceStep { kind : StepKind::Position, value : 2.0 },
+            SequenceStep { kind : StepKind::Velocity, value : 3.0 },
+        ];
+
+        run_sequence(commands.clone(), running, steps, Some(2));
+
+        let sent = commands.lock().iter().map(|cmd| format!("{:?}", cmd)).collect::<Vec<_>>();
+        let expected = [
+            "SendCommand(SetPositionControl)",
+            "PositionCommand(1.0)",
+            "PositionCommand(2.0)",
+            "SendCommand(SetVelocityControl)",
+            "SendCommand(VelocityCommand { velocity: 3.0 })",
+            "SendCommand(SetPositionControl)",
+            "PositionCommand(1.0)",
+            "PositionCommand(2.0)",
+            "SendCommand(SetVelocityControl)",
+            "SendCommand(VelocityCommand { velocity: 3.0 })",
+        ];
+
+        assert_eq!(sent, expected);
+    }
+}
@@ -0,0 +1,224 @@
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::controller_interface::OscilloscopeSamplePoint;
+use crate::controller_interface::SAMPLE_CHANNELS;
+
+/// Vertical bands of the plot, top to bottom. Bands without a visible trace are left out and the
+/// rest share the plot height.
+pub const PLOT_BANDS : [&str; 3] = ["Top", "Middle", "Bottom"];
+
+/// Where a band is drawn, in normalized device coordinates.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BandSlot {
+    pub center : f32,
+    pub half_height : f32,
+}
+
+impl BandSlot {
+    /// Maps a value in `[min, max]` into the band.
+    pub fn y(&self, val : f32, min : f32, max : f32) -> f32 {
+        self.half_height * (2.0 * (val - min) / (max - min) - 1.0) + self.center
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum TraceScale {
+    /// Fit the visible samples.
+    Auto,
+    /// Use `PlotChannel::fixed_range`, clipping samples outside it.
+    Fixed,
+    /// Fit the visible samples, keeping zero in the middle of the band.
+    Symmetric,
+}
+
+pub const TRACE_SCALES : [TraceScale; 3] = [TraceScale::Auto, TraceScale::Fixed, TraceScale::Symmetric];
+
+impl TraceScale {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TraceScale::Auto => "Auto",
+            TraceScale::Fixed => "Fixed",
+            TraceScale::Symmetric => "Symmetric",
+        }
+    }
+}
+
+impl Default for TraceScale {
+    fn default() -> Self {
+        TraceScale::Auto
+    }
+}
+
+fn default_fixed_range() -> [f32; 2] {
+    [-1.0, 1.0]
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PlotChannel {
+    /// Name of the channel in `SAMPLE_CHANNELS`.
+    pub name : String,
+    pub visible : bool,
+    pub color : [f32; 4],
+    /// Index into `PLOT_BANDS`.
+    pub band : usize,
+    #[serde(default)]
+    pub scale : TraceScale,
+    /// `[min, max]` used when `scale` is `Fixed`.
+    #[serde(default = "default_fixed_range")]
+    pub fixed_range : [f32; 2],
+}
+
+impl PlotChannel {
+    pub fn func(&self) -> Option<fn(&OscilloscopeSamplePoint) -> f32> {
+        SAMPLE_CHANNELS.iter()
+            .find(|(name, _)| *name == self.name)
+            .map(|(_, func)| *func)
+    }
+
+    pub fn band_index(&self) -> usize {
+        self.band.min(PLOT_BANDS.len() - 1)
+    }
+
+    /// The `(min, max)` the band spans for the given values.
+    pub fn y_range(&self, values : impl Iterator<Item = f32>) -> (f32, f32) {
+        let (lo, hi) = values.fold((f32::INFINITY, f32::NEG_INFINITY), |(lo, hi), v| (lo.min(v), hi.max(v)));
+        let (lo, hi) = if lo <= hi { (lo, hi) } else { (-1.0, 1.0) };
+
+        match self.scale {
+            TraceScale::Auto => (lo - 0.01, hi + 0.01),
+            TraceScale::Fixed => {
+                let [min, max] = self.fixed_range;
+                (min, max.max(min + 0.01))
+            }
+            TraceScale::Symmetric => {
+                let m = lo.abs().max(hi.abs()) + 0.01;
+                (-m, m)
+            }
+        }
+    }
+
+    /// Clips a value to the fixed range, other modes always fit their values.
+    pub fn clip(&self, val : f32, min : f32, max : f32) -> f32 {
+        match self.scale {
+            TraceScale::Fixed => val.max(min).min(max),
+            _ => val,
+        }
+    }
+}
+
+/// Spacing between gridlines over `[min, max]`, rounded to 1, 2 or 5 times a power of ten so that
+/// roughly `target` lines fit.
+pub fn grid_step(min : f32, max : f32, target : usize) -> f32 {
+    let raw = (max - min).abs() / target.max(1) as f32;
+    if !raw.is_finite() || raw <= 0.0 {
+        return 1.0;
+    }
+
+    let magnitude = 10f32.powf(raw.log10().floor());
+    let nice = match raw / magnitude {
+        r if r < 1.5 => 1.0,
+        r if r < 3.5 => 2.0,
+        r if r < 7.5 => 5.0,
+        _ => 10.0,
+    };
+
+    nice * magnitude
+}
+
+/// Multiples of `grid_step` that fall inside `[min, max]`.
+pub fn grid_levels(min : f32, max : f32, target : usize) -> Vec<f32> {
+    let step = grid_step(min, max, target);
+    let first = (min / step).ceil() as i64;
+    let last = (max / step).floor() as i64;
+
+    (first..=last).map(|i| i as f32 * step).collect()
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct PlotSettings {
+    pub channels : Vec<PlotChannel>,
+    pub window_seconds : f32,
+    pub refresh_hz : f32,
+    pub max_points : i32,
+    pub show_grid : bool,
+}
+
+impl Default for PlotSettings {
+    fn default() -> Self {
+        let setpoint = [0.2, 0.2, 0.8, 1.0];
+        let measured = [0.8, 0.4, 0.4, 1.0];
+
+        let channel = |name : &str, visible, color, band| PlotChannel {
+            name : name.to_string(),
+            visible,
+            color,
+            band,
+            scale : TraceScale::Auto,
+            fixed_range : default_fixed_range(),
+        };
+
+        PlotSettings {
+            channels : vec![
+                channel("Position Input",    true,  [0.0, 0.6, 0.0, 1.0], 0),
+                channel("Position Setpoint", true,  setpoint, 0),
+                channel("Velocity Setpoint", true,  setpoint, 1),
+                channel("Torque Setpoint",   true,  setpoint, 2),
+                channel("Position",          true,  measured, 0),
+                channel("Velocity",          true,  measured, 1),
+                channel("Acceleration",      true,  measured, 2),
+                channel("Velocity Input",    false, [0.0, 0.6, 0.0, 1.0], 1),
+            ],
+            window_seconds : 5.0,
+            refresh_hz : 30.0,
+            max_points : 4000,
+            show_grid : true,
+        }
+    }
+}
+
+impl PlotSettings {
+    fn band_used(&self, band : usize) -> bool {
+        self.channels.iter().any(|c| c.visible && c.band_index() == band && c.func().is_some())
+    }
+
+    /// Where `band` is drawn, or `None` if it has no visible traces.
+    pub fn band_slot(&self, band : usize) -> Option<BandSlot> {
+        if !self.band_used(band) {
+            return None;
+        }
+
+        let used = (0..PLOT_BANDS.len()).filter(|b| self.band_used(*b)).count();
+        let index = (0..band).filter(|b| self.band_used(*b)).count();
+        let half_height = 1.0 / used as f32;
+
+        Some(BandSlot {
+            center : -1.0 + (2 * index + 1) as f32 * half_height,
+            half_height,
+        })
+    }
+
+    /// The slot of the band the named channel is in.
+    pub fn channel_slot(&self, name : &str) -> Option<BandSlot> {
+        self.channels.iter()
+            .find(|c| c.name == name)
+            .and_then(|c| self.band_slot(c.band_index()))
+    }
+
+    // channels added to `SAMPLE_CHANNELS` after the settings were saved are appended, hidden
+    pub fn add_missing_channels(&mut self) {
+        for (name, _) in SAMPLE_CHANNELS {
+            if !self.channels.iter().any(|c| c.name == *name) {
+                self.channels.push(PlotChannel {
+                    name : name.to_string(),
+                    visible : false,
+                    color : [0.8, 0.8, 0.8, 1.0],
+                    band : 1,
+                    scale : TraceScale::Auto,
+                    fixed_range : default_fixed_range(),
+                });
+            }
+        }
+    }
+}
@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::mem::Discriminant;
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::controller_commands::Command;
+use crate::controller_interface::InterfaceCommand;
+
+#[derive(Debug, Clone)]
+pub struct CommandRateLimits {
+    pub config_interval : Duration,
+    pub position_interval : Duration,
+    pub command_interval : Duration,
+}
+
+impl Default for CommandRateLimits {
+    fn default() -> Self {
+        CommandRateLimits {
+            config_interval : Duration::from_millis(20),
+            position_interval : Duration::from_millis(5),
+            command_interval : Duration::from_millis(50),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+enum RateLimitKey {
+    ConfigParameter(u32),
+    ServoConfig,
+    Position,
+    Velocity,
+    Torque,
+    Command(Discriminant<Command>),
+}
+
+impl CommandRateLimits {
+    fn interval(&self, key : RateLimitKey) -> Duration {
+        match key {
+            RateLimitKey::ConfigParameter(_) | RateLimitKey::ServoConfig => self.config_interval,
+            // setpoints are streamed by the wizard and move sequences, so they share the fast interval
+            RateLimitKey::Position | RateLimitKey::Velocity | RateLimitKey::Torque => self.position_interval,
+            RateLimitKey::Command(_) => self.command_interval,
+        }
+    }
+}
+
+fn is_stop(cmd : &InterfaceCommand) -> bool {
+    match cmd {
+        InterfaceCommand::StopMotor | InterfaceCommand::EmergencyStop | InterfaceCommand::SendCommand(Command::MotorStop) => true,
+        _ => false,
+    }
+}
+
+// stopping the motor and host-side commands are never held back
+fn rate_limit_key(cmd : &InterfaceCommand) -> Option<RateLimitKey> {
+    match cmd {
+        InterfaceCommand::UpdateConfigParameter(offset, _) => Some(RateLimitKey::ConfigParameter(*offset)),
+        InterfaceCommand::WriteServoConfig(_) => Some(RateLimitKey::ServoConfig),
+        InterfaceCommand::PositionCommand(_) => Some(RateLimitKey::Position),
+        InterfaceCommand::SendCommand(Command::MotorStop) => None,
+        InterfaceCommand::SendCommand(Command::PositionCommand{..}) => Some(RateLimitKey::Position),
+        InterfaceCommand::SendCommand(Command::VelocityCommand{..}) => Some(RateLimitKey::Velocity),
+        InterfaceCommand::SendCommand(Command::TorqueCommand{..}) => Some(RateLimitKey::Torque),
+        InterfaceCommand::SendCommand(cmd) => Some(RateLimitKey::Command(std::mem::discriminant(cmd))),
+        _ => None,
+    }
+}
+
+#[derive(Default)]
+pub struct CommandRateLimiter {
+    last_sent : HashMap<RateLimitKey, Instant>,
+    pending : Vec<(RateLimitKey, InterfaceCommand)>,
+}
+
+impl CommandRateLimiter {
+
+    /// Returns the commands that may be sent now. Rate limited commands that arrive too soon are
+    /// held back, and a newer command of the same kind replaces the one being held. A stop drops
+    /// any held command that moves the motor, so nothing can start it again after the stop.
+    pub fn filter(&mut self, limits : &CommandRateLimits, cmds : Vec<InterfaceCommand>) -> Vec<InterfaceCommand> {
+
+        let now = Instant::now();
+        let mut ready = vec![];
+
+        for cmd in cmds {
+            match rate_limit_key(&cmd) {
+                Some(key) => {
+                    if let Some(slot) = self.pending.iter_mut().find(|(k, _)| *k == key) {
+                        slot.1 = cmd;
+                    } else {
+                        self.pending.push((key, cmd));
+                    }
+                }
+                None => {
+                    if is_stop(&cmd) {
+                        self.pending.retain(|(_, held)| !held.moves_motor());
+                    }
+                    ready.push(cmd);
+                }
+            }
+        }
+
+        for (key, cmd) in std::mem::take(&mut self.pending) {
+            let due = self.last_sent.get(&key)
+                .map_or(true, |t| now.duration_since(*t) >= limits.interval(key));
+
+            if due {
+                self.last_sent.insert(key, now);
+                ready.push(cmd);
+            } else {
+                self.pending.push((key, cmd));
+            }
+        }
+
+        ready
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn position(position : f32) -> InterfaceCommand {
+        InterfaceCommand::SendCommand(Command::PositionCommand { position })
+    }
+
+    #[test]
+    fn stop_drops_held_positions() {
+        let limits = CommandRateLimits::default();
+
+        for stop in vec![InterfaceCommand::StopMotor, InterfaceCommand::EmergencyStop, InterfaceCommand::SendCommand(Command::MotorStop)] {
+            let mut limiter = CommandRateLimiter::default();
+
+            assert_eq!(limiter.filter(&limits, vec![position(1.0)]).len(), 1);
+
+            // too soon after the first, so it's held
+            assert!(limiter.filter(&limits, vec![InterfaceCommand::PositionCommand(2.0)]).is_empty());
+
+            let ready = limiter.filter(&limits, vec![stop]);
+            assert_eq!(ready.len(), 1);
+            assert!(is_stop(&ready[0]));
+
+            std::thread::sleep(limits.position_interval * 2);
+            assert!(limiter.filter(&limits, vec![]).is_empty());
+        }
+    }
+
+    #[test]
+    fn stop_drops_a_held_start() {
+        let limits = CommandRateLimits::default();
+        let mut limiter = CommandRateLimiter::default();
+
+        let start = || vec![
+            InterfaceCommand::SendCommand(Command::MotorStart),
+            InterfaceCommand::SendCommand(Command::VelocityCommand { velocity : 1.0 }),
+        ];
+
+        assert_eq!(limiter.filter(&limits, start()).len(), 2);
+        assert!(limiter.filter(&limits, start()).is_empty());
+
+        assert_eq!(limiter.filter(&limits, vec![InterfaceCommand::StopMotor]).len(), 1);
+
+        std::thread::sleep(limits.command_interval * 2);
+        assert!(limiter.filter(&limits, vec![]).is_empty());
+    }
+
+    #[test]
+    fn positions_after_a_stop_are_kept() {
+        let limits = CommandRateLimits::default();
+        let mut limiter = CommandRateLimiter::default();
+
+        let ready = limiter.filter(&limits, vec![InterfaceCommand::StopMotor, position(1.0)]);
+        assert_eq!(ready.len(), 2);
+        assert!(is_stop(&ready[0]));
+    }
+}
@@ -0,0 +1,211 @@
+use crate::controller_interface::OscilloscopeSamplePoint;
+
+#[derive(Debug, Clone, Copy, Default)]
+pub struct ChannelStatistics {
+    pub count : usize,
+    pub min : f32,
+    pub max : f32,
+    pub mean : f32,
+    pub peak_to_peak : f32,
+    pub rms : f32,
+    pub std_dev : f32,
+}
+
+pub fn channel_statistics(samples : &[OscilloscopeSamplePoint], func : fn(&OscilloscopeSamplePoint) -> f32) -> Option<ChannelStatistics> {
+
+    if samples.is_empty() {
+        return None;
+    }
+
+    let mut min = f32::INFINITY;
+    let mut max = f32::NEG_INFINITY;
+    let mut sum = 0.0f64;
+    let mut sum_sq = 0.0f64;
+
+    for pt in samples {
+        let val = func(pt);
+
+        min = min.min(val);
+        max = max.max(val);
+        sum += val as f64;
+        sum_sq += (val as f64) * (val as f64);
+    }
+
+    let n = samples.len() as f64;
+    let mean = sum / n;
+    let mean_sq = sum_sq / n;
+
+    Some(ChannelStatistics {
+        count : samples.len(),
+        min,
+        max,
+        mean : mean as f32,
+        peak_to_peak : max - min,
+        rms : mean_sq.sqrt() as f32,
+        // clamp small negative values caused by rounding
+        std_dev : (mean_sq - mean * mean).max(0.0).sqrt() as f32,
+    })
+}
+
+// noise smaller than this fraction of the peak to peak swing doesn't count as a crossing
+const CROSSING_HYSTERESIS : f64 = 0.1;
+
+/// Estimates the dominant frequency of a channel in Hz from how often it crosses its mean. A
+/// crossing only counts once the signal has moved past a hysteresis band around the mean, so noise
+/// riding on a slow signal doesn't add crossings.
+pub fn dominant_frequency(samples : &[OscilloscopeSamplePoint], func : fn(&OscilloscopeSamplePoint) -> f32, sample_rate : f32) -> Option<f32> {
+
+    if samples.len() < 3 {
+        return None;
+    }
+
+    let values = samples.iter().map(|pt| func(pt) as f64).collect::<Vec<_>>();
+
+    let mean = values.iter().sum::<f64>() / values.len() as f64;
+    let min = values.iter().cloned().fold(f64::INFINITY, f64::min);
+    let max = values.iter().cloned().fold(f64::NEG_INFINITY, f64::max);
+    let band = CROSSING_HYSTERESIS * (max - min) * 0.5;
+
+    let mut above = None;
+    let mut crossings = 0;
+
+    for &value in &values {
+        let side = if value > mean + band {
+            Some(true)
+        } else if value < mean - band {
+            Some(false)
+        } else {
+            None
+        };
+
+        if let Some(side) = side {
+            if above.map_or(false, |above| above != side) {
+                crossings += 1;
+            }
+            above = Some(side);
+        }
+    }
+
+    // two crossings per period
+    let duration = (samples.len() - 1) as f32 / sample_rate;
+
+    Some(crossings as f32 / (2.0 * duration))
+}
+
+/// Fraction of the Nyquist frequency above which the plotted signal is likely aliased.
+pub const ALIASING_WARNING_FRACTION : f32 = 0.5;
+
+pub fn aliasing_likely(frequency : f32, sample_rate : f32) -> bool {
+    frequency >= ALIASING_WARNING_FRACTION * 0.5 * sample_rate
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct StepResponse {
+    /// Size of the setpoint step.
+    pub step : f32,
+    /// Time from 10% to 90% of the step, in seconds.
+    pub rise_time : Option<f32>,
+    /// Peak excursion past the final setpoint as a percentage of the step.
+    pub overshoot : f32,
+    /// Time after the step until the response stays within 2% of the step, in seconds.
+    pub settling_time : Option<f32>,
+    pub steady_state_error : f32,
+}
+
+/// Measures the response to the largest setpoint step in `samples`.
+pub fn step_response(
+    samples : &[OscilloscopeSamplePoint],
+    setpoint : fn(&OscilloscopeSamplePoint) -> f32,
+    measured : fn(&OscilloscopeSamplePoint) -> f32,
+    sample_period : f32) -> Option<StepResponse> {
+
+    let (step_index, _) = samples.windows(2)
+        .enumerate()
+        .map(|(i, w)| (i + 1, (setpoint(&w[1]) - setpoint(&w[0])).abs()))
+        .max_by(|a, b| a.1.partial_cmp(&b.1).unwrap_or(std::cmp::Ordering::Equal))?;
+
+    let response = &samples[step_index..];
+
+    if response.len() < 2 {
+        return None;
+    }
+
+    let initial = measured(&samples[step_index - 1]);
+    let target = setpoint(&response[0]);
+    let step = target - initial;
+
+    if step == 0.0 {
+        return None;
+    }
+
+    // normalized so the response goes from 0 to 1 regardless of the step direction
+    let normalized = response.iter()
+        .map(|pt| (measured(pt) - initial) / step)
+        .collect::<Vec<_>>();
+
+    let t10 = normalized.iter().position(|&x| x >= 0.1);
+    let t90 = normalized.iter().position(|&x| x >= 0.9);
+
+    let rise_time = match (t10, t90) {
+        (Some(a), Some(b)) => Some((b - a) as f32 * sample_period),
+        _ => None,
+    };
+
+    let peak = normalized.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+
+    let settling_time = normalized.iter()
+        .rposition(|&x| (x - 1.0).abs() > 0.02)
+        .map_or(Some(0.0), |i| if i + 1 < normalized.len() { Some((i + 1) as f32 * sample_period) } else { None });
+
+    let final_value = measured(&response[response.len() - 1]);
+
+    Some(StepResponse {
+        step,
+        rise_time,
+        overshoot : 100.0 * (peak - 1.0).max(0.0),
+        settling_time,
+        steady_state_error : setpoint(&response[response.len() - 1]) - final_value,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn samples(values : impl Iterator<Item = f32>) -> Vec<OscilloscopeSamplePoint> {
+        values
+            .map(|pos| OscilloscopeSamplePoint {
+                pos,
+                vel : 0.0,
+                acc : 0.0,
+                pos_setpoint : 0.0,
+                vel_setpoint : 0.0,
+                tor_setpoint : 0.0,
+                pos_input : 0.0,
+                vel_input : 0.0,
+            })
+            .collect()
+    }
+
+    #[test]
+    fn dominant_frequency_of_a_sine() {
+        let rate = 1000.0;
+        let sine = samples((0..1000).map(|i| (2.0 * std::f32::consts::PI * 5.0 * i as f32 / rate).sin()));
+
+        let freq = dominant_frequency(&sine, |p| p.pos, rate).unwrap();
+        assert!((freq - 5.0).abs() < 0.5, "{}", freq);
+    }
+
+    #[test]
+    fn noise_near_the_mean_does_not_add_crossings() {
+        let rate = 1000.0;
+        // alternating noise at 5% of the amplitude would cross the mean every sample
+        let noisy = samples((0..1000).map(|i| {
+            let noise = if i % 2 == 0 { 0.05 } else { -0.05 };
+            (2.0 * std::f32::consts::PI * 5.0 * i as f32 / rate).sin() + noise
+        }));
+
+        let freq = dominant_frequency(&noisy, |p| p.pos, rate).unwrap();
+        assert!((freq - 5.0).abs() < 0.5, "{}", freq);
+    }
+}
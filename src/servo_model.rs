@@ -0,0 +1,76 @@
+use std::f32::consts::TAU;
+
+/// Critically damped input filter gains `(kp, ki)` for the given bandwidth.
+pub fn input_filter_gains(bandwidth_hz : f32) -> (f32, f32) {
+    let w = TAU * bandwidth_hz;
+
+    (w * w, 2.0 * w)
+}
+
+/// Runs the recorded position inputs through a model of the firmware input filter, where the
+/// position error drives the acceleration through `kp` and the velocity through `ki`. Each input
+/// is held for `substeps` control cycles of length `dt`.
+pub fn simulate_input_filter(inputs : &[f32], kp : f32, ki : f32, dt : f32, substeps : u32) -> Vec<f32> {
+
+    let mut pos = inputs.first().copied().unwrap_or(0.0);
+    let mut vel = 0.0;
+
+    inputs.iter()
+        .map(|&input| {
+            for _ in 0..substeps {
+                let accel = kp * (input - pos) - ki * vel;
+                vel += accel * dt;
+                pos += vel * dt;
+            }
+            pos
+        })
+        .collect()
+}
+
+/// Gains and limits of the cascaded position/velocity loop, as used by `simulate_position_loop`.
+#[derive(Debug, Clone, Copy)]
+pub struct LoopModel {
+    pub position_gain : f32,
+    pub velocity_gain : f32,
+    pub velocity_integrator_gain : f32,
+    pub velocity_integrator_max_abs : f32,
+    pub vel_max_abs : f32,
+    pub tor_max_abs : f32,
+    pub inertia : f32,
+    pub torque_bandwidth : f32,
+}
+
+/// Predicts the position for the recorded position setpoints with a rigid inertia driven by the
+/// cascaded P position loop and PI velocity loop, with the current loop modelled as a first order
+/// lag at `torque_bandwidth` Hz. Friction, cogging and the encoder are ignored. Each setpoint is
+/// held for `substeps` control cycles of length `dt`.
+pub fn simulate_position_loop(setpoints : &[f32], model : &LoopModel, dt : f32, substeps : u32) -> Vec<f32> {
+
+    let mut pos = setpoints.first().copied().unwrap_or(0.0);
+    let mut vel = 0.0;
+    let mut integrator = 0.0;
+    let mut torque = 0.0;
+
+    let inertia = model.inertia.max(1e-9);
+    let torque_alpha = if model.torque_bandwidth > 0.0 { (TAU * model.torque_bandwidth * dt).min(1.0) } else { 1.0 };
+    // unset limits in the config mean unlimited
+    let limit = |value : f32, max : f32| if max > 0.0 { value.max(-max).min(max) } else { value };
+
+    setpoints.iter()
+        .map(|&setpoint| {
+            for _ in 0..substeps {
+                let vel_cmd = limit(model.position_gain * (setpoint - pos), model.vel_max_abs);
+                let vel_err = vel_cmd - vel;
+
+                integrator = limit(integrator + model.velocity_integrator_gain * vel_err * dt, model.velocity_integrator_max_abs);
+
+                let torque_cmd = limit(model.velocity_gain * vel_err + integrator, model.tor_max_abs);
+                torque += (torque_cmd - torque) * torque_alpha;
+
+                vel += torque / inertia * dt;
+                pos += vel * dt;
+            }
+            pos
+        })
+        .collect()
+}
@@ -0,0 +1,180 @@
+use std::collections::BTreeMap;
+use std::collections::BTreeSet;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::layout::LayoutSettings;
+use crate::plot_settings::PlotSettings;
+use crate::status_indicators::StatusIndicatorSettings;
+use crate::theme::Theme;
+
+const SETTINGS_FILE_NAME : &str = "config-tool-settings.toml";
+
+/// User preferences kept between runs. Missing fields fall back to their defaults so older
+/// settings files keep loading as new fields are added.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    pub status_indicators : StatusIndicatorSettings,
+    /// Encoder counts per turn, for jogging by counts.
+    pub counts_per_turn : u32,
+    /// Probes, by serial number, whose axis has its positions and velocities mirrored because the
+    /// encoder counts against the mechanical direction. Probes without a serial go by model name.
+    pub inverted_probes : BTreeSet<String>,
+    /// Export the controller config to `auto_save_dir` when the app is closed.
+    pub auto_save_config : bool,
+    pub auto_save_dir : String,
+    pub plot : PlotSettings,
+    /// How many motion profiles the firmware has, profile 0 is none.
+    pub motion_profile_count : u32,
+    pub theme : Theme,
+    pub layout : LayoutSettings,
+    /// Named plot setups, e.g. one for each motor or kind of tuning. `plot` is the one in use.
+    pub plot_profiles : BTreeMap<String, PlotSettings>,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            status_indicators : StatusIndicatorSettings::default(),
+            counts_per_turn : 16384,
+            inverted_probes : BTreeSet::new(),
+            auto_save_config : false,
+            auto_save_dir : "config-backups".to_string(),
+            plot : PlotSettings::default(),
+            motion_profile_count : 4,
+            theme : Theme::default(),
+            layout : LayoutSettings::default(),
+            plot_profiles : BTreeMap::new(),
+        }
+    }
+}
+
+impl Settings {
+    // kept next to the executable so each install has its own settings
+    fn exe_dir() -> Option<PathBuf> {
+        std::env::current_exe()
+            .ok()
+            .and_then(|exe| exe.parent().map(|dir| dir.to_path_buf()))
+    }
+
+    fn path() -> PathBuf {
+        Settings::exe_dir()
+            .map(|dir| dir.join(SETTINGS_FILE_NAME))
+            .unwrap_or_else(|| PathBuf::from(SETTINGS_FILE_NAME))
+    }
+
+    /// Where configs are exported on exit. A relative `auto_save_dir` is next to the executable,
+    /// like the settings file, rather than wherever the app was started from.
+    pub fn auto_save_path(&self) -> PathBuf {
+        let dir = PathBuf::from(&self.auto_save_dir);
+
+        match Settings::exe_dir() {
+            Some(exe_dir) if dir.is_relative() => exe_dir.join(dir),
+            _ => dir,
+        }
+    }
+
+    /// Loads the settings file, using the defaults if it's missing or unreadable.
+    pub fn load() -> Settings {
+        let path = Settings::path();
+
+        let mut settings = match std::fs::read_to_string(&path) {
+            Ok(text) => toml::from_str(&text).unwrap_or_else(|e| {
+                eprintln!("Ignoring invalid settings file {}: {}", path.display(), e);
+                Settings::default()
+            }),
+            Err(_) => Settings::default(),
+        };
+
+        settings.repair();
+        settings
+    }
+
+    // fills in and clamps what a hand edited or older settings file may have left out or broken
+    fn repair(&mut self) {
+        self.plot.add_missing_channels();
+        for profile in self.plot_profiles.values_mut() {
+            profile.add_missing_channels();
+        }
+
+        // profile 0, no profile, is always there
+        self.motion_profile_count = self.motion_profile_count.max(1);
+    }
+
+    pub fn save(&self) {
+        let path = Settings::path();
+
+        let result = toml::to_string_pretty(self)
+            .map_err(|e| std::io::Error::new(std::io::ErrorKind::Other, e))
+            .and_then(|text| std::fs::write(&path, text));
+
+        if let Err(e) = result {
+            eprintln!("Failed to save settings to {}: {}", path.display(), e);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn auto_save_is_off_by_default() {
+        assert!(!Settings::default().auto_save_config);
+    }
+
+    #[test]
+    fn relative_auto_save_dir_is_next_to_the_executable() {
+        let settings = Settings::default();
+        let exe_dir = std::env::current_exe().unwrap().parent().unwrap().to_path_buf();
+
+        assert_eq!(settings.auto_save_path(), exe_dir.join("config-backups"));
+    }
+
+    #[test]
+    fn absolute_auto_save_dir_is_kept() {
+        let dir = std::env::temp_dir().join("config-backups");
+        let settings = Settings { auto_save_dir : dir.to_string_lossy().to_string(), ..Settings::default() };
+
+        assert_eq!(settings.auto_save_path(), dir);
+    }
+
+    #[test]
+    fn plot_profiles_round_trip() {
+        let mut settings = Settings::default();
+        let mut profile = PlotSettings::default();
+        profile.channels[0].visible = false;
+        profile.window_seconds = 0.5;
+        settings.plot_profiles.insert("velocity tuning".to_string(), profile);
+
+        let text = toml::to_string_pretty(&settings).unwrap();
+        let loaded = toml::from_str::<Settings>(&text).unwrap();
+
+        let profile = &loaded.plot_profiles["velocity tuning"];
+        assert!(!profile.channels[0].visible);
+        assert_eq!(profile.window_seconds, 0.5);
+    }
+
+    #[test]
+    fn inverted_probes_round_trip() {
+        let mut settings = Settings::default();
+        settings.inverted_probes.insert("066DFF485550755187121814".to_string());
+
+        let text = toml::to_string_pretty(&settings).unwrap();
+        let loaded = toml::from_str::<Settings>(&text).unwrap();
+
+        assert!(loaded.inverted_probes.contains("066DFF485550755187121814"));
+        assert!(!loaded.inverted_probes.contains("Simulated"));
+    }
+
+    #[test]
+    fn zero_motion_profiles_is_repaired() {
+        let mut settings = toml::from_str::<Settings>("motion_profile_count = 0").unwrap();
+        settings.repair();
+
+        assert_eq!(settings.motion_profile_count, 1);
+    }
+}
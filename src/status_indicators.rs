@@ -0,0 +1,145 @@
+use serde::Deserialize;
+use serde::Serialize;
+
+use crate::controller_interface::ControllerData;
+use crate::controller_interface::ServoControlState;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Severity {
+    Error,
+    Warning,
+    Info,
+    Ignored,
+}
+
+pub const SEVERITIES : [Severity; 4] = [Severity::Error, Severity::Warning, Severity::Info, Severity::Ignored];
+
+impl Severity {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Severity::Error => "Error",
+            Severity::Warning => "Warning",
+            Severity::Info => "Info",
+            Severity::Ignored => "Ignored",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum StatusFlag {
+    Fault,
+    FirmwareStalled,
+    Uninitialized,
+    Disabled,
+    NotAligned,
+    AnticoggingNotCalibrated,
+    AnticoggingReturning,
+    IntegratorSaturated,
+}
+
+pub const STATUS_FLAGS : [StatusFlag; 8] = [
+    StatusFlag::Fault,
+    StatusFlag::FirmwareStalled,
+    StatusFlag::Uninitialized,
+    StatusFlag::Disabled,
+    StatusFlag::NotAligned,
+    StatusFlag::AnticoggingNotCalibrated,
+    StatusFlag::AnticoggingReturning,
+    StatusFlag::IntegratorSaturated,
+];
+
+impl StatusFlag {
+    pub fn name(&self) -> &'static str {
+        match self {
+            StatusFlag::Fault => "Fault",
+            StatusFlag::FirmwareStalled => "Firmware Control Loop Stalled",
+            StatusFlag::Uninitialized => "Uninitialized",
+            StatusFlag::Disabled => "Disabled",
+            StatusFlag::NotAligned => "Not Aligned",
+            StatusFlag::AnticoggingNotCalibrated => "Anticogging Not Calibrated",
+            StatusFlag::AnticoggingReturning => "Anticogging Returning",
+            StatusFlag::IntegratorSaturated => "Integrator Saturated",
+        }
+    }
+
+    pub fn is_set(&self, data : &ControllerData, integrator_saturated : bool) -> bool {
+        let state = &data.servo_state;
+
+        match self {
+            StatusFlag::Fault => data.fault_detected,
+            StatusFlag::FirmwareStalled => data.firmware_stalled,
+            StatusFlag::Uninitialized => state.state == ServoControlState::Uninit,
+            StatusFlag::Disabled => state.state == ServoControlState::Disabled,
+            StatusFlag::NotAligned => !state.aligned,
+            StatusFlag::AnticoggingNotCalibrated => !state.anticogging_calibrated,
+            StatusFlag::AnticoggingReturning => state.anticogging_returning,
+            StatusFlag::IntegratorSaturated => integrator_saturated,
+        }
+    }
+
+    fn default_severity(&self) -> Severity {
+        match self {
+            StatusFlag::Fault | StatusFlag::FirmwareStalled | StatusFlag::Uninitialized => Severity::Error,
+            StatusFlag::NotAligned | StatusFlag::IntegratorSaturated => Severity::Warning,
+            StatusFlag::Disabled | StatusFlag::AnticoggingNotCalibrated | StatusFlag::AnticoggingReturning => Severity::Info,
+        }
+    }
+}
+
+/// How each status flag is reported. Firmware builds use the flags differently, so both the
+/// severity of each flag and the color of each severity are configurable.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default)]
+pub struct StatusIndicatorSettings {
+    pub severities : Vec<(StatusFlag, Severity)>,
+    pub error_color : [f32; 4],
+    pub warning_color : [f32; 4],
+    pub info_color : [f32; 4],
+}
+
+impl Default for StatusIndicatorSettings {
+    fn default() -> Self {
+        StatusIndicatorSettings {
+            severities : STATUS_FLAGS.iter().map(|flag| (*flag, flag.default_severity())).collect(),
+            error_color : [1.0, 0.3, 0.3, 1.0],
+            warning_color : [0.9, 0.9, 0.2, 1.0],
+            info_color : [0.6, 0.8, 1.0, 1.0],
+        }
+    }
+}
+
+impl StatusIndicatorSettings {
+    pub fn severity(&self, flag : StatusFlag) -> Severity {
+        self.severities.iter()
+            .find(|(f, _)| *f == flag)
+            .map_or(flag.default_severity(), |(_, severity)| *severity)
+    }
+
+    pub fn set_severity(&mut self, flag : StatusFlag, severity : Severity) {
+        match self.severities.iter_mut().find(|(f, _)| *f == flag) {
+            Some(entry) => entry.1 = severity,
+            None => self.severities.push((flag, severity)),
+        }
+    }
+
+    pub fn color(&self, severity : Severity) -> [f32; 4] {
+        match severity {
+            Severity::Error => self.error_color,
+            Severity::Warning => self.warning_color,
+            Severity::Info | Severity::Ignored => self.info_color,
+        }
+    }
+
+    /// Flags currently set that aren't ignored, most severe first.
+    pub fn active(&self, data : &ControllerData, integrator_saturated : bool) -> Vec<(StatusFlag, Severity)> {
+        let mut active = STATUS_FLAGS.iter()
+            .filter(|flag| flag.is_set(data, integrator_saturated))
+            .map(|flag| (*flag, self.severity(*flag)))
+            .filter(|(_, severity)| *severity != Severity::Ignored)
+            .collect::<Vec<_>>();
+
+        active.sort_by_key(|(_, severity)| SEVERITIES.iter().position(|s| s == severity));
+
+        active
+    }
+}
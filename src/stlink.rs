@@ -1,14 +1,19 @@
-use core::mem::size_of;
 
 use std::time::Duration;
+use std::time::Instant;
 
 use rusb::Device;
 use rusb::DeviceHandle;
 use rusb::GlobalContext;
+use rusb::TransferType;
+
+use crate::memory_access::MemoryAccess;
 
 
 const STLINK_CMD_SIZE_V2 : u32 = 16;
 
+const STLINK_USB_PACKET_SIZE : usize = 64;
+
 #[derive(Debug, Clone, Copy)]
 pub enum STLinkVersion {
     V2,
@@ -17,6 +22,16 @@ pub enum STLinkVersion {
     V3,
 }
 
+impl STLinkVersion {
+    /// V3 probes set the SWD clock in kHz from a list they report instead of the V2 divider map.
+    pub fn is_v3(&self) -> bool {
+        match self {
+            STLinkVersion::V3 | STLinkVersion::V3E => true,
+            STLinkVersion::V2 | STLinkVersion::V2_1 => false,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub struct UsbDescriptor {
     pub version : STLinkVersion,
@@ -71,28 +86,150 @@ const DEV_TYPES : &[UsbDescriptor] = &[
     }
 ];
 
+#[derive(Debug, Clone)]
+pub struct UsbErrorCounters {
+    pub timeouts : u32,
+    pub short_reads : u32,
+    pub short_writes : u32,
+    pub retries : u32,
+    pub rw_status_failures : u32,
+    pub since : Instant,
+}
+
+impl Default for UsbErrorCounters {
+    fn default() -> Self {
+        UsbErrorCounters {
+            timeouts : 0,
+            short_reads : 0,
+            short_writes : 0,
+            retries : 0,
+            rw_status_failures : 0,
+            since : Instant::now(),
+        }
+    }
+}
+
+impl UsbErrorCounters {
+    pub fn total(&self) -> u32 {
+        self.timeouts + self.short_reads + self.short_writes + self.retries + self.rw_status_failures
+    }
+
+    pub fn per_minute(&self) -> f32 {
+        let minutes = self.since.elapsed().as_secs_f32() / 60.0;
+
+        if minutes > 0.0 {
+            self.total() as f32 / minutes
+        } else {
+            0.0
+        }
+    }
+
+    pub fn reset(&mut self) {
+        *self = UsbErrorCounters::default();
+    }
+}
+
+/// Firmware version reported by the probe, ST writes these as e.g. `V2J37S7` or `V3J7M2B4S1`.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct STLinkFirmwareVersion {
+    pub stlink : u8,
+    pub jtag : u8,
+    pub swim : u8,
+    /// Mass storage version, V2-1 and V3 probes only.
+    pub msd : u8,
+    /// Bridge version, V3 probes only.
+    pub bridge : u8,
+    pub vendor_id : u16,
+    pub product_id : u16,
+}
+
+impl std::fmt::Display for STLinkFirmwareVersion {
+    fn fmt(&self, f : &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(f, "V{}J{}", self.stlink, self.jtag)?;
+
+        if self.msd != 0 {
+            write!(f, "M{}", self.msd)?;
+        }
+        if self.bridge != 0 {
+            write!(f, "B{}", self.bridge)?;
+        }
+        if self.swim != 0 {
+            write!(f, "S{}", self.swim)?;
+        }
+
+        Ok(())
+    }
+}
+
 pub struct STLink {
     pub connected : bool,
     pub device : Device<GlobalContext>,
     pub handle : Option<DeviceHandle<GlobalContext>>,
     pub dev_type : UsbDescriptor,
+    pub swd_freq : Option<StlinkDebugApiv2SwdFreq>,
+    /// Frequency a V3 probe actually picked, which may differ from `swd_freq`.
+    pub com_freq_khz : Option<u32>,
+    /// Read from the probe by `init`.
+    pub firmware_version : Option<STLinkFirmwareVersion>,
+    pub usb_errors : UsbErrorCounters,
+    pub usb_timeout : Duration,
+    /// Interface number of the debug function, found when connecting.
+    pub interface : u8,
+    /// USB serial number, `None` if the probe couldn't be opened to read it.
+    pub serial : Option<String>,
+}
+
+// composite probes (VCP, mass storage) don't always put the debug function on interface 0, so look
+// for the vendor specific interface with the bulk endpoints we talk to
+fn find_debug_interface(device : &Device<GlobalContext>, dev_type : &UsbDescriptor) -> Option<(u8, u8)> {
+    let config = device.active_config_descriptor().ok()?;
+
+    for interface in config.interfaces() {
+        for desc in interface.descriptors() {
+            let has_endpoint = |address : u8| desc.endpoint_descriptors()
+                .any(|ep| ep.address() == address && ep.transfer_type() == TransferType::Bulk);
+
+            if desc.class_code() == 0xff && has_endpoint(dev_type.in_pipe) && has_endpoint(dev_type.out_pipe) {
+                return Some((desc.interface_number(), desc.setting_number()));
+            }
+        }
+    }
+
+    None
 }
 
 impl STLink {
     pub fn enumerate() -> Vec<STLink> {
-        rusb::devices().unwrap().iter()
+        // a USB stack that can't list devices just means no probes are found
+        let devices = match rusb::devices() {
+            Ok(devices) => devices,
+            Err(_) => return vec![],
+        };
+
+        devices.iter()
             .filter_map(|dev| {
 
-                let dsc = dev.device_descriptor().unwrap();
+                let dsc = dev.device_descriptor().ok()?;
 
                 for desc in DEV_TYPES {
                     if dsc.vendor_id() == desc.vendor_id && dsc.product_id() == desc.product_id {
 
+                        // opened briefly, a probe in use elsewhere just has no serial
+                        let serial = dev.open().ok()
+                            .and_then(|handle| handle.read_serial_number_string_ascii(&dsc).ok());
+
                         return Some(STLink {
                             connected: false,
                             device : dev,
                             dev_type : *desc,
                             handle : None,
+                            swd_freq : None,
+                            com_freq_khz : None,
+                            firmware_version : None,
+                            usb_errors : UsbErrorCounters::default(),
+                            usb_timeout : Duration::from_millis(200),
+                            interface : 0,
+                            serial,
                         });
                     }
                 }
@@ -103,66 +240,119 @@ impl STLink {
             .collect::<Vec<_>>()
     }
 
-    pub fn connect(&mut self) {
-        let mut handle = self.device.open().unwrap();
+    pub fn connect(&mut self) -> Result<(), rusb::Error> {
+        let mut handle = self.device.open()?;
+
+        let (interface, setting) = find_debug_interface(&self.device, &self.dev_type).unwrap_or((0, 0));
 
-        handle.claim_interface(0).unwrap();
+        handle.claim_interface(interface)?;
+
+        if setting != 0 {
+            handle.set_alternate_setting(interface, setting)?;
+        }
+
+        self.interface = interface;
 
         self.handle = Some(handle);
         
         // self.leave_state();
         self.connected = true;
+
+        Ok(())
     }
 
     pub fn disconnect(&mut self) {
-        let mut handle = core::mem::replace(&mut self.handle, None).unwrap();
-
-        handle.release_interface(0).unwrap();
+        // the device may already have been unplugged
+        if let Some(mut handle) = self.handle.take() {
+            handle.release_interface(self.interface).ok();
+        }
 
         self.connected = false;
     }
 
-    pub fn read(&mut self, buf : &mut [u8]) -> Option<usize> {
+    /// Reads a single bulk response into `buf`, returning the number of bytes actually received,
+    /// which may be less than `buf.len()`.
+    pub fn read(&mut self, buf : &mut [u8]) -> Result<usize, rusb::Error> {
+
+        // a bulk read into a buffer smaller than a packet can overflow, so go through a full packet
+        if buf.len() < STLINK_USB_PACKET_SIZE {
+            let mut packet = [0u8; STLINK_USB_PACKET_SIZE];
 
-        assert!(buf.len() >= 64);
-        assert!(buf.len() % 4 == 0);
+            let n = self.read_bulk_counted(&mut packet)?.min(buf.len());
 
-        if let Some(ref mut handle) = self.handle {
-            let n = handle.read_bulk(self.dev_type.in_pipe, buf, Duration::from_millis(200)).unwrap();
+            buf[..n].copy_from_slice(&packet[..n]);
 
-            Some(n)
+            Ok(n)
         } else {
-            None
+            let n = self.read_bulk_counted(buf)?;
+
+            if n < buf.len() {
+                self.usb_errors.short_reads += 1;
+            }
+
+            Ok(n)
+        }
+    }
+
+    // the response may just be slow, so a timed out read is retried once before giving up
+    fn read_bulk_counted(&mut self, buf : &mut [u8]) -> Result<usize, rusb::Error> {
+        let handle = self.handle.as_mut().ok_or(rusb::Error::NoDevice)?;
+
+        match handle.read_bulk(self.dev_type.in_pipe, buf, self.usb_timeout) {
+            Err(rusb::Error::Timeout) => {
+                self.usb_errors.retries += 1;
+
+                let result = handle.read_bulk(self.dev_type.in_pipe, buf, self.usb_timeout);
+
+                if result == Err(rusb::Error::Timeout) {
+                    self.usb_errors.timeouts += 1;
+                }
+
+                result
+            }
+            result => result,
         }
     }
     
 
-    pub fn write(&mut self, buf : &[u8]) {
+    pub fn write(&mut self, buf : &[u8]) -> Result<(), rusb::Error> {
+        let handle = self.handle.as_mut().ok_or(rusb::Error::NoDevice)?;
 
-        if let Some(ref mut handle) = self.handle {
-            let n = handle.write_bulk(self.dev_type.out_pipe, buf, Duration::from_millis(200)).unwrap();
+        let n = match handle.write_bulk(self.dev_type.out_pipe, buf, self.usb_timeout) {
+            Ok(n) => n,
+            Err(e) => {
+                if e == rusb::Error::Timeout {
+                    self.usb_errors.timeouts += 1;
+                }
+                return Err(e);
+            }
+        };
 
-            assert_eq!(buf.len(), n);
-        } else {
-            panic!()
+        if n != buf.len() {
+            self.usb_errors.short_writes += 1;
+            return Err(rusb::Error::Io);
         }
+
+        Ok(())
     }
 
-    pub fn transfer(&mut self, cmd : &[u8], data : Option<&[u8]>, rx_buf : Option<&mut [u8]>) -> Option<usize> {
+    /// Sends a command and its optional data, then reads the response into `rx_buf` if one is
+    /// given. Returns the number of bytes received, zero when no response was read.
+    pub fn transfer(&mut self, cmd : &[u8], data : Option<&[u8]>, rx_buf : Option<&mut [u8]>) -> Result<usize, rusb::Error> {
         let mut cmd_buf = [0u8; 16];
 
         cmd_buf[..(cmd.len())].copy_from_slice(cmd);
 
-        self.write(&cmd_buf);
+        self.write(&cmd_buf)?;
 
         if let Some(data) = data {
-            self.write(data);
+            self.write(data)?;
         }
 
         if let Some(rx_buf) = rx_buf {
             self.read(rx_buf)
         } else {
-            None
+            Ok(0)
         }
     }
 }
@@ -175,6 +365,38 @@ impl Drop for STLink {
     }
 }
 
+/// What `drive_nrst` does with the target's reset line.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum NrstMode {
+    Low,
+    High,
+    /// Low then high again, a full hardware reset.
+    Pulse,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum StlinkMode {
+    Dfu,
+    Mass,
+    Debug,
+    Swim,
+    Bootloader,
+    Unknown(u8),
+}
+
+impl StlinkMode {
+    fn from_byte(mode : u8) -> StlinkMode {
+        match mode {
+            STLINK_MODE_DFU        => StlinkMode::Dfu,
+            STLINK_MODE_MASS       => StlinkMode::Mass,
+            STLINK_MODE_DEBUG      => StlinkMode::Debug,
+            STLINK_MODE_SWIM       => StlinkMode::Swim,
+            STLINK_MODE_BOOTLOADER => StlinkMode::Bootloader,
+            other                  => StlinkMode::Unknown(other),
+        }
+    }
+}
+
 // STLINK V2 Implementation
 
 const STLINK_GET_VERSION                  : u8 = 0xf1;
@@ -239,6 +461,9 @@ const STLINK_DEBUG_ENTER_SWD              : u8 = 0xa3;
 const STLINK_DEBUG_APIV3_SET_COM_FREQ     : u8 = 0x61;
 const STLINK_DEBUG_APIV3_GET_COM_FREQ     : u8 = 0x62;
 
+const STLINK_V3_COM_SWD                   : u8 = 0x00;
+const STLINK_V3_MAX_FREQ_NB               : usize = 10;
+
 const STLINK_DEBUG_APIV2_DRIVE_NRST_LOW   : u8 = 0x00;
 const STLINK_DEBUG_APIV2_DRIVE_NRST_HIGH  : u8 = 0x01;
 const STLINK_DEBUG_APIV2_DRIVE_NRST_PULSE : u8 = 0x02;
@@ -258,6 +483,7 @@ const STLINK_DEBUG_APIV2_DRIVE_NRST_PULSE : u8 = 0x02;
 //     # 5000:  798
 // }
 
+#[derive(Debug, Clone, Copy, PartialEq)]
 #[repr(u8)]
 pub enum StlinkDebugApiv2SwdFreq {
     Freq4000000 = 0,
@@ -272,249 +498,463 @@ pub enum StlinkDebugApiv2SwdFreq {
     Freq25000   = 158,
 }
 
+// fastest first
+pub const SWD_FREQUENCIES : [StlinkDebugApiv2SwdFreq; 10] = [
+    StlinkDebugApiv2SwdFreq::Freq4000000,
+    StlinkDebugApiv2SwdFreq::Freq1800000,
+    StlinkDebugApiv2SwdFreq::Freq1200000,
+    StlinkDebugApiv2SwdFreq::Freq950000,
+    StlinkDebugApiv2SwdFreq::Freq480000,
+    StlinkDebugApiv2SwdFreq::Freq240000,
+    StlinkDebugApiv2SwdFreq::Freq125000,
+    StlinkDebugApiv2SwdFreq::Freq100000,
+    StlinkDebugApiv2SwdFreq::Freq50000,
+    StlinkDebugApiv2SwdFreq::Freq25000,
+];
+
+impl StlinkDebugApiv2SwdFreq {
+    pub fn hz(&self) -> u32 {
+        match self {
+            StlinkDebugApiv2SwdFreq::Freq4000000 => 4_000_000,
+            StlinkDebugApiv2SwdFreq::Freq1800000 => 1_800_000,
+            StlinkDebugApiv2SwdFreq::Freq1200000 => 1_200_000,
+            StlinkDebugApiv2SwdFreq::Freq950000  =>   950_000,
+            StlinkDebugApiv2SwdFreq::Freq480000  =>   480_000,
+            StlinkDebugApiv2SwdFreq::Freq240000  =>   240_000,
+            StlinkDebugApiv2SwdFreq::Freq125000  =>   125_000,
+            StlinkDebugApiv2SwdFreq::Freq100000  =>   100_000,
+            StlinkDebugApiv2SwdFreq::Freq50000   =>    50_000,
+            StlinkDebugApiv2SwdFreq::Freq25000   =>    25_000,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+pub struct LinkTestResult {
+    pub freq : StlinkDebugApiv2SwdFreq,
+    pub words_tested : usize,
+    pub corrupted_words : usize,
+}
+
+impl LinkTestResult {
+    /// For a frequency where the test couldn't complete at all.
+    pub fn failed(freq : StlinkDebugApiv2SwdFreq) -> LinkTestResult {
+        LinkTestResult {
+            freq,
+            words_tested : SELF_TEST_WORDS as usize,
+            corrupted_words : SELF_TEST_WORDS as usize,
+        }
+    }
+}
+
+pub const SELF_TEST_WORDS                 : u32 = 64;
+
+fn self_test_word(i : u32) -> u32 {
+    match i % 4 {
+        0 => 0xAAAA_AAAA,
+        1 => 0x5555_5555,
+        2 => 1 << (i % 32),
+        _ => !(1 << (i % 32)),
+    }
+}
+
 const STLINK_MAXIMUM_TRANSFER_SIZE        : usize = 1024;
 
 impl STLink {
 
-    pub fn init(&mut self) {
+    /// Reads the probe's firmware version, call after connecting.
+    pub fn init(&mut self) -> Result<(), rusb::Error> {
+        self.firmware_version = Some(self.get_version()?);
+
+        Ok(())
     }
 
-    pub fn leave_state(&mut self) {
+    pub fn get_version(&mut self) -> Result<STLinkFirmwareVersion, rusb::Error> {
         let mut rx_buf = [0u8; 64];
 
-        let n = self.transfer(&[STLINK_GET_CURRENT_MODE], None, Some(&mut rx_buf));
+        let n = self.transfer(&[STLINK_GET_VERSION], None, Some(&mut rx_buf))?;
 
-        assert_eq!(n, Some(2));
+        if n < 6 {
+            return Err(rusb::Error::Io);
+        }
 
-        match rx_buf[0] {
-            n if n == STLINK_MODE_DFU   => {self.transfer(&[STLINK_DFU_COMMAND,   STLINK_DFU_EXIT  ], None, None);}
-            n if n == STLINK_MODE_DEBUG => {self.transfer(&[STLINK_DEBUG_COMMAND, STLINK_DEBUG_EXIT], None, None);}
-            n if n == STLINK_MODE_SWIM  => {self.transfer(&[STLINK_SWIM_COMMAND,  STLINK_SWIM_EXIT ], None, None);}
-            _ => panic!()
+        // stlink, jtag and swim versions packed into 4, 6 and 6 bits, big endian
+        let packed = u16::from_be_bytes([rx_buf[0], rx_buf[1]]);
+
+        let mut version = STLinkFirmwareVersion {
+            stlink : ((packed >> 12) & 0x0f) as u8,
+            jtag : ((packed >> 6) & 0x3f) as u8,
+            swim : (packed & 0x3f) as u8,
+            msd : 0,
+            bridge : 0,
+            vendor_id : u16::from_le_bytes([rx_buf[2], rx_buf[3]]),
+            product_id : u16::from_le_bytes([rx_buf[4], rx_buf[5]]),
+        };
+
+        match self.dev_type.version {
+            // the last field is the mass storage version on V2-1, which has no SWIM
+            STLinkVersion::V2_1 => {
+                version.msd = version.swim;
+                version.swim = 0;
+            }
+            // the short form can't hold V3 versions, the extended command reports them all
+            STLinkVersion::V3 | STLinkVersion::V3E => {
+                let n = self.transfer(&[STLINK_APIV3_GET_VERSION_EX], None, Some(&mut rx_buf))?;
+
+                if n < 12 {
+                    return Err(rusb::Error::Io);
+                }
+
+                version = STLinkFirmwareVersion {
+                    stlink : rx_buf[0],
+                    swim : rx_buf[1],
+                    jtag : rx_buf[2],
+                    msd : rx_buf[3],
+                    bridge : rx_buf[4],
+                    vendor_id : u16::from_le_bytes([rx_buf[8], rx_buf[9]]),
+                    product_id : u16::from_le_bytes([rx_buf[10], rx_buf[11]]),
+                };
+            }
+            STLinkVersion::V2 => {}
         }
+
+        Ok(version)
     }
 
-    pub fn set_swd_freq(&mut self, freq : StlinkDebugApiv2SwdFreq) {
+    pub fn leave_state(&mut self) -> Result<(), rusb::Error> {
         let mut rx_buf = [0u8; 64];
-        self.transfer(&[STLINK_DEBUG_COMMAND, STLINK_DEBUG_APIV2_SWD_SET_FREQ, freq as u8], None, Some(&mut rx_buf));
 
-        assert_eq!(rx_buf[0], 0x80);
+        let n = self.transfer(&[STLINK_GET_CURRENT_MODE], None, Some(&mut rx_buf))?;
+
+        if n != 2 {
+            return Err(rusb::Error::Io);
+        }
+
+        match rx_buf[0] {
+            n if n == STLINK_MODE_DFU   => {self.transfer(&[STLINK_DFU_COMMAND,   STLINK_DFU_EXIT  ], None, None)?;}
+            n if n == STLINK_MODE_DEBUG => {self.transfer(&[STLINK_DEBUG_COMMAND, STLINK_DEBUG_EXIT], None, None)?;}
+            n if n == STLINK_MODE_SWIM  => {self.transfer(&[STLINK_SWIM_COMMAND,  STLINK_SWIM_EXIT ], None, None)?;}
+            n if n == STLINK_MODE_MASS  => {}
+            _ => return Err(rusb::Error::Io),
+        }
+
+        Ok(())
     }
 
-    pub fn enter_debug_swd(&mut self) {
+    /// Fails with `InvalidParam` if the probe rejects the frequency. V3 probes are set to the
+    /// closest frequency they support.
+    pub fn set_swd_freq(&mut self, freq : StlinkDebugApiv2SwdFreq) -> Result<(), rusb::Error> {
+        if self.dev_type.version.is_v3() {
+            self.set_com_freq_v3(freq.hz() / 1000)?;
+            self.swd_freq = Some(freq);
+            return Ok(());
+        }
+
         let mut rx_buf = [0u8; 64];
-        self.transfer(&[STLINK_DEBUG_COMMAND, STLINK_DEBUG_APIV2_ENTER, STLINK_DEBUG_ENTER_SWD], None, Some(&mut rx_buf));
+        self.transfer(&[STLINK_DEBUG_COMMAND, STLINK_DEBUG_APIV2_SWD_SET_FREQ, freq as u8], None, Some(&mut rx_buf))?;
+
+        if rx_buf[0] != 0x80 {
+            return Err(rusb::Error::InvalidParam);
+        }
+
+        self.swd_freq = Some(freq);
+
+        Ok(())
     }
-    
-    pub fn debug_resetsys(&mut self) {
+
+    /// SWD frequencies in kHz that a V3 probe supports, fastest first.
+    pub fn get_com_freq_v3(&mut self) -> Result<Vec<u32>, rusb::Error> {
         let mut rx_buf = [0u8; 64];
-        self.transfer(&[STLINK_DEBUG_COMMAND, STLINK_DEBUG_APIV2_RESETSYS], None, Some(&mut rx_buf));
+        self.transfer(&[STLINK_DEBUG_COMMAND, STLINK_DEBUG_APIV3_GET_COM_FREQ, STLINK_V3_COM_SWD], None, Some(&mut rx_buf))?;
+
+        if rx_buf[0] != 0x80 {
+            return Err(rusb::Error::InvalidParam);
+        }
+
+        let count = (rx_buf[8] as usize).min(STLINK_V3_MAX_FREQ_NB);
+
+        Ok(rx_buf[12..(12 + 4 * count)].chunks(4)
+            .map(|b| u32::from_le_bytes([b[0], b[1], b[2], b[3]]))
+            .collect())
     }
-    
-    pub fn get_mem32(&mut self, addr : u32, size : u32) -> Vec<u8> {
 
-        assert!(addr % 4 == 0);
-        assert!(size % 4 == 0);
-        assert!(size <= STLINK_MAXIMUM_TRANSFER_SIZE as u32);
+    /// Sets the SWD frequency of a V3 probe to the supported frequency closest to `khz`, returning
+    /// the one chosen.
+    pub fn set_com_freq_v3(&mut self, khz : u32) -> Result<u32, rusb::Error> {
+        let available = self.get_com_freq_v3()?;
 
-        let mut cmd = [STLINK_DEBUG_COMMAND, STLINK_DEBUG_READMEM_32BIT, 0,0,0,0, 0,0,0,0];
-        cmd[2..6].copy_from_slice(&addr.to_le_bytes());
-        cmd[6..10].copy_from_slice(&size.to_le_bytes());
+        let chosen = available.iter()
+            .copied()
+            .min_by_key(|f| (*f as i64 - khz as i64).abs())
+            .ok_or(rusb::Error::NotSupported)?;
 
-        let mut rx_buf = core::iter::repeat(0u8).take(size.max(64) as usize).collect::<Vec<_>>();
+        let mut cmd = [STLINK_DEBUG_COMMAND, STLINK_DEBUG_APIV3_SET_COM_FREQ, STLINK_V3_COM_SWD, 0, 0,0,0,0];
+        cmd[4..8].copy_from_slice(&chosen.to_le_bytes());
 
-        let n = self.transfer(&cmd, None, Some(&mut rx_buf)).unwrap();
+        let mut rx_buf = [0u8; 64];
+        self.transfer(&cmd, None, Some(&mut rx_buf))?;
 
-        rx_buf.truncate(n);
+        if rx_buf[0] != 0x80 {
+            return Err(rusb::Error::InvalidParam);
+        }
+
+        self.com_freq_khz = Some(chosen);
 
-        rx_buf
+        Ok(chosen)
     }
-    
-    pub fn set_mem32(&mut self, addr : u32, data : &[u8]) {
 
-        let size = data.len() as u32;
+    /// Writes a test pattern over `SELF_TEST_WORDS` words at `addr` at the given SWD frequency and
+    /// reads it back. The frequency is left at `freq` and the memory is left holding the pattern,
+    /// the caller saves and restores it at a frequency it trusts.
+    pub fn memory_self_test(&mut self, addr : u32, freq : StlinkDebugApiv2SwdFreq) -> Result<LinkTestResult, rusb::Error> {
 
-        assert!(addr % 4 == 0);
-        assert!(size % 4 == 0);
-        assert!(size <= STLINK_MAXIMUM_TRANSFER_SIZE as u32);
+        self.set_swd_freq(freq)?;
 
-        let mut cmd = [STLINK_DEBUG_COMMAND, STLINK_DEBUG_WRITEMEM_32BIT, 0,0,0,0, 0,0,0,0];
-        cmd[2..6 ].copy_from_slice(&addr.to_le_bytes());
-        cmd[6..10].copy_from_slice(&size.to_le_bytes());
+        let pattern = (0..SELF_TEST_WORDS)
+            .flat_map(|i| self_test_word(i).to_le_bytes().to_vec())
+            .collect::<Vec<u8>>();
 
-        self.transfer(&cmd, Some(data), None);
-    }
-    
-    pub fn get_mem16(&mut self, addr : u32, size : u32) -> Vec<u8> {
+        self.set_mem32(addr, &pattern)?;
 
-        assert!(addr % 2 == 0);
-        assert!(size % 2 == 0);
-        assert!(size <= STLINK_MAXIMUM_TRANSFER_SIZE as u32);
+        let readback = self.get_mem32(addr, pattern.len() as u32)?;
 
-        let mut cmd = [STLINK_DEBUG_COMMAND, STLINK_DEBUG_APIV2_READMEM_16BIT, 0,0,0,0, 0,0,0,0];
-        cmd[2..6].copy_from_slice(&addr.to_le_bytes());
-        cmd[6..10].copy_from_slice(&size.to_le_bytes());
+        let mismatched = pattern.chunks(4)
+            .zip(readback.chunks(4))
+            .filter(|(expected, read)| expected != read)
+            .count();
 
-        let mut rx_buf = core::iter::repeat(0u8).take(size as usize).collect::<Vec<_>>();
+        let missing = (pattern.len() - readback.len().min(pattern.len())) / 4;
 
-        let n = self.transfer(&cmd, None, Some(&mut rx_buf)).unwrap();
+        Ok(LinkTestResult {
+            freq,
+            words_tested : SELF_TEST_WORDS as usize,
+            corrupted_words : mismatched + missing,
+        })
+    }
 
-        rx_buf.truncate(n);
+    pub fn get_current_mode(&mut self) -> Result<Option<StlinkMode>, rusb::Error> {
+        let mut rx_buf = [0u8; 64];
 
-        rx_buf
+        match self.transfer(&[STLINK_GET_CURRENT_MODE], None, Some(&mut rx_buf))? {
+            n if n >= 1 => Ok(Some(StlinkMode::from_byte(rx_buf[0]))),
+            _ => Ok(None),
+        }
     }
-    
-    pub fn set_mem16(&mut self, addr : u32, size : u32, data : &[u8]) {
 
-        assert!(addr % 2 == 0);
-        assert!(size % 2 == 0);
-        assert!(size <= STLINK_MAXIMUM_TRANSFER_SIZE as u32);
+    /// Target supply voltage as measured by the probe, from the probe's ADC reading of VREF and
+    /// the halved target voltage.
+    pub fn get_target_voltage(&mut self) -> Result<f32, rusb::Error> {
+        let mut rx_buf = [0u8; 64];
 
-        let mut cmd = [STLINK_DEBUG_COMMAND, STLINK_DEBUG_APIV2_WRITEMEM_16BIT, 0,0,0,0, 0,0,0,0];
-        cmd[2..6 ].copy_from_slice(&addr.to_le_bytes());
-        cmd[6..10].copy_from_slice(&size.to_le_bytes());
+        let n = self.transfer(&[STLINK_GET_TARGET_VOLTAGE], None, Some(&mut rx_buf))?;
 
-        self.transfer(&cmd, Some(data), None);
-    }
+        if n < 8 {
+            return Err(rusb::Error::Io);
+        }
 
-    pub fn read_struct<T : Clone>(&mut self, addr : u32) -> T {
+        let adc0 = u32::from_le_bytes([rx_buf[0], rx_buf[1], rx_buf[2], rx_buf[3]]);
+        let adc1 = u32::from_le_bytes([rx_buf[4], rx_buf[5], rx_buf[6], rx_buf[7]]);
 
-        let mut len = size_of::<T>();
-        let mut buffer = Vec::with_capacity(len);
-        let mut offset = 0;
+        if adc0 == 0 {
+            return Ok(0.0);
+        }
 
-        loop {
-            let n = len.min(STLINK_MAXIMUM_TRANSFER_SIZE);
-            let data = self.get_mem32(addr + offset, n as u32);
+        Ok(2.0 * adc1 as f32 * 1.2 / adc0 as f32)
+    }
 
-            buffer.extend(data.into_iter());
+    /// Checks whether the last memory read/write succeeded on the target side.
+    pub fn get_last_rw_status(&mut self) -> Result<bool, rusb::Error> {
+        let mut rx_buf = [0u8; 64];
+        self.transfer(&[STLINK_DEBUG_COMMAND, STLINK_DEBUG_APIV2_GETLASTRWSTATUS], None, Some(&mut rx_buf))?;
 
-            if len < STLINK_MAXIMUM_TRANSFER_SIZE {
-                break;
-            }
+        let ok = rx_buf[0] == 0x80;
 
-            len -= STLINK_MAXIMUM_TRANSFER_SIZE;
-            offset += STLINK_MAXIMUM_TRANSFER_SIZE as u32;
+        if !ok {
+            self.usb_errors.rw_status_failures += 1;
         }
-        
-        unsafe {
-            let (head, item, _) = buffer.align_to::<T>();
 
-            assert!(head.is_empty());
+        Ok(ok)
+    }
 
-            item[0].clone()
-        }
+    pub fn exit_debug(&mut self) -> Result<(), rusb::Error> {
+        self.transfer(&[STLINK_DEBUG_COMMAND, STLINK_DEBUG_EXIT], None, None)?;
+        Ok(())
     }
 
-    pub fn read_struct_array<T : Clone>(&mut self, addr : u32, len : u32) -> Vec<T> {
+    pub fn enter_debug_swd(&mut self) -> Result<(), rusb::Error> {
+        // V3 probes need the clock set before entering, V2 probes start at 1.8MHz on their own
+        if self.dev_type.version.is_v3() {
+            let freq = self.swd_freq.unwrap_or(StlinkDebugApiv2SwdFreq::Freq1800000);
+            self.set_com_freq_v3(freq.hz() / 1000)?;
+        }
+
+        let mut rx_buf = [0u8; 64];
+        self.transfer(&[STLINK_DEBUG_COMMAND, STLINK_DEBUG_APIV2_ENTER, STLINK_DEBUG_ENTER_SWD], None, Some(&mut rx_buf))?;
+        Ok(())
+    }
+    
+    /// The SW-DP IDCODE of the target, read with the V1 API command.
+    pub fn read_core_id(&mut self) -> Result<u32, rusb::Error> {
+        let mut rx_buf = [0u8; 64];
 
+        let n = self.transfer(&[STLINK_DEBUG_COMMAND, STLINK_DEBUG_READCOREID], None, Some(&mut rx_buf))?;
 
-        let mut len = size_of::<T>() * len as usize;
-        let mut buffer = Vec::with_capacity(len);
-        let mut offset = 0;
+        if n < 4 {
+            return Err(rusb::Error::Io);
+        }
 
-        loop {
-            let n = len.min(STLINK_MAXIMUM_TRANSFER_SIZE);
-            let data = self.get_mem32(addr + offset, n as u32);
+        Ok(u32::from_le_bytes([rx_buf[0], rx_buf[1], rx_buf[2], rx_buf[3]]))
+    }
 
-            buffer.extend(data.into_iter());
+    /// The two ID codes the probe reports after the status word, the first is the SW-DP IDCODE.
+    pub fn read_idcodes(&mut self) -> Result<[u32; 2], rusb::Error> {
+        let mut rx_buf = [0u8; 64];
 
-            if len < STLINK_MAXIMUM_TRANSFER_SIZE {
-                break;
-            }
+        let n = self.transfer(&[STLINK_DEBUG_COMMAND, STLINK_DEBUG_APIV2_READ_IDCODES], None, Some(&mut rx_buf))?;
 
-            len -= STLINK_MAXIMUM_TRANSFER_SIZE;
-            offset += STLINK_MAXIMUM_TRANSFER_SIZE as u32;
+        if n < 12 || rx_buf[0] != 0x80 {
+            return Err(rusb::Error::Io);
         }
-        
 
-        unsafe {
-            let (head, items, tail) = buffer.align_to::<T>();
+        let word = |i : usize| u32::from_le_bytes([rx_buf[i], rx_buf[i + 1], rx_buf[i + 2], rx_buf[i + 3]]);
 
-            assert!(head.is_empty());
-            assert!(tail.is_empty());
+        Ok([word(4), word(8)])
+    }
+
+    /// Drives the target's NRST pin, which resets it even when the core is locked up.
+    pub fn drive_nrst(&mut self, mode : NrstMode) -> Result<(), rusb::Error> {
+        let mode = match mode {
+            NrstMode::Low => STLINK_DEBUG_APIV2_DRIVE_NRST_LOW,
+            NrstMode::High => STLINK_DEBUG_APIV2_DRIVE_NRST_HIGH,
+            NrstMode::Pulse => STLINK_DEBUG_APIV2_DRIVE_NRST_PULSE,
+        };
+
+        let mut rx_buf = [0u8; 64];
+        self.transfer(&[STLINK_DEBUG_COMMAND, STLINK_DEBUG_APIV2_DRIVE_NRST, mode], None, Some(&mut rx_buf))?;
 
-            items.to_vec()
+        if rx_buf[0] != 0x80 {
+            return Err(rusb::Error::Io);
         }
+
+        Ok(())
+    }
+
+    pub fn debug_resetsys(&mut self) -> Result<(), rusb::Error> {
+        let mut rx_buf = [0u8; 64];
+        self.transfer(&[STLINK_DEBUG_COMMAND, STLINK_DEBUG_APIV2_RESETSYS], None, Some(&mut rx_buf))?;
+        Ok(())
     }
     
-    pub fn read_struct_array_with_offset<T : Clone>(&mut self, addr : u32, len : u32, offset : u32) -> Vec<T> {
+    pub fn get_mem32(&mut self, addr : u32, size : u32) -> Result<Vec<u8>, rusb::Error> {
 
+        // unaligned or oversized accesses come from user entered addresses, so they're errors rather than bugs
+        if addr % 4 != 0 || size % 4 != 0 || size > STLINK_MAXIMUM_TRANSFER_SIZE as u32 {
+            return Err(rusb::Error::InvalidParam);
+        }
 
-        let mut len = size_of::<T>() * len as usize;
-        let mut buffer = Vec::with_capacity(len);
-        let mut offset = size_of::<T>() as u32 * offset;
+        let mut cmd = [STLINK_DEBUG_COMMAND, STLINK_DEBUG_READMEM_32BIT, 0,0,0,0, 0,0,0,0];
+        cmd[2..6].copy_from_slice(&addr.to_le_bytes());
+        cmd[6..10].copy_from_slice(&size.to_le_bytes());
 
-        loop {
-            let n = len.min(STLINK_MAXIMUM_TRANSFER_SIZE);
-            let data = self.get_mem32(addr + offset, n as u32);
+        let mut rx_buf = core::iter::repeat(0u8).take(size as usize).collect::<Vec<_>>();
 
-            buffer.extend(data.into_iter());
+        let n = self.transfer(&cmd, None, Some(&mut rx_buf))?;
 
-            if len < STLINK_MAXIMUM_TRANSFER_SIZE {
-                break;
-            }
+        rx_buf.truncate(n);
+
+        Ok(rx_buf)
+    }
+    
+    pub fn set_mem32(&mut self, addr : u32, data : &[u8]) -> Result<(), rusb::Error> {
 
-            len -= STLINK_MAXIMUM_TRANSFER_SIZE;
-            offset += STLINK_MAXIMUM_TRANSFER_SIZE as u32;
+        let size = data.len() as u32;
+
+        if addr % 4 != 0 || size % 4 != 0 || size > STLINK_MAXIMUM_TRANSFER_SIZE as u32 {
+            return Err(rusb::Error::InvalidParam);
         }
-        
 
-        unsafe {
-            let (head, items, tail) = buffer.align_to::<T>();
+        let mut cmd = [STLINK_DEBUG_COMMAND, STLINK_DEBUG_WRITEMEM_32BIT, 0,0,0,0, 0,0,0,0];
+        cmd[2..6 ].copy_from_slice(&addr.to_le_bytes());
+        cmd[6..10].copy_from_slice(&size.to_le_bytes());
 
-            assert!(head.is_empty());
-            assert!(tail.is_empty());
+        self.transfer(&cmd, Some(data), None)?;
+        Ok(())
+    }
+    
+    pub fn get_mem16(&mut self, addr : u32, size : u32) -> Result<Vec<u8>, rusb::Error> {
 
-            items.to_vec()
+        if addr % 2 != 0 || size % 2 != 0 || size > STLINK_MAXIMUM_TRANSFER_SIZE as u32 {
+            return Err(rusb::Error::InvalidParam);
         }
-    }
 
-    pub fn write_struct<T>(&mut self, addr : u32, item : T) {
+        let mut cmd = [STLINK_DEBUG_COMMAND, STLINK_DEBUG_APIV2_READMEM_16BIT, 0,0,0,0, 0,0,0,0];
+        cmd[2..6].copy_from_slice(&addr.to_le_bytes());
+        cmd[6..10].copy_from_slice(&size.to_le_bytes());
+
+        let mut rx_buf = core::iter::repeat(0u8).take(size as usize).collect::<Vec<_>>();
 
-        let array = [item];
-        let (_, data, _) = unsafe{ array.align_to::<u8>()};
+        let n = self.transfer(&cmd, None, Some(&mut rx_buf))?;
 
-        self.set_mem32(addr, data);
+        rx_buf.truncate(n);
+
+        Ok(rx_buf)
     }
     
-    pub fn write_struct_array<T>(&mut self, addr : u32, items : &[T]) {
-
-        let (_, buffer, _) = unsafe{items.align_to::<u8>()};
-        let mut offset = 0usize;
-        let addr_offset = 0usize;
-        let mut len = buffer.len();
+    pub fn set_mem16(&mut self, addr : u32, size : u32, data : &[u8]) -> Result<(), rusb::Error> {
 
-        loop {
-            let n = len.min(STLINK_MAXIMUM_TRANSFER_SIZE);
-            self.set_mem32(addr + addr_offset as u32 + offset as u32, &buffer[offset..(offset+n)]);
+        if addr % 2 != 0 || size % 2 != 0 || size > STLINK_MAXIMUM_TRANSFER_SIZE as u32 {
+            return Err(rusb::Error::InvalidParam);
+        }
 
-            if len < STLINK_MAXIMUM_TRANSFER_SIZE {
-                break;
-            }
+        let mut cmd = [STLINK_DEBUG_COMMAND, STLINK_DEBUG_APIV2_WRITEMEM_16BIT, 0,0,0,0, 0,0,0,0];
+        cmd[2..6 ].copy_from_slice(&addr.to_le_bytes());
+        cmd[6..10].copy_from_slice(&size.to_le_bytes());
 
-            len -= STLINK_MAXIMUM_TRANSFER_SIZE;
-            offset += STLINK_MAXIMUM_TRANSFER_SIZE;
-        }
+        self.transfer(&cmd, Some(data), None)?;
+        Ok(())
     }
-    
-    pub fn write_struct_array_offset<T>(&mut self, addr : u32, offset : u32, items : &[T]) {
-        
-        let (_, buffer, _) = unsafe{items.align_to::<u8>()};
-        let addr = addr + offset * size_of::<T>() as u32;
-        let mut offset = 0usize;
-        let mut len = buffer.len();
 
-        loop {
-            let n = len.min(STLINK_MAXIMUM_TRANSFER_SIZE);
-            self.set_mem32(addr + offset as u32, &buffer[offset..(offset+n)]);
+    /// Reads `len` bytes from any address, splitting the read into transfers the probe accepts.
+    /// Regions that reject word access are retried with half-word reads. A range that runs past the
+    /// top of the address space is rejected with `InvalidParam`.
+    pub fn read_bytes(&mut self, addr : u32, len : u32) -> Result<Vec<u8>, rusb::Error> {
+
+        let start = addr & !3;
+        let end = addr.checked_add(len)
+            .and_then(|end| end.checked_add(3))
+            .ok_or(rusb::Error::InvalidParam)? & !3;
+
+        let mut buffer = Vec::with_capacity((end - start) as usize);
+        let mut chunk_addr = start;
+
+        while chunk_addr < end {
+            let n = (end - chunk_addr).min(STLINK_MAXIMUM_TRANSFER_SIZE as u32);
+            let mut data = self.get_mem32(chunk_addr, n)?;
 
-            if len < STLINK_MAXIMUM_TRANSFER_SIZE {
-                break;
+            if data.len() < n as usize {
+                data = self.get_mem16(chunk_addr, n)?;
             }
 
-            len -= STLINK_MAXIMUM_TRANSFER_SIZE;
-            offset += STLINK_MAXIMUM_TRANSFER_SIZE;
+            data.resize(n as usize, 0);
+            buffer.extend(data.into_iter());
+
+            chunk_addr += n;
         }
+
+        let skip = (addr - start) as usize;
+
+        let mut bytes = buffer.split_off(skip);
+        bytes.truncate(len as usize);
+        Ok(bytes)
+    }
+}
+
+impl MemoryAccess for STLink {
+    fn get_mem32(&mut self, addr : u32, size : u32) -> Result<Vec<u8>, rusb::Error> {
+        STLink::get_mem32(self, addr, size)
     }
-}
\ No newline at end of file
+
+    fn set_mem32(&mut self, addr : u32, data : &[u8]) -> Result<(), rusb::Error> {
+        STLink::set_mem32(self, addr, data)
+    }
+}
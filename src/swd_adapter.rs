@@ -0,0 +1,108 @@
+use std::time::Duration;
+use std::time::Instant;
+
+use crate::debug_probe::DebugProbe;
+use crate::stlink::StlinkDebugApiv2SwdFreq;
+use crate::stlink::SWD_FREQUENCIES;
+
+#[derive(Debug, Clone)]
+pub struct AdaptiveSwdSettings {
+    /// Set when connecting, and lowered step by step if the controller can't be read at it.
+    pub initial_freq : StlinkDebugApiv2SwdFreq,
+    pub enabled : bool,
+    /// Errors per check interval above which the frequency is lowered one step.
+    pub error_threshold : u32,
+    /// Slowest frequency the adapter will drop to.
+    pub floor : StlinkDebugApiv2SwdFreq,
+    /// Step back up after this long without errors, `None` to only ever step down.
+    pub step_up_after : Option<Duration>,
+}
+
+impl Default for AdaptiveSwdSettings {
+    fn default() -> Self {
+        AdaptiveSwdSettings {
+            initial_freq : StlinkDebugApiv2SwdFreq::Freq1800000,
+            enabled : false,
+            error_threshold : 2,
+            floor : StlinkDebugApiv2SwdFreq::Freq100000,
+            step_up_after : Some(Duration::from_secs(60)),
+        }
+    }
+}
+
+const CHECK_INTERVAL : Duration = Duration::from_secs(1);
+
+pub struct SwdAdapter {
+    // fastest frequency it may step back up to, the one in use when adapting started
+    ceiling : StlinkDebugApiv2SwdFreq,
+    last_check : Instant,
+    last_error_total : u32,
+    stable_since : Instant,
+}
+
+impl SwdAdapter {
+    pub fn new(link : &dyn DebugProbe) -> SwdAdapter {
+        SwdAdapter {
+            ceiling : link.swd_freq().unwrap_or(StlinkDebugApiv2SwdFreq::Freq1800000),
+            last_check : Instant::now(),
+            last_error_total : link.usb_errors().total(),
+            stable_since : Instant::now(),
+        }
+    }
+
+    /// Checks the link's error counters and changes the SWD frequency if needed, returning the
+    /// old and new frequencies when it does.
+    pub fn update(&mut self, settings : &AdaptiveSwdSettings, link : &mut dyn DebugProbe) -> Result<Option<(StlinkDebugApiv2SwdFreq, StlinkDebugApiv2SwdFreq)>, rusb::Error> {
+
+        if self.last_check.elapsed() < CHECK_INTERVAL {
+            return Ok(None);
+        }
+
+        self.last_check = Instant::now();
+
+        // counters may have been reset from the diagnostics window
+        let total = link.usb_errors().total();
+        let new_errors = total.saturating_sub(self.last_error_total);
+        self.last_error_total = total;
+
+        if !settings.enabled {
+            self.ceiling = link.swd_freq().unwrap_or(self.ceiling);
+            self.stable_since = Instant::now();
+            return Ok(None);
+        }
+
+        let current = link.swd_freq().unwrap_or(StlinkDebugApiv2SwdFreq::Freq1800000);
+        let index = match SWD_FREQUENCIES.iter().position(|f| *f == current) {
+            Some(index) => index,
+            None => return Ok(None),
+        };
+
+        let target = if new_errors > settings.error_threshold {
+            self.stable_since = Instant::now();
+
+            SWD_FREQUENCIES.get(index + 1)
+                .filter(|f| f.hz() >= settings.floor.hz())
+                .copied()
+        } else if new_errors > 0 {
+            self.stable_since = Instant::now();
+            None
+        } else {
+            match settings.step_up_after {
+                Some(period) if self.stable_since.elapsed() >= period && current.hz() < self.ceiling.hz() => {
+                    self.stable_since = Instant::now();
+                    index.checked_sub(1).map(|i| SWD_FREQUENCIES[i])
+                }
+                _ => None,
+            }
+        };
+
+        let target = match target {
+            Some(target) => target,
+            None => return Ok(None),
+        };
+
+        link.set_swd_freq(target)?;
+
+        Ok(Some((current, target)))
+    }
+}
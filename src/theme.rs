@@ -0,0 +1,64 @@
+use serde::Deserialize;
+use serde::Serialize;
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Theme {
+    Dark,
+    Light,
+    Classic,
+}
+
+pub const THEMES : [Theme; 3] = [Theme::Dark, Theme::Light, Theme::Classic];
+
+impl Theme {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Theme::Dark => "Dark",
+            Theme::Light => "Light",
+            Theme::Classic => "Classic",
+        }
+    }
+
+    pub fn apply(&self, style : &mut imgui::Style) {
+        match self {
+            Theme::Dark => style.use_dark_colors(),
+            Theme::Light => style.use_light_colors(),
+            Theme::Classic => style.use_classic_colors(),
+        };
+    }
+
+    pub fn plot_background(&self) -> [f32; 4] {
+        match self {
+            Theme::Light => [0.97, 0.97, 0.97, 1.0],
+            _ => [0.05, 0.05, 0.05, 1.0],
+        }
+    }
+
+    pub fn plot_grid(&self) -> [f32; 4] {
+        match self {
+            Theme::Light => [0.75, 0.75, 0.75, 0.6],
+            _ => [0.3, 0.3, 0.3, 0.5],
+        }
+    }
+
+    pub fn plot_zero_line(&self) -> [f32; 4] {
+        match self {
+            Theme::Light => [0.35, 0.35, 0.35, 0.8],
+            _ => [0.7, 0.7, 0.7, 0.8],
+        }
+    }
+
+    /// Color of text and markers drawn over the plot, packed as ABGR for the draw list.
+    pub fn plot_text(&self) -> u32 {
+        match self {
+            Theme::Light => 0xFF404040,
+            _ => 0xFFB0B0B0,
+        }
+    }
+}
+
+impl Default for Theme {
+    fn default() -> Self {
+        Theme::Dark
+    }
+}
@@ -0,0 +1,121 @@
+use crate::controller_interface::OscilloscopeSamplePoint;
+use crate::controller_interface::SAMPLE_CHANNELS;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TriggerSource {
+    RisingEdge,
+    FallingEdge,
+    Fault,
+}
+
+pub const TRIGGER_SOURCES : [TriggerSource; 3] = [TriggerSource::RisingEdge, TriggerSource::FallingEdge, TriggerSource::Fault];
+
+impl TriggerSource {
+    pub fn name(&self) -> &'static str {
+        match self {
+            TriggerSource::RisingEdge => "Rising Edge",
+            TriggerSource::FallingEdge => "Falling Edge",
+            TriggerSource::Fault => "Fault",
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum TriggerState {
+    Idle,
+    Armed,
+    Triggered {
+        remaining : usize,
+    },
+    Captured,
+}
+
+#[derive(Debug, Clone)]
+pub struct Trigger {
+    pub source : TriggerSource,
+    pub channel : usize,
+    pub level : f32,
+    pub post_trigger_samples : usize,
+    pub state : TriggerState,
+    last_value : Option<f32>,
+}
+
+impl Default for Trigger {
+    fn default() -> Self {
+        Trigger {
+            source : TriggerSource::Fault,
+            channel : 0,
+            level : 0.0,
+            post_trigger_samples : 500,
+            state : TriggerState::Idle,
+            last_value : None,
+        }
+    }
+}
+
+impl Trigger {
+    pub fn arm(&mut self) {
+        self.state = TriggerState::Armed;
+        self.last_value = None;
+    }
+
+    pub fn disarm(&mut self) {
+        self.state = TriggerState::Idle;
+    }
+
+    fn fire(&mut self) {
+        self.state = if self.post_trigger_samples == 0 {
+            TriggerState::Captured
+        } else {
+            TriggerState::Triggered { remaining : self.post_trigger_samples }
+        };
+    }
+
+    /// Feeds newly recorded samples through the trigger, returning true if the post-trigger
+    /// capture completed during this call.
+    pub fn process_samples(&mut self, samples : &[OscilloscopeSamplePoint]) -> bool {
+
+        let func = SAMPLE_CHANNELS[self.channel].1;
+
+        for pt in samples {
+            match self.state {
+                TriggerState::Armed => {
+                    let value = func(pt);
+
+                    let fired = match (self.source, self.last_value) {
+                        (TriggerSource::RisingEdge, Some(last)) => last < self.level && value >= self.level,
+                        (TriggerSource::FallingEdge, Some(last)) => last > self.level && value <= self.level,
+                        _ => false,
+                    };
+
+                    self.last_value = Some(value);
+
+                    if fired {
+                        self.fire();
+                    }
+                }
+                TriggerState::Triggered { remaining } if remaining > 1 => {
+                    self.state = TriggerState::Triggered { remaining : remaining - 1 };
+                }
+                TriggerState::Triggered { .. } => {
+                    self.state = TriggerState::Captured;
+                    return true;
+                }
+                TriggerState::Idle | TriggerState::Captured => break,
+            }
+        }
+
+        false
+    }
+
+    /// Returns true if the trigger fired with no post-trigger samples to wait for.
+    pub fn process_fault(&mut self, fault : bool) -> bool {
+        if fault && self.source == TriggerSource::Fault && self.state == TriggerState::Armed {
+            self.fire();
+
+            return self.state == TriggerState::Captured;
+        }
+
+        false
+    }
+}
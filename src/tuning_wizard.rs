@@ -0,0 +1,223 @@
+use std::sync::Arc;
+use std::sync::atomic::AtomicBool;
+use std::sync::atomic::Ordering;
+
+use crate::controller_commands::Command;
+use crate::controller_interface::*;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WizardStep {
+    VelocityGain,
+    VelocityIntegrator,
+    PositionGain,
+    Done,
+}
+
+pub const WIZARD_STEPS : [WizardStep; 4] = [
+    WizardStep::VelocityGain,
+    WizardStep::VelocityIntegrator,
+    WizardStep::PositionGain,
+    WizardStep::Done,
+];
+
+impl WizardStep {
+    pub fn title(&self) -> &'static str {
+        match self {
+            WizardStep::VelocityGain => "1. Velocity Gain",
+            WizardStep::VelocityIntegrator => "2. Velocity Integrator",
+            WizardStep::PositionGain => "3. Position Gain",
+            WizardStep::Done => "Done",
+        }
+    }
+
+    pub fn instructions(&self) -> &'static str {
+        match self {
+            WizardStep::VelocityGain =>
+                "With the integrator at zero, raise the velocity gain until the velocity follows the square wave \
+                 quickly, then back off if it overshoots or buzzes.",
+            WizardStep::VelocityIntegrator =>
+                "Raise the integrator gain until the steady state error goes away, stopping before the overshoot grows.",
+            WizardStep::PositionGain =>
+                "Raise the position gain until the position step is fast, keeping overshoot small.",
+            WizardStep::Done =>
+                "Tuning complete. Save the config to keep these gains after a reset.",
+        }
+    }
+
+    /// Control mode the step runs the motor in.
+    pub fn control_mode(&self) -> Option<Command> {
+        match self {
+            WizardStep::VelocityGain | WizardStep::VelocityIntegrator => Some(Command::SetVelocityControl),
+            WizardStep::PositionGain => Some(Command::SetPositionControl),
+            WizardStep::Done => None,
+        }
+    }
+
+    /// Config parameter tuned in this step, as (name, offset).
+    pub fn parameter(&self) -> Option<(&'static str, u32)> {
+        match self {
+            WizardStep::VelocityGain => Some(("Velocity Gain", OFFSET_VELOCITY_GAIN)),
+            WizardStep::VelocityIntegrator => Some(("Velocity Integrator Gain", OFFSET_VELOCITY_INTEGRATOR_GAIN)),
+            WizardStep::PositionGain => Some(("Position Gain", OFFSET_POSITION_GAIN)),
+            WizardStep::Done => None,
+        }
+    }
+
+    pub fn parameter_value(&self, config : &ServoConfig) -> f32 {
+        match self {
+            WizardStep::VelocityGain => config.velocity_gain,
+            WizardStep::VelocityIntegrator => config.velocity_integrator_gain,
+            WizardStep::PositionGain => config.position_gain,
+            WizardStep::Done => 0.0,
+        }
+    }
+
+    /// Command used to drive the test signal.
+    pub fn excitation(&self) -> fn(f32) -> InterfaceCommand {
+        match self {
+            WizardStep::VelocityGain | WizardStep::VelocityIntegrator =>
+                |velocity| InterfaceCommand::SendCommand(Command::VelocityCommand{velocity}),
+            _ => InterfaceCommand::PositionCommand,
+        }
+    }
+
+    /// Setpoint and measured channels the step response is computed from.
+    pub fn response_channels(&self) -> (fn(&OscilloscopeSamplePoint) -> f32, fn(&OscilloscopeSamplePoint) -> f32) {
+        match self {
+            WizardStep::VelocityGain | WizardStep::VelocityIntegrator => (|p| p.vel_setpoint, |p| p.vel),
+            _ => (|p| p.pos_setpoint, |p| p.pos),
+        }
+    }
+
+    pub fn next(&self) -> WizardStep {
+        let index = WIZARD_STEPS.iter().position(|s| s == self).unwrap_or(0);
+
+        WIZARD_STEPS[(index + 1).min(WIZARD_STEPS.len() - 1)]
+    }
+}
+
+pub struct TuningWizard {
+    pub step : WizardStep,
+    pub amplitude : f32,
+    pub period : f32,
+    /// Value being tried for the current step's parameter.
+    pub candidate : f32,
+    /// Set while the test signal is running.
+    pub excitation : Option<Arc<AtomicBool>>,
+    /// Set while the panel is open, so the candidate is seeded when it's opened.
+    pub active : bool,
+    /// Integrator gain from before step 1 zeroed it, restored if the wizard is closed first.
+    integrator_gain : Option<f32>,
+}
+
+impl Default for TuningWizard {
+    fn default() -> Self {
+        TuningWizard {
+            step : WizardStep::VelocityGain,
+            amplitude : 1.0,
+            period : 1.0,
+            candidate : 0.0,
+            excitation : None,
+            active : false,
+            integrator_gain : None,
+        }
+    }
+}
+
+impl TuningWizard {
+    pub fn excitation_running(&self) -> bool {
+        self.excitation.as_ref().map_or(false, |running| running.load(Ordering::Relaxed))
+    }
+
+    pub fn stop_excitation(&mut self) {
+        if let Some(running) = self.excitation.take() {
+            running.store(false, Ordering::Relaxed);
+        }
+    }
+
+    /// Moves to `step`, starting its candidate from the value currently on the controller.
+    /// Returns the config change the step needs before it starts.
+    pub fn go_to(&mut self, step : WizardStep, config : &ServoConfig) -> Option<InterfaceCommand> {
+        self.stop_excitation();
+        self.step = step;
+        self.candidate = step.parameter_value(config);
+
+        match step {
+            // the velocity gain is tuned with the integrator out of the loop
+            WizardStep::VelocityGain => {
+                if self.integrator_gain.is_none() {
+                    self.integrator_gain = Some(config.velocity_integrator_gain);
+                }
+                Some(InterfaceCommand::UpdateConfigParameter(OFFSET_VELOCITY_INTEGRATOR_GAIN, 0.0))
+            }
+            // carries on from the gain that step 1 zeroed
+            WizardStep::VelocityIntegrator => {
+                if let Some(gain) = self.integrator_gain.take() {
+                    self.candidate = gain;
+                }
+                None
+            }
+            _ => None,
+        }
+    }
+
+    /// Starts the current step again with the controller's values when the panel is opened.
+    pub fn open(&mut self, config : &ServoConfig) -> Option<InterfaceCommand> {
+        self.active = true;
+        self.go_to(self.step, config)
+    }
+
+    /// Returns the change that puts back the integrator gain if step 1 zeroed it.
+    pub fn close(&mut self) -> Option<InterfaceCommand> {
+        self.stop_excitation();
+        self.active = false;
+
+        self.integrator_gain.take()
+            .map(|gain| InterfaceCommand::UpdateConfigParameter(OFFSET_VELOCITY_INTEGRATOR_GAIN, gain))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn config() -> ServoConfig {
+        ServoConfig {
+            velocity_gain : 0.02,
+            velocity_integrator_gain : 1.5,
+            ..ServoConfig::default()
+        }
+    }
+
+    #[test]
+    fn opening_seeds_the_candidate_and_zeroes_the_integrator() {
+        let mut wizard = TuningWizard::default();
+
+        let cmd = wizard.open(&config());
+
+        assert_eq!(wizard.candidate, 0.02);
+        assert!(matches!(cmd, Some(InterfaceCommand::UpdateConfigParameter(OFFSET_VELOCITY_INTEGRATOR_GAIN, gain)) if gain == 0.0));
+    }
+
+    #[test]
+    fn integrator_step_starts_from_the_zeroed_gain() {
+        let mut wizard = TuningWizard::default();
+        wizard.open(&config());
+
+        let zeroed = ServoConfig { velocity_integrator_gain : 0.0, ..config() };
+        assert!(wizard.go_to(WizardStep::VelocityIntegrator, &zeroed).is_none());
+        assert_eq!(wizard.candidate, 1.5);
+        assert!(wizard.close().is_none());
+    }
+
+    #[test]
+    fn closing_during_step_one_restores_the_integrator() {
+        let mut wizard = TuningWizard::default();
+        wizard.open(&config());
+
+        let cmd = wizard.close();
+
+        assert!(!wizard.active);
+        assert!(matches!(cmd, Some(InterfaceCommand::UpdateConfigParameter(OFFSET_VELOCITY_INTEGRATOR_GAIN, gain)) if gain == 1.5));
+    }
+}
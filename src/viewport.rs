@@ -34,11 +34,20 @@ impl Viewport {
     }
 
     pub fn update(&mut self, system : &mut System, width : u32, height : u32) {
+        // a collapsed plot can report a zero sized region, which isn't a valid image size
+        let width = width.max(1);
+        let height = height.max(1);
+
         if self.width != width || self.height != height {
 
             self.width = width;
             self.height = height;
 
+            // without images the plot just isn't drawn until the next resize tries again
+            self.image = None;
+            self.depth_image = None;
+            self.msaa_image = None;
+
             let image =
                 StorageImage::with_usage(
                     system.device.clone(), 
@@ -51,24 +60,17 @@ impl Viewport {
                     }, 
                     ImageCreateFlags::default(),
                     vec![system.queue.family()]
-                ).expect("Failed to create viewport storage image");
-
-            if self.texture_id == None {
-
-                let texture_id = system.renderer.textures().insert((ImageView::new(image.clone()).unwrap(), Sampler::simple_repeat_linear(system.device.clone())));
-                self.texture_id = Some(texture_id);
-            } else {
-
-                system.renderer.textures().replace(self.texture_id.unwrap(), (ImageView::new(image.clone()).unwrap(), Sampler::simple_repeat_linear(system.device.clone())));
-            }
+                ).map_err(|e| format!("Failed to create viewport storage image: {:?}", e));
 
+            let image_view = image.clone()
+                .and_then(|image| ImageView::new(image).map_err(|e| format!("Failed to create viewport image view: {:?}", e)));
 
             let depth_buffer = AttachmentImage::transient_multisampled_input_attachment(
                 system.device.clone(), 
                 [width, height],
                 vulkano::image::SampleCount::Sample4,
                 Format::D16Unorm
-            ).unwrap();
+            ).map_err(|e| format!("Failed to create viewport depth buffer: {:?}", e));
 
 
             let msaa_buffer = AttachmentImage::transient_multisampled_input_attachment(
@@ -76,7 +78,27 @@ impl Viewport {
                 [width, height],
                 vulkano::image::SampleCount::Sample4,
                 Format::R8G8B8A8Unorm
-            ).unwrap();
+            ).map_err(|e| format!("Failed to create viewport msaa buffer: {:?}", e));
+
+            let (image, image_view, depth_buffer, msaa_buffer) = match (image, image_view, depth_buffer, msaa_buffer) {
+                (Ok(image), Ok(image_view), Ok(depth_buffer), Ok(msaa_buffer)) => (image, image_view, depth_buffer, msaa_buffer),
+                (image, image_view, depth_buffer, msaa_buffer) => {
+                    for e in [image.err(), image_view.err(), depth_buffer.err(), msaa_buffer.err()].iter().flatten() {
+                        eprintln!("{}", e);
+                    }
+                    return;
+                }
+            };
+
+            match self.texture_id {
+                None => {
+                    let texture_id = system.renderer.textures().insert((image_view, Sampler::simple_repeat_linear(system.device.clone())));
+                    self.texture_id = Some(texture_id);
+                }
+                Some(texture_id) => {
+                    system.renderer.textures().replace(texture_id, (image_view, Sampler::simple_repeat_linear(system.device.clone())));
+                }
+            }
 
             self.image = Some(image);
             self.depth_image = Some(depth_buffer);
@@ -90,15 +112,25 @@ impl Viewport {
 
         if let (Some(ref image), Some(ref depth_buffer), Some(ref msaa_buffer)) = (&self.image, &self.depth_image, &self.msaa_image) {
 
-            let framebuffer = Arc::new(
-                Framebuffer::start(render_pass)
-                    .add(ImageView::new(depth_buffer.clone()).unwrap()).unwrap()
-                    .add(ImageView::new(msaa_buffer.clone()).unwrap()).unwrap()
-                    .add(ImageView::new(image.clone()).unwrap()).unwrap()
-                    .build().unwrap()
-            );
+            let framebuffer = (|| {
+                let depth_view = ImageView::new(depth_buffer.clone()).map_err(|e| format!("{:?}", e))?;
+                let msaa_view = ImageView::new(msaa_buffer.clone()).map_err(|e| format!("{:?}", e))?;
+                let image_view = ImageView::new(image.clone()).map_err(|e| format!("{:?}", e))?;
 
-            Some(Arc::new(framebuffer))
+                Framebuffer::start(render_pass)
+                    .add(depth_view).map_err(|e| format!("{:?}", e))?
+                    .add(msaa_view).map_err(|e| format!("{:?}", e))?
+                    .add(image_view).map_err(|e| format!("{:?}", e))?
+                    .build().map_err(|e| format!("{:?}", e))
+            })();
+
+            match framebuffer {
+                Ok(framebuffer) => Some(Arc::new(Arc::new(framebuffer))),
+                Err(e) => {
+                    eprintln!("Failed to create viewport framebuffer: {}", e);
+                    None
+                }
+            }
         } else {
             None
         }